@@ -0,0 +1,158 @@
+//! The in-memory image type shared by the renderer and the viewer, so that a render can be handed
+//! to the viewer without going through a file, and the bits of display processing they both do.
+
+use std::ops::{Index, IndexMut};
+
+use rayon::prelude::*;
+
+/// Pixels in rows, top row first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image<T> {
+    pub data: Box<[T]>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl<T> Image<T> {
+    pub fn new(width: usize, height: usize, init: impl Fn(usize, usize) -> T) -> Self {
+        let init = &init;
+        let data = (0..height)
+            .flat_map(|y| (0..width).map(move |x| init(x, y)))
+            .collect();
+        Image {
+            data,
+            width,
+            height,
+        }
+    }
+
+    /// The same image with `f` applied to every pixel, e.g. to convert between vector types.
+    pub fn map<U>(&self, f: impl Fn(&T) -> U) -> Image<U> {
+        Image {
+            data: self.data.iter().map(f).collect(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+impl<T: Send> Image<T> {
+    pub fn par_new(width: usize, height: usize, init: impl Fn(usize, usize) -> T + Sync) -> Self {
+        let data = (0..width * height)
+            .into_par_iter()
+            .map(|i| init(i % width, i / width))
+            .collect();
+        Image {
+            data,
+            width,
+            height,
+        }
+    }
+}
+
+impl<T> Index<(usize, usize)> for Image<T> {
+    type Output = T;
+
+    fn index(&self, (x, y): (usize, usize)) -> &Self::Output {
+        assert!(x < self.width);
+        assert!(y < self.height);
+        &self.data[x + y * self.width]
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Image<T> {
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut Self::Output {
+        assert!(x < self.width);
+        assert!(y < self.height);
+        &mut self.data[x + y * self.width]
+    }
+}
+
+/// The threshold in `[0, 1)` at which pixel `(x, y)` rounds up when dithering, from Martin
+/// Roberts' R2 sequence. Nearby thresholds are spread out evenly like blue noise, so the dither is
+/// fine grained without needing a precomputed noise texture.
+pub fn dither_threshold(x: usize, y: usize) -> f64 {
+    // the plastic number, the R2 sequence's analog of the golden ratio
+    const G: f64 = 1.324_717_957_244_746;
+    (x as f64 / G + y as f64 / (G * G)).fract()
+}
+
+/// Settings of a physical camera, which turn the absolute luminance of a render into the fraction
+/// of the sensor's saturation it exposes, so that a dim scene comes out dim instead of being
+/// brightened to the same average as every other scene.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CameraExposure {
+    /// ISO speed, by the saturation-based definition of ISO 12232.
+    pub iso: f64,
+    pub shutter_seconds: f64,
+    pub f_stop: f64,
+}
+
+impl CameraExposure {
+    /// The settings of the "sunny 16" rule at ISO 100, which suit a scene in full daylight.
+    pub const SUNNY_16: CameraExposure = CameraExposure {
+        iso: 100.0,
+        shutter_seconds: 0.01,
+        f_stop: 16.0,
+    };
+
+    /// The settings given on a command line, with those left out taken from [`Self::SUNNY_16`], or
+    /// `None` if none of them are given.
+    pub fn from_args(iso: Option<f64>, shutter: Option<f64>, f_stop: Option<f64>) -> Option<Self> {
+        if iso.is_none() && shutter.is_none() && f_stop.is_none() {
+            return None;
+        }
+        let default = CameraExposure::SUNNY_16;
+        Some(CameraExposure {
+            iso: iso.unwrap_or(default.iso),
+            shutter_seconds: shutter.unwrap_or(default.shutter_seconds),
+            f_stop: f_stop.unwrap_or(default.f_stop),
+        })
+    }
+
+    /// Factor from luminance in cd/m² to the fraction of saturation, so that 1 is the brightest
+    /// value the sensor records. From the photographic exposure equation, a luminance `L` exposes
+    /// the sensor to `q L t / N²` lux seconds with `q = 0.65`, and saturation is at `78 / S`.
+    pub fn scale(&self) -> f64 {
+        self.shutter_seconds * self.iso / (1.2 * 100.0 * self.f_stop * self.f_stop)
+    }
+
+    /// The exposure value at ISO 100 that the settings are equivalent to.
+    pub fn ev100(&self) -> f64 {
+        (self.f_stop * self.f_stop / self.shutter_seconds * 100.0 / self.iso).log2()
+    }
+}
+
+/// Parses a shutter time in seconds, either as a number or as a fraction like `1/250`.
+pub fn parse_shutter(s: &str) -> Result<f64, String> {
+    let parse = |s: &str| s.trim().parse::<f64>().map_err(|e| e.to_string());
+    let seconds = match s.split_once('/') {
+        Some((num, den)) => parse(num)? / parse(den)?,
+        None => parse(s)?,
+    };
+    match seconds.is_finite() && seconds > 0.0 {
+        true => Ok(seconds),
+        false => Err(format!("shutter time must be positive, got {s}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn par_new_matches_new() {
+        // an odd size, so that the rows don't split evenly between threads
+        let init = |x: usize, y: usize| {
+            let v = (x as f32 * 0.37 + y as f32 * 1.9).sin() * 1e3;
+            [v / (1.0 + v.abs()), v.ln_1p(), v * v]
+        };
+        let serial = Image::new(317, 211, init);
+        let parallel = Image::par_new(317, 211, init);
+        // compared as bits, since some of the logarithms are NaN
+        let bits = |image: &Image<[f32; 3]>| -> Vec<_> {
+            image.data.iter().map(|p| p.map(f32::to_bits)).collect()
+        };
+        assert_eq!(bits(&parallel), bits(&serial));
+    }
+}
@@ -1,9 +1,13 @@
 use std::f64::consts::PI;
+use std::sync::Arc;
 
-use glam::{DMat3, DVec3, DVec4, FloatExt, Vec3Swizzles};
+use glam::{DMat3, DVec2, DVec3, DVec4, FloatExt, Vec3Swizzles};
+use rand::Rng;
 
 use crate::random;
+use crate::spectrum::rgb::RgbAlbedo;
 use crate::spectrum::Spectrum;
+use crate::texture::{Filter, Texture};
 
 mod phong;
 pub use phong::*;
@@ -18,8 +22,22 @@ pub struct BrdfSample {
     pub dir: DVec3,
     pub pdf: f64,
     pub f: DVec4,
+    /// The sampled direction only holds for the hero wavelength `lambdas.x`, as with dispersive
+    /// refraction. The rest of the path is traced for the hero wavelength alone, so whatever it
+    /// passes through afterwards, like an absorbing medium behind the surface, only attenuates
+    /// that wavelength. Tinting still comes out right on average, since each wavelength takes its
+    /// turn as the hero.
     pub terminate_secondary: bool,
     pub singular: bool,
+    pub lobe: Lobe,
+}
+
+/// Which kind of reflection produced a [`BrdfSample`], used to split the image into AOVs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lobe {
+    Diffuse,
+    /// Singular and glossy reflection and transmission.
+    Specular,
 }
 
 pub trait Brdf: Send + Sync {
@@ -31,7 +49,15 @@ pub trait Brdf: Send + Sync {
     ///
     /// This function should be *energy conserving*: for all `outgoing`, the integral of
     /// `f(incoming, outgoing) * cos(theta)` wrt `incoming` over the sphere should be <= 1.
-    fn f(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambdas: DVec4) -> DVec4;
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4;
 
     /// Samples an incoming light direction from a distribution approximating [`Bsdf::f`], given
     /// canonical random variables on `[0, 1)`.
@@ -43,19 +69,37 @@ pub trait Brdf: Send + Sync {
     ///
     /// The default implementation samples the hemisphere with a cosine-weighted distribution,
     /// which effectively importance samples the `cos(theta)` term in the rendering equation.
-    fn sample(&self, outgoing: DVec3, normal: DVec3, lambdas: DVec4, random: DVec3) -> BrdfSample {
+    fn sample(
+        &self,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+        random: DVec3,
+    ) -> BrdfSample {
         _ = outgoing;
+        let Some((frame_x, frame_y)) = crate::build_frame(normal) else {
+            return BrdfSample {
+                dir: DVec3::ZERO,
+                pdf: 0.0,
+                f: DVec4::ZERO,
+                terminate_secondary: false,
+                singular: false,
+                lobe: Lobe::Diffuse,
+            };
+        };
         let d = random::disk(random.xy());
         let z = (1.0 - d.length_squared()).sqrt();
-        let (tangent, bitangent) = normal.any_orthonormal_pair();
-        let incoming = d.x * tangent + d.y * bitangent + z * normal;
+        let incoming = d.x * frame_x + d.y * frame_y + z * normal;
 
         BrdfSample {
             dir: incoming,
-            pdf: self.pdf(incoming, outgoing, normal, lambdas.x),
-            f: self.f(incoming, outgoing, normal, lambdas),
+            pdf: self.pdf(incoming, outgoing, normal, tangent, uv, lambdas.x),
+            f: self.f(incoming, outgoing, normal, tangent, uv, lambdas),
             terminate_secondary: false,
             singular: false,
+            lobe: Lobe::Diffuse,
         }
     }
 
@@ -66,11 +110,76 @@ pub trait Brdf: Send + Sync {
     ///
     /// Since this is a PDF, for all `outgoing`, the integral of `pdf(incoming, outgoing)` wrt
     /// `incoming` over the sphere should be exactly 1.
-    fn pdf(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambda: f64) -> f64 {
+    fn pdf(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambda: f64,
+    ) -> f64 {
         _ = outgoing;
+        _ = tangent;
+        _ = uv;
         _ = lambda;
         incoming.dot(normal).max(0.0) / PI
     }
+
+    /// Whether [`sample`](Self::sample) can send rays through the surface, which then needs media
+    /// for them to pass into.
+    fn transmits(&self) -> bool {
+        false
+    }
+
+    /// The lobe all of [`f`](Self::f) belongs to, for BRDFs with only one.
+    fn lobe(&self) -> Lobe {
+        Lobe::Diffuse
+    }
+
+    /// [`f`](Self::f) split into its diffuse and specular parts, in that order, so that light
+    /// reflected off a mix of lobes can be credited to each of their AOVs.
+    fn f_lobes(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> [DVec4; 2] {
+        let f = self.f(incoming, outgoing, normal, tangent, uv, lambdas);
+        match self.lobe() {
+            Lobe::Diffuse => [f, DVec4::ZERO],
+            Lobe::Specular => [DVec4::ZERO, f],
+        }
+    }
+}
+
+/// Estimates the reflectance of `brdf` placed in a uniform unit-radiance environment (a "white
+/// furnace") as seen from `outgoing`, by Monte-Carlo integrating over [`Brdf::sample`].
+///
+/// An energy-conserving BRDF never exceeds 1, and a lossless one approaches 1. For BRDFs which
+/// terminate secondary wavelengths (dispersive refraction), only the hero wavelength (`x`) is
+/// meaningful.
+pub fn furnace(
+    brdf: &dyn Brdf,
+    outgoing: DVec3,
+    normal: DVec3,
+    tangent: Option<DVec3>,
+    uv: Option<DVec2>,
+    lambdas: DVec4,
+    samples: usize,
+) -> DVec4 {
+    let mut total = DVec4::ZERO;
+    for _ in 0..samples {
+        let sample = brdf.sample(outgoing, normal, tangent, uv, lambdas, random::rng().gen());
+        if sample.dir == DVec3::ZERO || sample.pdf == 0.0 {
+            continue;
+        }
+        total += sample.f * sample.dir.dot(normal).abs() / sample.pdf;
+    }
+    total / samples as f64
 }
 
 #[derive(Clone)]
@@ -79,9 +188,19 @@ pub struct LambertianBrdf<S> {
 }
 
 impl<S: Spectrum> Brdf for LambertianBrdf<S> {
-    fn f(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambdas: DVec4) -> DVec4 {
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4 {
         _ = incoming;
         _ = outgoing;
+        _ = tangent;
+        _ = uv;
         if incoming.dot(normal) < 0.0 {
             return DVec4::ZERO;
         }
@@ -89,6 +208,37 @@ impl<S: Spectrum> Brdf for LambertianBrdf<S> {
     }
 }
 
+/// A Lambertian surface with its albedo looked up in `texture` at the hit's texture coordinates.
+/// Surfaces without texture coordinates get the albedo at `(0, 0)`.
+#[allow(unused)]
+#[derive(Clone)]
+pub struct TexturedLambertianBrdf {
+    pub texture: Arc<Texture<RgbAlbedo>>,
+    pub filter: Filter,
+}
+
+impl Brdf for TexturedLambertianBrdf {
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4 {
+        _ = outgoing;
+        _ = tangent;
+        if incoming.dot(normal) < 0.0 {
+            return DVec4::ZERO;
+        }
+        let uv = uv.unwrap_or_default();
+        self.texture
+            .lookup_with(uv, self.filter, |albedo| albedo.sample_multi(lambdas))
+            / PI
+    }
+}
+
 #[derive(Clone)]
 pub struct CompositeBrdf<A, B> {
     pub a_weight: f64,
@@ -97,26 +247,44 @@ pub struct CompositeBrdf<A, B> {
 }
 
 impl<A: Brdf, B: Brdf> Brdf for CompositeBrdf<A, B> {
-    fn f(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambdas: DVec4) -> DVec4 {
-        let a = self.a.f(incoming, outgoing, normal, lambdas);
-        let b = self.b.f(incoming, outgoing, normal, lambdas);
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4 {
+        let a = self.a.f(incoming, outgoing, normal, tangent, uv, lambdas);
+        let b = self.b.f(incoming, outgoing, normal, tangent, uv, lambdas);
         a.lerp(b, 1.0 - self.a_weight)
     }
 
-    fn sample(&self, outgoing: DVec3, normal: DVec3, lambdas: DVec4, random: DVec3) -> BrdfSample {
+    fn sample(
+        &self,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+        random: DVec3,
+    ) -> BrdfSample {
         if random.z < self.a_weight {
             let mut sample = self.a.sample(
                 outgoing,
                 normal,
+                tangent,
+                uv,
                 lambdas,
                 random.with_z(random.z / self.a_weight),
             );
             sample.pdf = sample.pdf.lerp(
-                self.b.pdf(sample.dir, outgoing, normal, lambdas.x),
+                self.b.pdf(sample.dir, outgoing, normal, tangent, uv, lambdas.x),
                 1.0 - self.a_weight,
             );
             sample.f = sample.f.lerp(
-                self.b.f(sample.dir, outgoing, normal, lambdas),
+                self.b.f(sample.dir, outgoing, normal, tangent, uv, lambdas),
                 1.0 - self.a_weight,
             );
             sample
@@ -124,26 +292,103 @@ impl<A: Brdf, B: Brdf> Brdf for CompositeBrdf<A, B> {
             let mut sample = self.b.sample(
                 outgoing,
                 normal,
+                tangent,
+                uv,
                 lambdas,
                 random.with_z((random.z - self.a_weight) / (1.0 - self.a_weight)),
             );
             sample.pdf = sample.pdf.lerp(
-                self.a.pdf(sample.dir, outgoing, normal, lambdas.x),
+                self.a
+                    .pdf(sample.dir, outgoing, normal, tangent, uv, lambdas.x),
                 self.a_weight,
             );
             sample.f = sample.f.lerp(
-                self.a.f(sample.dir, outgoing, normal, lambdas),
+                self.a.f(sample.dir, outgoing, normal, tangent, uv, lambdas),
                 self.a_weight,
             );
             sample
         }
     }
 
-    fn pdf(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambda: f64) -> f64 {
-        let a = self.a.pdf(incoming, outgoing, normal, lambda);
-        let b = self.b.pdf(incoming, outgoing, normal, lambda);
+    fn pdf(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambda: f64,
+    ) -> f64 {
+        let a = self.a.pdf(incoming, outgoing, normal, tangent, uv, lambda);
+        let b = self.b.pdf(incoming, outgoing, normal, tangent, uv, lambda);
         a.lerp(b, 1.0 - self.a_weight)
     }
+
+    fn transmits(&self) -> bool {
+        self.a.transmits() || self.b.transmits()
+    }
+
+    fn f_lobes(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> [DVec4; 2] {
+        let a = self
+            .a
+            .f_lobes(incoming, outgoing, normal, tangent, uv, lambdas);
+        let b = self
+            .b
+            .f_lobes(incoming, outgoing, normal, tangent, uv, lambdas);
+        [0, 1].map(|i| a[i].lerp(b[i], 1.0 - self.a_weight))
+    }
+}
+
+/// A distribution of microfacet normals for rough surfaces, with the Smith shadowing-masking
+/// model. As with [`Brdf`], `outgoing` is the direction of travel towards the surface.
+pub trait MicrofacetDistribution: Send + Sync {
+    /// Density of micro normals per unit projected area, so that the integral of
+    /// `d(m) * m.dot(macro_normal)` over the hemisphere is 1.
+    fn d(&self, micro_normal: DVec3, macro_normal: DVec3) -> f64;
+
+    /// Smith's auxiliary function, the ratio of masked to visible projected microfacet area seen
+    /// from `d`.
+    fn lambda(&self, d: DVec3, macro_normal: DVec3) -> f64;
+
+    /// Close enough to a perfect mirror that it should be treated as one.
+    fn effectively_smooth(&self) -> bool;
+
+    /// Samples a micro normal from the distribution of normals visible from `outgoing`, which has
+    /// the density [`density`](Self::density).
+    fn sample_micro_normal(
+        &self,
+        outgoing: DVec3,
+        macro_normal: DVec3,
+        random: DVec3,
+    ) -> Option<DVec3>;
+
+    fn g1(&self, d: DVec3, macro_normal: DVec3) -> f64 {
+        1.0 / (1.0 + self.lambda(d, macro_normal))
+    }
+
+    fn g(&self, incoming: DVec3, outgoing: DVec3, macro_normal: DVec3) -> f64 {
+        1.0 / (1.0 + self.lambda(incoming, macro_normal) + self.lambda(-outgoing, macro_normal))
+    }
+
+    fn density(&self, outgoing: DVec3, micro_normal: DVec3, macro_normal: DVec3) -> f64 {
+        let outgoing = -outgoing;
+        // micro normals facing away from `outgoing` aren't visible from it
+        self.g1(outgoing, macro_normal) / outgoing.dot(macro_normal).abs()
+            * self.d(micro_normal, macro_normal)
+            * outgoing.dot(micro_normal).max(0.0)
+    }
+
+    fn micro_normal_pdf(&self, d: DVec3, micro_normal: DVec3, macro_normal: DVec3) -> f64 {
+        self.density(d, micro_normal, macro_normal)
+    }
 }
 
 #[derive(Clone)]
@@ -151,8 +396,8 @@ pub struct TrowbridgeReitzDistribution {
     pub alpha: f64,
 }
 
-impl TrowbridgeReitzDistribution {
-    pub fn d(&self, micro_normal: DVec3, macro_normal: DVec3) -> f64 {
+impl MicrofacetDistribution for TrowbridgeReitzDistribution {
+    fn d(&self, micro_normal: DVec3, macro_normal: DVec3) -> f64 {
         let alpha2 = self.alpha * self.alpha;
         let cos_theta = micro_normal.dot(macro_normal);
         let cos2_theta = cos_theta * cos_theta;
@@ -166,15 +411,11 @@ impl TrowbridgeReitzDistribution {
         1.0 / (PI * alpha2 * cos4_theta * t * t)
     }
 
-    pub fn effectively_smooth(&self) -> bool {
+    fn effectively_smooth(&self) -> bool {
         self.alpha < 0.001
     }
 
-    pub fn g1(&self, d: DVec3, macro_normal: DVec3) -> f64 {
-        1.0 / (1.0 + self.lambda(d, macro_normal))
-    }
-
-    pub fn lambda(&self, d: DVec3, macro_normal: DVec3) -> f64 {
+    fn lambda(&self, d: DVec3, macro_normal: DVec3) -> f64 {
         let cos_theta = d.dot(macro_normal);
         let cos2_theta = cos_theta * cos_theta;
         let sin2_theta = 1.0 - cos2_theta;
@@ -185,43 +426,859 @@ impl TrowbridgeReitzDistribution {
         ((1.0 + self.alpha * self.alpha * tan2_theta).sqrt() - 1.0) / 2.0
     }
 
-    pub fn g(&self, incoming: DVec3, outgoing: DVec3, macro_normal: DVec3) -> f64 {
-        1.0 / (1.0 + self.lambda(incoming, macro_normal) + self.lambda(-outgoing, macro_normal))
+    fn sample_micro_normal(
+        &self,
+        outgoing: DVec3,
+        macro_normal: DVec3,
+        random: DVec3,
+    ) -> Option<DVec3> {
+        sample_trowbridge_reitz_visible(outgoing, macro_normal, DVec2::splat(self.alpha), random)
     }
+}
 
-    pub fn density(&self, outgoing: DVec3, micro_normal: DVec3, macro_normal: DVec3) -> f64 {
-        let outgoing = -outgoing;
-        self.g1(outgoing, macro_normal) / outgoing.dot(macro_normal).abs()
-            * self.d(micro_normal, macro_normal)
-            * outgoing.dot(micro_normal).abs()
+/// [`TrowbridgeReitzDistribution`] with different roughnesses along the two axes of
+/// [`build_frame`](crate::build_frame)`(macro_normal)`, for brushed metal and the like. Used in
+/// [`RoughConductorBrdf`], `alpha_x` runs along the surface's tangent.
+#[allow(unused)]
+#[derive(Clone)]
+pub struct AnisotropicTrowbridgeReitzDistribution {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+impl AnisotropicTrowbridgeReitzDistribution {
+    /// `d` in the frame of the macro normal, with z along the normal.
+    fn to_local(d: DVec3, macro_normal: DVec3) -> Option<DVec3> {
+        let (macro_x, macro_y) = crate::build_frame(macro_normal)?;
+        Some(DVec3::new(
+            d.dot(macro_x),
+            d.dot(macro_y),
+            d.dot(macro_normal),
+        ))
     }
+}
 
-    pub fn micro_normal_pdf(&self, d: DVec3, micro_normal: DVec3, macro_normal: DVec3) -> f64 {
-        self.density(d, micro_normal, macro_normal)
+impl MicrofacetDistribution for AnisotropicTrowbridgeReitzDistribution {
+    fn d(&self, micro_normal: DVec3, macro_normal: DVec3) -> f64 {
+        let Some(m) = Self::to_local(micro_normal, macro_normal) else {
+            return 0.0;
+        };
+        let cos2_theta = m.z * m.z;
+        if cos2_theta == 0.0 {
+            return 0.0;
+        }
+        // tan²θ (cos²φ / αx² + sin²φ / αy²), with the tangent-plane part of m standing in for
+        // sinθ (cosφ, sinφ)
+        let e = (m.x * m.x / (self.alpha_x * self.alpha_x)
+            + m.y * m.y / (self.alpha_y * self.alpha_y))
+            / cos2_theta;
+        let t = 1.0 + e;
+        1.0 / (PI * self.alpha_x * self.alpha_y * cos2_theta * cos2_theta * t * t)
+    }
+
+    fn effectively_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < 0.001
+    }
+
+    fn lambda(&self, d: DVec3, macro_normal: DVec3) -> f64 {
+        let Some(d) = Self::to_local(d, macro_normal) else {
+            return 0.0;
+        };
+        let cos2_theta = d.z * d.z;
+        if cos2_theta == 0.0 {
+            return 0.0;
+        }
+        let alpha2_tan2_theta =
+            ((d.x * self.alpha_x).powi(2) + (d.y * self.alpha_y).powi(2)) / cos2_theta;
+        ((1.0 + alpha2_tan2_theta).sqrt() - 1.0) / 2.0
+    }
+
+    fn sample_micro_normal(
+        &self,
+        outgoing: DVec3,
+        macro_normal: DVec3,
+        random: DVec3,
+    ) -> Option<DVec3> {
+        let alpha = DVec2::new(self.alpha_x, self.alpha_y);
+        sample_trowbridge_reitz_visible(outgoing, macro_normal, alpha, random)
+    }
+}
+
+/// Samples the Trowbridge-Reitz normals visible from `outgoing` by stretching the view direction
+/// to unit roughness, sampling the visible part of the hemisphere there and stretching back, with
+/// `alpha` along the axes of [`build_frame`](crate::build_frame)`(macro_normal)`.
+fn sample_trowbridge_reitz_visible(
+    outgoing: DVec3,
+    macro_normal: DVec3,
+    alpha: DVec2,
+    random: DVec3,
+) -> Option<DVec3> {
+    let (macro_x, macro_y) = crate::build_frame(macro_normal)?;
+    let to_global = DMat3::from_cols(macro_x, macro_y, macro_normal);
+    let to_local = to_global.transpose();
+    let outgoing = to_local * outgoing;
+
+    let mut wh = (outgoing.xy() * alpha).extend(outgoing.z).normalize();
+    if wh.z < 0.0 {
+        wh = -wh;
+    }
+
+    // the warp below squeezes samples towards the side of the disk away from the horizon, so
+    // t2 has to lie in the plane of wh and the normal
+    let t1 = match wh.z < 0.9999 {
+        true => wh.cross(DVec3::Z).normalize(),
+        false => DVec3::X,
+    };
+    let t2 = t1.cross(wh);
+
+    let mut p = crate::random::disk(random.xy());
+
+    let h = (1.0 - p.x * p.x).sqrt();
+    p.y = h.lerp(p.y, (1.0 + wh.z) / 2.0);
+
+    let pz = 0.0f64.max(1.0 - p.length_squared()).sqrt();
+    let nh = p.x * t1 + p.y * t2 + pz * wh;
+    let r = (nh.xy() * alpha).extend(nh.z.max(1e-6)).normalize();
+
+    Some(to_global * r)
+}
+
+/// The Beckmann distribution, a Gaussian distribution of microfacet slopes with standard
+/// deviation `alpha / sqrt(2)`. It falls off much faster away from the macro normal than
+/// [`TrowbridgeReitzDistribution`] at the same `alpha`, so highlights have shorter tails.
+#[allow(unused)]
+#[derive(Clone)]
+pub struct BeckmannDistribution {
+    pub alpha: f64,
+}
+
+impl MicrofacetDistribution for BeckmannDistribution {
+    fn d(&self, micro_normal: DVec3, macro_normal: DVec3) -> f64 {
+        let alpha2 = self.alpha * self.alpha;
+        let cos_theta = micro_normal.dot(macro_normal);
+        let cos2_theta = cos_theta * cos_theta;
+        let cos4_theta = cos2_theta * cos2_theta;
+        let sin2_theta = 1.0 - cos2_theta;
+        let tan2_theta = sin2_theta / cos2_theta;
+        if tan2_theta.is_infinite() {
+            return 0.0;
+        }
+        (-tan2_theta / alpha2).exp() / (PI * alpha2 * cos4_theta)
     }
 
-    pub fn sample_micro_normal(&self, outgoing: DVec3, macro_normal: DVec3, random: DVec3) -> DVec3 {
-        let (macro_x, macro_y) = macro_normal.any_orthonormal_pair();
+    fn effectively_smooth(&self) -> bool {
+        self.alpha < 0.001
+    }
+
+    fn lambda(&self, d: DVec3, macro_normal: DVec3) -> f64 {
+        let cos_theta = d.dot(macro_normal);
+        let cos2_theta = cos_theta * cos_theta;
+        let tan_theta = ((1.0 - cos2_theta).max(0.0) / cos2_theta).sqrt();
+        if tan_theta.is_infinite() {
+            return 0.0;
+        }
+        let a = 1.0 / (self.alpha * tan_theta);
+        if a.is_infinite() {
+            return 0.0;
+        }
+        (erf(a) - 1.0) / 2.0 + (-a * a).exp() / (2.0 * a * PI.sqrt())
+    }
+
+    /// Samples the visible slopes of the unit-roughness distribution as seen from the stretched
+    /// view direction, then rotates and unstretches them, as in Heitz & d'Eon 2014.
+    fn sample_micro_normal(
+        &self,
+        outgoing: DVec3,
+        macro_normal: DVec3,
+        random: DVec3,
+    ) -> Option<DVec3> {
+        let (macro_x, macro_y) = crate::build_frame(macro_normal)?;
         let to_global = DMat3::from_cols(macro_x, macro_y, macro_normal);
         let to_local = to_global.transpose();
         let outgoing = to_local * outgoing;
 
-        let mut wh = (outgoing.xy() * self.alpha).extend(outgoing.z).normalize();
-        if wh.z < 0.0 {
-            wh = -wh;
+        let mut wi = (outgoing.xy() * self.alpha).extend(outgoing.z).normalize();
+        if wi.z < 0.0 {
+            wi = -wi;
+        }
+
+        let slope = sample_beckmann_visible_slope(wi.z, random.x, random.y);
+        let (sin_phi, cos_phi) = match wi.xy().try_normalize() {
+            Some(d) => (d.y, d.x),
+            None => (0.0, 1.0),
+        };
+        let slope = DVec2::new(
+            cos_phi * slope.x - sin_phi * slope.y,
+            sin_phi * slope.x + cos_phi * slope.y,
+        ) * self.alpha;
+
+        Some(to_global * (-slope).extend(1.0).normalize())
+    }
+}
+
+/// Samples the slopes of a unit-roughness Beckmann surface visible from a direction at
+/// `cos_theta` to the normal and azimuth 0, by inverting the CDF of the x slope with Newton's
+/// method. This follows pbrt-v3, including its fitted initial guess.
+fn sample_beckmann_visible_slope(cos_theta: f64, u1: f64, u2: f64) -> DVec2 {
+    let sqrt_pi_inv = 1.0 / PI.sqrt();
+
+    if cos_theta > 0.9999 {
+        // normal incidence, where every slope is equally visible
+        let r = (-(1.0 - u1).ln()).sqrt();
+        let (sin_phi, cos_phi) = (2.0 * PI * u2).sin_cos();
+        return DVec2::new(r * cos_phi, r * sin_phi);
+    }
+
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let tan_theta = sin_theta / cos_theta;
+    let cot_theta = 1.0 / tan_theta;
+
+    let mut a = -1.0;
+    let mut c = erf(cot_theta);
+    let u1 = u1.max(1e-6);
+
+    let theta = cos_theta.acos();
+    let fit = 1.0 + theta * (-0.876 + theta * (0.4265 - 0.0594 * theta));
+    let mut b = c - (1.0 + c) * (1.0 - u1).powf(fit);
+
+    let normalization = 1.0 / (1.0 + c + sqrt_pi_inv * tan_theta * (-cot_theta * cot_theta).exp());
+
+    for _ in 0..10 {
+        // bisection keeps b in the bracket when a Newton step overshoots
+        if !(a..=c).contains(&b) {
+            b = 0.5 * (a + c);
         }
 
-        let (t1, t2) = wh.any_orthonormal_pair();
+        let inv_erf = erf_inv(b);
+        let value =
+            normalization * (1.0 + b + sqrt_pi_inv * tan_theta * (-inv_erf * inv_erf).exp()) - u1;
+        let derivative = normalization * (1.0 - inv_erf * tan_theta);
 
-        let mut p = crate::random::disk(random.xy());
+        if value.abs() < 1e-5 {
+            break;
+        }
+
+        if value > 0.0 {
+            c = b;
+        } else {
+            a = b;
+        }
+
+        b -= value / derivative;
+    }
+
+    DVec2::new(erf_inv(b), erf_inv(2.0 * u2.max(1e-6) - 1.0))
+}
+
+/// Abramowitz & Stegun 7.1.26, accurate to about 1e-7.
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    (1.0 - poly * (-x * x).exp()).copysign(x)
+}
+
+/// Giles' single-precision approximation of the inverse error function.
+fn erf_inv(x: f64) -> f64 {
+    let x = x.clamp(-0.99999, 0.99999);
+    let mut w = -((1.0 - x) * (1.0 + x)).ln();
+    let p = if w < 5.0 {
+        w -= 2.5;
+        [
+            3.43273939e-07,
+            -3.5233877e-06,
+            -4.39150654e-06,
+            0.00021858087,
+            -0.00125372503,
+            -0.00417768164,
+            0.246640727,
+            1.50140941,
+        ]
+        .iter()
+        .fold(2.81022636e-08, |p, c| c + p * w)
+    } else {
+        w = w.sqrt() - 3.0;
+        [
+            0.000100950558,
+            0.00134934322,
+            -0.00367342844,
+            0.00573950773,
+            -0.0076224613,
+            0.00943887047,
+            1.00167406,
+            2.83297682,
+        ]
+        .iter()
+        .fold(-0.000200214257, |p, c| c + p * w)
+    };
+    p * x
+}
 
-        let h = (1.0 - p.x * p.x).sqrt();
-        p.y = h.lerp(p.y, (1.0 + wh.z) / 2.0);
+#[cfg(test)]
+mod tests {
+    use glam::DQuat;
 
-        let pz = 0.0f64.max(1.0 - p.length_squared()).sqrt();
-        let nh = p.x * t1 + p.y * t2 + pz * wh;
-        let r = (nh.xy() * self.alpha).extend(nh.z.max(1e-6)).normalize();
+    use super::*;
+    use crate::material::physical::{ior_gold, ior_silver};
+    use crate::material::Material;
+    use crate::objects::{Object, Quad};
+    use crate::random::tests::check_sampler;
+    use crate::spectrum::ConstantSpectrum;
+    use crate::texture::Wrap;
+
+    const LAMBDAS: DVec4 = DVec4::new(450.0, 520.0, 600.0, 680.0);
+
+    /// A conductor that reflects everything: an enormous extinction coefficient makes the
+    /// Fresnel reflectance 1 at every angle.
+    const MIRROR: [ConstantSpectrum; 2] = [ConstantSpectrum(1.0), ConstantSpectrum(1e4)];
+
+    /// Largest relative difference allowed between `f` with its directions swapped, and between
+    /// the pdf a sample reports and [`Brdf::pdf`] of its direction. Both are the same formula
+    /// evaluated twice, so only rounding separates them.
+    const RECIPROCITY_TOLERANCE: f64 = 1e-9;
+
+    /// The BRDFs with a non-singular distribution, so that [`Brdf::pdf`] is a density.
+    fn glossy_brdfs() -> Vec<(String, Box<dyn Brdf>)> {
+        let mut brdfs: Vec<(String, Box<dyn Brdf>)> = vec![
+            (
+                "lambertian".into(),
+                Box::new(LambertianBrdf {
+                    albedo: ConstantSpectrum(0.8),
+                }),
+            ),
+            (
+                "composite".into(),
+                Box::new(CompositeBrdf {
+                    a_weight: 0.4,
+                    a: LambertianBrdf {
+                        albedo: ConstantSpectrum(0.8),
+                    },
+                    b: RoughConductorBrdf::new(ior_silver(), 0.3),
+                }),
+            ),
+        ];
+        for alpha in [0.1, 0.3, 0.8] {
+            brdfs.push((
+                format!("trowbridge-reitz {alpha}"),
+                Box::new(RoughConductorBrdf::new(ior_silver(), alpha)),
+            ));
+            brdfs.push((
+                format!("beckmann {alpha}"),
+                Box::new(RoughConductorBrdf::with_distribution(
+                    ior_silver(),
+                    BeckmannDistribution { alpha },
+                )),
+            ));
+            brdfs.push((
+                format!("anisotropic {alpha}"),
+                Box::new(RoughConductorBrdf::with_distribution(
+                    ior_silver(),
+                    AnisotropicTrowbridgeReitzDistribution {
+                        alpha_x: alpha,
+                        alpha_y: alpha * 0.5,
+                    },
+                )),
+            ));
+        }
+        for power in [1.0, 10.0, 50.0] {
+            brdfs.push((
+                format!("phong {power}"),
+                Box::new(PhongSpecularBrdf {
+                    albedo: ConstantSpectrum(0.8),
+                    power,
+                }),
+            ));
+            brdfs.push((
+                format!("retro {power}"),
+                Box::new(PhongRetroBrdf {
+                    albedo: ConstantSpectrum(0.8),
+                    power,
+                }),
+            ));
+        }
+        brdfs
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= RECIPROCITY_TOLERANCE * a.abs().max(b.abs()).max(1.0)
+    }
+
+    #[test]
+    fn samples_follow_the_pdf() {
+        random::reseed([2346, 0, 0]);
+        let tangent = Some(DVec3::X);
+        for (name, brdf) in glossy_brdfs() {
+            for outgoing in [DVec3::new(0.3, -0.9, 0.1), DVec3::new(0.7, -0.3, -0.6)] {
+                let outgoing = outgoing.normalize();
+                let name = format!("{name} from {outgoing}");
+                check_sampler(
+                    &name,
+                    DVec3::Y,
+                    true,
+                    |incoming| brdf.pdf(incoming, outgoing, DVec3::Y, tangent, None, LAMBDAS.x),
+                    || {
+                        let random = random::rng().gen();
+                        let sample =
+                            brdf.sample(outgoing, DVec3::Y, tangent, None, LAMBDAS, random);
+                        if sample.dir == DVec3::ZERO {
+                            return None;
+                        }
+                        let pdf =
+                            brdf.pdf(sample.dir, outgoing, DVec3::Y, tangent, None, LAMBDAS.x);
+                        assert!(
+                            close(sample.pdf, pdf),
+                            "{name}: sampled pdf {} vs {pdf}",
+                            sample.pdf
+                        );
+                        Some(sample.dir)
+                    },
+                );
+            }
+        }
+    }
 
-        to_global * r
+    #[test]
+    fn brdfs_are_reciprocal() {
+        random::reseed([2346, 1, 0]);
+        let tangent = Some(DVec3::X);
+        let hemisphere = || {
+            let d = random::sphere(random::rng().gen());
+            d.with_y(d.y.abs())
+        };
+        for (name, brdf) in glossy_brdfs() {
+            for _ in 0..1000 {
+                let (a, b) = (hemisphere(), hemisphere());
+                let ab = brdf.f(a, -b, DVec3::Y, tangent, None, LAMBDAS);
+                let ba = brdf.f(b, -a, DVec3::Y, tangent, None, LAMBDAS);
+                assert!(
+                    (0..4).all(|i| close(ab[i], ba[i])),
+                    "{name}: f({a}, {b}) = {ab} but f({b}, {a}) = {ba}"
+                );
+            }
+        }
+    }
+
+    /// Furnace albedos with the normal along +y, from head on down to grazing incidence.
+    fn albedos(brdf: &dyn Brdf, from_below: bool) -> Vec<(f64, DVec4)> {
+        let tangent = Some(DVec3::X);
+        [1.0, 0.7, 0.4, 0.1]
+            .into_iter()
+            .map(|cos_o: f64| {
+                let sin_o = (1.0 - cos_o * cos_o).sqrt();
+                let mut outgoing = DVec3::new(sin_o * 0.8, -cos_o, sin_o * 0.6);
+                if from_below {
+                    outgoing.y = -outgoing.y;
+                }
+                let albedo = furnace(brdf, outgoing, DVec3::Y, tangent, None, LAMBDAS, 20_000);
+                (cos_o, albedo)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn furnace_albedo_is_at_most_one() {
+        random::reseed([2345, 0, 0]);
+        let white = Arc::new(Texture {
+            width: 1,
+            height: 1,
+            data: vec![RgbAlbedo {
+                a: 0.0,
+                b: 0.0,
+                c: 1e6,
+            }],
+            wrap: Wrap::Repeat,
+        });
+        let phong = |power| PhongSpecularBrdf {
+            albedo: ConstantSpectrum(1.0),
+            power,
+        };
+        let retro = |power| PhongRetroBrdf {
+            albedo: ConstantSpectrum(1.0),
+            power,
+        };
+
+        // (name, brdf, lossless, checked from below the surface too)
+        let mut cases: Vec<(String, Box<dyn Brdf>, bool, bool)> = vec![
+            (
+                "lambertian".into(),
+                Box::new(LambertianBrdf {
+                    albedo: ConstantSpectrum(1.0),
+                }),
+                true,
+                false,
+            ),
+            (
+                "textured lambertian".into(),
+                Box::new(TexturedLambertianBrdf {
+                    texture: white,
+                    filter: Filter::Nearest,
+                }),
+                true,
+                false,
+            ),
+            (
+                "smooth mirror".into(),
+                Box::new(SmoothConductorBrdf::new(&MIRROR)),
+                true,
+                false,
+            ),
+            (
+                "smooth silver".into(),
+                Box::new(SmoothConductorBrdf::new(ior_silver())),
+                false,
+                false,
+            ),
+            (
+                "thin dielectric".into(),
+                Box::new(ThinDielectricBrdf {
+                    ior: ConstantSpectrum(1.5),
+                }),
+                true,
+                true,
+            ),
+            (
+                "dielectric".into(),
+                Box::new(DielectricBrdf {
+                    ior: ConstantSpectrum(1.5),
+                }),
+                false,
+                false,
+            ),
+            (
+                "composite".into(),
+                Box::new(CompositeBrdf {
+                    a_weight: 0.3,
+                    a: LambertianBrdf {
+                        albedo: ConstantSpectrum(1.0),
+                    },
+                    b: SmoothConductorBrdf::new(&MIRROR),
+                }),
+                true,
+                false,
+            ),
+        ];
+        for alpha in [0.0, 0.05, 0.3, 0.8] {
+            cases.push((
+                format!("trowbridge-reitz {alpha}"),
+                Box::new(RoughConductorBrdf::new(&MIRROR, alpha)),
+                alpha == 0.0,
+                false,
+            ));
+            cases.push((
+                format!("beckmann {alpha}"),
+                Box::new(RoughConductorBrdf::with_distribution(
+                    &MIRROR,
+                    BeckmannDistribution { alpha },
+                )),
+                alpha == 0.0,
+                false,
+            ));
+            cases.push((
+                format!("anisotropic {alpha}"),
+                Box::new(RoughConductorBrdf::with_distribution(
+                    &MIRROR,
+                    AnisotropicTrowbridgeReitzDistribution {
+                        alpha_x: alpha,
+                        alpha_y: alpha * 0.25,
+                    },
+                )),
+                alpha == 0.0,
+                false,
+            ));
+        }
+        for power in [1.0, 10.0, 100.0] {
+            cases.push((
+                format!("phong {power}"),
+                Box::new(phong(power)),
+                false,
+                false,
+            ));
+            cases.push((
+                format!("retro {power}"),
+                Box::new(retro(power)),
+                false,
+                false,
+            ));
+        }
+
+        for (name, brdf, lossless, both_sides) in &cases {
+            let mut results = albedos(&**brdf, false);
+            if *both_sides {
+                results.extend(albedos(&**brdf, true));
+            }
+            for (cos_o, albedo) in results {
+                // Only the hero wavelength is meaningful for the dielectric, since refraction
+                // drops the others.
+                let albedo = match brdf.transmits() && !*lossless {
+                    true => DVec4::splat(albedo.x),
+                    false => albedo,
+                };
+                assert!(
+                    albedo.cmple(DVec4::splat(1.02)).all(),
+                    "{name} at cos {cos_o} has albedo {albedo}"
+                );
+                if *lossless {
+                    assert!(
+                        albedo.cmpge(DVec4::splat(0.98)).all(),
+                        "{name} at cos {cos_o} loses energy: albedo {albedo}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn beckmann_falls_off_faster_than_ggx() {
+        random::reseed([2389, 0, 0]);
+        let alpha = 0.3;
+        let ggx = TrowbridgeReitzDistribution { alpha };
+        let beckmann = BeckmannDistribution { alpha };
+        check_distribution("ggx", ggx.clone());
+        check_distribution("beckmann", beckmann.clone());
+
+        // the same peak, but Beckmann's tails are much thinner
+        let peak = ggx.d(DVec3::Y, DVec3::Y);
+        assert!((beckmann.d(DVec3::Y, DVec3::Y) - peak).abs() < 1e-12 * peak);
+        for tan in [2.0 * alpha, 3.0 * alpha, 5.0 * alpha] {
+            let micro_normal = DVec3::new(tan, 1.0, 0.0).normalize();
+            let g = ggx.d(micro_normal, DVec3::Y);
+            let b = beckmann.d(micro_normal, DVec3::Y);
+            assert!(b < 0.5 * g, "at tan {tan}, beckmann {b}, ggx {g}");
+        }
+
+        // so looking straight down, far fewer of its micro normals reflect below the horizon
+        let albedo =
+            |brdf: &dyn Brdf| furnace(brdf, -DVec3::Y, DVec3::Y, None, None, LAMBDAS, 50_000).x;
+        let ggx = albedo(&RoughConductorBrdf::with_distribution(&MIRROR, ggx));
+        let beckmann = albedo(&RoughConductorBrdf::with_distribution(&MIRROR, beckmann));
+        assert!(
+            beckmann > 0.99 && ggx < 0.9,
+            "albedo {beckmann} beckmann, {ggx} ggx"
+        );
+    }
+
+    /// Checks that the projected micro normal area of `distribution` integrates to 1, and that a
+    /// perfectly reflective surface with it doesn't gain energy.
+    fn check_distribution(name: &str, distribution: impl MicrofacetDistribution) {
+        const STEPS: usize = 2000;
+        // midpoint rule in cos theta
+        let mut integral = 0.0;
+        for i in 0..STEPS {
+            let cos = (i as f64 + 0.5) / STEPS as f64;
+            let m = DVec3::new((1.0 - cos * cos).sqrt(), cos, 0.0);
+            integral += distribution.d(m, DVec3::Y) * cos * 2.0 * PI / STEPS as f64;
+        }
+        assert!(
+            (integral - 1.0).abs() < 1e-3,
+            "{name} integrates to {integral}"
+        );
+
+        let brdf = RoughConductorBrdf::with_distribution(&MIRROR, distribution);
+        for cos_o in [1.0f64, 0.7, 0.4, 0.1] {
+            let outgoing = DVec3::new((1.0 - cos_o * cos_o).sqrt(), -cos_o, 0.0);
+            let albedo = furnace(&brdf, outgoing, DVec3::Y, None, None, LAMBDAS, 50_000).x;
+            assert!(albedo < 1.01, "{name} at cos {cos_o} has albedo {albedo}");
+        }
+    }
+
+    #[test]
+    fn phong_albedo_is_the_integral_of_f() {
+        const STEPS: usize = 600;
+        random::reseed([2368, 0, 0]);
+        for power in [1.0, 10.0, 50.0] {
+            let brdf = PhongSpecularBrdf {
+                albedo: ConstantSpectrum(0.8),
+                power,
+            };
+            // towards grazing, more and more of the lobe is below the horizon
+            for cos_o in [1.0, 0.5, 0.15] {
+                let sin_o = f64::sqrt(1.0 - cos_o * cos_o);
+                let outgoing = DVec3::new(sin_o, -cos_o, 0.0);
+
+                // midpoint rule over the hemisphere, in cos theta and phi
+                let mut integral = 0.0;
+                for i in 0..STEPS {
+                    let cos_i = (i as f64 + 0.5) / STEPS as f64;
+                    let sin_i = f64::sqrt(1.0 - cos_i * cos_i);
+                    for j in 0..STEPS {
+                        let phi = (j as f64 + 0.5) / STEPS as f64 * 2.0 * PI;
+                        let incoming = DVec3::new(sin_i * phi.cos(), cos_i, sin_i * phi.sin());
+                        let f = brdf.f(incoming, outgoing, DVec3::Y, None, None, LAMBDAS);
+                        integral += f.x * cos_i;
+                    }
+                }
+                integral *= 2.0 * PI / (STEPS * STEPS) as f64;
+
+                let sampled = furnace(&brdf, outgoing, DVec3::Y, None, None, LAMBDAS, 100_000).x;
+                assert!(
+                    (sampled - integral).abs() < 0.02 * integral,
+                    "phong {power} at cos {cos_o}: sampled albedo {sampled}, integral {integral}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_normal_samples_contribute_nothing() {
+        let brdf = LambertianBrdf {
+            albedo: ConstantSpectrum(0.5),
+        };
+        let outgoing = DVec3::new(0.3, -0.8, 0.1).normalize();
+        for normal in [DVec3::ZERO, DVec3::NAN, DVec3::new(0.0, 2.0, 0.0)] {
+            let sample = brdf.sample(outgoing, normal, None, None, LAMBDAS, DVec3::splat(0.3));
+            assert_eq!(sample.dir, DVec3::ZERO, "normal {normal}");
+            assert_eq!(sample.f, DVec4::ZERO, "normal {normal}");
+            assert_eq!(sample.pdf, 0.0, "normal {normal}");
+
+            let albedo = furnace(&brdf, outgoing, normal, None, None, LAMBDAS, 16);
+            assert_eq!(albedo, DVec4::ZERO, "normal {normal}");
+        }
+    }
+
+    #[test]
+    fn zero_alpha_is_a_smooth_mirror() {
+        let rough = RoughConductorBrdf::new(ior_gold(), 0.0);
+        let smooth = SmoothConductorBrdf::new(ior_gold());
+        for outgoing in [
+            -DVec3::Y,
+            DVec3::new(0.6, -0.8, 0.0),
+            DVec3::new(-0.2, -0.1, 0.5).normalize(),
+        ] {
+            let random = DVec3::new(0.3, 0.7, 0.1);
+            let r = rough.sample(outgoing, DVec3::Y, None, None, LAMBDAS, random);
+            let s = smooth.sample(outgoing, DVec3::Y, None, None, LAMBDAS, random);
+            assert!(r.f.is_finite() && r.pdf.is_finite(), "outgoing {outgoing}");
+            assert_eq!(r.dir, outgoing.reject_from(DVec3::Y) * 2.0 - outgoing);
+            assert_eq!(
+                (r.dir, r.pdf, r.f, r.singular),
+                (s.dir, s.pdf, s.f, s.singular)
+            );
+
+            // no direction but the mirror one reflects anything
+            let elsewhere = DVec3::new(0.1, 0.9, 0.3).normalize();
+            let f = rough.f(elsewhere, outgoing, DVec3::Y, None, None, LAMBDAS);
+            let pdf = rough.pdf(elsewhere, outgoing, DVec3::Y, None, None, LAMBDAS.x);
+            assert_eq!((f, pdf), (DVec4::ZERO, 0.0));
+        }
+    }
+
+    #[test]
+    fn tangents_turn_anisotropic_highlights() {
+        crate::random::reseed([2409, 0, 0]);
+        let brushed = RoughConductorBrdf::with_distribution(
+            ior_gold(),
+            AnisotropicTrowbridgeReitzDistribution {
+                alpha_x: 0.05,
+                alpha_y: 0.4,
+            },
+        );
+        let isotropic = RoughConductorBrdf::new(ior_gold(), 0.2);
+        let outgoing = DVec3::new(0.3, -1.0, 0.1).normalize();
+        let incoming = DVec3::new(0.4, 1.0, -0.5).normalize();
+
+        for degrees in [30.0, 90.0, 135.0] {
+            let turn = DQuat::from_rotation_y(f64::to_radians(degrees));
+            let tangent = turn * DVec3::X;
+            let brdfs: [(&str, &dyn Brdf); 2] = [("brushed", &brushed), ("isotropic", &isotropic)];
+            for (name, brdf) in brdfs {
+                let f = brdf.f(incoming, outgoing, DVec3::Y, Some(DVec3::X), None, LAMBDAS);
+                // turning the tangent with the directions changes nothing
+                let turned = brdf.f(
+                    turn * incoming,
+                    turn * outgoing,
+                    DVec3::Y,
+                    Some(tangent),
+                    None,
+                    LAMBDAS,
+                );
+                assert!(
+                    ((turned - f) / f).abs().max_element() < 1e-9,
+                    "{name} turned by {degrees}: {turned}, {f} unturned"
+                );
+                // but turning it alone only matters to the anisotropic lobe
+                let alone = brdf.f(incoming, outgoing, DVec3::Y, Some(tangent), None, LAMBDAS);
+                let same = ((alone - f) / f).abs().max_element() < 1e-9;
+                assert_eq!(same, name == "isotropic", "{name} with tangent {tangent}");
+            }
+        }
+        let no_tangent = isotropic.f(incoming, outgoing, DVec3::Y, None, None, LAMBDAS);
+        let f = isotropic.f(incoming, outgoing, DVec3::Y, Some(DVec3::Z), None, LAMBDAS);
+        assert!(((no_tangent - f) / f).abs().max_element() < 1e-9);
+
+        // looking straight down, the sampled lobe is long across the tangent and narrow along it
+        for (tangent, along, across) in [
+            (DVec3::X, DVec3::X, DVec3::Z),
+            (DVec3::Z, DVec3::Z, DVec3::X),
+        ] {
+            let (mut along_sq, mut across_sq) = (0.0, 0.0);
+            for _ in 0..10_000 {
+                let random = crate::random::rng().gen();
+                let sample =
+                    brushed.sample(-DVec3::Y, DVec3::Y, Some(tangent), None, LAMBDAS, random);
+                along_sq += sample.dir.dot(along).powi(2);
+                across_sq += sample.dir.dot(across).powi(2);
+            }
+            assert!(
+                across_sq > 10.0 * along_sq,
+                "tangent {tangent}: {along_sq} along, {across_sq} across"
+            );
+        }
+    }
+
+    #[test]
+    fn checker_textures_alternate_across_a_quad() {
+        let light = RgbAlbedo::new(DVec3::splat(0.8));
+        let dark = RgbAlbedo::new(DVec3::new(0.1, 0.2, 0.05));
+        let checker = Arc::new(Texture {
+            width: 4,
+            height: 4,
+            data: (0..16)
+                .map(|i| match (i % 4 + i / 4) % 2 {
+                    0 => light,
+                    _ => dark,
+                })
+                .collect(),
+            wrap: Wrap::Repeat,
+        });
+        // facing up, with u along +Z and v along +X
+        let quad = Quad {
+            origin: DVec3::ZERO,
+            u: DVec3::Z * 4.0,
+            v: DVec3::X * 4.0,
+            material: Material {
+                emission: crate::spectrum::ZERO,
+                brdf: TexturedLambertianBrdf {
+                    texture: checker,
+                    filter: Filter::Bilinear,
+                },
+                enter_medium: (),
+                exit_medium: (),
+            },
+        };
+
+        for (x, z) in [(0.5, 0.5), (1.5, 0.5), (0.5, 1.5), (2.5, 3.5), (3.5, 3.5)] {
+            let hit = quad
+                .raycast(DVec3::new(x, 1.0, z), -DVec3::Y, f64::INFINITY)
+                .unwrap();
+            let uv = hit.uv.unwrap();
+            assert!(
+                uv.abs_diff_eq(DVec2::new(z, x) / 4.0, 1e-12),
+                "uv {uv} at ({x}, {z})"
+            );
+            let f = quad
+                .material
+                .brdf
+                .f(DVec3::Y, -DVec3::Y, hit.normal, None, hit.uv, LAMBDAS);
+            let expected = match (x as usize + z as usize) % 2 {
+                0 => light,
+                _ => dark,
+            };
+            let expected = expected.sample_multi(LAMBDAS) / PI;
+            assert!(
+                (f - expected).abs().max_element() < 1e-12,
+                "f = {f} at ({x}, {z}), expected {expected}"
+            );
+        }
     }
 }
@@ -1,9 +1,9 @@
-use glam::{DVec3, DVec4};
+use glam::{DMat3, DVec2, DVec3, DVec4};
 use num::complex::Complex64;
 
 use crate::spectrum::Spectrum;
 
-use super::{Brdf, BrdfSample, TrowbridgeReitzDistribution};
+use super::{Brdf, BrdfSample, Lobe, MicrofacetDistribution, TrowbridgeReitzDistribution};
 
 #[derive(Clone)]
 pub struct SmoothConductorBrdf<Sr, Si> {
@@ -21,15 +21,35 @@ impl<'a, S> SmoothConductorBrdf<&'a S, &'a S> {
 }
 
 impl<Sr: Spectrum, Si: Spectrum> Brdf for SmoothConductorBrdf<Sr, Si> {
-    fn f(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambdas: DVec4) -> DVec4 {
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4 {
         _ = incoming;
         _ = outgoing;
         _ = normal;
+        _ = tangent;
+        _ = uv;
         _ = lambdas;
         DVec4::ZERO
     }
 
-    fn sample(&self, outgoing: DVec3, normal: DVec3, lambdas: DVec4, random: DVec3) -> BrdfSample {
+    fn sample(
+        &self,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+        random: DVec3,
+    ) -> BrdfSample {
+        _ = tangent;
+        _ = uv;
         _ = random;
         let cos_i = -outgoing.dot(normal);
         if cos_i < 0.0 {
@@ -39,6 +59,7 @@ impl<Sr: Spectrum, Si: Spectrum> Brdf for SmoothConductorBrdf<Sr, Si> {
                 f: DVec4::ZERO,
                 terminate_secondary: false,
                 singular: true,
+                lobe: Lobe::Specular,
             };
         }
         let incoming = outgoing.reflect(normal);
@@ -54,27 +75,50 @@ impl<Sr: Spectrum, Si: Spectrum> Brdf for SmoothConductorBrdf<Sr, Si> {
             f: fresnel / cos_i,
             terminate_secondary: false,
             singular: true,
+            lobe: Lobe::Specular,
         }
     }
 
-    fn pdf(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambda: f64) -> f64 {
+    fn pdf(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambda: f64,
+    ) -> f64 {
         _ = incoming;
         _ = outgoing;
         _ = normal;
+        _ = tangent;
+        _ = uv;
         _ = lambda;
         0.0
     }
+
+    fn lobe(&self) -> Lobe {
+        Lobe::Specular
+    }
 }
 
+/// A conductor with microfacets, Trowbridge-Reitz unless another distribution is given. If the
+/// distribution is [effectively smooth](MicrofacetDistribution::effectively_smooth) it behaves
+/// exactly like [`SmoothConductorBrdf`], since the microfacet terms divide by zero as the
+/// roughness goes to 0.
+///
+/// The distribution's frame has its first axis along the surface's tangent, so anisotropic
+/// microfacets follow it. Without a tangent, it's an arbitrary direction in the surface.
 #[derive(Clone)]
-pub struct RoughConductorBrdf<Sr, Si> {
+pub struct RoughConductorBrdf<Sr, Si, D = TrowbridgeReitzDistribution> {
     pub ior_re: Sr,
     pub ior_im: Si,
-    pub microfacets: TrowbridgeReitzDistribution,
+    pub microfacets: D,
 }
 
 impl<'a, S> RoughConductorBrdf<&'a S, &'a S> {
     pub fn new(ior: &'a [S; 2], alpha: f64) -> Self {
+        assert!(alpha >= 0.0, "invalid microfacet roughness {alpha}");
         RoughConductorBrdf {
             ior_re: &ior[0],
             ior_im: &ior[1],
@@ -83,8 +127,46 @@ impl<'a, S> RoughConductorBrdf<&'a S, &'a S> {
     }
 }
 
-impl<Sr: Spectrum, Si: Spectrum> Brdf for RoughConductorBrdf<Sr, Si> {
-    fn f(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambdas: DVec4) -> DVec4 {
+impl<'a, S, D> RoughConductorBrdf<&'a S, &'a S, D> {
+    #[allow(unused)]
+    pub fn with_distribution(ior: &'a [S; 2], microfacets: D) -> Self {
+        RoughConductorBrdf {
+            ior_re: &ior[0],
+            ior_im: &ior[1],
+            microfacets,
+        }
+    }
+}
+
+impl<Sr: Spectrum, Si: Spectrum, D: MicrofacetDistribution> RoughConductorBrdf<Sr, Si, D> {
+    fn smooth(&self) -> Option<SmoothConductorBrdf<&Sr, &Si>> {
+        self.microfacets
+            .effectively_smooth()
+            .then_some(SmoothConductorBrdf {
+                ior_re: &self.ior_re,
+                ior_im: &self.ior_im,
+            })
+    }
+}
+
+impl<Sr: Spectrum, Si: Spectrum, D: MicrofacetDistribution> Brdf for RoughConductorBrdf<Sr, Si, D> {
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4 {
+        if let Some(smooth) = self.smooth() {
+            return smooth.f(incoming, outgoing, normal, tangent, uv, lambdas);
+        }
+        let Some(to_world) = tangent_frame(normal, tangent) else {
+            return DVec4::ZERO;
+        };
+        let to_local = to_world.transpose();
+        let (incoming, outgoing, normal) = (to_local * incoming, to_local * outgoing, DVec3::Z);
         if incoming.dot(normal) * outgoing.dot(normal) > 0.0 {
             return DVec4::ZERO;
         }
@@ -113,7 +195,29 @@ impl<Sr: Spectrum, Si: Spectrum> Brdf for RoughConductorBrdf<Sr, Si> {
         fresnel * factor
     }
 
-    fn sample(&self, outgoing: DVec3, normal: DVec3, lambdas: DVec4, random: DVec3) -> BrdfSample {
+    fn sample(
+        &self,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+        random: DVec3,
+    ) -> BrdfSample {
+        if let Some(smooth) = self.smooth() {
+            return smooth.sample(outgoing, normal, tangent, uv, lambdas, random);
+        }
+        let Some(to_world) = tangent_frame(normal, tangent) else {
+            return BrdfSample {
+                dir: DVec3::ZERO,
+                pdf: 0.0,
+                f: DVec4::ZERO,
+                terminate_secondary: false,
+                singular: false,
+                lobe: Lobe::Specular,
+            };
+        };
+        let (outgoing, normal) = (to_world.transpose() * outgoing, DVec3::Z);
         let cos_out = -outgoing.dot(normal);
         if cos_out < 0.0 {
             return BrdfSample {
@@ -122,12 +226,23 @@ impl<Sr: Spectrum, Si: Spectrum> Brdf for RoughConductorBrdf<Sr, Si> {
                 f: DVec4::ZERO,
                 terminate_secondary: false,
                 singular: true,
+                lobe: Lobe::Specular,
             };
         }
 
-        let micro_normal = self
+        let Some(micro_normal) = self
             .microfacets
-            .sample_micro_normal(outgoing, normal, random);
+            .sample_micro_normal(outgoing, normal, random)
+        else {
+            return BrdfSample {
+                dir: DVec3::ZERO,
+                pdf: 0.0,
+                f: DVec4::ZERO,
+                terminate_secondary: false,
+                singular: false,
+                lobe: Lobe::Specular,
+            };
+        };
         let incoming = outgoing.reflect(micro_normal);
         if outgoing.dot(normal) * incoming.dot(normal) > 0.0 {
             return BrdfSample {
@@ -136,6 +251,7 @@ impl<Sr: Spectrum, Si: Spectrum> Brdf for RoughConductorBrdf<Sr, Si> {
                 f: DVec4::ZERO,
                 terminate_secondary: false,
                 singular: true,
+                lobe: Lobe::Specular,
             };
         }
 
@@ -161,16 +277,32 @@ impl<Sr: Spectrum, Si: Spectrum> Brdf for RoughConductorBrdf<Sr, Si> {
             / (4.0 * cos_in * cos_out);
 
         BrdfSample {
-            dir: incoming,
+            dir: to_world * incoming,
             pdf,
             f: fresnel * factor,
             terminate_secondary: false,
             singular: false,
+            lobe: Lobe::Specular,
         }
     }
 
-    fn pdf(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambda: f64) -> f64 {
-        _ = lambda;
+    fn pdf(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambda: f64,
+    ) -> f64 {
+        if let Some(smooth) = self.smooth() {
+            return smooth.pdf(incoming, outgoing, normal, tangent, uv, lambda);
+        }
+        let Some(to_world) = tangent_frame(normal, tangent) else {
+            return 0.0;
+        };
+        let to_local = to_world.transpose();
+        let (incoming, outgoing, normal) = (to_local * incoming, to_local * outgoing, DVec3::Z);
         let Some(mut micro_normal) = (incoming - outgoing).try_normalize() else {
             return 0.0;
         };
@@ -181,6 +313,21 @@ impl<Sr: Spectrum, Si: Spectrum> Brdf for RoughConductorBrdf<Sr, Si> {
             .micro_normal_pdf(outgoing, micro_normal, normal)
             / (4.0 * outgoing.dot(micro_normal).abs())
     }
+
+    fn lobe(&self) -> Lobe {
+        Lobe::Specular
+    }
+}
+
+/// Rotates the surface to +Z for [`RoughConductorBrdf`]'s microfacets, with `tangent` along +X
+/// after making it perpendicular to `normal`, or any perpendicular direction if there's no usable
+/// tangent. That's the first axis of [`build_frame`](crate::build_frame)`(DVec3::Z)`, so
+/// anisotropic distributions follow the tangent.
+fn tangent_frame(normal: DVec3, tangent: Option<DVec3>) -> Option<DMat3> {
+    let x = tangent
+        .and_then(|t| (t - normal * normal.dot(t)).try_normalize())
+        .or_else(|| crate::build_frame(normal).map(|(x, _)| x))?;
+    Some(DMat3::from_cols(x, normal.cross(x), normal))
 }
 
 fn fresnel_reflectance_complex(cos_i: f64, rel_ior: Complex64) -> f64 {
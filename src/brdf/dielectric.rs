@@ -1,24 +1,47 @@
-use glam::{DVec3, DVec4};
+use glam::{DVec2, DVec3, DVec4};
 
 use crate::spectrum::Spectrum;
 
-use super::{Brdf, BrdfSample};
+use super::{Brdf, BrdfSample, Lobe};
 
+/// A smooth interface between two transparent media with relative index of refraction `ior`.
+/// Refraction always [terminates the secondary wavelengths](BrdfSample::terminate_secondary),
+/// since in general the refracted direction depends on the wavelength.
 #[derive(Clone)]
 pub struct DielectricBrdf<S> {
     pub ior: S,
 }
 
 impl<S: Spectrum> Brdf for DielectricBrdf<S> {
-    fn f(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambdas: DVec4) -> DVec4 {
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4 {
         _ = incoming;
         _ = outgoing;
         _ = normal;
+        _ = tangent;
+        _ = uv;
         _ = lambdas;
         DVec4::ZERO
     }
 
-    fn sample(&self, outgoing: DVec3, normal: DVec3, lambdas: DVec4, random: DVec3) -> BrdfSample {
+    fn sample(
+        &self,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+        random: DVec3,
+    ) -> BrdfSample {
+        _ = tangent;
+        _ = uv;
         _ = random;
         let ior = self.ior.sample_multi(lambdas);
         let (ior, normal) = match outgoing.dot(normal) < 0.0 {
@@ -36,6 +59,7 @@ impl<S: Spectrum> Brdf for DielectricBrdf<S> {
                 f: fresnel_reflect / cos_i,
                 terminate_secondary: false,
                 singular: true,
+                lobe: Lobe::Specular,
             }
         } else {
             let refracted = outgoing.refract(normal, 1.0 / ior.x);
@@ -50,17 +74,36 @@ impl<S: Spectrum> Brdf for DielectricBrdf<S> {
                 ),
                 terminate_secondary: true,
                 singular: true,
+                lobe: Lobe::Specular,
             }
         }
     }
 
-    fn pdf(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambda: f64) -> f64 {
+    fn pdf(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambda: f64,
+    ) -> f64 {
         _ = incoming;
         _ = outgoing;
         _ = normal;
+        _ = tangent;
+        _ = uv;
         _ = lambda;
         0.0
     }
+
+    fn transmits(&self) -> bool {
+        true
+    }
+
+    fn lobe(&self) -> Lobe {
+        Lobe::Specular
+    }
 }
 
 #[derive(Clone)]
@@ -69,10 +112,20 @@ pub struct ThinDielectricBrdf<S> {
 }
 
 impl<S: Spectrum> Brdf for ThinDielectricBrdf<S> {
-    fn f(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambdas: DVec4) -> DVec4 {
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4 {
         _ = incoming;
         _ = outgoing;
         _ = normal;
+        _ = tangent;
+        _ = uv;
         _ = lambdas;
         DVec4::ZERO
     }
@@ -81,9 +134,13 @@ impl<S: Spectrum> Brdf for ThinDielectricBrdf<S> {
         &self,
         outgoing: DVec3,
         mut normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
         lambdas: DVec4,
         random: DVec3,
     ) -> BrdfSample {
+        _ = tangent;
+        _ = uv;
         _ = random;
         let ior = self.ior.sample_multi(lambdas);
         if outgoing.dot(normal) > 0.0 {
@@ -104,6 +161,7 @@ impl<S: Spectrum> Brdf for ThinDielectricBrdf<S> {
                 f: fresnel_reflect / cos_i,
                 terminate_secondary: false,
                 singular: true,
+                lobe: Lobe::Specular,
             }
         } else {
             BrdfSample {
@@ -112,17 +170,36 @@ impl<S: Spectrum> Brdf for ThinDielectricBrdf<S> {
                 f: (1.0 - fresnel_reflect) / cos_i,
                 terminate_secondary: false,
                 singular: true,
+                lobe: Lobe::Specular,
             }
         }
     }
 
-    fn pdf(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambda: f64) -> f64 {
+    fn pdf(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambda: f64,
+    ) -> f64 {
         _ = incoming;
         _ = outgoing;
         _ = normal;
+        _ = tangent;
+        _ = uv;
         _ = lambda;
         0.0
     }
+
+    fn transmits(&self) -> bool {
+        true
+    }
+
+    fn lobe(&self) -> Lobe {
+        Lobe::Specular
+    }
 }
 
 fn fresnel_reflectance_real(cos_i: f64, rel_ior: f64) -> f64 {
@@ -4,8 +4,14 @@ use glam::{DVec2, DVec3, DVec4};
 
 use crate::spectrum::Spectrum;
 
-use super::{Brdf, BrdfSample};
-
+use super::{Brdf, BrdfSample, Lobe};
+
+/// A cosine-power lobe around the mirror direction.
+///
+/// The lobe is normalized over the whole sphere and clipped at the horizon, so the part of it
+/// below the surface is lost: the albedo is `albedo` near normal incidence and falls off towards
+/// grazing angles, where up to half of the lobe is below the horizon. Sampling the lobe is exact,
+/// but samples landing below the horizon are returned with a zero direction to end the path.
 #[derive(Clone)]
 pub struct PhongSpecularBrdf<S> {
     pub albedo: S,
@@ -13,7 +19,17 @@ pub struct PhongSpecularBrdf<S> {
 }
 
 impl<S: Spectrum> Brdf for PhongSpecularBrdf<S> {
-    fn f(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambdas: DVec4) -> DVec4 {
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4 {
+        _ = tangent;
+        _ = uv;
         if outgoing.dot(normal) > 0.0 || incoming.dot(normal) < 0.0 {
             return DVec4::ZERO;
         }
@@ -22,7 +38,15 @@ impl<S: Spectrum> Brdf for PhongSpecularBrdf<S> {
             * incoming.dot(reflect).max(0.0).powf(self.power)
     }
 
-    fn sample(&self, outgoing: DVec3, normal: DVec3, lambdas: DVec4, random: DVec3) -> BrdfSample {
+    fn sample(
+        &self,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+        random: DVec3,
+    ) -> BrdfSample {
         let reflect = outgoing.reflect(normal);
 
         let z = random.x.powf(1.0 / (self.power + 1.0));
@@ -31,24 +55,60 @@ impl<S: Spectrum> Brdf for PhongSpecularBrdf<S> {
         let r = (1.0 - z * z).sqrt();
         let d = DVec2::new(x, y) * r;
 
-        let tangent = reflect.cross(normal).normalize();
-        let bitangent = reflect.cross(tangent);
-        let incoming = d.x * tangent + d.y * bitangent + z * reflect;
+        // built from the mirror direction alone, which is parallel to the normal at normal incidence
+        let Some((frame_x, frame_y)) = crate::build_frame(reflect) else {
+            return BrdfSample {
+                dir: DVec3::ZERO,
+                pdf: 0.0,
+                f: DVec4::ZERO,
+                terminate_secondary: false,
+                singular: false,
+                lobe: Lobe::Specular,
+            };
+        };
+        let incoming = d.x * frame_x + d.y * frame_y + z * reflect;
+
+        if incoming.dot(normal) <= 0.0 {
+            // below the horizon, where f is zero; tracing it would only waste a bounce
+            return BrdfSample {
+                dir: DVec3::ZERO,
+                pdf: 0.0,
+                f: DVec4::ZERO,
+                terminate_secondary: false,
+                singular: false,
+                lobe: Lobe::Specular,
+            };
+        }
 
         BrdfSample {
             dir: incoming,
-            pdf: self.pdf(incoming, outgoing, normal, lambdas.x),
-            f: self.f(incoming, outgoing, normal, lambdas),
+            pdf: self.pdf(incoming, outgoing, normal, tangent, uv, lambdas.x),
+            f: self.f(incoming, outgoing, normal, tangent, uv, lambdas),
             terminate_secondary: false,
             singular: false,
+            lobe: Lobe::Specular,
         }
     }
 
-    fn pdf(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambda: f64) -> f64 {
+    fn pdf(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambda: f64,
+    ) -> f64 {
+        _ = tangent;
+        _ = uv;
         _ = lambda;
         let reflect = outgoing.reflect(normal);
         (self.power + 1.0) / (2.0 * PI) * incoming.dot(reflect).max(0.0).powf(self.power)
     }
+
+    fn lobe(&self) -> Lobe {
+        Lobe::Specular
+    }
 }
 
 #[derive(Clone)]
@@ -58,7 +118,17 @@ pub struct PhongRetroBrdf<S> {
 }
 
 impl<S: Spectrum> Brdf for PhongRetroBrdf<S> {
-    fn f(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambdas: DVec4) -> DVec4 {
+    fn f(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+    ) -> DVec4 {
+        _ = tangent;
+        _ = uv;
         if outgoing.dot(normal) > 0.0 || incoming.dot(normal) < 0.0 {
             return DVec4::ZERO;
         }
@@ -67,7 +137,15 @@ impl<S: Spectrum> Brdf for PhongRetroBrdf<S> {
             * incoming.dot(retro).max(0.0).powf(self.power)
     }
 
-    fn sample(&self, outgoing: DVec3, normal: DVec3, lambdas: DVec4, random: DVec3) -> BrdfSample {
+    fn sample(
+        &self,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambdas: DVec4,
+        random: DVec3,
+    ) -> BrdfSample {
         let retro = -outgoing;
 
         let z = random.x.powf(1.0 / (self.power + 1.0));
@@ -76,23 +154,38 @@ impl<S: Spectrum> Brdf for PhongRetroBrdf<S> {
         let r = (1.0 - z * z).sqrt();
         let d = DVec2::new(x, y) * r;
 
-        let tangent = retro.cross(normal).normalize();
-        let bitangent = retro.cross(tangent);
-        let incoming = d.x * tangent + d.y * bitangent + z * retro;
+        let frame_x = retro.cross(normal).normalize();
+        let frame_y = retro.cross(frame_x);
+        let incoming = d.x * frame_x + d.y * frame_y + z * retro;
 
         BrdfSample {
             dir: incoming,
-            pdf: self.pdf(incoming, outgoing, normal, lambdas.x),
-            f: self.f(incoming, outgoing, normal, lambdas),
+            pdf: self.pdf(incoming, outgoing, normal, tangent, uv, lambdas.x),
+            f: self.f(incoming, outgoing, normal, tangent, uv, lambdas),
             terminate_secondary: false,
             singular: false,
+            lobe: Lobe::Specular,
         }
     }
 
-    fn pdf(&self, incoming: DVec3, outgoing: DVec3, normal: DVec3, lambda: f64) -> f64 {
+    fn pdf(
+        &self,
+        incoming: DVec3,
+        outgoing: DVec3,
+        normal: DVec3,
+        tangent: Option<DVec3>,
+        uv: Option<DVec2>,
+        lambda: f64,
+    ) -> f64 {
         _ = normal;
+        _ = tangent;
+        _ = uv;
         _ = lambda;
         let retro = -outgoing;
         (self.power + 1.0) / (2.0 * PI) * incoming.dot(retro).max(0.0).powf(self.power)
     }
+
+    fn lobe(&self) -> Lobe {
+        Lobe::Specular
+    }
 }
\ No newline at end of file
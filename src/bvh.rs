@@ -1,15 +1,52 @@
 use core::f64;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, Read, Write};
+use std::ops::ControlFlow;
+use std::path::Path;
 use std::sync::Arc;
 
-use glam::DVec3;
+use glam::{DMat4, DVec2, DVec3};
 use ordered_float::OrderedFloat;
 
-use crate::objects::{Object, RayHit};
-use crate::Bounds;
+use crate::objects::{self, Object, Problem, RayHit, SurfacePoint, Transform};
+use crate::{stats, Bounds};
 
 pub struct Bvh {
     objs: Vec<Arc<dyn Object>>,
     root: BvhNode,
+    emitters: Emitters,
+}
+
+/// The emissive objects of a [`Bvh`], for sampling them in proportion to their area.
+struct Emitters {
+    any: bool,
+    /// Running total of the emissive area, with the index of the object that brings it there.
+    /// Empty if any emissive object can't be sampled, since then neither can the whole BVH.
+    cdf: Vec<(f64, usize)>,
+}
+
+impl Emitters {
+    fn new(objs: &[Arc<dyn Object>]) -> Self {
+        let mut total = 0.0;
+        let mut cdf = vec![];
+        let mut sampleable = true;
+        for (i, obj) in objs.iter().enumerate() {
+            if !obj.emissive() {
+                continue;
+            }
+            let area = obj.emissive_area();
+            sampleable &= area > 0.0;
+            total += area;
+            cdf.push((total, i));
+        }
+        Emitters {
+            any: !cdf.is_empty(),
+            cdf: match sampleable {
+                true => cdf,
+                false => vec![],
+            },
+        }
+    }
 }
 
 enum BvhChildren {
@@ -27,10 +64,154 @@ impl Bvh {
         let root =
             build_bvh_node(&mut objects.iter().map(|b| &**b).enumerate().collect::<Vec<_>>());
         Bvh {
+            emitters: Emitters::new(&objects),
             objs: objects,
             root,
         }
     }
+
+    /// Builds a top-level BVH over rigidly transformed instances of shared objects, typically
+    /// bottom-level [`Bvh`]s. Rays are only transformed into an instance's local space once they
+    /// reach its leaf.
+    pub fn instanced(instances: impl IntoIterator<Item = (DMat4, Arc<dyn Object>)>) -> Self {
+        Bvh::build(
+            instances
+                .into_iter()
+                .map(|(transform, obj)| Arc::new(Transform::new(transform, obj)) as Arc<dyn Object>)
+                .collect(),
+        )
+    }
+
+    /// Calls `f` with every hit along the ray within `max_t`, nearest first, until it breaks, and
+    /// returns what it broke with. See [`objects::raycast_all`]; [`Object::raycast`] stays the way
+    /// to find only the closest.
+    #[allow(unused)]
+    pub fn raycast_all<'a, B>(
+        &'a self,
+        origin: DVec3,
+        direction: DVec3,
+        max_t: f64,
+        f: impl FnMut(RayHit<'a>) -> ControlFlow<B>,
+    ) -> Option<B> {
+        objects::raycast_all(origin, direction, max_t, |o, d, t| self.raycast(o, d, t), f)
+    }
+}
+
+/// Identifies BVH cache files. Bump the version whenever the layout below changes.
+const CACHE_MAGIC: &[u8; 8] = b"pbrbvh\0\0";
+const CACHE_VERSION: u32 = 1;
+
+impl Bvh {
+    /// Writes the node hierarchy to `path`. The objects themselves are not stored; the cache
+    /// refers to them by their index in the list the BVH was built from.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut f = BufWriter::new(File::create(path)?);
+        f.write_all(CACHE_MAGIC)?;
+        f.write_all(&CACHE_VERSION.to_le_bytes())?;
+        f.write_all(&(self.objs.len() as u64).to_le_bytes())?;
+        write_bvh_node(&mut f, &self.root)?;
+        f.flush()
+    }
+
+    /// Reads a node hierarchy written by [`Bvh::save`] and attaches it to `objects`, which must
+    /// be the same objects in the same order as the BVH was originally built from.
+    pub fn load(path: impl AsRef<Path>, objects: Vec<Arc<dyn Object>>) -> Result<Self, Error> {
+        let mut f = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 8];
+        f.read_exact(&mut magic)?;
+        if &magic != CACHE_MAGIC {
+            return Err(Error::other("not a bvh cache file"));
+        }
+        let version = read_u32(&mut f)?;
+        if version != CACHE_VERSION {
+            return Err(Error::other(format!(
+                "unsupported bvh cache version {version}"
+            )));
+        }
+        let count = read_u64(&mut f)?;
+        if count != objects.len() as u64 {
+            return Err(Error::other(format!(
+                "bvh cache has {count} objects, but {} were given",
+                objects.len()
+            )));
+        }
+
+        let root = read_bvh_node(&mut f, objects.len())?;
+        Ok(Bvh {
+            emitters: Emitters::new(&objects),
+            objs: objects,
+            root,
+        })
+    }
+}
+
+fn write_bvh_node(f: &mut impl Write, node: &BvhNode) -> Result<(), Error> {
+    for v in node
+        .bounds
+        .min
+        .to_array()
+        .into_iter()
+        .chain(node.bounds.max.to_array())
+    {
+        f.write_all(&v.to_le_bytes())?;
+    }
+    match node.children {
+        BvhChildren::Leaf(index) => {
+            f.write_all(&[0])?;
+            f.write_all(&(index as u64).to_le_bytes())?;
+        }
+        BvhChildren::Node(ref children) => {
+            f.write_all(&[1])?;
+            write_bvh_node(f, &children[0])?;
+            write_bvh_node(f, &children[1])?;
+        }
+    }
+    Ok(())
+}
+
+fn read_bvh_node(f: &mut impl Read, num_objs: usize) -> Result<BvhNode, Error> {
+    let mut read_dvec3 =
+        || -> Result<DVec3, Error> { Ok(DVec3::new(read_f64(f)?, read_f64(f)?, read_f64(f)?)) };
+    let bounds = Bounds {
+        min: read_dvec3()?,
+        max: read_dvec3()?,
+    };
+
+    let mut tag = [0];
+    f.read_exact(&mut tag)?;
+    let children = match tag[0] {
+        0 => {
+            let index = read_u64(f)? as usize;
+            if index >= num_objs {
+                return Err(Error::other(format!("object index {index} out of range")));
+            }
+            BvhChildren::Leaf(index)
+        }
+        1 => BvhChildren::Node(Box::new([
+            read_bvh_node(f, num_objs)?,
+            read_bvh_node(f, num_objs)?,
+        ])),
+        t => return Err(Error::other(format!("invalid bvh node tag {t}"))),
+    };
+
+    Ok(BvhNode { bounds, children })
+}
+
+fn read_u32(f: &mut impl Read) -> Result<u32, Error> {
+    let mut buf = [0; 4];
+    f.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(f: &mut impl Read) -> Result<u64, Error> {
+    let mut buf = [0; 8];
+    f.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_f64(f: &mut impl Read) -> Result<f64, Error> {
+    read_u64(f).map(f64::from_bits)
 }
 
 fn build_bvh_node(objs: &mut [(usize, &(dyn Object))]) -> BvhNode {
@@ -69,23 +250,74 @@ fn build_bvh_node(objs: &mut [(usize, &(dyn Object))]) -> BvhNode {
     }
 }
 
+/// How deep [`Bvh::transformed_bounds`] looks into the tree. Below this the nodes' boxes are
+/// transformed instead of their contents, so it takes at most `2^depth` of them.
+const TRANSFORMED_BOUNDS_DEPTH: u32 = 10;
+
+impl Bvh {
+    fn node_transformed_bounds(&self, node: &BvhNode, transform: &DMat4, depth: u32) -> Bounds {
+        match &node.children {
+            BvhChildren::Leaf(index) => self.objs[*index].transformed_bounds(transform),
+            BvhChildren::Node(children) if depth > 0 => children
+                .iter()
+                .map(|child| self.node_transformed_bounds(child, transform, depth - 1))
+                .reduce(Bounds::union)
+                .unwrap(),
+            BvhChildren::Node(_) => node.bounds.transform(transform),
+        }
+    }
+}
+
 impl Object for Bvh {
     fn bounds(&self) -> Bounds {
         self.root.bounds
     }
 
+    /// Unions the transformed bounds of the nodes a few levels down, which hug a rotated mesh
+    /// much more closely than its root box does.
+    fn transformed_bounds(&self, transform: &DMat4) -> Bounds {
+        self.node_transformed_bounds(&self.root, transform, TRANSFORMED_BOUNDS_DEPTH)
+    }
+
+    fn emissive(&self) -> bool {
+        self.emitters.any
+    }
+
+    fn emissive_area(&self) -> f64 {
+        self.emitters.cdf.last().map_or(0.0, |&(total, _)| total)
+    }
+
+    /// Picks an object by area with `random.x`, then reuses the part of `random.x` within that
+    /// object's share of the total as its own.
+    fn sample_emissive(&self, random: DVec2) -> Option<SurfacePoint<'_>> {
+        let cdf = &self.emitters.cdf;
+        let target = random.x * self.emissive_area();
+        let i = cdf
+            .partition_point(|&(end, _)| end <= target)
+            .min(cdf.len().checked_sub(1)?);
+        let start = match i {
+            0 => 0.0,
+            _ => cdf[i - 1].0,
+        };
+        let (end, index) = cdf[i];
+        let x = ((target - start) / (end - start)).clamp(0.0, 1.0);
+        self.objs[index].sample_emissive(DVec2::new(x, random.y))
+    }
+
     fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit> {
         let mut stack = vec![&self.root];
         let mut closest = None;
         let mut t_hit = max_t;
 
         while let Some(node) = stack.pop() {
+            stats::node_visited();
             let Some((_, _)) = node.bounds.ray_intersect(origin, direction, t_hit) else {
                 continue;
             };
 
             match node.children {
                 BvhChildren::Leaf(index) => {
+                    stats::primitive_test();
                     if let Some(hit) = self.objs[index].raycast(origin, direction, max_t) {
                         if hit.t < t_hit - hit.normal.dot(direction) * 1.0e-12 {
                             t_hit = hit.t;
@@ -112,4 +344,201 @@ impl Object for Bvh {
 
         closest
     }
+
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        for obj in &self.objs {
+            obj.problems(problems);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow::{Break, Continue};
+
+    use glam::DQuat;
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::brdf::LambertianBrdf;
+    use crate::material::Material;
+    use crate::objects::Sphere;
+    use crate::spectrum::{self, ConstantSpectrum};
+
+    fn sphere(x: f64, radius: f64) -> Arc<dyn Object> {
+        Arc::new(Sphere {
+            origin: DVec3::new(x, 0.0, 0.0),
+            radius,
+            material: Material {
+                emission: spectrum::ZERO,
+                brdf: LambertianBrdf {
+                    albedo: ConstantSpectrum(0.5),
+                },
+                enter_medium: (),
+                exit_medium: (),
+            },
+        })
+    }
+
+    #[test]
+    fn raycast_all_orders_overlapping_spheres() {
+        let bvh = Bvh::build(vec![sphere(2.0, 1.5), sphere(0.0, 1.5)]);
+        let origin = DVec3::new(-5.0, 0.0, 0.0);
+
+        let mut hits = vec![];
+        let none = bvh.raycast_all(origin, DVec3::X, f64::INFINITY, |hit| {
+            hits.push((hit.t, hit.geo_normal.x));
+            Continue::<()>(())
+        });
+        assert_eq!(none, None);
+        // into the first sphere, into the second, out of the first, out of the second
+        let expected = [(3.5, -1.0), (5.5, -1.0), (6.5, 1.0), (8.5, 1.0)];
+        assert_eq!(hits.len(), expected.len(), "{hits:?}");
+        for ((t, normal), (expected_t, expected_normal)) in hits.into_iter().zip(expected) {
+            assert!(
+                (t - expected_t).abs() < 1e-9,
+                "t = {t}, expected {expected_t}"
+            );
+            assert_eq!(normal, expected_normal);
+        }
+
+        let mut count = 0;
+        bvh.raycast_all(origin, DVec3::X, 6.0, |_| {
+            count += 1;
+            Continue::<()>(())
+        });
+        assert_eq!(count, 2);
+
+        let second = bvh.raycast_all(origin, DVec3::X, f64::INFINITY, |hit| match hit.t > 4.0 {
+            true => Break(hit.t),
+            false => Continue(()),
+        });
+        assert!((second.unwrap() - 5.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn instances_hit_like_separate_transforms() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let blas: Arc<dyn Object> = Arc::new(Bvh::build(
+            (0..5)
+                .map(|i| sphere(i as f64 * 0.7 - 1.4, 0.2 + i as f64 * 0.05))
+                .collect(),
+        ));
+        let transforms: Vec<_> = (0..40)
+            .map(|_| {
+                DMat4::from_rotation_translation(
+                    DQuat::from_scaled_axis(rng.gen::<DVec3>() * 6.0 - 3.0),
+                    (rng.gen::<DVec3>() * 2.0 - 1.0) * 10.0,
+                )
+            })
+            .collect();
+        let tlas = Bvh::instanced(transforms.iter().map(|&t| (t, blas.clone())));
+        let naive: Vec<_> = transforms
+            .iter()
+            .map(|&t| Transform::new(t, blas.clone()))
+            .collect();
+
+        let mut hits = 0;
+        for _ in 0..10_000 {
+            let origin = (rng.gen::<DVec3>() * 2.0 - 1.0) * 12.0;
+            let target = (rng.gen::<DVec3>() * 2.0 - 1.0) * 10.0;
+            let direction = (target - origin).normalize();
+            let expected = naive
+                .iter()
+                .filter_map(|o| o.raycast(origin, direction, f64::INFINITY))
+                .min_by(|a, b| a.t.total_cmp(&b.t));
+            let actual = tlas.raycast(origin, direction, f64::INFINITY);
+            match (actual, expected) {
+                (None, None) => {}
+                (Some(actual), Some(expected)) => {
+                    hits += 1;
+                    assert!((actual.t - expected.t).abs() <= 1e-9 * expected.t);
+                    assert!(actual.normal.abs_diff_eq(expected.normal, 1e-9));
+                }
+                (actual, expected) => panic!(
+                    "ray from {origin} along {direction} hit at {:?}, expected {:?}",
+                    actual.map(|h| h.t),
+                    expected.map(|h| h.t)
+                ),
+            }
+        }
+        assert!(hits > 1000, "only {hits} rays hit anything");
+    }
+
+    #[test]
+    fn loaded_bvh_hits_like_the_built_one() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let spheres: Vec<_> = (0..200)
+            .map(|_| {
+                Arc::new(Sphere {
+                    origin: (rng.gen::<DVec3>() * 2.0 - 1.0) * 10.0,
+                    radius: rng.gen_range(0.1..1.0),
+                    material: Material {
+                        emission: spectrum::ZERO,
+                        brdf: LambertianBrdf {
+                            albedo: ConstantSpectrum(0.5),
+                        },
+                        enter_medium: (),
+                        exit_medium: (),
+                    },
+                }) as Arc<dyn Object>
+            })
+            .collect();
+        let built = Bvh::build(spheres.clone());
+        let path = std::env::temp_dir().join(format!("pbr-test-{}.bvh", std::process::id()));
+        built.save(&path).unwrap();
+        let loaded = Bvh::load(&path, spheres.clone());
+        let wrong_count = Bvh::load(&path, spheres[1..].to_vec());
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert!(wrong_count.is_err());
+
+        let mut hits = 0;
+        for _ in 0..10_000 {
+            let origin = (rng.gen::<DVec3>() * 2.0 - 1.0) * 12.0;
+            let direction = crate::random::sphere(rng.gen());
+            let expected = built.raycast(origin, direction, f64::INFINITY);
+            let actual = loaded.raycast(origin, direction, f64::INFINITY);
+            assert_eq!(actual.as_ref().map(|h| h.t), expected.as_ref().map(|h| h.t));
+            if let (Some(actual), Some(expected)) = (actual, expected) {
+                hits += 1;
+                assert_eq!(actual.normal, expected.normal);
+            }
+        }
+        assert!(hits > 1000, "only {hits} rays hit anything");
+    }
+
+    #[cfg(feature = "raycast-stats")]
+    #[test]
+    fn worse_trees_visit_more_nodes() {
+        let spheres: Vec<_> = (0..64).map(|i| sphere(i as f64 * 3.0, 1.0)).collect();
+        let balanced = Bvh::build(spheres.clone());
+        // a chain, each level holding one sphere and the rest of the chain
+        let chain = spheres
+            .iter()
+            .rev()
+            .cloned()
+            .reduce(|rest, sphere| Arc::new(Bvh::build(vec![sphere, rest])))
+            .unwrap();
+
+        let visits = |bvh: &dyn Object| {
+            stats::take_local();
+            let mut hits = vec![];
+            for i in 0..64 {
+                let origin = DVec3::new(i as f64 * 3.0 + 0.5, 5.0, 0.0);
+                hits.push(
+                    bvh.raycast(origin, -DVec3::Y, f64::INFINITY)
+                        .map(|hit| hit.t),
+                );
+            }
+            (stats::take_local().nodes_visited, hits)
+        };
+        let (balanced_visits, balanced_hits) = visits(&balanced);
+        let (chain_visits, chain_hits) = visits(&*chain);
+        assert_eq!(balanced_hits, chain_hits);
+        assert!(
+            chain_visits > 2 * balanced_visits,
+            "the chain visited {chain_visits} nodes, the balanced tree {balanced_visits}"
+        );
+    }
 }
@@ -0,0 +1,191 @@
+//! Edge-preserving smoothing of rendered images, guided by auxiliary buffers.
+
+use glam::DVec3;
+use rayon::prelude::*;
+
+/// The per-pixel buffers that decide how much two pixels are alike, all in the same row-major
+/// order as the image.
+pub struct Guides<'a> {
+    /// The noisy image itself.
+    pub color: &'a [DVec3],
+    /// Variance of each pixel of `color` as an estimate of its mean. Infinite where it isn't
+    /// known, and NaN for pixels without any samples, which are neither filtered nor used.
+    pub variance: &'a [DVec3],
+    pub albedo: &'a [DVec3],
+    /// Unit shading normals, or zero where nothing was hit.
+    pub normal: &'a [DVec3],
+}
+
+/// Widths of the Gaussians in [`joint_bilateral`].
+#[derive(Clone, Copy, Debug)]
+pub struct BilateralParams {
+    /// Half the side of the square window around each pixel.
+    pub radius: usize,
+    /// In pixels.
+    pub sigma_spatial: f64,
+    /// Per channel, in the units of the albedo buffer.
+    pub sigma_albedo: f64,
+    /// Distance between unit normals.
+    pub sigma_normal: f64,
+    /// In standard errors of the difference between two pixels' colors.
+    pub sigma_color: f64,
+}
+
+impl Default for BilateralParams {
+    fn default() -> Self {
+        BilateralParams {
+            radius: 6,
+            sigma_spatial: 3.0,
+            sigma_albedo: 0.05,
+            sigma_normal: 0.2,
+            sigma_color: 3.0,
+        }
+    }
+}
+
+/// A joint bilateral filter: each pixel becomes a weighted average of its neighbours, where a
+/// neighbour counts for less the further away it is and the more its albedo, normal or color
+/// differs. Only guides are compared, not the filtered values, so noise in the image doesn't
+/// break up the weights along texture and geometry edges.
+///
+/// The color difference is measured against the pixels' standard errors, so that differences
+/// which could be noise are smoothed over but shadow and highlight edges which are well resolved
+/// stay sharp.
+///
+/// Every layer in `layers` is filtered with the same weights and returned in the same order.
+pub fn joint_bilateral(
+    width: usize,
+    height: usize,
+    guides: &Guides,
+    params: &BilateralParams,
+    layers: &[&[DVec3]],
+) -> Vec<Vec<DVec3>> {
+    let filtered: Vec<Vec<DVec3>> = (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            if guides.variance[i].is_nan() {
+                return layers.iter().map(|layer| layer[i]).collect();
+            }
+            let (x, y) = (i % width, i / width);
+            let mut sum = vec![DVec3::ZERO; layers.len()];
+            let mut total_weight = 0.0;
+            for ny in y.saturating_sub(params.radius)..(y + params.radius + 1).min(height) {
+                for nx in x.saturating_sub(params.radius)..(x + params.radius + 1).min(width) {
+                    let j = nx + ny * width;
+                    let w = weight(guides, params, i, j, (x, y), (nx, ny));
+                    if w == 0.0 {
+                        continue;
+                    }
+                    total_weight += w;
+                    for (sum, layer) in sum.iter_mut().zip(layers) {
+                        *sum += layer[j] * w;
+                    }
+                }
+            }
+            sum.into_iter().map(|s| s / total_weight).collect()
+        })
+        .collect();
+
+    (0..layers.len())
+        .map(|l| filtered.iter().map(|values| values[l]).collect())
+        .collect()
+}
+
+fn weight(
+    guides: &Guides,
+    params: &BilateralParams,
+    i: usize,
+    j: usize,
+    (x, y): (usize, usize),
+    (nx, ny): (usize, usize),
+) -> f64 {
+    let variance = guides.variance[i] + guides.variance[j];
+    if variance.is_nan() {
+        return 0.0;
+    }
+
+    let dx = x as f64 - nx as f64;
+    let dy = y as f64 - ny as f64;
+    let spatial = (dx * dx + dy * dy) / params.sigma_spatial.powi(2);
+
+    let albedo =
+        (guides.albedo[i] - guides.albedo[j]).length_squared() / params.sigma_albedo.powi(2);
+    let normal =
+        (guides.normal[i] - guides.normal[j]).length_squared() / params.sigma_normal.powi(2);
+
+    // pixels that agree exactly, including two empty ones, are alike whatever their variance
+    let diff = guides.color[i] - guides.color[j];
+    let color = (diff * diff / variance)
+        .to_array()
+        .map(|c| match c.is_nan() {
+            true => 0.0,
+            false => c,
+        })
+        .iter()
+        .sum::<f64>()
+        / 3.0
+        / params.sigma_color.powi(2);
+
+    (-(spatial + albedo + normal + color) / 2.0).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::random;
+
+    #[test]
+    fn flat_regions_smooth_and_albedo_edges_stay_sharp() {
+        random::reseed([2393, 0, 0]);
+        let (width, height) = (32, 16);
+        let side = |i: usize| match i % width < width / 2 {
+            true => DVec3::splat(0.2),
+            false => DVec3::splat(0.8),
+        };
+        let albedo: Vec<_> = (0..width * height).map(side).collect();
+        let color: Vec<_> = albedo
+            .iter()
+            .map(|&a| a + (DVec3::from_array(random::rng().gen()) - 0.5) * 0.3)
+            .collect();
+        let variance = vec![DVec3::splat(0.0075); width * height];
+        let normal = vec![DVec3::Z; width * height];
+        let guides = Guides {
+            color: &color,
+            variance: &variance,
+            albedo: &albedo,
+            normal: &normal,
+        };
+        let filtered = joint_bilateral(
+            width,
+            height,
+            &guides,
+            &BilateralParams::default(),
+            &[&color],
+        );
+        let filtered = &filtered[0];
+
+        let error = |image: &[DVec3]| {
+            (0..width * height)
+                .map(|i| (image[i] - side(i)).length_squared())
+                .sum::<f64>()
+                / (width * height) as f64
+        };
+        let before = error(&color);
+        let after = error(filtered);
+        assert!(after < before / 10.0, "error {before} -> {after}");
+
+        for y in 0..height {
+            for x in [width / 2 - 1, width / 2] {
+                let i = x + y * width;
+                let off = (filtered[i] - side(i)).abs().max_element();
+                assert!(
+                    off < 0.1,
+                    "pixel ({x}, {y}) is {} beside the edge",
+                    filtered[i]
+                );
+            }
+        }
+    }
+}
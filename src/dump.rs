@@ -0,0 +1,185 @@
+//! `--dump`: prints built-in spectral data or a BRDF slice as CSV, for plotting and inspection.
+
+use std::io::Write;
+
+use glam::{DVec3, DVec4};
+
+use crate::brdf::{Brdf, RoughConductorBrdf};
+use crate::material::physical::{ior_copper, ior_glass, ior_gold, ior_silver};
+use crate::spectrum::physical::{
+    cie_d65_1nit, cie_xyz_absolute, extraterrestrial_solar_irradiance,
+    ozone_absorption_cross_section, Blackbody,
+};
+use crate::spectrum::{ConstantSpectrum, Spectrum, TabulatedSpectrum, VISIBLE};
+
+/// Something to dump, parsed from its `--dump` name.
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// `ior-gold`, `ior-silver`, `ior-copper`: real and imaginary parts of the index of refraction
+    Ior(Metal),
+    /// `ior-glass`
+    IorGlass,
+    /// `d65`: the CIE D65 illuminant, normalized to 1 cd/m²
+    D65,
+    /// `cie-xyz`: the color matching functions, scaled to give luminance
+    CieXyz,
+    /// `solar`: extraterrestrial solar irradiance
+    Solar,
+    /// `ozone`: ozone absorption cross section
+    Ozone,
+    /// `blackbody:<kelvin>`
+    Blackbody(f64),
+    /// `constant:<value>`
+    Constant(f64),
+    /// `brdf:<metal>:<alpha>`: a rough conductor lit along the normal, over viewing angle
+    Brdf(Metal, f64),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Metal {
+    Gold,
+    Silver,
+    Copper,
+}
+
+/// Wavelengths in nm at which [`Target::Brdf`] is evaluated, one column each.
+const BRDF_LAMBDAS: DVec4 = DVec4::new(450.0, 525.0, 600.0, 675.0);
+
+impl Target {
+    /// Parses a `--dump` argument.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let number = |v: &str| v.parse::<f64>().map_err(|e| format!("{v:?}: {e}"));
+        let target = match s.split(':').collect::<Vec<_>>()[..] {
+            ["ior-glass"] => Target::IorGlass,
+            ["d65"] => Target::D65,
+            ["cie-xyz"] => Target::CieXyz,
+            ["solar"] => Target::Solar,
+            ["ozone"] => Target::Ozone,
+            ["blackbody", kelvin] => Target::Blackbody(number(kelvin)?),
+            ["constant", value] => Target::Constant(number(value)?),
+            ["brdf", metal, alpha] => match number(alpha)? {
+                alpha if alpha >= 0.0 => Target::Brdf(Metal::parse(metal)?, alpha),
+                alpha => return Err(format!("invalid microfacet roughness {alpha}")),
+            },
+            [ior] if ior.starts_with("ior-") => Target::Ior(Metal::parse(&ior[4..])?),
+            _ => {
+                return Err(format!(
+                    "unknown dump target {s:?}; expected ior-<metal>, ior-glass, d65, cie-xyz, \
+                     solar, ozone, blackbody:<kelvin>, constant:<value> or brdf:<metal>:<alpha>"
+                ))
+            }
+        };
+        Ok(target)
+    }
+
+    /// Writes the CSV, with a header line naming the columns.
+    pub fn write(&self, out: &mut impl Write) -> std::io::Result<()> {
+        match *self {
+            Target::Ior(metal) => {
+                let [n, k] = metal.ior();
+                write_spectra(out, &["n", "k"], &[n, k])
+            }
+            Target::IorGlass => write_spectra(out, &["n"], &[ior_glass()]),
+            Target::D65 => write_spectra(out, &["d65"], &[cie_d65_1nit()]),
+            Target::CieXyz => {
+                let [x, y, z] = cie_xyz_absolute();
+                write_spectra(out, &["x", "y", "z"], &[x, y, z])
+            }
+            Target::Solar => {
+                write_spectra(out, &["irradiance"], &[extraterrestrial_solar_irradiance()])
+            }
+            Target::Ozone => {
+                write_spectra(out, &["cross_section"], &[ozone_absorption_cross_section()])
+            }
+            Target::Blackbody(temperature) => {
+                write_spectra(out, &["radiance"], &[&Blackbody { temperature }])
+            }
+            Target::Constant(value) => write_spectra(out, &["value"], &[&ConstantSpectrum(value)]),
+            Target::Brdf(metal, alpha) => {
+                let brdf = RoughConductorBrdf::new(metal.ior(), alpha);
+                write!(out, "theta")?;
+                for lambda in BRDF_LAMBDAS.to_array() {
+                    write!(out, ",f_{lambda}")?;
+                }
+                writeln!(out)?;
+                for degrees in 0..90 {
+                    let (sin, cos) = (degrees as f64).to_radians().sin_cos();
+                    let outgoing = -DVec3::new(sin, 0.0, cos);
+                    let f = brdf.f(DVec3::Z, outgoing, DVec3::Z, None, None, BRDF_LAMBDAS);
+                    writeln!(out, "{degrees},{},{},{},{}", f.x, f.y, f.z, f.w)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Metal {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "gold" => Ok(Metal::Gold),
+            "silver" => Ok(Metal::Silver),
+            "copper" => Ok(Metal::Copper),
+            _ => Err(format!(
+                "unknown metal {s:?}; expected gold, silver or copper"
+            )),
+        }
+    }
+
+    fn ior(self) -> &'static [TabulatedSpectrum; 2] {
+        match self {
+            Metal::Gold => ior_gold(),
+            Metal::Silver => ior_silver(),
+            Metal::Copper => ior_copper(),
+        }
+    }
+}
+
+/// Samples each spectrum at every whole nanometer of [`VISIBLE`].
+fn write_spectra(
+    out: &mut impl Write,
+    names: &[&str],
+    spectra: &[&dyn Spectrum],
+) -> std::io::Result<()> {
+    writeln!(out, "lambda,{}", names.join(","))?;
+    for lambda in VISIBLE.start as usize..VISIBLE.end as usize {
+        write!(out, "{lambda}")?;
+        for s in spectra {
+            write!(out, ",{}", s.sample(lambda as f64))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_dumps_are_constant_over_the_band() {
+        let mut csv = vec![];
+        Target::parse("constant:0.25")
+            .unwrap()
+            .write(&mut csv)
+            .unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("lambda,value"));
+        let rows: Vec<_> = lines
+            .map(|line| {
+                let (lambda, value) = line.split_once(',').unwrap();
+                (
+                    lambda.parse::<f64>().unwrap(),
+                    value.parse::<f64>().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(rows.len(), (VISIBLE.end - VISIBLE.start) as usize);
+        assert_eq!(rows[0].0, VISIBLE.start);
+        assert_eq!(rows[rows.len() - 1].0, VISIBLE.end - 1.0);
+        assert!(rows.iter().all(|&(_, value)| value == 0.25));
+
+        assert!(Target::parse("constant:bright").is_err());
+    }
+}
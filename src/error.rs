@@ -0,0 +1,74 @@
+use std::fmt::{Display, Formatter};
+
+/// Errors from loading assets and scenes.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Exr(exr::error::Error),
+    Image(image::ImageError),
+    /// A malformed or unsupported PLY mesh.
+    Ply(String),
+    /// A malformed voxel octree file.
+    Voxel(String),
+    /// A malformed CSV table of spectral data.
+    Csv(String),
+}
+
+impl Error {
+    pub fn ply(msg: impl Display) -> Self {
+        Error::Ply(msg.to_string())
+    }
+
+    pub fn voxel(msg: impl Display) -> Self {
+        Error::Voxel(msg.to_string())
+    }
+
+    pub fn csv(msg: impl Display) -> Self {
+        Error::Csv(msg.to_string())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Exr(e) => write!(f, "invalid exr file: {e}"),
+            Error::Image(e) => write!(f, "invalid image: {e}"),
+            Error::Ply(msg) => write!(f, "invalid ply file: {msg}"),
+            Error::Voxel(msg) => write!(f, "invalid voxel file: {msg}"),
+            Error::Csv(msg) => write!(f, "invalid csv file: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Exr(e) => Some(e),
+            Error::Image(e) => Some(e),
+            Error::Ply(_) | Error::Voxel(_) | Error::Csv(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<exr::error::Error> for Error {
+    fn from(e: exr::error::Error) -> Self {
+        Error::Exr(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        match e {
+            image::ImageError::IoError(e) => Error::Io(e),
+            e => Error::Image(e),
+        }
+    }
+}
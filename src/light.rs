@@ -1,14 +1,32 @@
 use std::f64::consts::PI;
+use std::sync::Arc;
 
-use glam::{DVec3, DVec4, FloatExt};
+use glam::{DVec2, DVec3, DVec4, Vec3Swizzles};
 
-use crate::spectrum::{AmplifiedSpectrum, Spectrum};
+use crate::objects::{offset_ray_origin, Object};
+use crate::random;
+use crate::spectrum::{AmplifiedSpectrum, Spectrum, VISIBLE};
+
+/// The mean of `radiance` over [`VISIBLE`], four wavelengths at a time.
+fn mean_radiance(radiance: impl Fn(DVec4) -> DVec4) -> f64 {
+    const BUNDLES: usize = 16;
+    let total: f64 = (0..BUNDLES)
+        .map(|i| {
+            let t = (DVec4::new(0.5, 1.5, 2.5, 3.5) + 4.0 * i as f64) / (4 * BUNDLES) as f64;
+            radiance(VISIBLE.start + t * (VISIBLE.end - VISIBLE.start)).element_sum()
+        })
+        .sum();
+    total / (4 * BUNDLES) as f64
+}
 
 pub struct LightSample {
     pub dir: DVec3,
     pub dist: f64,
     pub pdf: f64,
     pub emission: DVec4,
+    /// The light is a point, so `pdf` is a discrete probability and the direction can't be found
+    /// by BRDF sampling.
+    pub singular: bool,
 }
 
 pub trait Light: Send + Sync {
@@ -17,8 +35,22 @@ pub trait Light: Send + Sync {
     fn sample(&self, pos: DVec3, lambdas: DVec4, random: DVec3) -> LightSample;
 
     fn pdf(&self, pos: DVec3, dir: DVec3, lambdas: DVec4) -> f64;
+
+    /// Roughly the power the light gives off, averaged over the visible range, which
+    /// [`Scene::sample_light`](crate::scene::Scene::sample_light) chooses lights in proportion to.
+    /// Lights infinitely far away count what falls on a disk of `scene_radius`.
+    fn power(&self, scene_radius: f64) -> f64;
+
+    /// Whether the light emits nothing at any visible wavelength, which is almost certainly a
+    /// mistake in the scene. Lights which can't tell cheaply say no.
+    fn is_dark(&self) -> bool {
+        false
+    }
 }
 
+/// A light infinitely far away covering the cone of directions within `acos(cos_radius)` of `dir`
+/// with uniform radiance. `cos_radius = 0` is a hemisphere and `cos_radius = -1` the whole sphere,
+/// i.e. a uniform sky.
 pub struct DistantDiskLight<S> {
     pub emission: S,
     pub dir: DVec3,
@@ -26,14 +58,19 @@ pub struct DistantDiskLight<S> {
 }
 
 impl<S> DistantDiskLight<S> {
+    /// Creates a light producing `irradiance` on a surface facing `dir`.
+    ///
+    /// This is the radiance times the cosine-weighted solid angle of the cone, `π sin²(radius)`,
+    /// which is nearly the plain solid angle for small disks like the sun. Cones wider than a
+    /// hemisphere add nothing more to a surface facing `dir`, so `π` is the maximum.
     pub fn from_irradiance(
         dir: DVec3,
         cos_radius: f64,
         irradiance: S,
     ) -> DistantDiskLight<AmplifiedSpectrum<S>> {
-        let size_steradians = 2.0 * PI * (1.0 - cos_radius);
+        let projected_steradians = PI * (1.0 - cos_radius.max(0.0).powi(2));
         let emission = AmplifiedSpectrum {
-            factor: 1.0 / size_steradians,
+            factor: 1.0 / projected_steradians,
             s: irradiance,
         };
         DistantDiskLight {
@@ -55,18 +92,24 @@ impl<S: Spectrum + Send + Sync> Light for DistantDiskLight<S> {
     }
 
     fn sample(&self, pos: DVec3, lambdas: DVec4, random: DVec3) -> LightSample {
-        let z = self.cos_radius.lerp(1.0, random.x);
-        let (x, y) = (random.y * PI * 2.0).sin_cos();
-        let r = (1.0 - z * z).sqrt();
-
-        let (tangent, bitangent) = self.dir.any_orthonormal_pair();
-        let dir = x * r * tangent + y * r * bitangent + z * self.dir;
+        let Some((tangent, bitangent)) = crate::build_frame(self.dir) else {
+            return LightSample {
+                dir: DVec3::ZERO,
+                dist: f64::INFINITY,
+                pdf: 0.0,
+                emission: DVec4::ZERO,
+                singular: false,
+            };
+        };
+        let v = random::cone(random.xy(), self.cos_radius);
+        let dir = v.x * tangent + v.y * bitangent + v.z * self.dir;
 
         LightSample {
             dir,
             dist: f64::INFINITY,
             pdf: self.pdf(pos, dir, lambdas),
             emission: self.emission(pos, dir, lambdas, f64::INFINITY),
+            singular: false,
         }
     }
 
@@ -74,9 +117,632 @@ impl<S: Spectrum + Send + Sync> Light for DistantDiskLight<S> {
         _ = pos;
         _ = lambdas;
         if dir.dot(self.dir) >= self.cos_radius {
-            1.0 / ((1.0 - self.cos_radius) * 2.0 * PI)
+            random::cone_pdf(self.cos_radius)
         } else {
             0.0
         }
     }
+
+    fn power(&self, scene_radius: f64) -> f64 {
+        // the irradiance on a surface facing the light, as in `from_irradiance`
+        let projected_steradians = PI * (1.0 - self.cos_radius.max(0.0).powi(2));
+        let irradiance = mean_radiance(|l| self.emission.sample_multi(l)) * projected_steradians;
+        irradiance * PI * scene_radius * scene_radius
+    }
+
+    fn is_dark(&self) -> bool {
+        self.emission.is_zero()
+    }
+}
+
+/// A background infinitely far away in every direction, with radiance blending linearly in
+/// `dir · up` from `nadir` straight down to `zenith` straight up. Use [`uniform`](Self::uniform)
+/// for the same radiance everywhere.
+///
+/// Directions are sampled uniformly over the sphere.
+pub struct GradientSkyLight<S1, S2> {
+    pub up: DVec3,
+    pub zenith: S1,
+    pub nadir: S2,
+}
+
+impl<S: Clone> GradientSkyLight<S, S> {
+    pub fn uniform(radiance: S) -> Self {
+        GradientSkyLight {
+            up: DVec3::Y,
+            zenith: radiance.clone(),
+            nadir: radiance,
+        }
+    }
+}
+
+impl<S1, S2> Light for GradientSkyLight<S1, S2>
+where
+    S1: Spectrum + Send + Sync,
+    S2: Spectrum + Send + Sync,
+{
+    fn emission(&self, pos: DVec3, dir: DVec3, lambdas: DVec4, max_t: f64) -> DVec4 {
+        _ = pos;
+        if max_t < f64::INFINITY {
+            return DVec4::ZERO;
+        }
+        let t = (dir.dot(self.up) + 1.0) / 2.0;
+        let nadir = self.nadir.sample_multi(lambdas);
+        let zenith = self.zenith.sample_multi(lambdas);
+        nadir + (zenith - nadir) * t
+    }
+
+    fn sample(&self, pos: DVec3, lambdas: DVec4, random: DVec3) -> LightSample {
+        let dir = random::sphere(random.xy());
+        LightSample {
+            dir,
+            dist: f64::INFINITY,
+            pdf: self.pdf(pos, dir, lambdas),
+            emission: self.emission(pos, dir, lambdas, f64::INFINITY),
+            singular: false,
+        }
+    }
+
+    fn pdf(&self, pos: DVec3, dir: DVec3, lambdas: DVec4) -> f64 {
+        _ = pos;
+        _ = dir;
+        _ = lambdas;
+        1.0 / (4.0 * PI)
+    }
+
+    fn power(&self, scene_radius: f64) -> f64 {
+        let radiance =
+            mean_radiance(|l| (self.zenith.sample_multi(l) + self.nadir.sample_multi(l)) / 2.0);
+        radiance * 4.0 * PI * PI * scene_radius * scene_radius
+    }
+
+    fn is_dark(&self) -> bool {
+        self.zenith.is_zero() && self.nadir.is_zero()
+    }
+}
+
+/// A spotlight: a point at `pos` emitting `intensity` (radiant intensity, per steradian) into the
+/// cone of directions within `acos(cos_angle)` of `dir`, with a hard edge.
+#[allow(unused)]
+pub struct ConeLight<S> {
+    pub pos: DVec3,
+    pub dir: DVec3,
+    pub cos_angle: f64,
+    pub intensity: S,
+}
+
+impl<S: Spectrum + Send + Sync> Light for ConeLight<S> {
+    fn emission(&self, pos: DVec3, dir: DVec3, lambdas: DVec4, max_t: f64) -> DVec4 {
+        _ = (pos, dir, lambdas, max_t);
+        DVec4::ZERO
+    }
+
+    fn sample(&self, pos: DVec3, lambdas: DVec4, random: DVec3) -> LightSample {
+        _ = random;
+        let to_light = self.pos - pos;
+        let dist = to_light.length();
+        let dir = to_light / dist;
+        if dist == 0.0 || (-dir).dot(self.dir) < self.cos_angle {
+            return LightSample {
+                dir: DVec3::ZERO,
+                dist: f64::INFINITY,
+                pdf: 0.0,
+                emission: DVec4::ZERO,
+                singular: true,
+            };
+        }
+
+        LightSample {
+            dir,
+            dist,
+            pdf: 1.0,
+            emission: self.intensity.sample_multi(lambdas) / (dist * dist),
+            singular: true,
+        }
+    }
+
+    fn pdf(&self, pos: DVec3, dir: DVec3, lambdas: DVec4) -> f64 {
+        _ = (pos, dir, lambdas);
+        0.0
+    }
+
+    fn power(&self, scene_radius: f64) -> f64 {
+        _ = scene_radius;
+        mean_radiance(|l| self.intensity.sample_multi(l)) * 2.0 * PI * (1.0 - self.cos_angle)
+    }
+
+    fn is_dark(&self) -> bool {
+        self.intensity.is_zero()
+    }
+}
+
+/// A sphere with uniform radiance `emission` on its surface, seen from both outside and inside.
+/// It isn't part of the scene geometry, so it doesn't block rays.
+///
+/// From outside, directions are sampled uniformly in the cone the sphere subtends, which has far
+/// less variance than sampling its surface. From inside, every direction sees the sphere, so they
+/// are sampled uniformly over the whole sphere of directions.
+#[allow(unused)]
+pub struct SphereLight<S> {
+    pub center: DVec3,
+    pub radius: f64,
+    pub emission: S,
+}
+
+#[allow(unused)]
+impl<S> SphereLight<S> {
+    /// Returns the direction to the center and the cosine of the half-angle of the cone the sphere
+    /// subtends from `pos` along with one minus it, or `None` if `pos` is inside the sphere.
+    fn cone(&self, pos: DVec3) -> Option<(DVec3, f64, f64)> {
+        let to_center = self.center - pos;
+        let dist_sq = to_center.length_squared();
+        if dist_sq <= self.radius * self.radius {
+            return None;
+        }
+        let sin2_max = self.radius * self.radius / dist_sq;
+        let cos_max = (1.0 - sin2_max).max(0.0).sqrt();
+        // 1 - cos_max, without cancellation for distant spheres
+        let one_minus_cos_max = sin2_max / (1.0 + cos_max);
+        Some((to_center / dist_sq.sqrt(), cos_max, one_minus_cos_max))
+    }
+
+    /// Distance along `dir` from `pos` to the sphere's surface, if it is in front of `pos`.
+    fn intersect(&self, pos: DVec3, dir: DVec3) -> Option<f64> {
+        let o = pos - self.center;
+        let b = o.dot(dir);
+        let c = o.length_squared() - self.radius * self.radius;
+        let det = b * b - c;
+        if det < 0.0 {
+            return None;
+        }
+        let sqrt = det.sqrt();
+        [-b - sqrt, -b + sqrt].into_iter().find(|&t| t > 0.0)
+    }
+}
+
+impl<S: Spectrum + Send + Sync> Light for SphereLight<S> {
+    fn emission(&self, pos: DVec3, dir: DVec3, lambdas: DVec4, max_t: f64) -> DVec4 {
+        match self.intersect(pos, dir) {
+            Some(t) if t <= max_t => self.emission.sample_multi(lambdas),
+            _ => DVec4::ZERO,
+        }
+    }
+
+    fn sample(&self, pos: DVec3, lambdas: DVec4, random: DVec3) -> LightSample {
+        let dir = match self.cone(pos) {
+            Some((axis, _, one_minus_cos_max)) => {
+                let Some((tangent, bitangent)) = crate::build_frame(axis) else {
+                    return LightSample {
+                        dir: DVec3::ZERO,
+                        dist: f64::INFINITY,
+                        pdf: 0.0,
+                        emission: DVec4::ZERO,
+                        singular: false,
+                    };
+                };
+                let z = 1.0 - random.x * one_minus_cos_max;
+                let (x, y) = (random.y * PI * 2.0).sin_cos();
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                x * r * tangent + y * r * bitangent + z * axis
+            }
+            None => {
+                let z = 1.0 - 2.0 * random.x;
+                let (x, y) = (random.y * PI * 2.0).sin_cos();
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                DVec3::new(x * r, y * r, z)
+            }
+        };
+
+        // directions at the very edge of the cone may graze past the sphere due to rounding
+        let dist = self
+            .intersect(pos, dir)
+            .unwrap_or((self.center - pos).length());
+
+        LightSample {
+            dir,
+            dist,
+            pdf: self.pdf(pos, dir, lambdas),
+            emission: self.emission.sample_multi(lambdas),
+            singular: false,
+        }
+    }
+
+    fn pdf(&self, pos: DVec3, dir: DVec3, lambdas: DVec4) -> f64 {
+        _ = lambdas;
+        match self.cone(pos) {
+            Some((axis, cos_max, one_minus_cos_max)) => match dir.dot(axis) >= cos_max {
+                true => 1.0 / (2.0 * PI * one_minus_cos_max),
+                false => 0.0,
+            },
+            None => 1.0 / (4.0 * PI),
+        }
+    }
+
+    fn power(&self, scene_radius: f64) -> f64 {
+        _ = scene_radius;
+        let area = 4.0 * PI * self.radius * self.radius;
+        mean_radiance(|l| self.emission.sample_multi(l)) * PI * area
+    }
+
+    fn is_dark(&self) -> bool {
+        self.emission.is_zero()
+    }
+}
+
+/// A parallelogram spanned by `u` and `v` from `origin` with uniform radiance `emission`, like a
+/// [`Quad`](crate::objects::Quad) that glows. It emits from the side `u.cross(v)` faces, and from
+/// the back too if `two_sided`. It isn't part of the scene geometry, so it doesn't block rays.
+///
+/// Points are sampled uniformly over its area.
+#[allow(unused)]
+pub struct AreaLight<S> {
+    pub origin: DVec3,
+    pub u: DVec3,
+    pub v: DVec3,
+    pub emission: S,
+    pub two_sided: bool,
+}
+
+#[allow(unused)]
+impl<S> AreaLight<S> {
+    /// Distance along `dir` from `pos` to the parallelogram and the cosine between `dir` and the
+    /// unit normal, if `dir` hits a side that emits.
+    fn intersect(&self, pos: DVec3, dir: DVec3) -> Option<(f64, f64)> {
+        let n = self.u.cross(self.v);
+        let cos = dir.dot(n.normalize());
+        if cos == 0.0 || (cos > 0.0 && !self.two_sided) {
+            return None;
+        }
+
+        let t = n.dot(self.origin - pos) / n.dot(dir);
+        if t <= 0.0 {
+            return None;
+        }
+
+        // solve p = a*u + b*v for the parallelogram coordinates
+        let p = pos + t * dir - self.origin;
+        let w = n / n.length_squared();
+        let a = w.dot(p.cross(self.v));
+        let b = w.dot(self.u.cross(p));
+        if !(0.0..=1.0).contains(&a) || !(0.0..=1.0).contains(&b) {
+            return None;
+        }
+        Some((t, cos))
+    }
+
+    /// Converts the uniform area density to solid angle at distance `t` and cosine `cos`.
+    fn solid_angle_pdf(&self, t: f64, cos: f64) -> f64 {
+        t * t / (cos.abs() * self.u.cross(self.v).length())
+    }
+}
+
+impl<S: Spectrum + Send + Sync> Light for AreaLight<S> {
+    fn emission(&self, pos: DVec3, dir: DVec3, lambdas: DVec4, max_t: f64) -> DVec4 {
+        match self.intersect(pos, dir) {
+            Some((t, _)) if t <= max_t => self.emission.sample_multi(lambdas),
+            _ => DVec4::ZERO,
+        }
+    }
+
+    fn sample(&self, pos: DVec3, lambdas: DVec4, random: DVec3) -> LightSample {
+        let p = self.origin + random.x * self.u + random.y * self.v;
+        let to_light = p - pos;
+        let dist = to_light.length();
+        let dir = to_light / dist;
+        let cos = dir.dot(self.u.cross(self.v).normalize());
+        if dist == 0.0 || cos == 0.0 || (cos > 0.0 && !self.two_sided) {
+            return LightSample {
+                dir: DVec3::ZERO,
+                dist: f64::INFINITY,
+                pdf: 0.0,
+                emission: DVec4::ZERO,
+                singular: false,
+            };
+        }
+
+        LightSample {
+            dir,
+            dist,
+            pdf: self.solid_angle_pdf(dist, cos),
+            emission: self.emission.sample_multi(lambdas),
+            singular: false,
+        }
+    }
+
+    fn pdf(&self, pos: DVec3, dir: DVec3, lambdas: DVec4) -> f64 {
+        _ = lambdas;
+        match self.intersect(pos, dir) {
+            Some((t, cos)) => self.solid_angle_pdf(t, cos),
+            None => 0.0,
+        }
+    }
+
+    fn power(&self, scene_radius: f64) -> f64 {
+        _ = scene_radius;
+        let sides = if self.two_sided { 2.0 } else { 1.0 };
+        let area = self.u.cross(self.v).length() * sides;
+        mean_radiance(|l| self.emission.sample_multi(l)) * PI * area
+    }
+
+    fn is_dark(&self) -> bool {
+        self.emission.is_zero()
+    }
+}
+
+/// The emissive surface of an object in the scene, which [`Scene::add`](crate::scene::Scene::add)
+/// creates for every [emissive](Object::emissive) object. Unlike the other lights it is scene
+/// geometry, so it's found by raycasting the object, and its radiance is the emission of the
+/// material hit, from both sides.
+///
+/// Points are sampled uniformly over the [emissive area](Object::emissive_area). Objects which
+/// can't sample their surface have a pdf of zero, so they're left to BRDF sampling entirely.
+pub struct ObjectLight {
+    object: Arc<dyn Object>,
+    area: f64,
+}
+
+impl ObjectLight {
+    pub fn new(object: Arc<dyn Object>) -> Self {
+        ObjectLight {
+            area: object.emissive_area(),
+            object,
+        }
+    }
+
+    /// Converts the uniform area density to solid angle at distance `t` and cosine `cos`.
+    fn solid_angle_pdf(&self, t: f64, cos: f64) -> f64 {
+        match self.area > 0.0 {
+            true => t * t / (cos.abs() * self.area),
+            false => 0.0,
+        }
+    }
+}
+
+impl Light for ObjectLight {
+    fn emission(&self, pos: DVec3, dir: DVec3, lambdas: DVec4, max_t: f64) -> DVec4 {
+        // when the object is the closest hit, the scene found it at exactly `max_t`
+        match self.object.raycast(pos, dir, max_t * (1.0 + 1.0e-9)) {
+            Some(hit) => hit.material.emission_sample(lambdas),
+            None => DVec4::ZERO,
+        }
+    }
+
+    fn sample(&self, pos: DVec3, lambdas: DVec4, random: DVec3) -> LightSample {
+        let no_sample = LightSample {
+            dir: DVec3::ZERO,
+            dist: f64::INFINITY,
+            pdf: 0.0,
+            emission: DVec4::ZERO,
+            singular: false,
+        };
+        if self.area <= 0.0 {
+            return no_sample;
+        }
+        let Some(point) = self.object.sample_emissive(random.xy()) else {
+            return no_sample;
+        };
+        let to_light = point.pos - pos;
+        let dist = to_light.length();
+        let dir = to_light / dist;
+        let cos = dir.dot(point.geo_normal);
+        if dist == 0.0 || cos == 0.0 {
+            return no_sample;
+        }
+
+        // stop short of the surface so shadow rays don't find the light itself in the way, and
+        // allow for them starting up to an offset further along than `pos` too
+        let end = offset_ray_origin(point.pos, point.geo_normal, -dir);
+        let start = offset_ray_origin(pos, dir, dir);
+        LightSample {
+            dir,
+            dist: (end - start).dot(dir),
+            pdf: self.solid_angle_pdf(dist, cos),
+            emission: point.material.emission_sample(lambdas),
+            singular: false,
+        }
+    }
+
+    fn pdf(&self, pos: DVec3, dir: DVec3, lambdas: DVec4) -> f64 {
+        _ = lambdas;
+        match self.object.raycast(pos, dir, f64::INFINITY) {
+            Some(hit) => self.solid_angle_pdf(hit.t, dir.dot(hit.geo_normal)),
+            None => 0.0,
+        }
+    }
+
+    fn power(&self, scene_radius: f64) -> f64 {
+        _ = scene_radius;
+        // the emission where the middle of the sampling domain lands stands in for all of it
+        match self.object.sample_emissive(DVec2::splat(0.5)) {
+            Some(point) if self.area > 0.0 => {
+                mean_radiance(|l| point.material.emission_sample(l)) * PI * self.area
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::spectrum::ConstantSpectrum;
+
+    const LAMBDAS: DVec4 = DVec4::new(450.0, 520.0, 600.0, 680.0);
+
+    /// Monte-Carlo estimate of the irradiance `light` casts on a surface at `pos` facing `normal`,
+    /// by sampling the light.
+    fn irradiance(light: &dyn Light, pos: DVec3, normal: DVec3, samples: usize) -> DVec4 {
+        let mut total = DVec4::ZERO;
+        for _ in 0..samples {
+            let sample = light.sample(pos, LAMBDAS, random::rng().gen());
+            if sample.pdf == 0.0 {
+                continue;
+            }
+            total += sample.emission * sample.dir.dot(normal).max(0.0) / sample.pdf;
+        }
+        total / samples as f64
+    }
+
+    #[test]
+    fn wide_distant_lights_give_the_requested_irradiance() {
+        random::reseed([2369, 0, 0]);
+        let tilted = DVec3::new(1.0, 1.0, 0.0).normalize();
+        // (cos_radius, surface normal, fraction of the requested irradiance it gets)
+        let cases = [
+            // a uniform sphere lights every surface the same
+            (-1.0, DVec3::Y, 1.0),
+            (-1.0, DVec3::X, 1.0),
+            (-1.0, -DVec3::Y, 1.0),
+            // a hemisphere lights a surface at 45 degrees with 1/2 + cos(45) / 2 of it
+            (0.0, DVec3::Y, 1.0),
+            (0.0, tilted, 0.5 + 0.5 * tilted.y),
+            (0.0, DVec3::X, 0.5),
+            (0.5, DVec3::Y, 1.0),
+            (0.99, DVec3::Y, 1.0),
+        ];
+        for (cos_radius, normal, fraction) in cases {
+            let light =
+                DistantDiskLight::from_irradiance(DVec3::Y, cos_radius, ConstantSpectrum(2.0));
+            let e = irradiance(&light, DVec3::ZERO, normal, 200_000);
+            let expected = 2.0 * fraction;
+            assert!(
+                (e - expected).abs().max_element() < 0.01 * expected,
+                "cos_radius {cos_radius} gives {e} on a surface facing {normal}, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn cone_light_lights_only_its_cone() {
+        // a spotlight 2m up pointing straight down, with a 30 degree half angle
+        let light = ConeLight {
+            pos: DVec3::Y * 2.0,
+            dir: -DVec3::Y,
+            cos_angle: 30.0f64.to_radians().cos(),
+            intensity: ConstantSpectrum(5.0),
+        };
+        let edge = 2.0 * 30.0f64.to_radians().tan();
+        for x in [edge * 1.01, edge * 2.0, -edge * 1.5] {
+            let sample = light.sample(DVec3::new(x, 0.0, 0.0), LAMBDAS, DVec3::splat(0.5));
+            assert_eq!(sample.emission, DVec4::ZERO, "lit at x = {x}");
+        }
+        // above the light, and where it is
+        assert_eq!(
+            light.sample(DVec3::Y * 3.0, LAMBDAS, DVec3::ZERO).emission,
+            DVec4::ZERO
+        );
+        assert_eq!(
+            light.sample(light.pos, LAMBDAS, DVec3::ZERO).emission,
+            DVec4::ZERO
+        );
+
+        // the power landing on a 1m square of floor inside the cone is the intensity times the
+        // solid angle the square subtends, by the midpoint rule
+        const STEPS: usize = 200;
+        let (size, height) = (1.0, 2.0);
+        let mut power = DVec4::ZERO;
+        for i in 0..STEPS {
+            for j in 0..STEPS {
+                let x = ((i as f64 + 0.5) / STEPS as f64 - 0.5) * size;
+                let z = ((j as f64 + 0.5) / STEPS as f64 - 0.5) * size;
+                let sample = light.sample(DVec3::new(x, 0.0, z), LAMBDAS, DVec3::ZERO);
+                assert_eq!(sample.dist, DVec3::new(x, -2.0, z).length());
+                power += sample.emission * sample.dir.dot(DVec3::Y) / sample.pdf;
+            }
+        }
+        power *= (size / STEPS as f64).powi(2);
+        let solid_angle = 4.0 * f64::asin(size * size / (size * size + 4.0 * height * height));
+        let expected = 5.0 * solid_angle;
+        assert!(
+            (power - expected).abs().max_element() < 1e-4 * expected,
+            "{power} lands on the square, expected {expected}"
+        );
+    }
+
+    #[test]
+    fn sphere_light_matches_the_form_factor() {
+        random::reseed([2375, 0, 0]);
+        let light = SphereLight {
+            center: DVec3::Y * 3.0,
+            radius: 0.5,
+            emission: ConstantSpectrum(2.0),
+        };
+        // a small patch facing the center sees pi L sin^2 of the cone's half angle, times the
+        // cosine of its tilt while the whole sphere stays above its horizon
+        for (pos, tilt) in [
+            (DVec3::ZERO, 0.0),
+            (DVec3::Y * 2.0, 0.0),
+            (DVec3::Y * -50.0, 0.0),
+        ] {
+            for tilt in [tilt, 30.0f64.to_radians()] {
+                let normal = DVec3::new(tilt.sin(), tilt.cos(), 0.0);
+                let d = (light.center - pos).length();
+                let expected = PI * 2.0 * (0.5 / d).powi(2) * tilt.cos();
+                let e = irradiance(&light, pos, normal, 100_000);
+                assert!(
+                    (e - expected).abs().max_element() < 0.01 * expected,
+                    "{e} at {pos} tilted by {tilt}, expected {expected}"
+                );
+            }
+        }
+
+        // inside, the sphere is all around, and the samples still find its surface
+        for pos in [light.center, light.center + DVec3::new(0.2, -0.1, 0.3)] {
+            for _ in 0..1000 {
+                let sample = light.sample(pos, LAMBDAS, random::rng().gen());
+                let hit = pos + sample.dir * sample.dist;
+                assert!(((hit - light.center).length() - 0.5).abs() < 1e-12);
+                assert_eq!(sample.pdf, 1.0 / (4.0 * PI));
+            }
+            let e = irradiance(&light, pos, DVec3::Y, 100_000);
+            let expected = PI * 2.0;
+            assert!(
+                (e - expected).abs().max_element() < 0.01 * expected,
+                "{e} inside at {pos}, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn one_sided_quads_light_only_their_front() {
+        random::reseed([2404, 0, 0]);
+        let quad = |two_sided| AreaLight {
+            origin: DVec3::new(-0.5, -0.5, 0.0),
+            u: DVec3::X,
+            v: DVec3::Y,
+            emission: ConstantSpectrum(2.0),
+            two_sided,
+        };
+        // a patch 1 below the center of a unit square, facing it, sees the form factor of its four
+        // quarters, each an a by b rectangle with the patch under a corner
+        let (a, b) = (0.5f64, 0.5f64);
+        let (sa, sb) = ((1.0 + a * a).sqrt(), (1.0 + b * b).sqrt());
+        let corner = (a / sa * (b / sa).atan() + b / sb * (a / sb).atan()) / (2.0 * PI);
+        let expected = PI * 2.0 * 4.0 * corner;
+
+        let (front, back) = (DVec3::Z, -DVec3::Z);
+        for two_sided in [false, true] {
+            let light = quad(two_sided);
+            for side in [front, back] {
+                let lit = side == front || two_sided;
+                let e = irradiance(&light, side, -side, 100_000);
+                match lit {
+                    true => assert!(
+                        (e - expected).abs().max_element() < 0.01 * expected,
+                        "{e} at {side}, expected {expected}"
+                    ),
+                    false => assert_eq!(e, DVec4::ZERO, "{e} behind a one-sided light"),
+                }
+
+                let dir = (DVec3::new(0.1, -0.2, 0.0) - side).normalize();
+                let emission = light.emission(side, dir, LAMBDAS, f64::INFINITY);
+                let pdf = light.pdf(side, dir, LAMBDAS);
+                assert_eq!(emission == DVec4::splat(2.0), lit);
+                assert_eq!(pdf > 0.0, lit);
+                assert_eq!(light.emission(side, dir, LAMBDAS, 0.5), DVec4::ZERO);
+            }
+        }
+    }
 }
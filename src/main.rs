@@ -1,21 +1,33 @@
 use std::f64::consts::PI;
-use std::path::Path;
-use std::sync::LazyLock;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::ops::{AddAssign, Div, Mul, Sub};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
 use std::time::{Duration, Instant};
 
-use clap::Parser;
-use glam::{DMat3, DVec2, DVec3, DVec4};
+use clap::{Parser, ValueEnum};
+use glam::{BVec3, DMat3, DMat4, DVec2, DVec3, DVec4, EulerRot};
+use imagebuf::{dither_threshold, CameraExposure, Image};
+use light::GradientSkyLight;
 use medium::Medium;
+use objects::TransformKeyframes;
 use ordered_float::OrderedFloat;
-use rand::{thread_rng, Rng};
+use plymesh::{MeshImport, UpAxis};
+use rand::Rng;
 use random::Tabulated1DFunction;
+use rayon::iter::Either;
 use rayon::prelude::*;
 use scene::Scene;
-use spectrum::physical::cie_xyz_absolute;
-use spectrum::{Spectrum, VISIBLE};
+use spectrum::physical::{cie_d65_1nit, cie_xyz_absolute};
+use spectrum::{AmplifiedSpectrum, SensorResponse, Spectrum, VISIBLE};
 
 mod brdf;
 mod bvh;
+mod denoise;
+mod dump;
+mod error;
 mod light;
 mod material;
 mod medium;
@@ -27,8 +39,12 @@ mod random;
 mod scene;
 mod scene_description;
 mod spectrum;
+mod stats;
+mod texture;
 mod vol_trace;
 
+pub use error::Error;
+
 #[derive(clap::Parser)]
 struct Options {
     #[arg(short = 'W', default_value_t = 960)]
@@ -39,17 +55,537 @@ struct Options {
     samples: u32,
     #[arg(long, default_value_t = 6.0, allow_negative_numbers(true))]
     time: f64,
+    /// Render a sequence of frames to `frame_NNNN` next to `--output`, with the time of day
+    /// stepping evenly from `--time-start` to `--time-end` inclusive
+    #[arg(long, requires_all = ["time_start", "time_end"])]
+    frames: Option<usize>,
+    #[arg(long, allow_negative_numbers(true))]
+    time_start: Option<f64>,
+    #[arg(long, allow_negative_numbers(true))]
+    time_end: Option<f64>,
+    /// Render a turntable to `frame_NNNN` next to `--output`: the scene's objects spin by DEGREES
+    /// about the AXIS (x, y or z) through the center of their bounds over FRAMES frames, while the
+    /// camera, sun, sky and the scene's ground and walls hold still. The last frame stops one step
+    /// short of DEGREES, so that a whole turn loops.
+    #[arg(
+        long,
+        num_args = 3,
+        value_names = ["AXIS", "DEGREES", "FRAMES"],
+        allow_negative_numbers(true),
+        conflicts_with = "frames"
+    )]
+    turntable: Option<Vec<String>>,
     #[arg(long, default_value_t = 10.0)]
     altitude: f64,
+    /// Number of spheres in the `sphere-field` scene
+    #[arg(long, default_value_t = 10000)]
+    spheres: usize,
+    /// Seed for the `sphere-field` scene's layout
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Axis pointing up in the mesh files of the `models` scene, which is turned to point up in the
+    /// render
+    #[arg(long, value_enum, default_value_t = UpAxis::Y)]
+    up_axis: UpAxis,
+    /// The mesh files of the `models` scene are left handed, so mirror them to keep them from
+    /// coming out reversed
+    #[arg(long)]
+    left_handed: bool,
+    /// Add a D65 background with this luminance in cd/m², seen by rays escaping the scene. With a
+    /// second value, it blends from the first straight up to the second straight down.
+    #[arg(long, num_args = 1..=2, value_names = ["ZENITH", "NADIR"])]
+    background: Option<Vec<f64>>,
+    /// Scenes to render, comma separated or repeated. With more than one, each is written next to
+    /// `--output` under the scene's name.
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "atmosphere")]
+    scene: Vec<SceneName>,
+    /// Only render pixels within `x0 <= x < x1`, `y0 <= y < y1`
+    #[arg(long, num_args = 4, value_names = ["X0", "Y0", "X1", "Y1"])]
+    crop: Option<Vec<usize>>,
+    /// Override the scene's camera position
+    #[arg(long, num_args = 3, value_names = ["X", "Y", "Z"], allow_negative_numbers(true))]
+    cam_pos: Option<Vec<f64>>,
+    /// Move the camera back along its view direction until everything in the scene just fits in
+    /// the perspective view, instead of using the scene's camera position
+    #[arg(long, conflicts_with = "cam_pos")]
+    auto_frame: bool,
+    /// Override the scene's camera orientation; the unspecified angle defaults to 0
+    #[arg(long, allow_negative_numbers(true))]
+    cam_yaw: Option<f64>,
+    #[arg(long, allow_negative_numbers(true))]
+    cam_pitch: Option<f64>,
+    /// Vertical field of view in degrees for perspective renders
+    #[arg(long, default_value_t = 53.13)]
+    fov: f64,
+    /// How directions around the camera map to the image; defaults to equal-area for the
+    /// atmosphere scene and perspective otherwise
+    #[arg(long, value_enum)]
+    projection: Option<Projection>,
+    /// Where to write the final image; the format is picked from the extension (exr, pfm, hdr,
+    /// png). PNGs are 8-bit sRGB, exposed so that the log-average luminance is 18% gray unless
+    /// `--iso`, `--shutter` or `--f-stop` give a camera exposure. Progressive passes are saved to
+    /// the `partial` directory beside it.
+    #[arg(short, long, default_value = "raw.exr")]
+    output: PathBuf,
+    /// Dither PNG output before rounding it to 8 bits, so that smooth gradients like the sky don't
+    /// band. The pattern is fixed, so the same film always gives the same image.
+    #[arg(long)]
+    dither: bool,
+    /// Expose PNG output like a camera at this ISO speed instead of by the image's average, so
+    /// that a sequence of frames keeps the brightness differences between them. The settings that
+    /// aren't given are those of the sunny 16 rule: ISO 100, 1/100 s at f/16.
+    #[arg(long)]
+    iso: Option<f64>,
+    /// Shutter time of the camera exposure in seconds, e.g. `0.004` or `1/250`
+    #[arg(long, value_parser = imagebuf::parse_shutter)]
+    shutter: Option<f64>,
+    /// Aperture of the camera exposure as an f-number
+    #[arg(long)]
+    f_stop: Option<f64>,
+    /// Instead of rendering, resample the equal-area EXR at `--input` to `-W`x`-H` and save it
+    #[arg(long, value_enum, requires = "input")]
+    reproject: Option<Reprojection>,
+    #[arg(long)]
+    input: Option<PathBuf>,
+    /// Instead of rendering, combine the samples of EXRs rendered separately, e.g. on several
+    /// machines, and save them as if they came from one render
+    #[arg(long, num_args = 1.., conflicts_with = "reproject")]
+    merge: Option<Vec<PathBuf>>,
+    /// Instead of rendering, print built-in data as CSV: ior-<metal>, ior-glass, d65, cie-xyz,
+    /// solar, ozone, blackbody:<kelvin>, constant:<value>, or brdf:<metal>:<alpha> for a rough
+    /// conductor over viewing angle. The metals are gold, silver and copper.
+    #[arg(long, value_parser = dump::Target::parse)]
+    dump: Option<dump::Target>,
+    /// Stop adding samples once the film's relative standard error is below this, checked after
+    /// each progressive pass; `-s` still caps the sample count
+    #[arg(long)]
+    target_error: Option<f64>,
+    /// Which standard error `--target-error` applies to
+    #[arg(long, value_enum, default_value_t = ErrorMetric::Average)]
+    error_metric: ErrorMetric,
+    /// What to compute for each pixel; the modes other than `path` are for checking geometry
+    #[arg(long, value_enum, default_value_t = Integrator::Path)]
+    integrator: Integrator,
+    /// End paths after this many scattering events, counting surfaces and media alike, once the
+    /// light reaching the last one has been gathered; 1 is direct lighting only. Without it paths
+    /// only end by Russian roulette.
+    #[arg(long)]
+    max_depth: Option<u32>,
+    /// How the hero wavelength is distributed; the other three follow it at even steps through the
+    /// distribution
+    #[arg(long, value_enum, default_value_t = WavelengthSampling::Xyz)]
+    wavelength_sampling: WavelengthSampling,
+    /// CSV of spectral sensitivities (wavelength in nm, then three channels) to record instead of
+    /// CIE XYZ, e.g. a camera's raw RGB. Output files still label the channels as XYZ.
+    #[arg(long)]
+    sensor: Option<PathBuf>,
+    /// Also write the spectral radiance, averaged over `--spectral-bins` equal bins spanning the
+    /// visible range, next to the output as `<name>.spectral.exr`. Only the path integrator
+    /// fills it.
+    #[arg(long)]
+    spectral: bool,
+    #[arg(long, default_value = "32")]
+    spectral_bins: NonZeroUsize,
+    /// Also write the raycast statistics to this file as JSON; requires the `raycast-stats`
+    /// feature
+    #[arg(long)]
+    stats_json: Option<PathBuf>,
+    /// Number of buckets in the path length histograms of the raycast statistics; the last one
+    /// also counts every longer path
+    #[arg(long, default_value = "32")]
+    stats_path_buckets: NonZeroUsize,
+    /// Smooth out noise before saving, guided by albedo and normal buffers rendered alongside
+    #[arg(long)]
+    denoise: bool,
+    /// Grayscale image scaling each pixel's sample count, from `-s` where it's black to
+    /// `--sample-map-max` times that where it's white. It's stretched over the image if the sizes
+    /// differ.
+    #[arg(long)]
+    sample_map: Option<PathBuf>,
+    #[arg(long, default_value_t = 4.0)]
+    sample_map_max: f64,
+    /// Seed for the random numbers of the render, so that the same options give the same image.
+    /// Without one every run draws fresh samples, which is what `--merge` needs.
+    #[arg(long)]
+    render_seed: Option<u64>,
+    /// Number of worker threads; defaults to one per core. The image doesn't depend on it, since
+    /// each pixel's samples come from their own stream of `--render-seed`.
+    #[arg(long)]
+    threads: Option<NonZeroUsize>,
+}
+
+impl Options {
+    fn camera_exposure(&self) -> Option<CameraExposure> {
+        CameraExposure::from_args(self.iso, self.shutter, self.f_stop)
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Reprojection {
+    Equirect,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Projection {
+    /// A pinhole camera with `--fov`
+    Perspective,
+    /// The whole sphere of directions on a square image, each pixel covering the same solid
+    /// angle. It's in world space, ignoring the camera orientation, so that `--reproject` and the
+    /// viewer know which way is up.
+    EqualArea,
+    /// Longitude across and latitude down the whole image, for 360 degree panoramas; usually 2:1
+    Equirect,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ErrorMetric {
+    /// Root mean square over all pixels
+    Average,
+    /// The worst pixel
+    Max,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum WavelengthSampling {
+    /// Proportional to the sum of the CIE XYZ matching functions
+    Xyz,
+    /// Uniform over the visible range
+    Uniform,
+    /// pbrt-v4's smooth fit to the visible range, peaking at 538 nm
+    Visible,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Integrator {
+    /// Spectral volumetric path tracing
+    Path,
+    /// The first hit's shading normal, mapped from [-1, 1] to [0, 1] per channel
+    Normals,
+    /// The distance to the first hit, divided by the largest one in the image
+    Depth,
+    /// The directional albedo of the first hit's BRDF, estimated with one BRDF sample
+    Albedo,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum SceneName {
+    Atmosphere,
+    Models,
+    SimpleVolume,
+    CornellBox,
+    /// `--spheres` random spheres in a BVH, for stress testing it
+    SphereField,
+}
+
+impl SceneName {
+    /// The projection used unless `--projection` is given.
+    fn default_projection(self) -> Projection {
+        match self {
+            SceneName::Atmosphere => Projection::EqualArea,
+            _ => Projection::Perspective,
+        }
+    }
+}
+
+/// The parsed arguments of `--turntable`.
+struct Turntable {
+    axis: DVec3,
+    degrees: f64,
+    frames: usize,
+}
+
+impl Turntable {
+    fn parse(args: &[String]) -> Result<Turntable, String> {
+        let axis = match args[0].as_str() {
+            "x" => DVec3::X,
+            "y" => DVec3::Y,
+            "z" => DVec3::Z,
+            axis => return Err(format!("the axis must be x, y or z, not {axis}")),
+        };
+        let degrees = args[1]
+            .parse()
+            .map_err(|e| format!("invalid angle {}: {e}", args[1]))?;
+        let frames = match args[2].parse() {
+            Ok(0) => return Err("there must be at least one frame".to_owned()),
+            Ok(frames) => frames,
+            Err(e) => return Err(format!("invalid frame count {}: {e}", args[2])),
+        };
+        Ok(Turntable {
+            axis,
+            degrees,
+            frames,
+        })
+    }
 }
 
 fn main() {
     let opt = Options::parse();
 
-    let (scene, camera, looking, camera_medium) =
-        scene_description::atmosphere_scene(opt.time, opt.altitude);
-        // scene_description::load();
-        // scene_description::simple_volume_scene();
+    if let Some(&[x0, y0, x1, y1]) = opt.crop.as_deref() {
+        if !(x0 < x1 && x1 <= opt.width && y0 < y1 && y1 <= opt.height) {
+            eprintln!(
+                "error: --crop {x0} {y0} {x1} {y1} isn't a nonempty rectangle within the {}x{} \
+                 image",
+                opt.width, opt.height
+            );
+            std::process::exit(1);
+        }
+    }
+
+    let threads = opt.threads.map_or(0, NonZeroUsize::get);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("error: failed to start the worker threads: {e}");
+            std::process::exit(1);
+        });
+    pool.install(|| run(&opt));
+}
+
+fn run(opt: &Options) {
+    if let Some(target) = &opt.dump {
+        target.write(&mut std::io::stdout().lock()).unwrap();
+        return;
+    }
+
+    if let Some(paths) = &opt.merge {
+        let mut merged: Option<Film> = None;
+        for path in paths {
+            let film = or_exit(
+                Film::load_raw(path),
+                &format!("failed to read {}", path.display()),
+            );
+            match &mut merged {
+                None => merged = Some(film),
+                Some(merged) => {
+                    if (film.width, film.height) != (merged.width, merged.height) {
+                        eprintln!(
+                            "error: {} is {}x{}, but the films before it are {}x{}",
+                            path.display(),
+                            film.width,
+                            film.height,
+                            merged.width,
+                            merged.height
+                        );
+                        std::process::exit(1);
+                    }
+                    merged.merge(&film);
+                }
+            }
+        }
+        merged
+            .unwrap()
+            .save(&opt.output, opt.dither, opt.camera_exposure());
+        return;
+    }
+
+    if let Some(Reprojection::Equirect) = opt.reproject {
+        let src = or_exit(
+            Film::load_raw(opt.input.as_ref().unwrap()),
+            "failed to read input",
+        );
+        if src.width != src.height {
+            eprintln!(
+                "error: equal-area images must be square, but the input is {}x{}",
+                src.width, src.height
+            );
+            std::process::exit(1);
+        }
+        equal_area_to_equirect(&src, opt.width, opt.height).save(
+            &opt.output,
+            opt.dither,
+            opt.camera_exposure(),
+        );
+        return;
+    }
+
+    stats::set_path_length_buckets(opt.stats_path_buckets.get());
+
+    let turntable = opt.turntable.as_deref().map(|args| {
+        Turntable::parse(args).unwrap_or_else(|e| {
+            eprintln!("error: invalid --turntable: {e}");
+            std::process::exit(1);
+        })
+    });
+
+    let batch = opt.scene.len() > 1;
+    let t = Instant::now();
+    for &scene in &opt.scene {
+        let name = scene.to_possible_value().unwrap().get_name().to_owned();
+        let (output, frame_prefix) = match batch {
+            true => (named_output(&opt.output, &name), format!("{name}_frame")),
+            false => (opt.output.clone(), "frame".to_owned()),
+        };
+        if batch {
+            println!("rendering {name}");
+        }
+
+        let scene_t = Instant::now();
+        match opt.frames {
+            Some(frames) => {
+                let (start, end) = (opt.time_start.unwrap(), opt.time_end.unwrap());
+                for i in 0..frames {
+                    let time = frame_time(start, end, frames, i);
+                    let output = frame_output(&output, &frame_prefix, i);
+                    println!("frame {i} at time {time:.3} to {}", output.display());
+                    render_frame(opt, scene, time, None, &output);
+                }
+            }
+            None => match &turntable {
+                Some(turntable) => {
+                    let spin = TransformKeyframes::turntable(turntable.axis, turntable.degrees);
+                    for i in 0..turntable.frames {
+                        let t = i as f64 / turntable.frames as f64;
+                        let output = frame_output(&output, &frame_prefix, i);
+                        println!(
+                            "frame {i} at {:.1} degrees to {}",
+                            turntable.degrees * t,
+                            output.display()
+                        );
+                        render_frame(opt, scene, opt.time, Some(spin.at(t)), &output);
+                    }
+                }
+                None => render_frame(opt, scene, opt.time, None, &output),
+            },
+        }
+
+        if batch {
+            println!("{name} took {:.2?}", scene_t.elapsed());
+        }
+    }
+    if batch {
+        println!("all scenes took {:.2?}", t.elapsed());
+    }
+
+    if stats::enabled() {
+        let stats = stats::total();
+        let path_lengths = stats::path_lengths();
+        println!("raycasts: {stats}");
+        println!("path lengths: {path_lengths}");
+        if let Some(path) = &opt.stats_json {
+            if let Err(e) = std::fs::write(path, stats.to_json(&path_lengths)) {
+                eprintln!("error: failed to write {}: {e}", path.display());
+            }
+        }
+    } else if opt.stats_json.is_some() {
+        eprintln!("warning: --stats-json needs the `raycast-stats` feature, nothing was written");
+    }
+}
+
+/// `name` in the same directory and with the same extension as `output`.
+fn named_output(output: &Path, name: &str) -> PathBuf {
+    let ext = output.extension().and_then(|e| e.to_str()).unwrap_or("exr");
+    output.with_file_name(format!("{name}.{ext}"))
+}
+
+/// Where frame `i` of a sequence rendered to `output` goes: `{prefix}_{i:04}` beside it.
+fn frame_output(output: &Path, prefix: &str, i: usize) -> PathBuf {
+    named_output(output, &format!("{prefix}_{i:04}"))
+}
+
+/// The time of day of frame `i` of a sequence of `frames` stepping evenly from `start` to `end`
+/// inclusive.
+fn frame_time(start: f64, end: f64, frames: usize, i: usize) -> f64 {
+    match frames {
+        1 => start,
+        _ => start + (end - start) * i as f64 / (frames - 1) as f64,
+    }
+}
+
+/// Builds the scene at the given time of day, with its objects other than the fixed ones turned by
+/// `spin` about the center of their bounds, and renders it to `output`.
+fn render_frame(opt: &Options, scene: SceneName, time: f64, spin: Option<DMat4>, output: &Path) {
+    let projection = opt.projection.unwrap_or(scene.default_projection());
+    if projection == Projection::EqualArea && opt.width != opt.height {
+        eprintln!(
+            "error: equal-area renders must be square, but the image is {}x{}; set -W and -H to \
+             the same size or pick another --projection",
+            opt.width, opt.height
+        );
+        std::process::exit(1);
+    }
+    let loaded = match scene {
+        SceneName::Atmosphere => scene_description::atmosphere_scene(time, opt.altitude).map(boxed),
+        SceneName::Models => scene_description::load(MeshImport {
+            up: opt.up_axis,
+            left_handed: opt.left_handed,
+        })
+        .map(boxed),
+        SceneName::SimpleVolume => Ok(boxed(scene_description::simple_volume_scene())),
+        SceneName::CornellBox => Ok(boxed(scene_description::cornell_box())),
+        SceneName::SphereField => Ok(boxed(scene_description::sphere_field(
+            opt.spheres,
+            opt.seed,
+        ))),
+    };
+    let (mut scene, mut camera, mut looking, camera_medium) =
+        or_exit(loaded, "failed to load scene");
+
+    if let Some(b) = &opt.background {
+        let d65 = |nits| AmplifiedSpectrum {
+            factor: nits,
+            s: cie_d65_1nit(),
+        };
+        scene.set_background(match b[..] {
+            [zenith, nadir] => GradientSkyLight {
+                up: DVec3::Y,
+                zenith: d65(zenith),
+                nadir: d65(nadir),
+            },
+            _ => GradientSkyLight::uniform(d65(b[0])),
+        });
+    }
+
+    if let Err(warnings) = scene.validate() {
+        for warning in warnings {
+            eprintln!("warning: {warning}");
+        }
+    }
+
+    if let Some(p) = &opt.cam_pos {
+        camera = DVec3::new(p[0], p[1], p[2]);
+    }
+    if opt.cam_yaw.is_some() || opt.cam_pitch.is_some() {
+        let yaw = opt.cam_yaw.unwrap_or(0.0);
+        let pitch = opt.cam_pitch.unwrap_or(0.0);
+        looking = Camera::orientation(yaw, pitch);
+    }
+    let camera = match opt.auto_frame {
+        false => Camera {
+            pos: camera,
+            looking,
+            vfov: opt.fov.to_radians(),
+            projection,
+        },
+        true => {
+            if projection != Projection::Perspective {
+                eprintln!("error: --auto-frame only works with the perspective projection");
+                std::process::exit(1);
+            }
+            let Some(bounds) = scene.bounds() else {
+                eprintln!("error: --auto-frame needs a scene with objects in it");
+                std::process::exit(1);
+            };
+            let aspect = opt.width as f64 / opt.height as f64;
+            Camera::auto_frame(bounds, looking, opt.fov.to_radians(), aspect)
+        }
+    };
+
+    // after framing, so that the camera holds still while the objects turn
+    if let Some(spin) = spin {
+        match scene.movable_bounds() {
+            Some(bounds) => {
+                let center = DMat4::from_translation(bounds.centroid());
+                scene.transform_objects(center * spin * center.inverse());
+            }
+            None => eprintln!("warning: --turntable has nothing to turn, the scene is all fixed"),
+        }
+    }
+
+    // the scene's medium is the one outside of everything, which isn't where a camera inside a
+    // volume starts out
+    let camera_medium = scene.medium_at(camera.pos, &camera_medium);
 
     // let mut pixel = Pixel::default();
     // for _ in 0..100000 {
@@ -104,42 +640,116 @@ fn main() {
     // return;
 
     let mut film = Film::new(opt.width, opt.height);
+    let mut spectral = match (opt.spectral, opt.integrator) {
+        (false, _) => None,
+        (true, Integrator::Path) => Some(SpectralFilm::new(
+            opt.width,
+            opt.height,
+            opt.spectral_bins.get(),
+        )),
+        (true, _) => {
+            eprintln!("warning: --spectral only applies to the path integrator, it's ignored");
+            None
+        }
+    };
+    let crop = opt.crop.as_deref().map(|c| Crop {
+        min: (c[0], c[1]),
+        max: (c[2], c[3]),
+    });
+
+    let sample_map = opt.sample_map.as_ref().map(|path| {
+        or_exit(
+            SampleMap::load(path, opt.sample_map_max),
+            "failed to load sample map",
+        )
+    });
+
+    let sensor = match &opt.sensor {
+        Some(path) => or_exit(
+            std::fs::read_to_string(path)
+                .map_err(Error::from)
+                .and_then(|csv| SensorResponse::from_csv(&csv)),
+            "failed to load sensor response",
+        ),
+        None => SensorResponse::Xyz,
+    };
+
+    let settings = RenderSettings {
+        samples: opt.samples,
+        scene: &scene,
+        camera: &camera,
+        camera_medium,
+        integrator: opt.integrator,
+        max_depth: opt.max_depth,
+        wavelengths: opt.wavelength_sampling,
+        sensor: &sensor,
+        crop,
+        sample_map: sample_map.as_ref(),
+        seed: opt.render_seed,
+    };
+
+    // nothing cancels renders from the command line
+    let cancel = AtomicBool::new(false);
 
     let t = Instant::now();
-    let mut last = 0;
-    for j in 1.. {
-        let to_render = opt.samples.min(2.0f64.powf(j as f64 / 2.0).round() as u32);
-        if to_render == last {
-            break;
+    let target = opt.target_error.map(|e| (e, opt.error_metric));
+    let partial = output.with_file_name("partial");
+    render_passes(
+        &mut film,
+        &settings,
+        spectral.as_mut(),
+        &cancel,
+        target,
+        |film, samples| {
+            film.save_raw(partial.join(format!("{samples}.exr")));
+
+            let d = t.elapsed();
+            println!(
+                "{:>8}/{} in {:>8.2} {:>12.2} paths/sec   {:>8.5} avg   {:>8.5} max",
+                samples,
+                opt.samples,
+                Time(d),
+                film.num_paths() / d.as_secs_f64(),
+                film.average_sterr_sq().sqrt(),
+                film.max_sterr_sq().sqrt()
+            );
+        },
+    );
+
+    if opt.integrator == Integrator::Depth {
+        let max_depth = film.rendered().map(|p| p.mean.y).fold(0.0, f64::max);
+        if max_depth > 0.0 {
+            film.scale(1.0 / max_depth);
         }
-        render(
-            &mut film,
-            to_render - last,
-            &scene,
-            camera,
-            looking,
-            &camera_medium,
-        );
-        last = to_render;
+    }
 
-        film.save_raw(format!("partial/{to_render}.exr"));
-
-        let d = t.elapsed();
-        println!(
-            "{:>8}/{} in {:>8.2} {:>12.2} paths/sec   {:>8.5} avg   {:>8.5} max",
-            to_render,
-            opt.samples,
-            Time(d),
-            film.num_paths() / d.as_secs_f64(),
-            film.average_sterr_sq().sqrt(),
-            film.max_sterr_sq().sqrt()
-        );
+    // denoising changes the means the error is relative to, so measure it on the raw film
+    let average_sterr_sq = film.average_sterr_sq();
+    if opt.denoise && opt.integrator == Integrator::Path {
+        let mut albedo = Film::new(opt.width, opt.height);
+        let mut normals = Film::new(opt.width, opt.height);
+        for (guide, integrator) in [
+            (&mut albedo, Integrator::Albedo),
+            (&mut normals, Integrator::Normals),
+        ] {
+            let guide_settings = RenderSettings {
+                samples: DENOISE_GUIDE_SAMPLES,
+                integrator,
+                sample_map: None,
+                ..settings
+            };
+            render(guide, &guide_settings, None, &cancel);
+        }
+        film.denoise(&albedo, &normals);
     }
 
-    film.save_raw("raw.exr");
+    film.save(output, opt.dither, opt.camera_exposure());
+    if let Some(spectral) = &spectral {
+        spectral.save(output.with_extension("spectral.exr"));
+    }
 
     let d = t.elapsed();
-    let efficiency = 1.0 / (film.average_sterr_sq() * d.as_secs_f64());
+    let efficiency = 1.0 / (average_sterr_sq * d.as_secs_f64());
     println!(
         "rendered in {:.2?} ({:.2} paths/sec) with efficiency {efficiency}",
         d,
@@ -147,6 +757,19 @@ fn main() {
     );
 }
 
+fn or_exit<T>(result: Result<T, Error>, context: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {context}: {e}");
+        std::process::exit(1);
+    })
+}
+
+fn boxed<M: Medium + 'static>(
+    (scene, camera, looking, medium): (Scene, DVec3, DMat3, M),
+) -> (Scene, DVec3, DMat3, Box<dyn Medium>) {
+    (scene, camera, looking, Box::new(medium))
+}
+
 struct Film {
     width: usize,
     height: usize,
@@ -158,6 +781,8 @@ struct Pixel {
     mean: DVec3,
     m2: DVec3,
     count: f64,
+    /// Running means of the emitted, diffuse and specular parts of `mean`.
+    lobes: [DVec3; 3],
 }
 
 impl Film {
@@ -172,6 +797,23 @@ impl Film {
     }
 
     fn save_raw(&self, path: impl AsRef<Path>) {
+        std::fs::write(path, self.to_exr_bytes()).unwrap();
+    }
+
+    /// The XYZ means as the viewer's [`Image`], rounded to `f32` as written to the `beauty` layer
+    /// of [`save_raw`](Film::save_raw), so that a render can be shown without the round trip
+    /// through an EXR. The viewer turns the pixels into its `Vec3`s with `Vec3::from`.
+    fn to_image(&self) -> Image<[f32; 3]> {
+        Image::new(self.width, self.height, |x, y| {
+            self.data[x + y * self.width].mean.as_vec3().to_array()
+        })
+    }
+
+    /// Encodes the film as EXR, with the XYZ means in the `beauty` layer and their split by first
+    /// scattering lobe in the `emitted`, `diffuse` and `specular` layers. The sample counts and
+    /// sums of squared deviations from the means are kept in the `count` and `m2` layers, so that
+    /// films can be [merged](Film::merge) after loading.
+    fn to_exr_bytes(&self) -> Vec<u8> {
         use exr::prelude::*;
 
         let attributes = ImageAttributes {
@@ -190,20 +832,201 @@ impl Film {
             other: Default::default(),
         };
 
+        let lobe = |name: &str, lobe: usize| {
+            Layer::new(
+                (self.width, self.height),
+                LayerAttributes::named(name),
+                Encoding::FAST_LOSSLESS,
+                SpecificChannels::rgb(move |Vec2(x, y): Vec2<usize>| {
+                    self.data[x + y * self.width].lobes[lobe].as_vec3().into()
+                }),
+            )
+        };
+
+        let beauty = self.to_image();
+        let mut bytes = vec![];
         Image::empty(attributes)
             .with_layer(Layer::new(
                 (self.width, self.height),
-                LayerAttributes::default(),
+                LayerAttributes::named("beauty"),
+                Encoding::FAST_LOSSLESS,
+                SpecificChannels::rgb(|Vec2(x, y): Vec2<usize>| beauty[(x, y)].into()),
+            ))
+            .with_layer(lobe("emitted", 0))
+            .with_layer(lobe("diffuse", 1))
+            .with_layer(lobe("specular", 2))
+            .with_layer(Layer::new(
+                (self.width, self.height),
+                LayerAttributes::named("m2"),
                 Encoding::FAST_LOSSLESS,
                 SpecificChannels::rgb(|Vec2(x, y): Vec2<usize>| {
-                    self.data[x + y * self.width].mean.as_vec3().into()
+                    self.data[x + y * self.width].m2.as_vec3().into()
                 }),
             ))
+            .with_layer(Layer::new(
+                (self.width, self.height),
+                LayerAttributes::named("count"),
+                Encoding::FAST_LOSSLESS,
+                SpecificChannels::build()
+                    .with_channel("Y")
+                    .with_pixel_fn(|Vec2(x, y)| (self.data[x + y * self.width].count as f32,)),
+            ))
             .write()
-            .to_file(path)
+            .to_buffered(std::io::Cursor::new(&mut bytes))
+            .unwrap();
+        bytes
+    }
+
+    fn load_raw(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_exr_bytes(&std::fs::read(path)?)
+    }
+
+    /// Decodes an EXR as written by [`Film::to_exr_bytes`], taking the first RGB layer as the
+    /// means. The lobe, `count` and `m2` layers are read if they're there; without them every
+    /// pixel counts as one sample.
+    fn from_exr_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        use exr::prelude::*;
+
+        let image = read()
+            .no_deep_data()
+            .largest_resolution_level()
+            .all_channels()
+            .all_layers()
+            .all_attributes()
+            .from_buffered(std::io::Cursor::new(bytes))?;
+
+        let channel = |layer: &Layer<AnyChannels<FlatSamples>>, name: &str| {
+            let channel = layer.channel_data.list.iter().find(|c| c.name == *name)?;
+            Some(
+                channel
+                    .sample_data
+                    .values_as_f32()
+                    .map(f64::from)
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let rgb = |layer: &Layer<AnyChannels<FlatSamples>>| {
+            let [r, g, b] = ["R", "G", "B"].map(|name| channel(layer, name));
+            let (r, g, b) = (r?, g?, b?);
+            Some(
+                (0..r.len())
+                    .map(|i| DVec3::new(r[i], g[i], b[i]))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let named = |name: &str| {
+            image.layer_data.iter().find(|l| {
+                l.attributes
+                    .layer_name
+                    .as_ref()
+                    .is_some_and(|n| *n == *name)
+            })
+        };
+
+        let (beauty, means) = image
+            .layer_data
+            .iter()
+            .find_map(|layer| Some((layer, rgb(layer)?)))
+            .ok_or(exr::error::Error::Invalid("no RGB layer".into()))?;
+        let (width, height) = (beauty.size.0, beauty.size.1);
+        let mut film = Film::new(width, height);
+        for (p, mean) in film.data.iter_mut().zip(means) {
+            p.accumulate_sample(mean);
+        }
+
+        let same_size = |layer: &&Layer<_>| layer.size == beauty.size;
+        for (l, name) in ["emitted", "diffuse", "specular"].into_iter().enumerate() {
+            if let Some(lobe) = named(name).filter(same_size).and_then(rgb) {
+                for (p, value) in film.data.iter_mut().zip(lobe) {
+                    p.lobes[l] = value;
+                }
+            }
+        }
+        let count = named("count")
+            .filter(same_size)
+            .and_then(|l| channel(l, "Y"));
+        let m2 = named("m2").filter(same_size).and_then(rgb);
+        if let (Some(count), Some(m2)) = (count, m2) {
+            for ((p, count), m2) in film.data.iter_mut().zip(count).zip(m2) {
+                p.count = count;
+                p.m2 = m2;
+            }
+        }
+        Ok(film)
+    }
+
+    /// Converts the film to 8-bit sRGB with opaque alpha, top row first. `tonemap` maps each
+    /// pixel's XYZ to the display-referred XYZ to encode, which is clipped to the sRGB gamut.
+    ///
+    /// With `dither`, each pixel is rounded up or down by comparing its fraction with
+    /// [`dither_threshold`] instead of one half, which keeps the average of a region but breaks up
+    /// the steps between levels.
+    fn to_rgba8(&self, tonemap: &dyn Fn(DVec3) -> DVec3, dither: bool) -> Vec<u8> {
+        self.data
+            .iter()
+            .enumerate()
+            .flat_map(|(i, p)| {
+                let srgb = spectrum::xyz_to_srgb(tonemap(p.mean)).clamp(DVec3::ZERO, DVec3::ONE);
+                let offset = match dither {
+                    true => dither_threshold(i % self.width, i / self.width),
+                    false => 0.5,
+                };
+                let [r, g, b] = (srgb * 255.0 + offset).floor().to_array().map(|c| c as u8);
+                [r, g, b, 255]
+            })
+            .collect()
+    }
+
+    /// Writes the film in the format picked by `path`'s extension, EXR if there's no other match.
+    /// `dither` and `camera` only apply to PNGs; the other formats keep absolute luminance.
+    fn save(&self, path: &Path, dither: bool, camera: Option<CameraExposure>) {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("pfm") => self.save_pfm(path),
+            Some("hdr") => self.save_hdr(path),
+            Some("png") => self.save_png(path, dither, camera),
+            _ => self.save_raw(path),
+        }
+    }
+
+    /// Writes 8-bit sRGB, exposed by `camera` so that its saturation is white, or without one so
+    /// that the log-average luminance comes out as 18% gray.
+    fn save_png(&self, path: impl AsRef<Path>, dither: bool, camera: Option<CameraExposure>) {
+        let exposure = match camera {
+            Some(camera) => camera.scale(),
+            None => 0.18 / self.l_avg(),
+        };
+        let rgba = self.to_rgba8(&|xyz| xyz * exposure, dither);
+        image::RgbaImage::from_raw(self.width as u32, self.height as u32, rgba)
+            .unwrap()
+            .save(path)
             .unwrap();
     }
 
+    /// Writes linear sRGB as a little-endian PFM. Scanlines are stored bottom to top.
+    fn save_pfm(&self, path: impl AsRef<Path>) {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+        write!(out, "PF\n{} {}\n-1.0\n", self.width, self.height).unwrap();
+        for row in self.data.chunks_exact(self.width).rev() {
+            for p in row {
+                let rgb = spectrum::xyz_to_srgb_linear(p.mean).as_vec3();
+                for c in rgb.to_array() {
+                    out.write_all(&c.to_le_bytes()).unwrap();
+                }
+            }
+        }
+        out.flush().unwrap();
+    }
+
+    /// Writes linear sRGB as a Radiance RGBE image.
+    fn save_hdr(&self, path: impl AsRef<Path>) {
+        image::Rgb32FImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let p = &self.data[x as usize + y as usize * self.width];
+            image::Rgb(spectrum::xyz_to_srgb_linear(p.mean).as_vec3().to_array())
+        })
+        .save(path)
+        .unwrap();
+    }
+
     fn par_iter_mut(&mut self) -> impl IndexedParallelIterator<Item = (usize, usize, &mut Pixel)> {
         self.data.par_iter_mut().enumerate().map(|(i, p)| {
             let x = i % self.width;
@@ -221,23 +1044,26 @@ impl Film {
         })
     }
 
+    /// Pixels which have received at least one sample; cropped renders leave the rest empty.
+    fn rendered(&self) -> impl Iterator<Item = &Pixel> {
+        self.data.iter().filter(|p| p.count > 0.0)
+    }
+
     fn l_avg(&self) -> f64 {
         (self
-            .data
-            .iter()
+            .rendered()
             .map(|p| p.mean.y.max(0.001).ln())
             .sum::<f64>()
-            / (self.data.len() as f64))
+            / (self.rendered().count() as f64))
             .exp()
     }
 
     fn average_sterr_sq(&self) -> f64 {
         let l_avg = self.l_avg();
-        self.data
-            .iter()
+        self.rendered()
             .map(|p| p.sterr_sq().element_sum())
             .sum::<f64>()
-            / self.data.len() as f64
+            / self.rendered().count() as f64
             / 3.0
             / l_avg
             / l_avg
@@ -245,8 +1071,7 @@ impl Film {
 
     fn max_sterr_sq(&self) -> f64 {
         let l_avg = self.l_avg();
-        self.data
-            .iter()
+        self.rendered()
             .map(|p| OrderedFloat(p.sterr_sq().element_sum()))
             .max()
             .unwrap()
@@ -256,6 +1081,79 @@ impl Film {
             / l_avg
     }
 
+    /// Multiplies every pixel by `factor`.
+    fn scale(&mut self, factor: f64) {
+        for p in self.data.iter_mut() {
+            p.mean *= factor;
+            p.m2 *= factor * factor;
+            for lobe in &mut p.lobes {
+                *lobe *= factor;
+            }
+        }
+    }
+
+    /// Smooths the means and their lobes with [`denoise::joint_bilateral`], guided by films of
+    /// the same size rendered with [`Integrator::Albedo`] and [`Integrator::Normals`]. The
+    /// standard errors are left as they were.
+    fn denoise(&mut self, albedo: &Film, normals: &Film) {
+        let color: Vec<_> = self.data.iter().map(|p| p.mean).collect();
+        let variance: Vec<_> = self
+            .data
+            .iter()
+            .map(|p| match p.count {
+                0.0 => DVec3::NAN,
+                1.0 => DVec3::INFINITY,
+                _ => p.sterr_sq(),
+            })
+            .collect();
+        let albedo: Vec<_> = albedo.data.iter().map(|p| p.mean).collect();
+        // undo the debug integrator's mapping to colors
+        let normal: Vec<_> = normals
+            .data
+            .iter()
+            .map(|p| match p.mean {
+                DVec3::ZERO => DVec3::ZERO,
+                xyz => (spectrum::xyz_to_srgb_linear(xyz) * 2.0 - 1.0).normalize_or_zero(),
+            })
+            .collect();
+        let guides = denoise::Guides {
+            color: &color,
+            variance: &variance,
+            albedo: &albedo,
+            normal: &normal,
+        };
+
+        let lobes: [Vec<_>; 3] =
+            std::array::from_fn(|l| self.data.iter().map(|p| p.lobes[l]).collect());
+        let filtered = denoise::joint_bilateral(
+            self.width,
+            self.height,
+            &guides,
+            &Default::default(),
+            &[&color, &lobes[0], &lobes[1], &lobes[2]],
+        );
+        for (i, p) in self.data.iter_mut().enumerate() {
+            p.mean = filtered[0][i];
+            for l in 0..3 {
+                p.lobes[l] = filtered[l + 1][i];
+            }
+        }
+    }
+
+    /// Adds the samples of `other`, a film of the same size rendered independently, as if they had
+    /// been accumulated into this one.
+    fn merge(&mut self, other: &Film) {
+        assert_eq!(
+            (self.width, self.height),
+            (other.width, other.height),
+            "merged films must be the same size"
+        );
+        self.data
+            .par_iter_mut()
+            .zip(other.data.par_iter())
+            .for_each(|(p, q)| p.merge(q));
+    }
+
     fn num_paths(&self) -> f64 {
         self.data.iter().map(|p| p.count).sum()
     }
@@ -263,98 +1161,589 @@ impl Film {
 
 impl Pixel {
     fn accumulate_sample(&mut self, value: DVec3) {
-        let delta = value - self.mean;
         self.count += 1.0;
-        self.mean += delta / self.count;
-        let delta2 = value - self.mean;
-        self.m2 += delta * delta2;
+        welford(&mut self.mean, &mut self.m2, self.count, value);
     }
 
-    fn sterr_sq(&self) -> DVec3 {
-        self.m2 / (self.count - 1.0) / self.count
+    /// Accumulates a sample split into its emitted, diffuse and specular parts.
+    fn accumulate_lobes(&mut self, lobes: [DVec3; 3]) {
+        self.accumulate_sample(lobes.iter().sum());
+        for (mean, value) in self.lobes.iter_mut().zip(lobes) {
+            *mean += (value - *mean) / self.count;
+        }
     }
-}
-
-fn render(
-    film: &mut Film,
-    samples: u32,
-    scene: &Scene,
-    camera: DVec3,
-    looking: DMat3,
-    camera_medium: &dyn Medium,
-) {
-    let width = film.width;
-    let height = film.height;
-    let fov = 1.0;
-    film.par_iter_mut().for_each(|(x, y, pixel)| {
-        for _ in 0..samples {
-            let d;
-            if width == height {
-                let p = (DVec2::new(x as f64, y as f64) + thread_rng().gen::<DVec2>())
-                    / DVec2::new(width as f64, height as f64);
-                d = equal_area_square_to_sphere(p);
-            } else {
-                let x = x as f64 + thread_rng().gen::<f64>() - width as f64 / 2.0;
-                let y = y as f64 + thread_rng().gen::<f64>() - height as f64 / 2.0;
-                let v = DVec3::new(x / height as f64 * fov, -y / height as f64 * fov, 1.0);
-                d = looking * v.normalize();
-            }
-
-            let random = thread_rng().gen_range(0.0..1.0);
-            let stratified = (DVec4::splat(random) + DVec4::new(0.0, 0.25, 0.5, 0.75)) % 1.0;
-            let lambdas = stratified.map(sample_wavelengths);
-            let pdf = lambdas.map(wavelength_pdf);
-
-            // let radiance = path_trace::path_trace(scene, camera, d, lambdas, camera_medium);
-            let radiance = vol_trace::path_trace(scene, camera, d, lambdas, camera_medium);
-            let mut value = DVec3::ZERO;
-            for i in 0..4 {
-                value +=
-                    (radiance[i] / pdf[i] / 4.0) * spectrum::lambda_to_xyz_absolute(lambdas[i]);
-            }
 
-            pixel.accumulate_sample(value);
+    /// Combines the statistics of two disjoint sets of samples with Chan et al.'s parallel form of
+    /// Welford's algorithm.
+    fn merge(&mut self, other: &Pixel) {
+        if other.count == 0.0 {
+            return;
         }
-    });
-}
+        let count = self.count + other.count;
+        let weight = other.count / count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * weight;
+        self.m2 += other.m2 + delta * delta * self.count * weight;
+        for (mean, value) in self.lobes.iter_mut().zip(other.lobes) {
+            *mean += (value - *mean) * weight;
+        }
+        self.count = count;
+    }
 
-static XYZ_SUM: LazyLock<Tabulated1DFunction> = LazyLock::new(|| {
-    let [x, y, z] = cie_xyz_absolute();
-    let mut data = vec![0.0; x.raw().raw().len()];
-    for i in 0..data.len() {
-        data[i] += x.raw().raw()[i].abs();
-        data[i] += y.raw().raw()[i].abs();
-        data[i] += z.raw().raw()[i].abs();
+    fn sterr_sq(&self) -> DVec3 {
+        self.m2 / (self.count - 1.0) / self.count
     }
-    Tabulated1DFunction::new(&data, VISIBLE.start, VISIBLE.end)
-});
+}
 
-fn sample_wavelengths(random: f64) -> f64 {
-    XYZ_SUM.sample(random)
+/// Adds `value` to a running `mean` and sum of squared deviations from it, `m2`, with Welford's
+/// algorithm. `count` includes the new value.
+fn welford<T>(mean: &mut T, m2: &mut T, count: f64, value: T)
+where
+    T: Copy + Sub<Output = T> + Mul<Output = T> + Div<f64, Output = T> + AddAssign,
+{
+    let delta = value - *mean;
+    *mean += delta / count;
+    let delta2 = value - *mean;
+    *m2 += delta * delta2;
 }
 
-fn wavelength_pdf(lambda: f64) -> f64 {
-    XYZ_SUM.pdf(lambda)
+/// Spectral radiance averaged over equal-width wavelength bins spanning [`VISIBLE`], for
+/// radiometric analysis rather than viewing.
+struct SpectralFilm {
+    width: usize,
+    height: usize,
+    bins: usize,
+    data: Box<[SpectralPixel]>,
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Bounds {
-    min: DVec3,
-    max: DVec3,
+/// Like [`Pixel`], with one mean and `m2` per bin.
+struct SpectralPixel {
+    mean: Box<[f64]>,
+    m2: Box<[f64]>,
+    count: f64,
 }
 
-impl Bounds {
-    fn point(p: DVec3) -> Self {
-        Bounds {
-            min: p - 1.0e-9,
-            max: p + 1.0e-9,
+impl SpectralFilm {
+    fn new(width: usize, height: usize, bins: usize) -> Self {
+        SpectralFilm {
+            width,
+            height,
+            bins,
+            data: std::iter::repeat_with(|| SpectralPixel {
+                mean: vec![0.0; bins].into(),
+                m2: vec![0.0; bins].into(),
+                count: 0.0,
+            })
+            .take(width * height)
+            .collect(),
         }
     }
 
-    fn ray_intersect(self, origin: DVec3, dir: DVec3, t_max: f64) -> Option<(f64, f64)> {
-        let t_l = (self.min - origin) / dir;
-        let t_u = (self.max - origin) / dir;
-        let t_near = t_l.min(t_u);
+    fn bin_width(bins: usize) -> f64 {
+        (VISIBLE.end - VISIBLE.start) / bins as f64
+    }
+
+    /// The bin `lambda` falls in, if it's visible.
+    fn bin(bins: usize, lambda: f64) -> Option<usize> {
+        VISIBLE
+            .contains(&lambda)
+            .then(|| (((lambda - VISIBLE.start) / Self::bin_width(bins)) as usize).min(bins - 1))
+    }
+
+    /// Writes the means to the `radiance` layer of an EXR and their standard errors to the
+    /// `sterr` layer, with one channel per bin named after its center wavelength in nm. The
+    /// sample counts go in the `Y` channel of the `count` layer.
+    fn save(&self, path: impl AsRef<Path>) {
+        use exr::prelude::*;
+
+        let layer = |name: &str, value: &dyn Fn(&SpectralPixel, usize) -> f64| {
+            let channels = (0..self.bins).map(|bin| {
+                let center = VISIBLE.start + (bin as f64 + 0.5) * Self::bin_width(self.bins);
+                let values = self.data.iter().map(|p| value(p, bin) as f32).collect();
+                AnyChannel::new(format!("{center:.1}").as_str(), FlatSamples::F32(values))
+            });
+            Layer::new(
+                (self.width, self.height),
+                LayerAttributes::named(name),
+                Encoding::FAST_LOSSLESS,
+                AnyChannels::sort(channels.collect()),
+            )
+        };
+        let count = Layer::new(
+            (self.width, self.height),
+            LayerAttributes::named("count"),
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(SmallVec::from_elem(
+                AnyChannel::new(
+                    "Y",
+                    FlatSamples::F32(self.data.iter().map(|p| p.count as f32).collect()),
+                ),
+                1,
+            )),
+        );
+
+        let layers: Layers<_> = [
+            layer("radiance", &|p, bin| p.mean[bin]),
+            layer("sterr", &|p, bin| {
+                (p.m2[bin] / (p.count - 1.0) / p.count).sqrt()
+            }),
+            count,
+        ]
+        .into_iter()
+        .collect();
+        Image::from_layers(
+            ImageAttributes::new(IntegerBounds::from_dimensions((self.width, self.height))),
+            layers,
+        )
+        .write()
+        .to_file(path)
+        .unwrap();
+    }
+}
+
+impl SpectralPixel {
+    fn accumulate_sample(&mut self, values: &[f64]) {
+        self.count += 1.0;
+        for ((mean, m2), &value) in self.mean.iter_mut().zip(&mut self.m2[..]).zip(values) {
+            welford(mean, m2, self.count, value);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Crop {
+    min: (usize, usize),
+    max: (usize, usize),
+}
+
+impl Crop {
+    fn contains(self, x: usize, y: usize) -> bool {
+        (self.min.0..self.max.0).contains(&x) && (self.min.1..self.max.1).contains(&y)
+    }
+
+    fn area(self) -> usize {
+        (self.min.0..self.max.0).len() * (self.min.1..self.max.1).len()
+    }
+}
+
+/// Per-pixel multipliers of the sample count, from a grayscale mask.
+struct SampleMap {
+    width: usize,
+    height: usize,
+    factors: Vec<f64>,
+}
+
+impl SampleMap {
+    /// Maps the mask's luma from black to white onto factors from 1 to `max_factor`.
+    fn load(path: impl AsRef<Path>, max_factor: f64) -> Result<Self, Error> {
+        let mask = image::open(path)?.into_luma16();
+        Ok(SampleMap {
+            width: mask.width() as usize,
+            height: mask.height() as usize,
+            factors: mask
+                .pixels()
+                .map(|p| 1.0 + (max_factor - 1.0) * p.0[0] as f64 / u16::MAX as f64)
+                .collect(),
+        })
+    }
+
+    /// The number of samples to take at pixel `(x, y)` of a `width`x`height` film instead of
+    /// `samples`. Fractional counts are rounded up or down at random so that they come out right on
+    /// average over progressive passes.
+    fn samples(&self, x: usize, y: usize, width: usize, height: usize, samples: u32) -> u32 {
+        let mx = x * self.width / width;
+        let my = y * self.height / height;
+        let scaled = samples as f64 * self.factors[mx + my * self.width];
+        (scaled + random::rng().gen::<f64>()).floor() as u32
+    }
+}
+
+/// A camera at `pos` looking along `looking`'s +Z axis with +Y up.
+struct Camera {
+    pos: DVec3,
+    looking: DMat3,
+    /// Vertical field of view in radians of the perspective projection. The horizontal field of
+    /// view follows from the aspect ratio so that pixels stay square.
+    vfov: f64,
+    projection: Projection,
+}
+
+impl Camera {
+    /// The orientation `--cam-yaw` and `--cam-pitch` give, in radians: tilted down by `pitch`, then
+    /// turned by `yaw` about +Y from +Z towards +X.
+    fn orientation(yaw: f64, pitch: f64) -> DMat3 {
+        DMat3::from_euler(EulerRot::YXZ, yaw, pitch, 0.0)
+    }
+
+    /// A perspective camera looking along `looking`'s +Z axis from as close as it can get while
+    /// still seeing all of `bounds`, on a film `aspect` times as wide as it is high. Some corner of
+    /// the box lands on the edge of the view, and the box is centered on the view axis.
+    fn auto_frame(bounds: Bounds, looking: DMat3, vfov: f64, aspect: f64) -> Camera {
+        let tan_y = (vfov / 2.0).tan();
+        let tan_x = tan_y * aspect;
+        let center = bounds.centroid();
+        // how far back from the center the camera has to be for each corner to be in view
+        let distance = bounds
+            .corners()
+            .map(|p| {
+                let p = looking.transpose() * (p - center);
+                (p.x.abs() / tan_x).max(p.y.abs() / tan_y) - p.z
+            })
+            .fold(f64::NEG_INFINITY, f64::max);
+        Camera {
+            pos: center - looking.z_axis * distance,
+            looking,
+            vfov,
+            projection: Projection::Perspective,
+        }
+    }
+
+    /// Direction through the point `p` on a `width`x`height` pixel film, with `(0, 0)` at the
+    /// top-left corner.
+    fn ray_dir(&self, p: DVec2, width: usize, height: usize) -> DVec3 {
+        let p = p / DVec2::new(width as f64, height as f64);
+        let uv = p * 2.0 - 1.0;
+        let v = match self.projection {
+            Projection::EqualArea => return equal_area_square_to_sphere(p),
+            Projection::Perspective => {
+                let half_height = (self.vfov / 2.0).tan();
+                let aspect = width as f64 / height as f64;
+                DVec3::new(uv.x * aspect * half_height, -uv.y * half_height, 1.0).normalize()
+            }
+            Projection::Equirect => equirect_to_sphere(p),
+        };
+        self.looking * v
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ProgressEvent {
+    completed_pixels: usize,
+    total_pixels: usize,
+    /// Mean over the completed pixels of their squared standard error, averaged over channels.
+    mean_sterr_sq: f64,
+}
+
+/// Samples per pixel for the guide buffers of `--denoise`. The guides converge far faster than the
+/// image, since they only look at the first hit.
+const DENOISE_GUIDE_SAMPLES: u32 = 16;
+
+/// Everything that decides what a render computes, apart from the films it goes into.
+#[derive(Clone, Copy)]
+struct RenderSettings<'a> {
+    samples: u32,
+    scene: &'a Scene,
+    camera: &'a Camera,
+    camera_medium: &'a dyn Medium,
+    integrator: Integrator,
+    max_depth: Option<u32>,
+    wavelengths: WavelengthSampling,
+    sensor: &'a SensorResponse,
+    crop: Option<Crop>,
+    sample_map: Option<&'a SampleMap>,
+    seed: Option<u64>,
+}
+
+fn render(
+    film: &mut Film,
+    settings: &RenderSettings,
+    spectral: Option<&mut SpectralFilm>,
+    cancel: &AtomicBool,
+) -> bool {
+    render_with_progress(film, settings, spectral, cancel, |_| {})
+}
+
+/// Renders `settings.samples` samples per pixel in passes of growing size, calling `after_pass`
+/// with the samples per pixel so far after each, and showing the progress of each pass on stderr.
+///
+/// With a `target` error, checked after each pass by the given metric, stops as soon as the film
+/// is below it. Returns the samples per pixel rendered.
+fn render_passes(
+    film: &mut Film,
+    settings: &RenderSettings,
+    mut spectral: Option<&mut SpectralFilm>,
+    cancel: &AtomicBool,
+    target: Option<(f64, ErrorMetric)>,
+    mut after_pass: impl FnMut(&Film, u32),
+) -> u32 {
+    let mut last = 0;
+    for j in 1.. {
+        let to_render = settings
+            .samples
+            .min(2.0f64.powf(j as f64 / 2.0).round() as u32);
+        if to_render == last {
+            break;
+        }
+        let pass = RenderSettings {
+            samples: to_render - last,
+            ..*settings
+        };
+        render_with_progress(film, &pass, spectral.as_deref_mut(), cancel, |p| {
+            eprint!(
+                "\r{:>8}/{} pixels   {:>12.5} sterr",
+                p.completed_pixels,
+                p.total_pixels,
+                p.mean_sterr_sq.sqrt()
+            );
+            if p.completed_pixels == p.total_pixels {
+                eprint!("\r\x1b[K");
+            }
+        });
+        last = to_render;
+        after_pass(film, to_render);
+
+        if let Some((target, metric)) = target {
+            let error = match metric {
+                ErrorMetric::Average => film.average_sterr_sq().sqrt(),
+                ErrorMetric::Max => film.max_sterr_sq().sqrt(),
+            };
+            if error < target {
+                println!("reached target error {target} with {to_render} samples");
+                break;
+            } else if to_render == settings.samples {
+                println!("target error {target} not reached with {to_render} samples");
+            }
+        }
+    }
+    last
+}
+
+/// Like [`render`], but calls `on_progress` from the worker threads after every `film.width`
+/// completed pixels, and once all pixels are done. Events that arrive after a later one was
+/// already reported are dropped, so `completed_pixels` never goes backwards.
+///
+/// Pixels not yet started when `cancel` is set are skipped, and those in progress stop after their
+/// current sample, so the film holds whatever was rendered up to that point. Returns whether every
+/// pixel got all of its samples.
+///
+/// With a `seed`, each pixel draws from its own random stream, keyed by its position and how many
+/// samples it already has, so the result doesn't depend on which thread renders it or when.
+///
+/// The path integrator's samples are also binned into `spectral`, which must be the same size as
+/// `film`.
+fn render_with_progress(
+    film: &mut Film,
+    settings: &RenderSettings,
+    spectral: Option<&mut SpectralFilm>,
+    cancel: &AtomicBool,
+    on_progress: impl Fn(ProgressEvent) + Sync,
+) -> bool {
+    let &RenderSettings {
+        samples,
+        scene,
+        camera,
+        camera_medium,
+        integrator,
+        max_depth,
+        wavelengths,
+        sensor,
+        crop,
+        sample_map,
+        seed,
+    } = settings;
+    let width = film.width;
+    let height = film.height;
+    let total_pixels = match crop {
+        Some(crop) => crop.area(),
+        None => width * height,
+    };
+    let completed = AtomicUsize::new(0);
+    let sterr_sq_sum = AtomicU64::new(0.0f64.to_bits());
+    let last_reported = Mutex::new(0);
+    let bins = spectral.as_ref().map_or(0, |s| s.bins);
+    let spectral = match spectral {
+        Some(spectral) => Either::Left(spectral.data.par_iter_mut().map(Some)),
+        None => Either::Right(rayon::iter::repeat_n((), width * height).map(|_| None)),
+    };
+    let pixels = film.par_iter_mut().zip(spectral);
+    let pixels = pixels.filter(|&((x, y, _), _)| crop.is_none_or(|crop| crop.contains(x, y)));
+    pixels.for_each(|((x, y, pixel), mut spectral)| {
+        let mut binned = vec![0.0; bins];
+        if let Some(seed) = seed {
+            // a pass starts where the pixel's previous ones left off
+            random::reseed([seed, (x + y * width) as u64, pixel.count as u64]);
+        }
+        let samples = sample_map.map_or(samples, |map| map.samples(x, y, width, height, samples));
+        for _ in 0..samples {
+            if cancel.load(Ordering::Relaxed) {
+                stats::flush();
+                return;
+            }
+
+            let p = DVec2::new(x as f64, y as f64) + random::rng().gen::<DVec2>();
+            let d = camera.ray_dir(p, width, height);
+
+            let (lambdas, pdf) = wavelengths.sample_bundle(random::rng().gen_range(0.0..1.0));
+
+            if integrator != Integrator::Path {
+                let value = debug_sample(integrator, scene, camera.pos, d, lambdas, pdf);
+                pixel.accumulate_sample(value);
+                continue;
+            }
+
+            // let radiance =
+            //     path_trace::path_trace(scene, camera.pos, d, lambdas, camera_medium, max_depth);
+            let radiance =
+                vol_trace::path_trace(scene, camera.pos, d, lambdas, camera_medium, max_depth);
+            let response = lambdas.to_array().map(|lambda| sensor.response(lambda));
+            let mut lobes = [DVec3::ZERO; 3];
+            let split = [radiance.emitted, radiance.diffuse, radiance.specular];
+            for (value, radiance) in lobes.iter_mut().zip(split) {
+                for i in 0..4 {
+                    *value += radiance[i] / pdf[i] * response[i];
+                }
+            }
+
+            pixel.accumulate_lobes(lobes);
+
+            if let Some(spectral) = &mut spectral {
+                binned.fill(0.0);
+                let total = radiance.emitted + radiance.diffuse + radiance.specular;
+                for i in 0..4 {
+                    if let Some(bin) = SpectralFilm::bin(bins, lambdas[i]) {
+                        binned[bin] += total[i] / pdf[i] / SpectralFilm::bin_width(bins);
+                    }
+                }
+                spectral.accumulate_sample(&binned);
+            }
+        }
+        stats::flush();
+
+        let sterr_sq = pixel.sterr_sq().element_sum() / 3.0;
+        _ = sterr_sq_sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+            Some((f64::from_bits(sum) + sterr_sq).to_bits())
+        });
+        let completed_pixels = completed.fetch_add(1, Ordering::AcqRel) + 1;
+        if completed_pixels.is_multiple_of(width) || completed_pixels == total_pixels {
+            let mut last_reported = last_reported.lock().unwrap();
+            if completed_pixels > *last_reported {
+                *last_reported = completed_pixels;
+                let sterr_sq_sum = f64::from_bits(sterr_sq_sum.load(Ordering::Relaxed));
+                on_progress(ProgressEvent {
+                    completed_pixels,
+                    total_pixels,
+                    mean_sterr_sq: sterr_sq_sum / completed_pixels as f64,
+                });
+            }
+        }
+    });
+
+    completed.into_inner() == total_pixels
+}
+
+/// One sample of the debug integrators, in XYZ.
+fn debug_sample(
+    integrator: Integrator,
+    scene: &Scene,
+    origin: DVec3,
+    dir: DVec3,
+    lambdas: DVec4,
+    pdf: DVec4,
+) -> DVec3 {
+    // Y of a constant unit spectrum, so that a perfectly white albedo comes out with Y = 1
+    static WHITE_Y: LazyLock<f64> = LazyLock::new(|| {
+        spectrum::integrate_product(&spectrum::ConstantSpectrum(1.0), &cie_xyz_absolute()[1])
+    });
+
+    let Some(hit) = scene.raycast(origin, dir, f64::INFINITY) else {
+        return DVec3::ZERO;
+    };
+    match integrator {
+        Integrator::Path => unreachable!(),
+        Integrator::Normals => spectrum::srgb_linear_to_xyz(hit.normal * 0.5 + 0.5),
+        Integrator::Depth => spectrum::srgb_linear_to_xyz(DVec3::splat(hit.t)),
+        Integrator::Albedo => {
+            let Some(brdf) = hit.material.brdf() else {
+                return DVec3::ZERO;
+            };
+            let albedo = brdf::furnace(brdf, dir, hit.normal, hit.tangent, hit.uv, lambdas, 1);
+            let mut value = DVec3::ZERO;
+            for i in 0..4 {
+                value += albedo[i] / pdf[i] * spectrum::lambda_to_xyz_absolute(lambdas[i]);
+            }
+            value / *WHITE_Y
+        }
+    }
+}
+
+static XYZ_SUM: LazyLock<Tabulated1DFunction> = LazyLock::new(|| {
+    let [x, y, z] = cie_xyz_absolute();
+    let mut data = vec![0.0; x.raw().raw().len()];
+    for i in 0..data.len() {
+        data[i] += x.raw().raw()[i].abs();
+        data[i] += y.raw().raw()[i].abs();
+        data[i] += z.raw().raw()[i].abs();
+    }
+    Tabulated1DFunction::new(&data, VISIBLE.start, VISIBLE.end)
+});
+
+fn sample_wavelengths(random: f64) -> f64 {
+    XYZ_SUM.sample(random)
+}
+
+fn wavelength_pdf(lambda: f64) -> f64 {
+    XYZ_SUM.pdf(lambda)
+}
+
+impl WavelengthSampling {
+    /// Draws the four wavelengths of a hero bundle from one random number, evenly spaced through
+    /// the distribution's cdf. Returns them along with the density of the bundle as a whole at
+    /// each one, that is of any of its four wavelengths landing there, so that summing
+    /// `f(lambdas[i]) / pdf[i]` estimates the integral of `f`.
+    ///
+    /// The four are as correlated as can be, but each one on its own is distributed exactly like
+    /// a single sample since the random number is uniform over the whole cdf, so the bundle's
+    /// density is four times the single wavelength [`pdf`](Self::pdf) and the sum is unbiased.
+    /// Path pdfs that depend on the wavelength are weighted between the four by the tracer.
+    fn sample_bundle(self, random: f64) -> (DVec4, DVec4) {
+        let stratified = (DVec4::splat(random) + DVec4::new(0.0, 0.25, 0.5, 0.75)) % 1.0;
+        let lambdas = stratified.map(|u| self.sample(u));
+        (lambdas, 4.0 * lambdas.map(|l| self.pdf(l)))
+    }
+
+    fn sample(self, random: f64) -> f64 {
+        match self {
+            WavelengthSampling::Xyz => sample_wavelengths(random),
+            WavelengthSampling::Uniform => VISIBLE.start + random * (VISIBLE.end - VISIBLE.start),
+            // inverse of the cdf of `pdf` below, from pbrt-v4
+            WavelengthSampling::Visible => {
+                538.0 - 138.888889 * (0.85691062 - 1.82750197 * random).atanh()
+            }
+        }
+    }
+
+    fn pdf(self, lambda: f64) -> f64 {
+        if !VISIBLE.contains(&lambda) {
+            return 0.0;
+        }
+        match self {
+            WavelengthSampling::Xyz => wavelength_pdf(lambda),
+            WavelengthSampling::Uniform => 1.0 / (VISIBLE.end - VISIBLE.start),
+            WavelengthSampling::Visible => {
+                0.0039398042 / (0.0072 * (lambda - 538.0)).cosh().powi(2)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bounds {
+    min: DVec3,
+    max: DVec3,
+}
+
+impl Bounds {
+    fn point(p: DVec3) -> Self {
+        Bounds {
+            min: p - 1.0e-9,
+            max: p + 1.0e-9,
+        }
+    }
+
+    fn ray_intersect(self, origin: DVec3, dir: DVec3, t_max: f64) -> Option<(f64, f64)> {
+        let t_l = (self.min - origin) / dir;
+        let t_u = (self.max - origin) / dir;
+        let t_near = t_l.min(t_u);
         let t_far = t_l.max(t_u);
         let t_0 = t_near.max_element().max(0.0);
         let t_1 = t_far.min_element().min(t_max);
@@ -371,6 +1760,21 @@ impl Bounds {
             max: self.max.max(other.max),
         }
     }
+
+    /// Bounds of the eight transformed corners. These contain whatever `self` contains, but a
+    /// rotated box's bounds are larger than the box, by up to `sqrt(3)` times in each axis.
+    fn transform(self, transform: &DMat4) -> Self {
+        self.corners()
+            .map(|p| transform.transform_point3(p))
+            .collect()
+    }
+
+    fn corners(self) -> impl Iterator<Item = DVec3> {
+        (0..8).map(move |corner| {
+            let bvec = BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0);
+            DVec3::select(bvec, self.min, self.max)
+        })
+    }
 }
 
 impl FromIterator<DVec3> for Bounds {
@@ -422,6 +1826,71 @@ impl std::fmt::Display for Time {
     }
 }
 
+/// Builds a tangent and bitangent completing an orthonormal basis around `n`.
+///
+/// Returns `None` if `n` is not unit length (e.g. zero or NaN shading normals from degenerate
+/// meshes), in which case the caller should produce a zero-contribution sample.
+fn build_frame(n: DVec3) -> Option<(DVec3, DVec3)> {
+    n.is_normalized().then(|| n.any_orthonormal_pair())
+}
+
+/// Resamples a square equal-area film to a `width`x`height` latitude-longitude film laid out like
+/// [`Projection::Equirect`] looking down +Z.
+fn equal_area_to_equirect(src: &Film, width: usize, height: usize) -> Film {
+    const STRATA: usize = 4;
+
+    let mut film = Film::new(width, height);
+    film.par_iter_mut().for_each(|(x, y, pixel)| {
+        for i in 0..STRATA * STRATA {
+            let offset =
+                (DVec2::new((i % STRATA) as f64, (i / STRATA) as f64) + 0.5) / STRATA as f64;
+            let uv =
+                (DVec2::new(x as f64, y as f64) + offset) / DVec2::new(width as f64, height as f64);
+            let p = sphere_to_equal_area_square(equirect_to_sphere(uv)) * src.width as f64;
+            let sx = (p.x as usize).min(src.width - 1);
+            let sy = (p.y as usize).min(src.height - 1);
+            pixel.accumulate_sample(src.data[sx + sy * src.width].mean);
+        }
+    });
+    film
+}
+
+/// Inverse of [`equal_area_square_to_sphere`].
+fn sphere_to_equal_area_square(d: DVec3) -> DVec2 {
+    let x = d.x.abs();
+    let y = d.z.abs();
+    let z = d.y.abs();
+
+    let r = (1.0 - z).max(0.0).sqrt();
+
+    let a = x.max(y);
+    let b = match a == 0.0 {
+        true => 0.0,
+        false => x.min(y) / a,
+    };
+    let mut phi = b.atan() * 2.0 / PI;
+    if x < y {
+        phi = 1.0 - phi;
+    }
+
+    let mut v = phi * r;
+    let mut u = r - v;
+    if d.y < 0.0 {
+        (u, v) = (1.0 - v, 1.0 - u);
+    }
+
+    let uv = DVec2::new(u.copysign(d.x), v.copysign(d.z));
+    (uv + 1.0) / 2.0
+}
+
+/// Direction at `p` on a latitude-longitude image spanning `[0, 1]`, with +Y at the top and +Z at
+/// the center; longitude increases towards +X, and the left and right edges meet at -Z.
+fn equirect_to_sphere(p: DVec2) -> DVec3 {
+    let (sin_lon, cos_lon) = ((p.x * 2.0 - 1.0) * PI).sin_cos();
+    let (sin_lat, cos_lat) = ((0.5 - p.y) * PI).sin_cos();
+    DVec3::new(cos_lat * sin_lon, sin_lat, cos_lat * cos_lon)
+}
+
 fn equal_area_square_to_sphere(p: DVec2) -> DVec3 {
     let uv = 2.0 * p - 1.0;
     let uvp = uv.abs();
@@ -444,3 +1913,1084 @@ fn equal_area_square_to_sphere(p: DVec2) -> DVec3 {
     let factor = r * (2.0 - r * r).sqrt();
     DVec3::new(cos_phi * factor, z, sin_phi * factor)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A film whose pixels each have a few different samples, so their means aren't round numbers.
+    fn test_film(width: usize, height: usize) -> Film {
+        let mut film = Film::new(width, height);
+        film.par_iter_mut().for_each(|(x, y, p)| {
+            for i in 0..3 {
+                let i = i as f64;
+                p.accumulate_sample(DVec3::new(
+                    x as f64 + i / 7.0,
+                    y as f64 * 0.1 + i,
+                    1.0 / (i + 3.0),
+                ));
+            }
+        });
+        film
+    }
+
+    /// Calls `f` with the settings to render a built-in scene with `samples` per pixel and a fixed
+    /// seed.
+    fn with_settings<M: Medium, R>(
+        (scene, pos, looking, medium): (Scene, DVec3, DMat3, M),
+        samples: u32,
+        f: impl FnOnce(RenderSettings) -> R,
+    ) -> R {
+        let camera = Camera {
+            pos,
+            looking,
+            vfov: 40.0f64.to_radians(),
+            projection: Projection::Perspective,
+        };
+        f(RenderSettings {
+            samples,
+            scene: &scene,
+            camera: &camera,
+            camera_medium: &medium,
+            integrator: Integrator::Path,
+            max_depth: None,
+            wavelengths: WavelengthSampling::Xyz,
+            sensor: &SensorResponse::Xyz,
+            crop: None,
+            sample_map: None,
+            seed: Some(1),
+        })
+    }
+
+    /// Renders a built-in scene into a `size`x`size` film with `samples` per pixel and a fixed
+    /// seed, after `adjust` has had a go at the settings.
+    fn render_scene<M: Medium>(
+        scene: (Scene, DVec3, DMat3, M),
+        size: usize,
+        samples: u32,
+        adjust: impl FnOnce(&mut RenderSettings),
+    ) -> Film {
+        with_settings(scene, samples, |mut settings| {
+            adjust(&mut settings);
+            let mut film = Film::new(size, size);
+            assert!(render(&mut film, &settings, None, &AtomicBool::new(false)));
+            film
+        })
+    }
+
+    fn render_cornell(size: usize, samples: u32, adjust: impl FnOnce(&mut RenderSettings)) -> Film {
+        render_scene(scene_description::cornell_box(), size, samples, adjust)
+    }
+
+    fn means(film: &Film) -> Vec<DVec3> {
+        film.data.iter().map(|p| p.mean).collect()
+    }
+
+    #[test]
+    fn cornell_light_directly_lights_the_floor() {
+        // the light's underside spans x 21.3cm to 34.3cm and z 22.3cm to 32.8cm, 55.4cm up. The
+        // floor is lit at a spot in front of the tall box that sees all of it.
+        let light_center = DVec3::new(0.278, 0.554, 0.2755);
+        let half_size = DVec3::new(0.065, 0.0, 0.0525);
+        let spot = DVec3::new(0.45, 0.0, 0.5);
+
+        let (scene, _, _, medium) = scene_description::cornell_box();
+        let look_down = DMat3::from_cols(DVec3::X, DVec3::Z, -DVec3::Y);
+        let floor = render_scene(
+            (scene, spot + DVec3::Y * 0.3, look_down, medium),
+            15,
+            64,
+            |s| s.max_depth = Some(1),
+        );
+        let (scene, _, _, medium) = scene_description::cornell_box();
+        let look_up = DMat3::from_cols(DVec3::X, -DVec3::Z, DVec3::Y);
+        let light = render_scene(
+            (scene, light_center - DVec3::Y * 0.05, look_up, medium),
+            15,
+            16,
+            |_| {},
+        );
+        let floor = floor.data[7 + 7 * 15].mean.y;
+        let radiance = light.data[7 + 7 * 15].mean.y;
+
+        // form factor from a point to a parallel rectangle with corners at (0, 0) and (a, b) above
+        // it, which is odd in both, so any rectangle is the sum over its corners
+        let height = light_center.y - spot.y;
+        let corner = |a: f64, b: f64| {
+            let (a, b) = (a / height, b / height);
+            let (ra, rb) = ((1.0 + a * a).sqrt(), (1.0 + b * b).sqrt());
+            (a / ra * (b / ra).atan() + b / rb * (a / rb).atan()) / (2.0 * PI)
+        };
+        let min = light_center - half_size - spot;
+        let max = light_center + half_size - spot;
+        let form_factor = corner(max.x, max.z) - corner(min.x, max.z) - corner(max.x, min.z)
+            + corner(min.x, min.z);
+
+        // the white floor reflects 74% to 77% where the light emits
+        let expected = 0.74 * form_factor * radiance * 0.95..0.77 * form_factor * radiance * 1.05;
+        assert!(
+            expected.contains(&floor),
+            "floor luminance {floor}, expected {expected:?}"
+        );
+    }
+
+    #[test]
+    fn yaw_turns_the_view() {
+        // a glowing sphere 10 degrees right of +Z, in the dark
+        let scene = || {
+            let mut scene = Scene::new();
+            let (sin, cos) = 10.0f64.to_radians().sin_cos();
+            scene.add(objects::Sphere {
+                origin: DVec3::new(sin, 0.0, cos) * 5.0,
+                radius: 0.3,
+                material: material::EmissiveMaterial::new(spectrum::ConstantSpectrum(1.0)),
+            });
+            scene
+        };
+
+        // with the 40 degree field of view, x = 16.5 + tan(angle) / tan(20 degrees) * 16.5
+        for (yaw, expected_x) in [(0.0, 24.49), (10.0, 16.5), (20.0, 8.51)] {
+            let looking = Camera::orientation(f64::to_radians(yaw), 0.0);
+            let film = render_scene(
+                (scene(), DVec3::ZERO, looking, medium::Vacuum),
+                33,
+                4,
+                |_| {},
+            );
+            let (mut total, mut center) = (0.0, DVec2::ZERO);
+            for y in 0..33 {
+                for x in 0..33 {
+                    let weight = film.data[x + y * 33].mean.y;
+                    total += weight;
+                    center += DVec2::new(x as f64 + 0.5, y as f64 + 0.5) * weight;
+                }
+            }
+            let center = center / total;
+            assert!(
+                (center - DVec2::new(expected_x, 16.5)).abs().max_element() < 0.5,
+                "yaw {yaw} puts the sphere at {center}, expected x {expected_x}"
+            );
+        }
+    }
+
+    #[test]
+    fn wide_renders_keep_markers_square_and_centered() {
+        let camera = Camera {
+            pos: DVec3::ZERO,
+            looking: DMat3::IDENTITY,
+            vfov: 60.0f64.to_radians(),
+            projection: Projection::Perspective,
+        };
+        let (width, height) = (48, 27);
+        let angle = |p: DVec2| {
+            let dir = camera.ray_dir(p, width, height);
+            DVec2::new(dir.x.atan2(dir.z), dir.y.atan2(dir.z)) * 180.0 / PI
+        };
+        let half_width = (30.0f64.to_radians().tan() * 48.0 / 27.0)
+            .atan()
+            .to_degrees();
+        let expected = [
+            (DVec2::new(24.0, 13.5), DVec2::ZERO),
+            (DVec2::new(24.0, 0.0), DVec2::new(0.0, 30.0)),
+            (DVec2::new(24.0, 27.0), DVec2::new(0.0, -30.0)),
+            (DVec2::new(48.0, 13.5), DVec2::new(half_width, 0.0)),
+            (DVec2::new(0.0, 13.5), DVec2::new(-half_width, 0.0)),
+        ];
+        for (p, expected) in expected {
+            let angle = angle(p);
+            assert!(
+                (angle - expected).abs().max_element() < 1e-9,
+                "{p} looks at {angle}, expected {expected}"
+            );
+        }
+
+        // a glowing square straight ahead, in the dark
+        let mut scene = Scene::new();
+        scene.add(objects::Quad {
+            origin: DVec3::new(-0.5, -0.5, 5.0),
+            u: DVec3::Y,
+            v: DVec3::X,
+            material: material::EmissiveMaterial::new(spectrum::ConstantSpectrum(1.0)),
+        });
+        let looking = Camera::orientation(0.0, 0.0);
+        let film = with_settings((scene, DVec3::ZERO, looking, medium::Vacuum), 16, |s| {
+            let mut film = Film::new(width, height);
+            assert!(render(&mut film, &s, None, &AtomicBool::new(false)));
+            film
+        });
+        let (mut total, mut center, mut spread) = (0.0, DVec2::ZERO, DVec2::ZERO);
+        for y in 0..height {
+            for x in 0..width {
+                let weight = film.data[x + y * width].mean.y;
+                let p = DVec2::new(x as f64 + 0.5, y as f64 + 0.5);
+                total += weight;
+                center += p * weight;
+                spread += p * p * weight;
+            }
+        }
+        let center = center / total;
+        let spread = (spread / total - center * center).powf(0.5);
+        assert!(
+            (center - DVec2::new(24.0, 13.5)).abs().max_element() < 0.1,
+            "marker centered at {center}"
+        );
+        assert!(
+            (spread.x / spread.y - 1.0).abs() < 0.05,
+            "marker spread {spread}"
+        );
+    }
+
+    #[test]
+    fn normals_integrator_paints_a_normal_map() {
+        let mut scene = Scene::new();
+        scene.add(objects::Sphere {
+            origin: DVec3::Z * 5.0,
+            radius: 1.0,
+            material: material::EmissiveMaterial::new(spectrum::ConstantSpectrum(1.0)),
+        });
+        let looking = Camera::orientation(0.0, 0.0);
+        let film = render_scene((scene, DVec3::ZERO, looking, medium::Vacuum), 33, 4, |s| {
+            s.integrator = Integrator::Normals
+        });
+        let camera = Camera {
+            pos: DVec3::ZERO,
+            looking,
+            vfov: 40.0f64.to_radians(),
+            projection: Projection::Perspective,
+        };
+
+        for y in 0..33 {
+            for x in 0..33 {
+                let rgb = spectrum::xyz_to_srgb_linear(film.data[x + y * 33].mean);
+                let dir = camera.ray_dir(DVec2::new(x as f64 + 0.5, y as f64 + 0.5), 33, 33);
+                // distance of the ray from the sphere's center, in radii
+                let miss = (DVec3::Z * 5.0).reject_from(dir).length();
+                if miss > 1.1 {
+                    assert!(rgb.abs().max_element() < 1e-9, "pixel ({x}, {y}) is {rgb}");
+                } else if miss < 0.8 {
+                    let t = 5.0 * dir.z - (1.0 - miss * miss).sqrt();
+                    let normal = dir * t - DVec3::Z * 5.0;
+                    let expected = normal * 0.5 + 0.5;
+                    assert!(
+                        (rgb - expected).abs().max_element() < 0.05,
+                        "pixel ({x}, {y}) is {rgb}, expected {expected}"
+                    );
+                }
+            }
+        }
+
+        // facing the camera is blue-free, up is green and right is red
+        let rgb = |x: usize, y: usize| spectrum::xyz_to_srgb_linear(film.data[x + y * 33].mean);
+        assert!(rgb(16, 16).z < 0.01);
+        assert!(rgb(16, 9).y > 0.8 && rgb(16, 23).y < 0.2);
+        assert!(rgb(23, 16).x > 0.8 && rgb(9, 16).x < 0.2);
+    }
+
+    #[test]
+    fn lobes_add_up_to_the_beauty() {
+        // a mirrored sphere on a matte floor under a uniform sky, seen level from 4m away
+        let mut scene = Scene::new();
+        scene.add(objects::Quad {
+            origin: DVec3::new(-50.0, 0.0, -50.0),
+            u: DVec3::Z * 100.0,
+            v: DVec3::X * 100.0,
+            material: material::Material {
+                emission: spectrum::ZERO,
+                brdf: brdf::LambertianBrdf {
+                    albedo: spectrum::ConstantSpectrum(0.5),
+                },
+                enter_medium: (),
+                exit_medium: (),
+            },
+        });
+        scene.add(objects::Sphere {
+            origin: DVec3::Y,
+            radius: 1.0,
+            material: material::Material {
+                emission: spectrum::ZERO,
+                brdf: brdf::SmoothConductorBrdf::new(material::physical::ior_gold()),
+                enter_medium: (),
+                exit_medium: (),
+            },
+        });
+        scene.set_background(GradientSkyLight::uniform(spectrum::ConstantSpectrum(1.0)));
+        let looking = Camera::orientation(0.0, 0.0);
+        let film = render_scene(
+            (scene, DVec3::new(0.0, 1.0, -4.0), looking, medium::Vacuum),
+            24,
+            4,
+            |_| {},
+        );
+
+        for p in film.data.iter() {
+            let sum: DVec3 = p.lobes.iter().sum();
+            assert!((sum - p.mean).abs().max_element() <= 1e-9 * p.mean.abs().max_element());
+        }
+        let [emitted, diffuse, specular] = film.data[12].lobes;
+        assert!(emitted.y > 0.0 && diffuse == DVec3::ZERO && specular == DVec3::ZERO);
+        let [emitted, diffuse, specular] = film.data[12 + 12 * 24].lobes;
+        assert!(emitted == DVec3::ZERO && diffuse == DVec3::ZERO && specular.y > 0.0);
+        let [emitted, diffuse, specular] = film.data[12 + 23 * 24].lobes;
+        assert!(emitted == DVec3::ZERO && diffuse.y > 0.0 && specular == DVec3::ZERO);
+    }
+
+    #[test]
+    fn crop_renders_only_its_rectangle() {
+        let crop = Crop {
+            min: (3, 5),
+            max: (11, 9),
+        };
+        let full = render_cornell(16, 4, |_| {});
+        let cropped = render_cornell(16, 4, |s| s.crop = Some(crop));
+        for y in 0..16 {
+            for x in 0..16 {
+                let (full, cropped) = (&full.data[x + y * 16], &cropped.data[x + y * 16]);
+                match crop.contains(x, y) {
+                    true => assert_eq!(cropped.mean, full.mean, "pixel ({x}, {y})"),
+                    false => assert_eq!(cropped.count, 0.0, "pixel ({x}, {y})"),
+                }
+            }
+        }
+        assert_eq!(cropped.rendered().count(), crop.area());
+        assert!(cropped.rendered().any(|p| p.mean != DVec3::ZERO));
+    }
+
+    #[test]
+    fn progress_counts_up_to_every_pixel() {
+        let crop = Crop {
+            min: (2, 1),
+            max: (13, 10),
+        };
+        for crop in [None, Some(crop)] {
+            let events = Mutex::new(vec![]);
+            with_settings(scene_description::cornell_box(), 2, |settings| {
+                let settings = RenderSettings { crop, ..settings };
+                let mut film = Film::new(16, 12);
+                let cancel = AtomicBool::new(false);
+                let done = render_with_progress(&mut film, &settings, None, &cancel, |event| {
+                    events.lock().unwrap().push(event)
+                });
+                assert!(done);
+            });
+
+            let events = events.into_inner().unwrap();
+            let total = crop.map_or(16 * 12, |crop| crop.area());
+            assert!(!events.is_empty());
+            assert!(events.iter().all(|e| e.total_pixels == total));
+            assert!(events
+                .windows(2)
+                .all(|w| w[0].completed_pixels < w[1].completed_pixels));
+            assert_eq!(events.last().unwrap().completed_pixels, total);
+            assert!(events.iter().all(|e| e.mean_sterr_sq.is_finite()));
+        }
+    }
+
+    #[test]
+    fn batches_write_one_output_per_scene() {
+        let dir = std::env::temp_dir().join(format!("pbr-test-{}-batch", std::process::id()));
+        // progressive passes are saved to `partial/` beside the output
+        std::fs::create_dir_all(dir.join("partial")).unwrap();
+        let output = dir.join("render.exr");
+        let opt = Options::parse_from([
+            "pbr".as_ref(),
+            "-W8".as_ref(),
+            "-H6".as_ref(),
+            "-s2".as_ref(),
+            "--scene=cornell-box,simple-volume".as_ref(),
+            "--output".as_ref(),
+            output.as_os_str(),
+        ]);
+        run(&opt);
+
+        let films = ["cornell-box", "simple-volume"].map(|name| {
+            Film::load_raw(dir.join(format!("{name}.exr")))
+                .unwrap_or_else(|e| panic!("no output for {name}: {e}"))
+        });
+        let leftovers = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(leftovers, 3);
+        for film in &films {
+            assert_eq!((film.width, film.height), (8, 6));
+        }
+        assert_ne!(means(&films[0]), means(&films[1]));
+    }
+
+    #[test]
+    fn target_error_stops_early_or_at_the_cap() {
+        let render = |target| {
+            let mut passes = vec![];
+            let rendered = with_settings(scene_description::cornell_box(), 256, |settings| {
+                let settings = RenderSettings {
+                    integrator: Integrator::Albedo,
+                    ..settings
+                };
+                let mut film = Film::new(12, 12);
+                let cancel = AtomicBool::new(false);
+                let target = Some((target, ErrorMetric::Average));
+                render_passes(
+                    &mut film,
+                    &settings,
+                    None,
+                    &cancel,
+                    target,
+                    |film, samples| passes.push((samples, film.average_sterr_sq().sqrt())),
+                )
+            });
+            assert_eq!(passes.last().unwrap().0, rendered);
+            (rendered, passes)
+        };
+
+        let (rendered, passes) = render(0.05);
+        assert!(rendered < 256, "a loose target took {rendered} samples");
+        let (_, error) = passes[passes.len() - 1];
+        assert!(error < 0.05);
+        // one sample per pixel has no error estimate
+        assert!(passes[1..passes.len() - 1].iter().all(|&(_, e)| e >= 0.05));
+
+        let (rendered, passes) = render(0.0);
+        assert_eq!(rendered, 256);
+        assert!(passes.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn disabled_lights_go_dark() {
+        // a matte floor lit only by a sphere light above it, seen from above
+        let render = |toggles: &[bool]| {
+            let mut scene = Scene::new();
+            scene.add(objects::Quad {
+                origin: DVec3::new(-5.0, 0.0, -5.0),
+                u: DVec3::Z * 10.0,
+                v: DVec3::X * 10.0,
+                material: material::Material {
+                    emission: spectrum::ZERO,
+                    brdf: brdf::LambertianBrdf {
+                        albedo: spectrum::ConstantSpectrum(0.5),
+                    },
+                    enter_medium: (),
+                    exit_medium: (),
+                },
+            });
+            let light = scene.add_light(light::SphereLight {
+                center: DVec3::new(1.0, 2.0, 0.0),
+                radius: 0.3,
+                emission: spectrum::ConstantSpectrum(10.0),
+            });
+            for &enabled in toggles {
+                scene.set_enabled(light, enabled);
+            }
+            let look_down = DMat3::from_cols(DVec3::X, DVec3::Z, -DVec3::Y);
+            let camera = (scene, DVec3::Y * 3.0, look_down, medium::Vacuum);
+            means(&render_scene(camera, 8, 4, |_| {}))
+        };
+
+        let lit = render(&[]);
+        assert!(lit.iter().all(|p| p.y > 0.0));
+        assert!(render(&[false]).iter().all(|&p| p == DVec3::ZERO));
+        assert_eq!(render(&[false, true]), lit);
+    }
+
+    #[test]
+    fn seeded_renders_are_reproducible() {
+        for volume in [false, true] {
+            let scene = || match volume {
+                false => boxed(scene_description::cornell_box()),
+                true => boxed(scene_description::simple_volume_scene()),
+            };
+            let first = render_scene(scene(), 12, 4, |_| {});
+            let again = render_scene(scene(), 12, 4, |_| {});
+            let reseeded = render_scene(scene(), 12, 4, |s| s.seed = Some(2));
+            assert_eq!(means(&first), means(&again));
+            assert_ne!(means(&first), means(&reseeded));
+        }
+    }
+
+    #[test]
+    fn pfm_round_trips() {
+        let film = test_film(6, 4);
+        let path = std::env::temp_dir().join(format!("pbr-test-{}.pfm", std::process::id()));
+        film.save_pfm(&path);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // three header lines, the last a negative scale for little-endian floats
+        let mut lines = bytes.splitn(4, |&b| b == b'\n');
+        assert_eq!(lines.next(), Some(&b"PF"[..]));
+        assert_eq!(lines.next(), Some(&b"6 4"[..]));
+        let scale: f32 = std::str::from_utf8(lines.next().unwrap())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(scale < 0.0);
+        let floats: Vec<f32> = lines
+            .next()
+            .unwrap()
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(floats.len(), 6 * 4 * 3);
+
+        // scanlines run from the bottom up
+        for (i, rgb) in floats.chunks_exact(3).enumerate() {
+            let (x, y) = (i % 6, 3 - i / 6);
+            let expected = spectrum::xyz_to_srgb_linear(film.data[x + y * 6].mean).as_vec3();
+            assert_eq!(rgb, expected.to_array(), "pixel ({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn equirect_reprojection_keeps_constants_and_the_sun() {
+        let mut film = Film::new(64, 64);
+        film.par_iter_mut()
+            .for_each(|(_, _, p)| p.accumulate_sample(DVec3::new(0.5, 1.0, 2.0)));
+        let equirect = equal_area_to_equirect(&film, 128, 64);
+        assert!(equirect
+            .data
+            .iter()
+            .all(|p| p.mean == DVec3::new(0.5, 1.0, 2.0)));
+
+        // a sun 30 degrees above the horizon at longitude 100 degrees, measured from +Z towards +X
+        let (lat, lon) = (30.0f64.to_radians(), 100.0f64.to_radians());
+        let sun = DVec3::new(lat.cos() * lon.sin(), lat.sin(), lat.cos() * lon.cos());
+        let p = sphere_to_equal_area_square(sun) * 64.0;
+        film.data[p.x as usize + p.y as usize * 64].accumulate_sample(DVec3::splat(1e6));
+        let equirect = equal_area_to_equirect(&film, 128, 64);
+        let brightest = (0..128 * 64)
+            .max_by(|&a, &b| equirect.data[a].mean.y.total_cmp(&equirect.data[b].mean.y))
+            .unwrap();
+        let (x, y) = (
+            (brightest % 128) as f64 + 0.5,
+            (brightest / 128) as f64 + 0.5,
+        );
+        let (lon, lat) = (x / 128.0 * 360.0 - 180.0, 90.0 - y / 64.0 * 180.0);
+        // a source pixel is about 3.2 degrees across, an output pixel 2.8
+        assert!(
+            (lon - 100.0).abs() < 4.0 && (lat - 30.0).abs() < 4.0,
+            "sun at longitude {lon}, latitude {lat}"
+        );
+    }
+
+    #[test]
+    fn to_image_holds_the_means() {
+        let film = test_film(7, 5);
+        let image = film.to_image();
+        assert_eq!((image.width, image.height), (7, 5));
+        for y in 0..5 {
+            for x in 0..7 {
+                let mean = film.data[x + y * 7].mean;
+                assert_eq!(image[(x, y)], mean.as_vec3().to_array(), "pixel ({x}, {y})");
+            }
+        }
+
+        let loaded = Film::from_exr_bytes(&film.to_exr_bytes()).unwrap();
+        assert_eq!(loaded.to_image(), image);
+    }
+
+    #[test]
+    fn exr_bytes_round_trip_the_film() {
+        let film = render_cornell(6, 4, |_| {});
+        let loaded = Film::from_exr_bytes(&film.to_exr_bytes()).unwrap();
+        assert_eq!((loaded.width, loaded.height), (6, 6));
+        // everything is stored as f32
+        let f32 = |v: DVec3| v.as_vec3().as_dvec3();
+        for (p, q) in film.data.iter().zip(&loaded.data) {
+            assert_eq!(q.mean, f32(p.mean));
+            assert_eq!(q.m2, f32(p.m2));
+            assert_eq!(q.count, p.count);
+            assert_eq!(q.lobes, p.lobes.map(f32));
+        }
+
+        // white and black come out as full and no brightness, always opaque
+        let mut film = Film::new(2, 1);
+        film.data[0].accumulate_sample(spectrum::srgb_linear_to_xyz(DVec3::ONE));
+        film.data[1].accumulate_sample(DVec3::ZERO);
+        let rgba = film.to_rgba8(&|xyz| xyz, false);
+        assert_eq!(rgba, [255, 255, 255, 255, 0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn malformed_exrs_are_errors() {
+        let bytes = test_film(7, 5).to_exr_bytes();
+        assert!(Film::from_exr_bytes(b"garbage").is_err());
+        assert!(Film::from_exr_bytes(&bytes[..bytes.len() / 2]).is_err());
+        assert!(Film::load_raw(std::env::temp_dir().join("pbr-test-missing.exr")).is_err());
+    }
+
+    #[test]
+    fn time_lapse_frames_differ_and_count_up() {
+        let output = Path::new("renders/sky.exr");
+        let names = [0, 1, 2, 10, 1234].map(|i| frame_output(output, "frame", i));
+        let expected = ["0000", "0001", "0002", "0010", "1234"]
+            .map(|n| PathBuf::from(format!("renders/frame_{n}.exr")));
+        assert_eq!(names, expected);
+        let times = [0, 1, 2, 3, 4].map(|i| frame_time(6.0, 18.0, 5, i));
+        assert_eq!(times, [6.0, 9.0, 12.0, 15.0, 18.0]);
+        assert_eq!(frame_time(6.0, 18.0, 1, 0), 6.0);
+
+        // the sky is much brighter at noon than just after sunrise
+        let sky = |time| {
+            let film = render_scene(scene_description::sky_scene(time, 0.0), 4, 8, |_| {});
+            film.data.iter().map(|p| p.mean.y).sum::<f64>()
+        };
+        let (dawn, noon) = (sky(6.5), sky(12.0));
+        assert!(
+            noon > 2.0 * dawn,
+            "sky luminance {dawn} at 6:30, {noon} at noon"
+        );
+    }
+
+    #[test]
+    fn cancelling_after_the_first_row_keeps_only_that_row() {
+        let (width, samples) = (16, 4);
+        // one thread, so that no other pixel is in progress when the flag is set
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        with_settings(scene_description::cornell_box(), samples, |settings| {
+            let mut film = Film::new(width, width);
+            let cancel = AtomicBool::new(false);
+            let finished = pool.install(|| {
+                render_with_progress(&mut film, &settings, None, &cancel, |_| {
+                    cancel.store(true, Ordering::Relaxed)
+                })
+            });
+            assert!(!finished);
+            let counts: Vec<_> = film.data.iter().map(|p| p.count).collect();
+            let done = counts.iter().filter(|&&c| c == samples as f64).count();
+            let empty = counts.iter().filter(|&&c| c == 0.0).count();
+            assert_eq!((done, empty), (width, width * width - width));
+
+            // a later pass adds to what was rendered
+            cancel.store(false, Ordering::Relaxed);
+            assert!(render(&mut film, &settings, None, &cancel));
+            for (p, before) in film.data.iter().zip(counts) {
+                assert_eq!(p.count, before + samples as f64);
+            }
+        });
+    }
+
+    #[test]
+    fn masked_pixels_get_more_samples() {
+        // black on the left half, white on the right
+        let path = std::env::temp_dir().join(format!("pbr-test-{}-mask.png", std::process::id()));
+        image::GrayImage::from_fn(2, 1, |x, _| image::Luma([x as u8 * 255]))
+            .save(&path)
+            .unwrap();
+        let map = SampleMap::load(&path, 4.0).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let film = with_settings(scene_description::cornell_box(), 3, |settings| {
+            let mut film = Film::new(8, 8);
+            let settings = RenderSettings {
+                sample_map: Some(&map),
+                ..settings
+            };
+            assert!(render(&mut film, &settings, None, &AtomicBool::new(false)));
+            film
+        });
+        for (i, p) in film.data.iter().enumerate() {
+            let expected = match i % 8 < 4 {
+                true => 3.0,
+                false => 12.0,
+            };
+            assert_eq!(p.count, expected, "pixel {i}");
+        }
+    }
+
+    #[test]
+    fn wavelength_samplers_are_normalized_and_agree() {
+        let strategies = [
+            WavelengthSampling::Xyz,
+            WavelengthSampling::Uniform,
+            WavelengthSampling::Visible,
+        ];
+        let mut images = vec![];
+        for strategy in strategies {
+            const N: usize = 10_000;
+            let step = (VISIBLE.end - VISIBLE.start) / N as f64;
+            let mut cdf = vec![0.0];
+            for i in 0..N {
+                let lambda = VISIBLE.start + (i as f64 + 0.5) * step;
+                cdf.push(cdf[i] + strategy.pdf(lambda) * step);
+            }
+            assert!((cdf[N] - 1.0).abs() < 1e-3, "pdf integrates to {}", cdf[N]);
+            // the sampler inverts the cdf of the pdf
+            for u in (1..100).map(|i| i as f64 / 100.0) {
+                let lambda = strategy.sample(u);
+                let cdf = cdf[((lambda - VISIBLE.start) / step).round() as usize];
+                assert!(
+                    (cdf - u).abs() < 1e-3,
+                    "sample({u}) = {lambda}, where the cdf is {cdf}"
+                );
+            }
+
+            let film = render_cornell(4, 1024, |settings| settings.wavelengths = strategy);
+            let mean = film.data.iter().map(|p| p.mean).sum::<DVec3>() / 16.0;
+            let sterr_sq = film.data.iter().map(|p| p.sterr_sq()).sum::<DVec3>() / 256.0;
+            images.push((mean, sterr_sq));
+        }
+        let (xyz, xyz_sterr_sq) = images[0];
+        for &(mean, sterr_sq) in &images[1..] {
+            let sigmas = (mean - xyz).abs() / (sterr_sq + xyz_sterr_sq).powf(0.5);
+            assert!(
+                sigmas.max_element() < 4.0,
+                "mean {mean}, {xyz} with xyz sampling"
+            );
+        }
+    }
+
+    #[test]
+    fn merged_films_match_one_run_of_all_samples() {
+        random::reseed([2399, 0, 0]);
+        let (mut single, mut first, mut second) =
+            (Film::new(3, 2), Film::new(3, 2), Film::new(3, 2));
+        for i in 0..6 {
+            // uneven splits, including none on one side
+            let split = [0, 1, 7, 50, 99, 100][i];
+            for j in 0..100 {
+                let value = DVec3::new(1.0, 10.0, 1000.0) * (i + 1) as f64
+                    + DVec3::from_array(random::rng().gen());
+                single.data[i].accumulate_sample(value);
+                match j < split {
+                    true => first.data[i].accumulate_sample(value),
+                    false => second.data[i].accumulate_sample(value),
+                }
+            }
+        }
+        first.merge(&second);
+        for (merged, single) in first.data.iter().zip(&single.data) {
+            assert_eq!(merged.count, single.count);
+            assert!((merged.mean - single.mean).abs().max_element() < 1e-9);
+            let variance = |p: &Pixel| p.m2 / (p.count - 1.0);
+            let error = (variance(merged) - variance(single)) / variance(single);
+            assert!(error.abs().max_element() < 1e-9, "variance off by {error}");
+        }
+    }
+
+    #[test]
+    fn equirect_center_looks_forward_and_edges_wrap() {
+        let camera = Camera {
+            pos: DVec3::ZERO,
+            looking: Camera::orientation(0.7, 0.3),
+            vfov: 40.0f64.to_radians(),
+            projection: Projection::Equirect,
+        };
+        for (width, height) in [(64, 32), (16, 16)] {
+            let dir = |x: f64, y: f64| camera.ray_dir(DVec2::new(x, y), width, height);
+            let (w, h) = (width as f64, height as f64);
+            assert!(dir(w / 2.0, h / 2.0).abs_diff_eq(camera.looking.z_axis, 1e-12));
+            for y in [0.5, h / 3.0, h - 0.5] {
+                assert!(dir(0.0, y).abs_diff_eq(dir(w, y), 1e-12), "row {y}");
+            }
+            for x in [0.0, w / 3.0, w] {
+                let (top, bottom) = (dir(x, 0.0), dir(x, h));
+                assert!(top.abs_diff_eq(camera.looking.y_axis, 1e-12), "top {top}");
+                assert!(
+                    bottom.abs_diff_eq(-camera.looking.y_axis, 1e-12),
+                    "bottom {bottom}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sensors_respond_only_to_their_curves() {
+        // the CIE observer written out as a custom sensor, at the centers of the nanometer bins
+        // the built-in curves are tabulated at
+        let mut cie = String::new();
+        for lambda in VISIBLE.start as usize..VISIBLE.end as usize {
+            let lambda = lambda as f64 + 0.5;
+            let xyz = spectrum::lambda_to_xyz_absolute(lambda);
+            cie += &format!("{lambda},{},{},{}\n", xyz.x, xyz.y, xyz.z);
+        }
+        let cie = SensorResponse::from_csv(&cie).unwrap();
+        // and one that only sees 540-560 nm
+        let green = SensorResponse::from_csv("540,0,0,0\n541,0,1,0\n559,0,1,0\n560,0,0,0").unwrap();
+        assert_eq!(green.response(530.0), DVec3::ZERO);
+        assert_eq!(green.response(550.0), DVec3::Y);
+
+        let render_with = |sensor: &SensorResponse| {
+            with_settings(scene_description::cornell_box(), 64, |settings| {
+                let mut film = Film::new(4, 4);
+                let settings = RenderSettings { sensor, ..settings };
+                assert!(render(&mut film, &settings, None, &AtomicBool::new(false)));
+                means(&film)
+            })
+        };
+        let xyz = render_with(&SensorResponse::Xyz);
+        for (custom, xyz) in render_with(&cie).into_iter().zip(xyz) {
+            assert!(
+                ((custom - xyz) / xyz).abs().max_element() < 1e-6,
+                "{custom} from the custom CIE sensor, {xyz} from the built-in one"
+            );
+        }
+        let green = render_with(&green);
+        assert!(green.iter().all(|rgb| rgb.x == 0.0 && rgb.z == 0.0));
+        assert!(green.iter().all(|rgb| rgb.y > 0.0));
+    }
+
+    #[test]
+    fn square_perspective_renders_show_a_round_sphere() {
+        // only the atmosphere looks all around unless asked to
+        for scene in SceneName::value_variants() {
+            let expected = match scene {
+                SceneName::Atmosphere => Projection::EqualArea,
+                _ => Projection::Perspective,
+            };
+            assert!(scene.default_projection() == expected);
+        }
+
+        let mut scene = Scene::new();
+        scene.add(objects::Sphere {
+            origin: DVec3::Z * 5.0,
+            radius: 1.0,
+            material: material::EmissiveMaterial::new(spectrum::ConstantSpectrum(1.0)),
+        });
+        let looking = Camera::orientation(0.0, 0.0);
+        let film = render_scene((scene, DVec3::ZERO, looking, medium::Vacuum), 256, 1, |s| {
+            s.integrator = Integrator::Normals
+        });
+
+        // the sphere subtends asin(1 / 5) around the center of the 40 degree view
+        let radius = (0.2f64.asin().tan() / 20.0f64.to_radians().tan()) * 128.0;
+        let mut area = 0.0;
+        for y in 0..256 {
+            for x in 0..256 {
+                let hit = film.data[x + y * 256].mean != DVec3::ZERO;
+                let r = DVec2::new(x as f64 + 0.5 - 128.0, y as f64 + 0.5 - 128.0).length();
+                if (r - radius).abs() > 1.0 {
+                    assert_eq!(hit, r < radius, "pixel ({x}, {y}) at {r} from the center");
+                }
+                area += hit as u32 as f64;
+            }
+        }
+        let expected = PI * radius * radius;
+        assert!(
+            (area - expected).abs() < 0.01 * expected,
+            "disk of {area} pixels, expected {expected}"
+        );
+    }
+
+    #[test]
+    fn hero_bundles_integrate_a_constant_spectrum_exactly() {
+        random::reseed([2407, 0, 0]);
+        // the response is constant over each nanometer
+        let exact: DVec3 = (VISIBLE.start as usize..VISIBLE.end as usize)
+            .map(|lambda| spectrum::lambda_to_xyz_absolute(lambda as f64 + 0.5))
+            .sum();
+
+        const N: usize = 10_000;
+        let mut bundles = Pixel::default();
+        let mut singles = Pixel::default();
+        for _ in 0..N {
+            let u = random::rng().gen_range(0.0..1.0);
+            let (lambdas, pdf) = WavelengthSampling::Xyz.sample_bundle(u);
+            let mut estimate = DVec3::ZERO;
+            for i in 0..4 {
+                estimate += spectrum::lambda_to_xyz_absolute(lambdas[i]) / pdf[i];
+            }
+            bundles.accumulate_sample(estimate);
+            let lambda = WavelengthSampling::Xyz.sample(u);
+            let pdf = WavelengthSampling::Xyz.pdf(lambda);
+            singles.accumulate_sample(spectrum::lambda_to_xyz_absolute(lambda) / pdf);
+        }
+
+        let error = (bundles.mean - exact) / exact;
+        assert!(
+            error.abs().max_element() < 1e-3,
+            "{} from the bundles, exactly {exact}",
+            bundles.mean
+        );
+        // stratifying the four is far better than one at a time
+        let ratio = bundles.sterr_sq() / singles.sterr_sq();
+        assert!(ratio.max_element() < 0.1, "variance ratio {ratio}");
+    }
+
+    #[test]
+    fn seeded_renders_repeat_exactly() {
+        let opt = Options::parse_from(["pbr", "--threads", "1", "--render-seed", "1"]);
+        assert_eq!(opt.threads, NonZeroUsize::new(1));
+        assert_eq!(opt.render_seed, Some(1));
+
+        let render_with = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let film = pool.install(|| render_cornell(8, 4, |_| {}));
+            film.data
+                .iter()
+                .map(|p| (p.count, p.mean, p.m2))
+                .collect::<Vec<_>>()
+        };
+        let first = render_with(1);
+        assert_eq!(render_with(1), first);
+        // each pixel has its own random numbers, so the threads can take them in any order
+        assert_eq!(render_with(4), first);
+    }
+
+    #[test]
+    fn spectral_films_follow_the_planck_curve() {
+        let planck = || spectrum::physical::Blackbody {
+            temperature: 3000.0,
+        };
+        // a glowing wall filling the view
+        let mut scene = Scene::new();
+        scene.add(objects::Quad {
+            origin: DVec3::new(-10.0, -10.0, 1.0),
+            u: DVec3::Y * 20.0,
+            v: DVec3::X * 20.0,
+            material: material::EmissiveMaterial::new(planck()),
+        });
+        let bins = 16;
+        let mut spectral = SpectralFilm::new(2, 2, bins);
+        let looking = Camera::orientation(0.0, 0.0);
+        with_settings(
+            (scene, DVec3::ZERO, looking, medium::Vacuum),
+            1024,
+            |settings| {
+                // so that the far red bins get as many samples as the rest
+                let settings = RenderSettings {
+                    wavelengths: WavelengthSampling::Uniform,
+                    ..settings
+                };
+                let mut film = Film::new(2, 2);
+                let cancel = AtomicBool::new(false);
+                assert!(render(&mut film, &settings, Some(&mut spectral), &cancel));
+            },
+        );
+
+        let width = SpectralFilm::bin_width(bins);
+        for bin in 0..bins {
+            // the average radiance over the bin
+            let start = VISIBLE.start + bin as f64 * width;
+            let expected = (0..100)
+                .map(|i| planck().sample(start + (i as f64 + 0.5) / 100.0 * width))
+                .sum::<f64>()
+                / 100.0;
+            for p in &spectral.data {
+                assert_eq!(p.count, 1024.0);
+                let sterr = (p.m2[bin] / (p.count - 1.0) / p.count).sqrt();
+                assert!(
+                    (p.mean[bin] - expected).abs() < 4.0 * sterr + 1e-3 * expected,
+                    "bin {bin}: {} +- {sterr}, expected {expected}",
+                    p.mean[bin]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn auto_framed_bounds_touch_the_edge_of_the_view() {
+        let mut scene = Scene::new();
+        scene.add(objects::Sphere {
+            origin: DVec3::new(1.0, 2.0, 3.0),
+            radius: 1.0,
+            material: material::EmissiveMaterial::new(spectrum::ConstantSpectrum(1.0)),
+        });
+        let bounds = scene.bounds().unwrap();
+        let vfov = 40.0f64.to_radians();
+        for (looking, (width, height)) in [
+            (Camera::orientation(0.0, 0.0), (32, 32)),
+            (Camera::orientation(0.0, 0.0), (64, 32)),
+            (Camera::orientation(2.5, -0.4), (48, 27)),
+        ] {
+            let aspect = width as f64 / height as f64;
+            let camera = Camera::auto_frame(bounds, looking, vfov, aspect);
+            let tan_y = (vfov / 2.0).tan();
+            // where each corner of the box lands, with the edges of the view at -1 and 1
+            let furthest = bounds
+                .corners()
+                .map(|p| {
+                    let p = looking.transpose() * (p - camera.pos);
+                    (p.x / p.z / (tan_y * aspect))
+                        .abs()
+                        .max((p.y / p.z / tan_y).abs())
+                })
+                .fold(f64::NEG_INFINITY, f64::max);
+            assert!(
+                (furthest - 1.0).abs() < 1e-9,
+                "furthest corner at {furthest}"
+            );
+
+            // so the whole sphere is in view, and rays around the border miss it
+            let hits = |p: DVec2| {
+                let dir = camera.ray_dir(p, width, height);
+                let to_center = DVec3::new(1.0, 2.0, 3.0) - camera.pos;
+                to_center.dot(dir) > 0.0 && to_center.reject_from(dir).length() < 1.0
+            };
+            let (w, h) = (width as f64, height as f64);
+            for i in 0..=100 {
+                let t = i as f64 / 100.0;
+                for p in [(t * w, 0.0), (t * w, h), (0.0, t * h), (w, t * h)] {
+                    assert!(!hits(DVec2::from(p)), "the ray through {p:?} hits");
+                }
+            }
+            assert!(hits(DVec2::new(w, h) / 2.0));
+        }
+
+        // looking straight at a unit sphere's box, its near corners are in the corners of a square
+        // view, so the corner rays just graze the box
+        let camera = Camera::auto_frame(
+            Bounds {
+                min: -DVec3::ONE,
+                max: DVec3::ONE,
+            },
+            DMat3::IDENTITY,
+            vfov,
+            1.0,
+        );
+        let dir = camera.ray_dir(DVec2::ZERO, 16, 16);
+        let corner = DVec3::new(-1.0, 1.0, -1.0) - camera.pos;
+        assert!(corner.reject_from(dir).length() < 1e-9);
+    }
+
+    #[test]
+    fn dithered_gradients_have_no_bands_and_keep_their_means() {
+        // a slow ramp over 8 levels of red, green and blue, so that rounding alone leaves a band
+        // every 32 columns
+        let (width, height) = (256, 64);
+        let exact = |x: usize| 40.0 + 8.0 * x as f64 / width as f64;
+        let mut film = Film::new(width, height);
+        film.par_iter_mut().for_each(|(x, _, p)| {
+            p.accumulate_sample(spectrum::srgb_to_xyz(DVec3::splat(exact(x) / 255.0)));
+        });
+        let column_means = |dither| {
+            let rgba = film.to_rgba8(&|xyz| xyz, dither);
+            let mut sums = vec![0.0; width];
+            for (i, px) in rgba.chunks_exact(4).enumerate() {
+                sums[i % width] += px[..3].iter().map(|&c| c as f64).sum::<f64>() / 3.0;
+            }
+            sums.into_iter()
+                .map(|s| s / height as f64)
+                .collect::<Vec<_>>()
+        };
+        let banded = column_means(false);
+        let dithered = column_means(true);
+
+        let worst = |means: &[f64]| {
+            (0..width)
+                .map(|x| (means[x] - exact(x)).abs())
+                .fold(0.0, f64::max)
+        };
+        assert!(
+            worst(&banded) > 0.45,
+            "{} without dithering",
+            worst(&banded)
+        );
+        assert!(
+            worst(&dithered) < 0.1,
+            "{} with dithering",
+            worst(&dithered)
+        );
+
+        for region in 0..8 {
+            let mean = |means: &[f64]| means[region * 32..][..32].iter().sum::<f64>() / 32.0;
+            let (banded, dithered) = (mean(&banded), mean(&dithered));
+            assert!(
+                (dithered - banded).abs() < 0.05,
+                "region {region}: {dithered} with dithering, {banded} without"
+            );
+        }
+    }
+}
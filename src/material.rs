@@ -1,8 +1,11 @@
-use glam::DVec4;
+use std::sync::Arc;
 
-use crate::brdf::Brdf;
+use glam::{DVec2, DVec4};
+
+use crate::brdf::{Brdf, LambertianBrdf};
 use crate::medium::Medium;
-use crate::spectrum::Spectrum;
+use crate::spectrum::{self, ConstantSpectrum, Spectrum};
+use crate::texture::{Filter, Texture};
 
 pub mod physical;
 
@@ -14,13 +17,48 @@ pub struct Material<E, B, Mi, Mo> {
     pub exit_medium: Mo,
 }
 
+/// An opaque surface that glows with `emission` from both sides and reflects nothing, like the
+/// diffuse area lights of other renderers. Objects made of it become lights when added to a
+/// [`Scene`](crate::scene::Scene), as do objects of any other material with emission.
+pub type EmissiveMaterial<E> = Material<E, LambertianBrdf<ConstantSpectrum>, (), ()>;
+
+#[allow(unused)]
+impl<E: Spectrum> EmissiveMaterial<E> {
+    pub fn new(emission: E) -> Self {
+        Material {
+            emission,
+            brdf: LambertianBrdf {
+                albedo: spectrum::ZERO,
+            },
+            enter_medium: (),
+            exit_medium: (),
+        }
+    }
+}
+
 pub trait MaterialErased: Send + Sync {
     fn emission_sample(&self, lambdas: DVec4) -> DVec4;
 
+    /// Whether the emission is nonzero anywhere in the visible range. Objects with such a material
+    /// become lights when they're added to a [`Scene`](crate::scene::Scene).
+    fn emissive(&self) -> bool;
+
     fn brdf(&self) -> Option<&dyn Brdf>;
 
+    /// Whether rays can pass through the surface into its enter medium. Only then can
+    /// [`enter_medium`](Self::enter_medium) and [`exit_medium`](Self::exit_medium) be called.
+    fn transmissive(&self) -> bool;
+
     fn enter_medium(&self) -> &dyn Medium;
     fn exit_medium(&self) -> &dyn Medium;
+
+    /// Whether the surface has a hole at texture coordinates `uv`, which rays go straight through
+    /// as if the surface wasn't there. [`Scene::raycast`](crate::scene::Scene::raycast) never
+    /// returns hits in holes.
+    fn cut_out(&self, uv: Option<DVec2>) -> bool {
+        _ = uv;
+        false
+    }
 }
 
 trait Maybe<T: ?Sized>: Send + Sync {
@@ -52,10 +90,18 @@ impl<E: Spectrum, B: Maybe<dyn Brdf>, Mi: Maybe<dyn Medium>, Mo: Maybe<dyn Mediu
         self.emission.sample_multi(lambdas)
     }
 
+    fn emissive(&self) -> bool {
+        !self.emission.is_zero()
+    }
+
     fn brdf(&self) -> Option<&dyn Brdf> {
         self.brdf.get()
     }
 
+    fn transmissive(&self) -> bool {
+        self.enter_medium.get().is_some()
+    }
+
     fn enter_medium(&self) -> &dyn Medium {
         self.enter_medium
             .get()
@@ -68,3 +114,46 @@ impl<E: Spectrum, B: Maybe<dyn Brdf>, Mi: Maybe<dyn Medium>, Mo: Maybe<dyn Mediu
             .expect("transmitting non-transmissive surface")
     }
 }
+
+/// `material` with holes wherever `alpha` is below `threshold`, for leaves, fences and the like.
+/// Hits without texture coordinates are never in a hole.
+///
+/// The holes don't take anything away from emission, so light sampling of an emissive cutout would
+/// still pick points in them; cut out emitters aren't supported.
+#[allow(unused)]
+pub struct CutoutMaterial<M> {
+    pub material: M,
+    pub alpha: Arc<Texture<f64>>,
+    pub filter: Filter,
+    pub threshold: f64,
+}
+
+impl<M: MaterialErased> MaterialErased for CutoutMaterial<M> {
+    fn emission_sample(&self, lambdas: DVec4) -> DVec4 {
+        self.material.emission_sample(lambdas)
+    }
+
+    fn emissive(&self) -> bool {
+        self.material.emissive()
+    }
+
+    fn brdf(&self) -> Option<&dyn Brdf> {
+        self.material.brdf()
+    }
+
+    fn transmissive(&self) -> bool {
+        self.material.transmissive()
+    }
+
+    fn enter_medium(&self) -> &dyn Medium {
+        self.material.enter_medium()
+    }
+
+    fn exit_medium(&self) -> &dyn Medium {
+        self.material.exit_medium()
+    }
+
+    fn cut_out(&self, uv: Option<DVec2>) -> bool {
+        uv.is_some_and(|uv| self.alpha.lookup(uv, self.filter) < self.threshold)
+    }
+}
@@ -1,31 +1,36 @@
 use std::sync::LazyLock;
 
-use crate::spectrum::{PiecewiseLinearSpectrum, Spectrum};
+use crate::spectrum::{PiecewiseLinearSpectrum, Spectrum, TabulatedSpectrum};
 
-pub fn ior_gold() -> &'static [impl Spectrum; 2] {
-    static IOR: LazyLock<[PiecewiseLinearSpectrum; 2]> = LazyLock::new(|| {
+pub fn ior_gold() -> &'static [TabulatedSpectrum; 2] {
+    static IOR: LazyLock<[TabulatedSpectrum; 2]> = LazyLock::new(|| {
         PiecewiseLinearSpectrum::from_csv_multi(include_str!("ior-gold_Johnson.csv"))
+            .map(TabulatedSpectrum::from_spectrum)
     });
     &IOR
 }
 
-pub fn ior_silver() -> &'static [impl Spectrum; 2] {
-    static IOR: LazyLock<[PiecewiseLinearSpectrum; 2]> = LazyLock::new(|| {
+pub fn ior_silver() -> &'static [TabulatedSpectrum; 2] {
+    static IOR: LazyLock<[TabulatedSpectrum; 2]> = LazyLock::new(|| {
         PiecewiseLinearSpectrum::from_csv_multi(include_str!("ior-silver_Johnson.csv"))
+            .map(TabulatedSpectrum::from_spectrum)
     });
     &IOR
 }
 
-pub fn ior_copper() -> &'static [impl Spectrum; 2] {
-    static IOR: LazyLock<[PiecewiseLinearSpectrum; 2]> = LazyLock::new(|| {
+pub fn ior_copper() -> &'static [TabulatedSpectrum; 2] {
+    static IOR: LazyLock<[TabulatedSpectrum; 2]> = LazyLock::new(|| {
         PiecewiseLinearSpectrum::from_csv_multi(include_str!("ior-copper_Johnson.csv"))
+            .map(TabulatedSpectrum::from_spectrum)
     });
     &IOR
 }
 
 pub fn ior_glass() -> &'static impl Spectrum {
-    static IOR: LazyLock<PiecewiseLinearSpectrum> = LazyLock::new(|| {
-        PiecewiseLinearSpectrum::from_csv(include_str!("ior-glass_Rubin.csv"))
+    static IOR: LazyLock<TabulatedSpectrum> = LazyLock::new(|| {
+        TabulatedSpectrum::from_spectrum(PiecewiseLinearSpectrum::from_csv(include_str!(
+            "ior-glass_Rubin.csv"
+        )))
     });
     &*IOR
 }
@@ -1,9 +1,11 @@
 use std::f64::consts::PI;
+use std::ops::Range;
 
 use glam::{DVec3, DVec4, Vec3Swizzles};
 
+use crate::material::MaterialErased;
 use crate::phase::{Draine, Phase};
-use crate::spectrum::{self, Spectrum};
+use crate::spectrum::{self, PiecewiseLinearSpectrum, Spectrum, TabulatedSpectrum, VISIBLE};
 
 #[derive(Debug)]
 pub struct MediumProperties {
@@ -60,6 +62,53 @@ impl Medium for Box<dyn Medium> {
     }
 }
 
+/// The media a ray is inside of, for tracking which one it's in through nested volumes like a
+/// bubble in a glass of water.
+///
+/// Passing into a surface pushes its enter medium and passing out pops it again, returning to
+/// whatever the ray was in before rather than to the surface's exit medium. This relies on volumes
+/// nesting properly; where they overlap, leaving the outer one first pops the inner medium instead.
+/// Leaving a surface the ray was never seen entering, as when it starts inside an object, replaces
+/// the outermost medium with the surface's exit medium.
+#[derive(Clone)]
+pub struct MediumStack<'a> {
+    outermost: &'a dyn Medium,
+    entered: Vec<&'a dyn Medium>,
+}
+
+impl<'a> MediumStack<'a> {
+    pub fn new(outermost: &'a dyn Medium) -> Self {
+        MediumStack {
+            outermost,
+            entered: vec![],
+        }
+    }
+
+    /// The medium the ray is currently in.
+    pub fn current(&self) -> &'a dyn Medium {
+        self.entered.last().copied().unwrap_or(self.outermost)
+    }
+
+    /// Updates the stack for a ray with direction `dir` passing through a surface of `material`.
+    pub fn transmit(&mut self, material: &'a dyn MaterialErased, geo_normal: DVec3, dir: DVec3) {
+        if dir.dot(geo_normal) > 0.0 {
+            if self.entered.pop().is_none() {
+                self.outermost = material.exit_medium();
+            }
+        } else {
+            self.entered.push(material.enter_medium());
+        }
+    }
+}
+
+/// The wavelengths spanned by `lambdas`, for majorants bounding a medium over all of them. That
+/// gives the whole bundle the same majorant, so that collisions sampled for the hero wavelength
+/// are as likely as they would be for the others, and their weights don't blow up when the hero
+/// falls where the medium is thin.
+fn band(lambdas: DVec4) -> Range<f64> {
+    lambdas.min_element()..lambdas.max_element()
+}
+
 #[derive(Copy, Clone)]
 pub struct Vacuum;
 
@@ -94,6 +143,28 @@ pub struct CombinedMedium<M1, M2> {
     pub m2: M2,
 }
 
+impl<M1: Medium, M2: Medium> CombinedMedium<M1, M2> {
+    /// Probability of sampling a direction from `m1`'s phase function rather than `m2`'s.
+    ///
+    /// The path tracer treats the phase pdf as the same for every wavelength (`r_l = r_u / pdf`),
+    /// so the choice is weighted by the scattering averaged over all four wavelengths instead of
+    /// only the hero wavelength. This also keeps the other wavelengths from being starved when one
+    /// of the media scatters much more strongly at the hero wavelength than at the rest.
+    fn selection_weight(&self, pos: DVec3, outgoing: DVec3, lambdas: DVec4) -> f64 {
+        let s1 = self
+            .m1
+            .properties(pos, outgoing, lambdas)
+            .scattering
+            .element_sum();
+        let s2 = self
+            .m2
+            .properties(pos, outgoing, lambdas)
+            .scattering
+            .element_sum();
+        s1 / (s1 + s2)
+    }
+}
+
 impl<M1: Medium, M2: Medium> Medium for CombinedMedium<M1, M2> {
     fn majorant(&self, lambdas: DVec4) -> DVec4 {
         self.m1.majorant(lambdas) + self.m2.majorant(lambdas)
@@ -102,9 +173,17 @@ impl<M1: Medium, M2: Medium> Medium for CombinedMedium<M1, M2> {
     fn properties(&self, pos: DVec3, outgoing: DVec3, lambdas: DVec4) -> MediumProperties {
         let mp1 = self.m1.properties(pos, outgoing, lambdas);
         let mp2 = self.m2.properties(pos, outgoing, lambdas);
+        // the tracers emit `absorption * emission`, so each medium's emission counts in
+        // proportion to its share of the absorption
+        let absorption = mp1.absorption + mp2.absorption;
+        let emitted = mp1.absorption * mp1.emission + mp2.absorption * mp2.emission;
         MediumProperties {
-            emission: mp1.emission + mp2.emission,
-            absorption: mp1.absorption + mp2.absorption,
+            emission: DVec4::select(
+                absorption.cmpgt(DVec4::ZERO),
+                emitted / absorption,
+                DVec4::ZERO,
+            ),
+            absorption,
             scattering: mp1.scattering + mp2.scattering,
         }
     }
@@ -119,9 +198,7 @@ impl<M1: Medium, M2: Medium> Medium for CombinedMedium<M1, M2> {
     }
 
     fn sample_phase(&self, pos: DVec3, outgoing: DVec3, lambdas: DVec4, random: DVec3) -> DVec3 {
-        let s1 = self.m1.properties(pos, outgoing, lambdas).scattering.x;
-        let s2 = self.m2.properties(pos, outgoing, lambdas).scattering.x;
-        let t = s1 / (s1 + s2);
+        let t = self.selection_weight(pos, outgoing, lambdas);
         if random.z < t {
             let random = random.with_z(random.z / t);
             self.m1.sample_phase(pos, outgoing, lambdas, random)
@@ -132,9 +209,7 @@ impl<M1: Medium, M2: Medium> Medium for CombinedMedium<M1, M2> {
     }
 
     fn pdf_phase(&self, pos: DVec3, incoming: DVec3, outgoing: DVec3, lambdas: DVec4) -> f64 {
-        let s1 = self.m1.properties(pos, outgoing, lambdas).scattering.x;
-        let s2 = self.m2.properties(pos, outgoing, lambdas).scattering.x;
-        let t = s1 / (s1 + s2);
+        let t = self.selection_weight(pos, outgoing, lambdas);
         let pdf1 = self.m1.pdf_phase(pos, incoming, outgoing, lambdas);
         let pdf2 = self.m2.pdf_phase(pos, incoming, outgoing, lambdas);
         pdf1 * t + pdf2 * (1.0 - t)
@@ -150,7 +225,9 @@ pub struct TestMedium<Sa, Se, Ss> {
 
 impl<Sa: Spectrum, Se: Spectrum, Ss: Spectrum> Medium for TestMedium<Sa, Se, Ss> {
     fn majorant(&self, lambdas: DVec4) -> DVec4 {
-        self.absorption.sample_multi(lambdas) + self.scattering.sample_multi(lambdas)
+        // the density peaks at 1 in the middle
+        let band = band(lambdas);
+        DVec4::splat(self.absorption.max_over(band.clone()) + self.scattering.max_over(band))
     }
 
     fn properties(&self, pos: DVec3, outgoing: DVec3, lambdas: DVec4) -> MediumProperties {
@@ -168,6 +245,62 @@ impl<Sa: Spectrum, Se: Spectrum, Ss: Spectrum> Medium for TestMedium<Sa, Se, Ss>
     }
 }
 
+/// A homogeneous medium which only absorbs, for tinted glass and liquids.
+#[allow(unused)]
+pub struct ColoredGlassMedium {
+    pub absorption: TabulatedSpectrum,
+}
+
+#[allow(unused)]
+impl ColoredGlassMedium {
+    /// Chooses the absorption so that a slab `reference_thickness` thick transmits the linear sRGB
+    /// color `rgb`, each channel in `(0, 1]`.
+    ///
+    /// The color is upsampled to a smooth transmission spectrum through the channel values at
+    /// 460, 540 and 610 nm, held constant towards the ends of the visible range. The transmitted
+    /// color matches `rgb` for neutral tints and is within about 0.15 per channel for saturated ones.
+    pub fn from_transmission(rgb: DVec3, reference_thickness: f64) -> Self {
+        let transmission = PiecewiseLinearSpectrum::from_points(&[
+            (VISIBLE.start, rgb.z),
+            (460.0, rgb.z),
+            (540.0, rgb.y),
+            (610.0, rgb.x),
+            (VISIBLE.end + 1.0, rgb.x),
+        ]);
+        let points: Vec<_> = (VISIBLE.start as usize..=VISIBLE.end as usize)
+            .map(|wl| {
+                let t = transmission.sample(wl as f64).max(1.0e-6);
+                (wl as f64, -t.ln() / reference_thickness)
+            })
+            .collect();
+        ColoredGlassMedium {
+            absorption: TabulatedSpectrum::from_spectrum(PiecewiseLinearSpectrum::from_points(
+                &points,
+            )),
+        }
+    }
+}
+
+impl Medium for ColoredGlassMedium {
+    fn majorant(&self, lambdas: DVec4) -> DVec4 {
+        self.absorption.sample_multi(lambdas)
+    }
+
+    fn properties(&self, pos: DVec3, outgoing: DVec3, lambdas: DVec4) -> MediumProperties {
+        _ = (pos, outgoing);
+        MediumProperties {
+            emission: DVec4::ZERO,
+            absorption: self.absorption.sample_multi(lambdas),
+            scattering: DVec4::ZERO,
+        }
+    }
+
+    fn phase(&self, pos: DVec3, incoming: DVec3, outgoing: DVec3, lambdas: DVec4) -> DVec4 {
+        _ = (pos, incoming, outgoing, lambdas);
+        DVec4::ZERO
+    }
+}
+
 #[derive(Clone)]
 pub struct AtmosphereDryAir {
     pub origin: DVec3,
@@ -227,14 +360,18 @@ impl AtmosphereDryAir {
 
 impl Medium for AtmosphereDryAir {
     fn majorant(&self, lambdas: DVec4) -> DVec4 {
-        let peak_rayleigh = Self::rayleigh_cross_section(lambdas) * self.sea_level_air_density;
+        let band = band(lambdas);
+        // the cross section falls off with wavelength, so it peaks at the shortest
+        let peak_rayleigh =
+            Self::rayleigh_cross_section(DVec4::splat(band.start)) * self.sea_level_air_density;
+        let ozone_cross_section =
+            spectrum::physical::ozone_absorption_cross_section().max_over(band);
         let peak_ozone = (-self.ozone_start_altitude / self.height_scale).exp()
             * self.sea_level_air_density
             * self.ozone_peak_concentration
-            * spectrum::physical::ozone_absorption_cross_section().sample_multi(lambdas);
-        let sea_level_ozone = self.sea_level_air_density
-            * self.min_ozone_concentration
-            * spectrum::physical::ozone_absorption_cross_section().sample_multi(lambdas);
+            * ozone_cross_section;
+        let sea_level_ozone =
+            self.sea_level_air_density * self.min_ozone_concentration * ozone_cross_section;
         peak_rayleigh + peak_ozone.max(sea_level_ozone)
     }
 
@@ -306,3 +443,189 @@ impl Medium for AtmosphereAerosols {
         self.phase.pdf(incoming, outgoing, lambdas)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec4Swizzles;
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::material::Material;
+    use crate::objects::Sphere;
+    use crate::scene::Scene;
+    use crate::spectrum::physical::{cie_d65_1nit, Blackbody};
+    use crate::spectrum::ConstantSpectrum;
+
+    #[test]
+    fn colored_glass_transmits_its_color() {
+        // the linear sRGB color of D65 after a slab of the reference thickness, relative to white
+        let transmitted = |rgb: DVec3| {
+            let glass = ColoredGlassMedium::from_transmission(rgb, 0.02);
+            let (mut tinted, mut white) = (DVec3::ZERO, DVec3::ZERO);
+            for wl in VISIBLE.start as usize..=VISIBLE.end as usize {
+                let wl = wl as f64;
+                let light = spectrum::lambda_to_xyz_absolute(wl) * cie_d65_1nit().sample(wl);
+                tinted += light * (-glass.absorption.sample(wl) * 0.02).exp();
+                white += light;
+            }
+            spectrum::xyz_to_srgb_linear(tinted) / spectrum::xyz_to_srgb_linear(white)
+        };
+        for (rgb, tolerance) in [
+            (DVec3::ONE, 1e-3),
+            (DVec3::splat(0.4), 1e-3),
+            (DVec3::new(0.9, 0.5, 0.2), 0.15),
+            (DVec3::new(0.2, 0.7, 0.9), 0.15),
+            (DVec3::new(0.3, 0.8, 0.3), 0.15),
+        ] {
+            let color = transmitted(rgb);
+            assert!(
+                (color - rgb).abs().max_element() < tolerance,
+                "{rgb} came out {color}"
+            );
+        }
+
+        // and rays through a ball of it are attenuated by the length of their chord, without
+        // scattering
+        let rgb = DVec3::new(0.9, 0.5, 0.2);
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            origin: DVec3::ZERO,
+            radius: 0.01,
+            material: Material {
+                emission: spectrum::ZERO,
+                brdf: (),
+                enter_medium: ColoredGlassMedium::from_transmission(rgb, 0.02),
+                exit_medium: Vacuum,
+            },
+        });
+        let glass = ColoredGlassMedium::from_transmission(rgb, 0.02);
+        let lambdas = DVec4::new(450.0, 520.0, 580.0, 650.0);
+        let properties = glass.properties(DVec3::ZERO, DVec3::Z, lambdas);
+        assert_eq!(properties.scattering, DVec4::ZERO);
+        assert_eq!(glass.majorant(lambdas), properties.absorption);
+        let expected = (-properties.absorption * 0.02).exp();
+        crate::random::reseed([2377, 0, 0]);
+        let mut sum = DVec4::ZERO;
+        for _ in 0..10000 {
+            sum += scene.transmittance(-DVec3::Z, DVec3::Z, lambdas, &Vacuum, 2.0);
+        }
+        let t = sum / 10000.0;
+        assert!(
+            (t - expected).abs().max_element() < 0.01,
+            "transmittance {t}, expected {expected}"
+        );
+    }
+
+    #[test]
+    fn blended_phase_pdf_integrates_to_one() {
+        crate::random::reseed([2380, 0, 0]);
+        // Rayleigh scattering at sea level and a gray haze of about the same strength
+        let medium = CombinedMedium {
+            m1: AtmosphereDryAir {
+                origin: DVec3::Y * -6371000.0,
+                sea_level: 6371000.0,
+                height_scale: 8000.0,
+                sea_level_air_density: 2.504e25,
+                ozone_start_altitude: 12_000.0,
+                ozone_peak_altitude: 32_000.0,
+                ozone_peak_concentration: 5e-6,
+                ozone_height_scale: 15_000.0,
+                min_ozone_concentration: 10e-9,
+            },
+            m2: TestMedium {
+                absorption: spectrum::ZERO,
+                emission: spectrum::ZERO,
+                scattering: ConstantSpectrum(1.0e-5),
+            },
+        };
+        let (pos, outgoing) = (DVec3::ZERO, DVec3::new(0.6, 0.0, 0.8));
+        for lambdas in [
+            DVec4::new(400.0, 450.0, 500.0, 550.0),
+            DVec4::new(700.0, 380.0, 520.0, 640.0),
+        ] {
+            // the hero wavelength doesn't change which lobe gets picked
+            let incoming = DVec3::new(0.0, 0.8, -0.6);
+            let pdf = medium.pdf_phase(pos, incoming, outgoing, lambdas);
+            assert_eq!(
+                pdf,
+                medium.pdf_phase(pos, incoming, outgoing, lambdas.wzxy())
+            );
+
+            const N: usize = 200_000;
+            let mut integral = 0.0;
+            let mut weights = DVec4::ZERO;
+            for _ in 0..N {
+                let dir = crate::random::sphere(crate::random::rng().gen());
+                integral += medium.pdf_phase(pos, dir, outgoing, lambdas) * 4.0 * PI;
+                let dir = medium.sample_phase(pos, outgoing, lambdas, crate::random::rng().gen());
+                let pdf = medium.pdf_phase(pos, dir, outgoing, lambdas);
+                weights += medium.phase(pos, dir, outgoing, lambdas) / pdf;
+            }
+            let (integral, weights) = (integral / N as f64, weights / N as f64);
+            assert!(
+                (integral - 1.0).abs() < 0.01,
+                "pdf integrates to {integral}"
+            );
+            assert!(
+                (weights - 1.0).abs().max_element() < 0.01,
+                "phase over pdf averages {weights} at {lambdas}"
+            );
+        }
+    }
+
+    #[test]
+    fn aerosols_dont_change_the_emission_of_a_hot_medium() {
+        let hot = || TestMedium {
+            absorption: ConstantSpectrum(2.0),
+            emission: Blackbody {
+                temperature: 1500.0,
+            },
+            scattering: spectrum::ZERO,
+        };
+        let aerosols = || AtmosphereAerosols {
+            origin: DVec3::Y * -6371000.0,
+            sea_level: 6371000.0,
+            sea_level_density: 3.0,
+            height_scale: 1200.0,
+            max_height: 20000.0,
+            phase: Draine {
+                alpha: 1.0,
+                g: 0.85,
+            },
+        };
+        let (pos, outgoing) = (DVec3::ZERO, DVec3::Z);
+        let lambdas = DVec4::new(450.0, 550.0, 650.0, 750.0);
+        let source = |mp: MediumProperties| mp.absorption * mp.emission;
+        let alone = source(hot().properties(pos, outgoing, lambdas));
+        assert!(alone.cmpgt(DVec4::ZERO).all());
+
+        let with_aerosols = CombinedMedium {
+            m1: hot(),
+            m2: aerosols(),
+        };
+        let mp = with_aerosols.properties(pos, outgoing, lambdas);
+        assert!(mp.absorption.cmpgt(DVec4::splat(2.0)).all());
+        let combined = source(mp);
+        assert!(
+            ((combined - alone) / alone).abs().max_element() < 1e-12,
+            "emits {combined} with aerosols, {alone} without"
+        );
+        let reversed = CombinedMedium {
+            m1: aerosols(),
+            m2: hot(),
+        };
+        assert_eq!(
+            source(reversed.properties(pos, outgoing, lambdas)),
+            combined
+        );
+
+        let cold = CombinedMedium {
+            m1: aerosols(),
+            m2: aerosols(),
+        };
+        assert_eq!(
+            cold.properties(pos, outgoing, lambdas).emission,
+            DVec4::ZERO
+        );
+    }
+}
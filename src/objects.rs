@@ -1,26 +1,132 @@
+use std::ops::ControlFlow;
 use std::sync::Arc;
 
-use glam::{BVec3, DMat4, DVec3, Vec3Swizzles};
+use glam::{DMat3, DMat4, DVec2, DVec3, Vec3Swizzles};
 
 use crate::material::MaterialErased;
-use crate::Bounds;
+use crate::{random, Bounds};
 
+mod normal_map;
 mod voxel;
+pub use normal_map::*;
 pub use voxel::*;
 
 pub struct RayHit<'a> {
     pub t: f64,
+    /// Unit shading normal.
     pub normal: DVec3,
+    /// Unit normal of the actual surface. Its orientation is a property of the object (outwards for
+    /// spheres, by winding for triangles) and never of the ray, so the sign of
+    /// `geo_normal.dot(dir)` tells which side of the surface a ray is on. Medium transitions and ray
+    /// offsets depend on this.
     pub geo_normal: DVec3,
+    /// Unit direction perpendicular to `normal` that anisotropic BRDFs orient their roughness
+    /// along, for surfaces which have one.
+    pub tangent: Option<DVec3>,
+    /// Texture coordinates of the hit, for surfaces which have them.
+    pub uv: Option<DVec2>,
     pub material: &'a dyn MaterialErased,
 }
 
 pub trait Object: Send + Sync {
     fn bounds(&self) -> Bounds;
     fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit>;
+
+    /// Bounds of the object moved by `transform`. By default these are the bounds of the
+    /// transformed corners of [`Object::bounds`], which are loose under rotation; objects that can
+    /// bound their transformed geometry directly should, since [`Transform`] uses this.
+    fn transformed_bounds(&self, transform: &DMat4) -> Bounds {
+        self.bounds().transform(transform)
+    }
+
+    /// Whether any of the surface has an [emissive](MaterialErased::emissive) material, in which
+    /// case [`Scene::add`](crate::scene::Scene::add) makes the object a light as well.
+    fn emissive(&self) -> bool {
+        self.emissive_area() > 0.0
+    }
+
+    /// Area of the emissive parts of the surface, which [`Object::sample_emissive`] picks points
+    /// from uniformly. Zero for objects that can't sample their surface, which can then only be
+    /// found by BRDF sampling even if they are [emissive](Object::emissive).
+    fn emissive_area(&self) -> f64 {
+        0.0
+    }
+
+    /// A point on the emissive parts of the surface, uniformly distributed by area.
+    fn sample_emissive(&self, random: DVec2) -> Option<SurfacePoint<'_>> {
+        _ = random;
+        None
+    }
+
+    /// Adds the authoring mistakes in the object to `problems`, for
+    /// [`Scene::validate`](crate::scene::Scene::validate). Nothing by default.
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        _ = problems;
+    }
+}
+
+/// An authoring mistake in an object, as found by [`Object::problems`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Problem {
+    /// A material which rays pass through, either because its BRDF
+    /// [transmits](crate::brdf::Brdf::transmits) or because it has none, but which has no media
+    /// for them to pass into. The first ray through it panics.
+    MissingMedium,
+    /// A triangle or quad with no area, or with corners that aren't finite, given by three of its
+    /// corners in the object's own coordinates. Rays never hit it.
+    Degenerate([DVec3; 3]),
+}
+
+impl Problem {
+    fn of_material(material: &dyn MaterialErased) -> Option<Problem> {
+        let passes_through = material.brdf().is_none_or(|brdf| brdf.transmits());
+        (passes_through && !material.transmissive()).then_some(Problem::MissingMedium)
+    }
+}
+
+/// A point on the surface of an object, as sampled by [`Object::sample_emissive`].
+pub struct SurfacePoint<'a> {
+    pub pos: DVec3,
+    /// Unit normal of the actual surface, oriented like [`RayHit::geo_normal`].
+    pub geo_normal: DVec3,
+    pub material: &'a dyn MaterialErased,
+}
+
+/// Moves a ray origin `p` on a surface off of it to the side `dir` leaves towards, so that the
+/// new ray doesn't intersect the surface it starts on.
+///
+/// The offset scales with the magnitude of `p`, since that is what bounds the floating-point error
+/// in the computed hit position; a fixed epsilon is too small at planetary scale and too large for
+/// tiny meshes.
+pub fn offset_ray_origin(p: DVec3, geo_normal: DVec3, dir: DVec3) -> DVec3 {
+    let d = 1.0e-12 + 1.0e-9 * p.abs().max_element();
+    p + geo_normal * (d * geo_normal.dot(dir).signum())
+}
+
+/// Calls `f` with every hit along the ray within `max_t`, nearest first, until it breaks, and
+/// returns what it broke with. Each hit is found with `raycast`, casting again from just past the
+/// previous one, so this costs a full cast per hit.
+pub fn raycast_all<'a, B>(
+    origin: DVec3,
+    direction: DVec3,
+    max_t: f64,
+    mut raycast: impl FnMut(DVec3, DVec3, f64) -> Option<RayHit<'a>>,
+    mut f: impl FnMut(RayHit<'a>) -> ControlFlow<B>,
+) -> Option<B> {
+    let mut from = origin;
+    loop {
+        // measured from the offset origin rather than summed, so that the offsets don't add up
+        let skipped = (from - origin).dot(direction);
+        let mut hit = raycast(from, direction, max_t - skipped)?;
+        from = offset_ray_origin(from + hit.t * direction, hit.geo_normal, direction);
+        hit.t += skipped;
+        if let ControlFlow::Break(result) = f(hit) {
+            return Some(result);
+        }
+    }
 }
 
-impl<O: Object> Object for Arc<O> {
+impl<O: Object + ?Sized> Object for Arc<O> {
     fn bounds(&self) -> Bounds {
         O::bounds(self)
     }
@@ -28,8 +134,30 @@ impl<O: Object> Object for Arc<O> {
     fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit> {
         O::raycast(self, origin, direction, max_t)
     }
+
+    fn transformed_bounds(&self, transform: &DMat4) -> Bounds {
+        O::transformed_bounds(self, transform)
+    }
+
+    fn emissive(&self) -> bool {
+        O::emissive(self)
+    }
+
+    fn emissive_area(&self) -> f64 {
+        O::emissive_area(self)
+    }
+
+    fn sample_emissive(&self, random: DVec2) -> Option<SurfacePoint<'_>> {
+        O::sample_emissive(self, random)
+    }
+
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        O::problems(self, problems)
+    }
 }
 
+/// A sphere, with equirectangular texture coordinates: u runs once around the +Y axis starting
+/// from -X, and v from 0 at the top to 1 at the bottom.
 pub struct Sphere<M> {
     pub origin: DVec3,
     pub radius: f64,
@@ -76,6 +204,8 @@ impl<M: MaterialErased> Object for Sphere<M> {
             t,
             normal,
             geo_normal: normal,
+            tangent: None,
+            uv: Some(sphere_uv(normal)),
             material: &self.material,
         })
     }
@@ -86,8 +216,56 @@ impl<M: MaterialErased> Object for Sphere<M> {
             max: self.origin + self.radius,
         }
     }
+
+    /// The transformed sphere is an ellipsoid, which reaches as far along each axis as the length
+    /// of the corresponding row of the linear part times the radius.
+    fn transformed_bounds(&self, transform: &DMat4) -> Bounds {
+        let center = transform.transform_point3(self.origin);
+        let linear = DMat3::from_mat4(*transform);
+        let extent = DVec3::new(
+            linear.row(0).length(),
+            linear.row(1).length(),
+            linear.row(2).length(),
+        ) * self.radius;
+        Bounds {
+            min: center - extent,
+            max: center + extent,
+        }
+    }
+
+    fn emissive_area(&self) -> f64 {
+        match self.material.emissive() {
+            true => 4.0 * std::f64::consts::PI * self.radius * self.radius,
+            false => 0.0,
+        }
+    }
+
+    fn sample_emissive(&self, random: DVec2) -> Option<SurfacePoint<'_>> {
+        let normal = random::sphere(random);
+        Some(SurfacePoint {
+            pos: self.origin + normal * self.radius,
+            geo_normal: normal,
+            material: &self.material,
+        })
+    }
+
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        problems.extend(Problem::of_material(&self.material));
+    }
 }
 
+/// Texture coordinates of the point on a [`Sphere`] with outward unit normal `n`.
+fn sphere_uv(n: DVec3) -> DVec2 {
+    let u = n.z.atan2(-n.x) / (2.0 * std::f64::consts::PI);
+    DVec2::new(
+        u.rem_euclid(1.0),
+        n.y.clamp(-1.0, 1.0).acos() / std::f64::consts::PI,
+    )
+}
+
+/// A triangle whose geometric normal is `(b - a).cross(c - a)`, i.e. counter-clockwise winding
+/// faces the viewer. The vertex normals are interpolated for shading, as are the vertex tangents
+/// and texture coordinates if there are any, for `a`, `b`, `c` in that order.
 pub struct Triangle<M> {
     pub a: DVec3,
     pub b: DVec3,
@@ -95,12 +273,52 @@ pub struct Triangle<M> {
     pub a_n: DVec3,
     pub b_n: DVec3,
     pub c_n: DVec3,
+    pub tangents: Option<[DVec3; 3]>,
+    pub uvs: Option<[DVec2; 3]>,
     pub material: M,
 }
 
-impl<M: MaterialErased> Object for Triangle<M> {
-    fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit> {
-        let n = (self.c - self.b).cross(self.a - self.b);
+impl<M> Triangle<M> {
+    /// Interpolates the vertex normals at `barycentric`. Falls back to `geo_normal` if any vertex
+    /// normal disagrees with it about which side `direction` arrives from, since the ray would
+    /// then see the back of the shading surface, or if the interpolated normal is degenerate.
+    fn shading_normal(&self, barycentric: DVec3, geo_normal: DVec3, direction: DVec3) -> DVec3 {
+        let dots = DVec3::new(
+            self.a_n.dot(direction),
+            self.b_n.dot(direction),
+            self.c_n.dot(direction),
+        );
+        if dots.signum() != DVec3::splat(geo_normal.dot(direction).signum()) {
+            return geo_normal;
+        }
+        let interpolated =
+            self.a_n * barycentric.x + self.b_n * barycentric.y + self.c_n * barycentric.z;
+        interpolated.try_normalize().unwrap_or(geo_normal)
+    }
+
+    /// Interpolates the vertex tangents at `barycentric` and makes the result perpendicular to
+    /// `normal`. None if there are no vertex tangents or they cancel out.
+    fn shading_tangent(&self, barycentric: DVec3, normal: DVec3) -> Option<DVec3> {
+        let [a, b, c] = self.tangents?;
+        let interpolated = a * barycentric.x + b * barycentric.y + c * barycentric.z;
+        (interpolated - normal * normal.dot(interpolated)).try_normalize()
+    }
+
+    /// Interpolates the vertex texture coordinates at `barycentric`, if there are any.
+    fn shading_uv(&self, barycentric: DVec3) -> Option<DVec2> {
+        let [a, b, c] = self.uvs?;
+        Some(a * barycentric.x + b * barycentric.y + c * barycentric.z)
+    }
+
+    /// Returns the distance to the intersection, its barycentric coordinates with respect to
+    /// `a`, `b`, `c`, and the unit geometric normal.
+    fn intersect(
+        &self,
+        origin: DVec3,
+        direction: DVec3,
+        max_t: f64,
+    ) -> Option<(f64, DVec3, DVec3)> {
+        let n = (self.b - self.a).cross(self.c - self.a);
 
         if n.length_squared() == 0.0 {
             return None;
@@ -149,24 +367,21 @@ impl<M: MaterialErased> Object for Triangle<M> {
             return None;
         }
 
-        let n = n.normalize();
+        Some((t, e * scale, n.normalize()))
+    }
+}
 
-        let dots = DVec3::new(
-            self.a_n.dot(direction),
-            self.b_n.dot(direction),
-            self.c_n.dot(direction),
-        );
-        let n_dot = n.dot(direction);
-        let normal = if dots.signum() == DVec3::splat(n_dot.signum()) {
-            ((self.a_n * e_a + self.b_n * e_b + self.c_n * e_c) * scale).normalize()
-        } else {
-            n
-        };
+impl<M: MaterialErased> Object for Triangle<M> {
+    fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit<'_>> {
+        let (t, barycentric, geo_normal) = self.intersect(origin, direction, max_t)?;
+        let normal = self.shading_normal(barycentric, geo_normal, direction);
 
         Some(RayHit {
             t,
             normal,
-            geo_normal: n,
+            geo_normal,
+            tangent: self.shading_tangent(barycentric, normal),
+            uv: self.shading_uv(barycentric),
             material: &self.material,
         })
     }
@@ -177,35 +392,169 @@ impl<M: MaterialErased> Object for Triangle<M> {
             max: self.a.max(self.b).max(self.c),
         }
     }
+
+    fn transformed_bounds(&self, transform: &DMat4) -> Bounds {
+        [self.a, self.b, self.c]
+            .into_iter()
+            .map(|p| transform.transform_point3(p))
+            .collect()
+    }
+
+    fn emissive_area(&self) -> f64 {
+        match self.material.emissive() {
+            true => (self.b - self.a).cross(self.c - self.a).length() / 2.0,
+            false => 0.0,
+        }
+    }
+
+    fn sample_emissive(&self, random: DVec2) -> Option<SurfacePoint<'_>> {
+        // uniform barycentric coordinates by the square root warp
+        let s = random.x.sqrt();
+        let (u, v) = (1.0 - s, random.y * s);
+        Some(SurfacePoint {
+            pos: self.a * u + self.b * v + self.c * (1.0 - u - v),
+            geo_normal: (self.b - self.a).cross(self.c - self.a).try_normalize()?,
+            material: &self.material,
+        })
+    }
+
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        problems.extend(Problem::of_material(&self.material));
+        let corners = [self.a, self.b, self.c];
+        let area = (self.b - self.a).cross(self.c - self.a).length();
+        if !area.is_normal() {
+            problems.push(Problem::Degenerate(corners));
+        }
+    }
 }
 
+/// A parallelogram spanned by `u` and `v` from `origin`. The normal is `u.cross(v)`, and the
+/// texture coordinates run from 0 to 1 along `u` and `v`.
+pub struct Quad<M> {
+    pub origin: DVec3,
+    pub u: DVec3,
+    pub v: DVec3,
+    pub material: M,
+}
+
+impl<M: MaterialErased> Object for Quad<M> {
+    fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit<'_>> {
+        let n = self.u.cross(self.v);
+        let denom = n.dot(direction);
+        if denom == 0.0 {
+            return None;
+        }
+
+        let t = n.dot(self.origin - origin) / denom;
+        if t < 0.0 || t > max_t {
+            return None;
+        }
+
+        // solve p = a*u + b*v for the parallelogram coordinates
+        let p = origin + t * direction - self.origin;
+        let w = n / n.length_squared();
+        let a = w.dot(p.cross(self.v));
+        let b = w.dot(self.u.cross(p));
+        if !(0.0..=1.0).contains(&a) || !(0.0..=1.0).contains(&b) {
+            return None;
+        }
+
+        let normal = n.normalize();
+        Some(RayHit {
+            t,
+            normal,
+            geo_normal: normal,
+            tangent: None,
+            uv: Some(DVec2::new(a, b)),
+            material: &self.material,
+        })
+    }
+
+    fn bounds(&self) -> Bounds {
+        [
+            self.origin,
+            self.origin + self.u,
+            self.origin + self.v,
+            self.origin + self.u + self.v,
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn transformed_bounds(&self, transform: &DMat4) -> Bounds {
+        [
+            self.origin,
+            self.origin + self.u,
+            self.origin + self.v,
+            self.origin + self.u + self.v,
+        ]
+        .into_iter()
+        .map(|p| transform.transform_point3(p))
+        .collect()
+    }
+
+    fn emissive_area(&self) -> f64 {
+        match self.material.emissive() {
+            true => self.u.cross(self.v).length(),
+            false => 0.0,
+        }
+    }
+
+    fn sample_emissive(&self, random: DVec2) -> Option<SurfacePoint<'_>> {
+        Some(SurfacePoint {
+            pos: self.origin + random.x * self.u + random.y * self.v,
+            geo_normal: self.u.cross(self.v).try_normalize()?,
+            material: &self.material,
+        })
+    }
+
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        problems.extend(Problem::of_material(&self.material));
+        if !self.u.cross(self.v).length().is_normal() {
+            let corners = [self.origin, self.origin + self.u, self.origin + self.v];
+            problems.push(Problem::Degenerate(corners));
+        }
+    }
+}
+
+/// `obj` moved by an affine `transform`. Its bounds come from [`Object::transformed_bounds`],
+/// computed once up front since that can walk the whole object.
 pub struct Transform<O> {
     transform: DMat4,
     inverse: DMat4,
+    bounds: Bounds,
     obj: O,
 }
 
-impl<O> Transform<O> {
+impl<O: Object> Transform<O> {
     pub fn new(transform: DMat4, obj: O) -> Self {
         Transform {
             inverse: transform.inverse(),
+            bounds: obj.transformed_bounds(&transform),
             transform,
             obj,
         }
     }
+
+    /// The factor areas are scaled by, if the transform scales them all equally. Other affine
+    /// transforms stretch some parts of a surface more than others, so they don't keep a uniform
+    /// distribution of points uniform.
+    fn area_scale(&self) -> Option<f64> {
+        let linear = DMat3::from_mat4(self.transform);
+        let gram = linear.transpose() * linear;
+        let scale = gram.x_axis.x;
+        gram.abs_diff_eq(DMat3::from_diagonal(DVec3::splat(scale)), scale * 1.0e-9)
+            .then_some(scale)
+    }
 }
 
 impl<O: Object> Object for Transform<O> {
     fn bounds(&self) -> Bounds {
-        let obj_bounds = self.obj.bounds();
-        (0..8)
-            .map(|corner| {
-                let bvec = BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0);
-                let p = DVec3::select(bvec, obj_bounds.min, obj_bounds.max);
-                Bounds::point(self.transform.transform_point3(p))
-            })
-            .reduce(Bounds::union)
-            .unwrap()
+        self.bounds
+    }
+
+    fn transformed_bounds(&self, transform: &DMat4) -> Bounds {
+        self.obj.transformed_bounds(&(*transform * self.transform))
     }
 
     fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit> {
@@ -216,9 +565,93 @@ impl<O: Object> Object for Transform<O> {
             .map(|mut hit| {
                 hit.normal = self.transform.transform_vector3(hit.normal).normalize();
                 hit.geo_normal = self.transform.transform_vector3(hit.geo_normal).normalize();
+                hit.tangent = hit.tangent.and_then(|t| {
+                    let t = self.transform.transform_vector3(t);
+                    (t - hit.normal * hit.normal.dot(t)).try_normalize()
+                });
                 hit
             })
     }
+
+    fn emissive(&self) -> bool {
+        self.obj.emissive()
+    }
+
+    fn emissive_area(&self) -> f64 {
+        self.area_scale()
+            .map_or(0.0, |scale| scale * self.obj.emissive_area())
+    }
+
+    fn sample_emissive(&self, random: DVec2) -> Option<SurfacePoint<'_>> {
+        self.area_scale()?;
+        let point = self.obj.sample_emissive(random)?;
+        Some(SurfacePoint {
+            pos: self.transform.transform_point3(point.pos),
+            geo_normal: self
+                .transform
+                .transform_vector3(point.geo_normal)
+                .normalize(),
+            ..point
+        })
+    }
+
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        self.obj.problems(problems)
+    }
+}
+
+/// A transform that changes over time, given at keyframes and interpolated between them: scale
+/// and translation linearly, and rotation by slerp, which turns at a steady rate but always the
+/// short way around, so consecutive keyframes need to be less than half a turn apart. Before the
+/// first keyframe and after the last it holds still.
+#[derive(Clone, Debug)]
+pub struct TransformKeyframes {
+    keys: Vec<(f64, DMat4)>,
+}
+
+impl TransformKeyframes {
+    /// Keyframes at the given times, in any order. There has to be at least one.
+    pub fn new(mut keys: Vec<(f64, DMat4)>) -> Self {
+        assert!(!keys.is_empty(), "an animation needs at least one keyframe");
+        keys.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        TransformKeyframes { keys }
+    }
+
+    /// A rotation by `degrees` about `axis` through the origin, at a steady rate from time 0 to
+    /// time 1. There's a keyframe at least every quarter turn, so any angle works.
+    pub fn turntable(axis: DVec3, degrees: f64) -> Self {
+        let axis = axis.normalize();
+        let steps = (degrees.abs() / 90.0).ceil().max(1.0) as usize;
+        TransformKeyframes::new(
+            (0..=steps)
+                .map(|i| {
+                    let t = i as f64 / steps as f64;
+                    (t, DMat4::from_axis_angle(axis, (degrees * t).to_radians()))
+                })
+                .collect(),
+        )
+    }
+
+    /// The transform at `time`, for a [`Transform`] of that moment.
+    pub fn at(&self, time: f64) -> DMat4 {
+        let i = self.keys.partition_point(|&(t, _)| t <= time);
+        if i == 0 {
+            return self.keys[0].1;
+        }
+        if i == self.keys.len() {
+            return self.keys[i - 1].1;
+        }
+        let (t0, from) = self.keys[i - 1];
+        let (t1, to) = self.keys[i];
+        let f = (time - t0) / (t1 - t0);
+        let (scale0, rotation0, translation0) = from.to_scale_rotation_translation();
+        let (scale1, rotation1, translation1) = to.to_scale_rotation_translation();
+        DMat4::from_scale_rotation_translation(
+            scale0.lerp(scale1, f),
+            rotation0.slerp(rotation1, f),
+            translation0.lerp(translation1, f),
+        )
+    }
 }
 
 pub struct SetMaterial<O, M> {
@@ -231,6 +664,10 @@ impl<O: Object, M: MaterialErased> Object for SetMaterial<O, M> {
         self.obj.bounds()
     }
 
+    fn transformed_bounds(&self, transform: &DMat4) -> Bounds {
+        self.obj.transformed_bounds(transform)
+    }
+
     fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit> {
         self.obj
             .raycast(origin, direction, max_t)
@@ -239,4 +676,258 @@ impl<O: Object, M: MaterialErased> Object for SetMaterial<O, M> {
                 ..hit
             })
     }
+
+    /// The material applies to the whole surface, but sampling that would need the area of the
+    /// object regardless of emission, so emissive `SetMaterial`s aren't sampled.
+    fn emissive(&self) -> bool {
+        self.material.emissive()
+    }
+
+    /// The geometry's problems, but only `material`'s rather than the ones it replaces.
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        let mut geometry = vec![];
+        self.obj.problems(&mut geometry);
+        geometry.retain(|p| *p != Problem::MissingMedium);
+        problems.extend(geometry);
+        problems.extend(Problem::of_material(&self.material));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use rand::prelude::*;
+
+    use glam::DVec4;
+
+    use super::*;
+    use crate::brdf::LambertianBrdf;
+    use crate::bvh::Bvh;
+    use crate::material::Material;
+    use crate::medium::{Medium, MediumStack, TestMedium};
+    use crate::spectrum::{self, ConstantSpectrum};
+
+    fn matte() -> Material<ConstantSpectrum, LambertianBrdf<ConstantSpectrum>, (), ()> {
+        Material {
+            emission: spectrum::ZERO,
+            brdf: LambertianBrdf {
+                albedo: ConstantSpectrum(0.5),
+            },
+            enter_medium: (),
+            exit_medium: (),
+        }
+    }
+
+    #[test]
+    fn offset_rays_leave_spheres_cleanly() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // the earth, and a sphere smaller than a fixed epsilon of 1e-6 would be
+        for radius in [6.37e6, 1.0e-7] {
+            let sphere = Sphere {
+                origin: DVec3::new(0.0, -radius, 0.0),
+                radius,
+                material: matte(),
+            };
+            for _ in 0..10_000 {
+                // look at a random point on the ground from up to a twentieth of the radius above
+                let up = random::sphere(rng.gen());
+                let ground = sphere.origin + up * radius;
+                let tilt = random::sphere(rng.gen()) * 0.5;
+                let height = radius * 10.0f64.powf(rng.gen_range(-6.0..-1.3));
+                let eye = ground + (up + tilt).normalize() * height;
+                let to_ground = (ground - eye).normalize();
+                let hit = sphere.raycast(eye, to_ground, f64::INFINITY).unwrap();
+                let p = eye + hit.t * to_ground;
+
+                let dir = random::sphere(rng.gen());
+                let cos = dir.dot(hit.geo_normal);
+                if cos.abs() < 0.01 {
+                    continue;
+                }
+                let origin = offset_ray_origin(p, hit.geo_normal, dir);
+                let next = sphere.raycast(origin, dir, f64::INFINITY);
+                match cos > 0.0 {
+                    // a shadow ray towards the sky mustn't hit the ground it starts on
+                    true => assert!(
+                        next.is_none(),
+                        "ray from {p} along {dir} hit at {}",
+                        next.unwrap().t
+                    ),
+                    // and one into the ground must stay inside, leaving at the far side
+                    false => {
+                        let next =
+                            next.unwrap_or_else(|| panic!("ray from {p} along {dir} escaped"));
+                        let chord = -2.0 * radius * cos;
+                        assert!(
+                            next.geo_normal.dot(dir) > 0.0 && next.t > chord / 2.0,
+                            "ray from {p} along {dir} hit at {}, expected about {chord}",
+                            next.t
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn triangles_are_entered_from_the_front() {
+        // media told apart by their absorption
+        let medium = |absorption| TestMedium {
+            absorption: ConstantSpectrum(absorption),
+            emission: spectrum::ZERO,
+            scattering: spectrum::ZERO,
+        };
+        let absorption = |medium: &dyn Medium| {
+            medium
+                .properties(DVec3::ZERO, DVec3::Z, DVec4::splat(550.0))
+                .absorption
+                .x
+        };
+        let (inside, outside) = (medium(1.0), medium(2.0));
+
+        // wound to face +Z, with vertex normals that agree and that don't
+        for vertex_normal in [DVec3::new(0.3, 0.0, 1.0).normalize(), -DVec3::Z] {
+            let triangle = Triangle {
+                a: DVec3::ZERO,
+                b: DVec3::X * 2.0,
+                c: DVec3::Y * 2.0,
+                a_n: vertex_normal,
+                b_n: vertex_normal,
+                c_n: vertex_normal,
+                tangents: None,
+                uvs: None,
+                material: Material {
+                    emission: spectrum::ZERO,
+                    brdf: (),
+                    enter_medium: inside.clone(),
+                    exit_medium: outside.clone(),
+                },
+            };
+            for (side, dir) in [(1.0, -DVec3::Z), (-1.0, DVec3::Z)] {
+                let origin = DVec3::new(0.5, 0.5, side);
+                let hit = triangle.raycast(origin, dir, f64::INFINITY).unwrap();
+                assert_eq!(hit.geo_normal, DVec3::Z);
+                assert!((hit.t - 1.0).abs() < 1e-12);
+
+                // from the front the ray goes from outside to inside, and from the back the other
+                // way, whichever way the shading normal faces
+                let (from, to) = match side > 0.0 {
+                    true => (&outside, &inside),
+                    false => (&inside, &outside),
+                };
+                let mut media = MediumStack::new(from);
+                media.transmit(hit.material, hit.geo_normal, dir);
+                assert_eq!(absorption(media.current()), absorption(to));
+            }
+        }
+    }
+
+    #[test]
+    fn rotated_box_bounds_hug_the_box() {
+        let face = |origin: DVec3, u: DVec3, v: DVec3| -> Arc<dyn Object> {
+            Arc::new(Quad {
+                origin,
+                u,
+                v,
+                material: matte(),
+            })
+        };
+        let unit_box = || {
+            Bvh::build(vec![
+                face(DVec3::ZERO, DVec3::Y, DVec3::X),
+                face(DVec3::ZERO, DVec3::Z, DVec3::Y),
+                face(DVec3::ZERO, DVec3::X, DVec3::Z),
+                face(DVec3::ONE, -DVec3::X, -DVec3::Y),
+                face(DVec3::ONE, -DVec3::Y, -DVec3::Z),
+                face(DVec3::ONE, -DVec3::Z, -DVec3::X),
+            ])
+        };
+        let rotation = DMat4::from_rotation_y(45.0f64.to_radians());
+        let assert_bounds = |bounds: Bounds, min: DVec3, max: DVec3| {
+            assert!(
+                bounds.min.abs_diff_eq(min, 1e-8) && bounds.max.abs_diff_eq(max, 1e-8),
+                "bounds {bounds:?}, expected {min} to {max}"
+            );
+        };
+
+        let once = Transform::new(rotation, unit_box());
+        let half = 0.5f64.sqrt();
+        assert_bounds(
+            once.bounds(),
+            DVec3::new(0.0, 0.0, -half),
+            DVec3::new(2.0 * half, 1.0, half),
+        );
+
+        // turned a full 90 degrees in two steps, the box is back to being axis-aligned, but the
+        // corners of the first step's bounds stick out
+        let twice = Transform::new(rotation, once);
+        assert_bounds(
+            twice.bounds(),
+            DVec3::new(0.0, 0.0, -1.0),
+            DVec3::new(1.0, 1.0, 0.0),
+        );
+        let corners = twice.obj.bounds().transform(&rotation);
+        assert_bounds(
+            corners,
+            DVec3::new(-0.5, 0.0, -1.5),
+            DVec3::new(1.5, 1.0, 0.5),
+        );
+    }
+
+    #[test]
+    fn half_a_turntable_is_half_a_turn() {
+        let frames = 12;
+        let spin = TransformKeyframes::turntable(DVec3::Y, 360.0);
+        let frame = |i: usize| spin.at(i as f64 / frames as f64);
+        assert!(frame(0).abs_diff_eq(DMat4::IDENTITY, 1e-12));
+        assert!(frame(frames / 2).abs_diff_eq(DMat4::from_rotation_y(PI), 1e-12));
+
+        // an off-center sphere and a tilted quad, seen from above
+        let scene = |i| -> Vec<Arc<dyn Object>> {
+            vec![
+                Arc::new(Transform::new(
+                    frame(i),
+                    Sphere {
+                        origin: DVec3::new(1.5, 0.0, 0.5),
+                        radius: 0.6,
+                        material: matte(),
+                    },
+                )),
+                Arc::new(Transform::new(
+                    frame(i),
+                    Quad {
+                        origin: DVec3::new(-2.0, -1.0, -1.0),
+                        u: DVec3::new(0.0, 0.5, 2.0),
+                        v: DVec3::X,
+                        material: matte(),
+                    },
+                )),
+            ]
+        };
+        let depth = |objects: &[Arc<dyn Object>], x: f64, z: f64| {
+            objects
+                .iter()
+                .filter_map(|obj| obj.raycast(DVec3::new(x, 5.0, z), -DVec3::Y, f64::INFINITY))
+                .map(|hit| hit.t)
+                .reduce(f64::min)
+        };
+        let (start, halfway) = (scene(0), scene(frames / 2));
+        let mut hits = 0;
+        for i in 0..40 {
+            for j in 0..40 {
+                let (x, z) = (i as f64 * 0.15 - 2.93, j as f64 * 0.15 - 2.93);
+                let expected = depth(&start, -x, -z);
+                let actual = depth(&halfway, x, z);
+                hits += expected.is_some() as usize;
+                match (actual, expected) {
+                    (Some(a), Some(e)) => assert!((a - e).abs() < 1e-9, "({x}, {z}): {a}, {e}"),
+                    _ => assert_eq!(actual, expected, "({x}, {z})"),
+                }
+            }
+        }
+        assert!(hits > 100, "only {hits} rays hit anything");
+        // and half a turn doesn't look the same as no turn at all
+        assert_ne!(depth(&start, 1.5, 0.5), depth(&halfway, 1.5, 0.5));
+    }
 }
@@ -0,0 +1,251 @@
+use std::path::Path;
+
+use glam::{DMat4, DVec2, DVec3};
+
+use crate::material::MaterialErased;
+use crate::texture::{Filter, Texture, Wrap};
+use crate::{Bounds, Error};
+
+use super::{Object, Problem, RayHit, SurfacePoint, Triangle};
+
+/// A perturbation of the shading normal over a surface's texture coordinates.
+#[allow(unused)]
+pub trait NormalMap: Send + Sync {
+    /// Returns the normal at `uv` in tangent space, where +x follows increasing u, +y follows
+    /// increasing v, and +z is the unperturbed shading normal. The result need not be normalized.
+    fn normal(&self, uv: DVec2) -> DVec3;
+}
+
+impl<F: Fn(DVec2) -> DVec3 + Send + Sync> NormalMap for F {
+    fn normal(&self, uv: DVec2) -> DVec3 {
+        self(uv)
+    }
+}
+
+/// A normal map texture in the usual encoding, where each channel maps `[0, 1]` to `[-1, 1]`.
+/// Texture coordinates wrap around, with `(0, 0)` at the top left of the image.
+#[allow(unused)]
+pub struct ImageNormalMap {
+    texture: Texture<DVec3>,
+    /// Bilinear unless set otherwise.
+    pub filter: Filter,
+}
+
+#[allow(unused)]
+impl ImageNormalMap {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        use exr::prelude::*;
+
+        let image = read()
+            .no_deep_data()
+            .largest_resolution_level()
+            .rgb_channels(
+                |size, _| ImageNormalMap {
+                    texture: Texture {
+                        width: size.0,
+                        height: size.1,
+                        data: vec![DVec3::Z; size.0 * size.1],
+                        wrap: Wrap::Repeat,
+                    },
+                    filter: Filter::Bilinear,
+                },
+                |map, Vec2(x, y), (r, g, b): (f32, f32, f32)| {
+                    let texture = &mut map.texture;
+                    texture.data[x + y * texture.width] =
+                        DVec3::new(r as f64, g as f64, b as f64) * 2.0 - 1.0;
+                },
+            )
+            .first_valid_layer()
+            .all_attributes()
+            .from_file(path)?;
+        Ok(image.layer_data.channel_data.pixels)
+    }
+}
+
+impl NormalMap for ImageNormalMap {
+    fn normal(&self, uv: DVec2) -> DVec3 {
+        self.texture.lookup(uv, self.filter)
+    }
+}
+
+/// A [`Triangle`] with texture coordinates at its vertices and a normal map applied over them.
+///
+/// The tangent frame comes from how the texture coordinates are laid out over the triangle, so
+/// +x of the normal map follows increasing u. Only the shading normal is perturbed; the geometric
+/// normal, which decides ray offsets and medium transitions, stays that of the flat triangle.
+#[allow(unused)]
+pub struct NormalMappedTriangle<M, N> {
+    pub triangle: Triangle<M>,
+    pub a_uv: DVec2,
+    pub b_uv: DVec2,
+    pub c_uv: DVec2,
+    pub normal_map: N,
+}
+
+#[allow(unused)]
+impl<M, N: NormalMap> NormalMappedTriangle<M, N> {
+    /// Falls back to the unperturbed `normal` if the texture coordinates are degenerate, or if the
+    /// perturbed normal would face away from the geometric normal or show the ray the back of the
+    /// shading surface.
+    fn perturb(
+        &self,
+        normal: DVec3,
+        barycentric: DVec3,
+        geo_normal: DVec3,
+        direction: DVec3,
+    ) -> DVec3 {
+        let tri = &self.triangle;
+        let e1 = tri.b - tri.a;
+        let e2 = tri.c - tri.a;
+        let duv1 = self.b_uv - self.a_uv;
+        let duv2 = self.c_uv - self.a_uv;
+        let det = duv1.perp_dot(duv2);
+        if det == 0.0 {
+            return normal;
+        }
+        let dpdu = (e1 * duv2.y - e2 * duv1.y) / det;
+        let dpdv = (e2 * duv1.x - e1 * duv2.x) / det;
+
+        let Some(tangent) = (dpdu - normal * normal.dot(dpdu)).try_normalize() else {
+            return normal;
+        };
+        // mirrored texture coordinates flip the bitangent
+        let bitangent = normal.cross(tangent) * normal.cross(tangent).dot(dpdv).signum();
+
+        let m = self.normal_map.normal(self.uv(barycentric));
+        let Some(perturbed) = (tangent * m.x + bitangent * m.y + normal * m.z).try_normalize()
+        else {
+            return normal;
+        };
+
+        if perturbed.dot(geo_normal) * normal.dot(geo_normal) <= 0.0
+            || perturbed.dot(direction).signum() != geo_normal.dot(direction).signum()
+        {
+            return normal;
+        }
+        perturbed
+    }
+
+    fn uv(&self, barycentric: DVec3) -> DVec2 {
+        self.a_uv * barycentric.x + self.b_uv * barycentric.y + self.c_uv * barycentric.z
+    }
+}
+
+impl<M: MaterialErased, N: NormalMap> Object for NormalMappedTriangle<M, N> {
+    fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit<'_>> {
+        let (t, barycentric, geo_normal) = self.triangle.intersect(origin, direction, max_t)?;
+        let normal = self
+            .triangle
+            .shading_normal(barycentric, geo_normal, direction);
+        let normal = self.perturb(normal, barycentric, geo_normal, direction);
+
+        Some(RayHit {
+            t,
+            normal,
+            geo_normal,
+            tangent: self.triangle.shading_tangent(barycentric, normal),
+            uv: Some(self.uv(barycentric)),
+            material: &self.triangle.material,
+        })
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.triangle.bounds()
+    }
+
+    fn transformed_bounds(&self, transform: &DMat4) -> Bounds {
+        self.triangle.transformed_bounds(transform)
+    }
+
+    fn emissive_area(&self) -> f64 {
+        self.triangle.emissive_area()
+    }
+
+    fn sample_emissive(&self, random: DVec2) -> Option<SurfacePoint<'_>> {
+        self.triangle.sample_emissive(random)
+    }
+
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        self.triangle.problems(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brdf::LambertianBrdf;
+    use crate::material::Material;
+    use crate::spectrum::{self, ConstantSpectrum};
+
+    /// The unit square on the XZ plane facing +Y, as two triangles with u along +X and v along +Z,
+    /// bumped by a hemisphere filling it.
+    fn bumped_square() -> [impl Object; 2] {
+        let bump = |uv: DVec2| {
+            let p = uv * 2.0 - 1.0;
+            DVec3::new(p.x, p.y, (1.0 - p.length_squared()).max(0.0).sqrt())
+        };
+        let triangle = |[a, b, c]: [DVec2; 3]| NormalMappedTriangle {
+            triangle: Triangle {
+                a: DVec3::new(a.x, 0.0, a.y),
+                b: DVec3::new(b.x, 0.0, b.y),
+                c: DVec3::new(c.x, 0.0, c.y),
+                a_n: DVec3::Y,
+                b_n: DVec3::Y,
+                c_n: DVec3::Y,
+                tangents: None,
+                uvs: None,
+                material: Material {
+                    emission: spectrum::ZERO,
+                    brdf: LambertianBrdf {
+                        albedo: ConstantSpectrum(0.5),
+                    },
+                    enter_medium: (),
+                    exit_medium: (),
+                },
+            },
+            a_uv: a,
+            b_uv: b,
+            c_uv: c,
+            normal_map: bump,
+        };
+        [
+            triangle([DVec2::ZERO, DVec2::Y, DVec2::X]),
+            triangle([DVec2::ONE, DVec2::X, DVec2::Y]),
+        ]
+    }
+
+    #[test]
+    fn bumped_quad_shades_like_a_dome() {
+        let square = bumped_square();
+        let raycast = |origin, direction| {
+            square
+                .iter()
+                .find_map(|tri| tri.raycast(origin, direction, f64::INFINITY))
+                .unwrap()
+        };
+
+        // seen from above, the normals are those of a dome of radius 0.5 sitting on the square
+        for (x, z) in [(0.5, 0.5), (0.2, 0.5), (0.7, 0.3), (0.9, 0.6), (0.35, 0.8)] {
+            let hit = raycast(DVec3::new(x, 1.0, z), -DVec3::Y);
+            let p = DVec2::new(x, z) * 2.0 - 1.0;
+            let dome = DVec3::new(p.x, (1.0 - p.length_squared()).sqrt(), p.y);
+            assert_eq!(hit.geo_normal, DVec3::Y);
+            assert!(
+                (hit.normal - dome).length() < 1e-9,
+                "normal {} at ({x}, {z}), expected {dome}",
+                hit.normal
+            );
+        }
+
+        // so a light overhead falls off towards the rim like on a real dome
+        let cosines = [0.5, 0.7, 0.9].map(|x| raycast(DVec3::new(x, 1.0, 0.5), -DVec3::Y).normal.y);
+        assert!(cosines[0] > cosines[1] && cosines[1] > cosines[2]);
+
+        // a grazing ray would see the back of the bump's far side, so it gets the flat normal
+        let grazing = DVec3::new(1.0, -0.05, 0.0).normalize();
+        let hit = raycast(DVec3::new(0.95, 0.0, 0.5) - grazing, grazing);
+        assert_eq!(hit.normal, DVec3::Y);
+        let hit = raycast(DVec3::new(0.05, 0.0, 0.5) - grazing, grazing);
+        assert!(hit.normal.x < -0.8);
+    }
+}
@@ -6,9 +6,9 @@ use std::sync::Arc;
 use glam::{BVec3, DVec3};
 
 use crate::material::MaterialErased;
-use crate::Bounds;
+use crate::{Bounds, Error};
 
-use super::{Object, RayHit};
+use super::{Object, Problem, RayHit};
 
 pub struct VoxelOctree {
     materials: Vec<Arc<dyn MaterialErased>>,
@@ -56,43 +56,61 @@ impl VoxelOctree {
         }
     }
 
-    pub fn load(path: impl AsRef<Path>, materials: Vec<Arc<dyn MaterialErased>>) -> Self {
-        let mut f = BufReader::new(File::open(path).unwrap());
-        let mut read_u32 = || -> Option<u32> {
+    pub fn load(
+        path: impl AsRef<Path>,
+        materials: Vec<Arc<dyn MaterialErased>>,
+    ) -> Result<Self, Error> {
+        let mut f = BufReader::new(File::open(path)?);
+        let mut read_u32 = || -> Result<Option<u32>, Error> {
             let mut u32_buf = [0; 4];
             match f.read_exact(&mut u32_buf) {
-                Ok(()) => Some(u32::from_le_bytes(u32_buf)),
+                Ok(()) => Ok(Some(u32::from_le_bytes(u32_buf))),
                 Err(e) => match e.kind() {
-                    ErrorKind::UnexpectedEof => None,
-                    _ => Err(e).unwrap(),
+                    ErrorKind::UnexpectedEof => Ok(None),
+                    _ => Err(e.into()),
                 },
             }
         };
 
-        let num_materials = read_u32().unwrap();
+        let num_materials = read_u32()?.ok_or(Error::voxel("missing material count"))?;
         if materials.len() < num_materials as usize {
-            panic!(
+            return Err(Error::voxel(format!(
                 "need {num_materials} materials, but only {} were specified",
                 materials.len()
-            );
+            )));
         }
 
-        let root = Node(read_u32().unwrap());
+        let root = Node(read_u32()?.ok_or(Error::voxel("missing root node"))?);
 
         let mut nodes = vec![];
-        while let Some(c1) = read_u32() {
+        while let Some(c1) = read_u32()? {
             let mut children = [Node(c1); 8];
             for i in 1..8 {
-                children[i] = Node(read_u32().unwrap());
+                children[i] = Node(read_u32()?.ok_or(Error::voxel("truncated node"))?);
             }
             nodes.push(children);
         }
 
-        VoxelOctree {
+        let check = |node: Node| match node.get() {
+            NodeKind::Empty => Ok(()),
+            NodeKind::Material(idx) if idx < materials.len() => Ok(()),
+            NodeKind::Internal(idx) if idx < nodes.len() => Ok(()),
+            kind => Err(Error::voxel(format!("{kind:?} out of range"))),
+        };
+        check(root)?;
+        nodes.iter().flatten().try_for_each(|&n| check(n))?;
+
+        Ok(VoxelOctree {
             materials,
             root,
             nodes,
-        }
+        })
+    }
+
+    /// Voxels with materials that don't scatter at their surface are traversed as a volume of
+    /// the material's `enter_medium`; rays get a hit both where they enter and leave the region.
+    fn is_volume(&self, idx: usize) -> bool {
+        self.materials[idx].brdf().is_none()
     }
 }
 
@@ -104,6 +122,11 @@ impl Object for VoxelOctree {
         }
     }
 
+    /// Voxel faces aren't sampled, so emissive voxels are only found by BRDF sampling.
+    fn emissive(&self) -> bool {
+        self.materials.iter().any(|m| m.emissive())
+    }
+
     fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit> {
         let flip = direction.cmplt(DVec3::ZERO);
         let d_sign = direction.signum();
@@ -126,9 +149,26 @@ impl Object for VoxelOctree {
 
         node_stack[1] = self.root;
 
+        // material of the volume voxels the ray is currently passing through
+        let mut inside = None;
+
         while height > 0 && t < max_t {
-            match node_stack[height].get() {
-                NodeKind::Empty => {
+            let kind = node_stack[height].get();
+            if let NodeKind::Material(idx) = kind {
+                if inside.is_none() && t == 0.0 && self.is_volume(idx) {
+                    // the ray starts inside this volume
+                    inside = Some(idx);
+                }
+            }
+
+            let same_region = match kind {
+                NodeKind::Empty => inside.is_none(),
+                NodeKind::Material(idx) => inside == Some(idx),
+                NodeKind::Internal(_) => false,
+            };
+
+            match kind {
+                _ if same_region => {
                     let exit_coord = offset_stack[height] + 2.0 * two_exp_minus_height - origin;
                     let t_exit = (exit_coord / direction).min_element();
 
@@ -136,12 +176,26 @@ impl Object for VoxelOctree {
                     height -= 1;
                     two_exp_minus_height *= 2.0;
                 }
+                NodeKind::Empty | NodeKind::Material(_) if inside.is_some() => {
+                    // leaving the volume, so the boundary faces along the ray
+                    return Some(RayHit {
+                        t,
+                        normal: DVec3::select(enter_dir, d_sign, DVec3::ZERO),
+                        geo_normal: DVec3::select(enter_dir, d_sign, DVec3::ZERO),
+                        tangent: None,
+                        uv: None,
+                        material: &*self.materials[inside.unwrap()],
+                    });
+                }
+                NodeKind::Empty => unreachable!(),
                 NodeKind::Material(idx) => {
                     assert_ne!(enter_dir, BVec3::FALSE, "{origin:?} {octree_enter:?}");
                     return Some(RayHit {
                         t,
                         normal: DVec3::select(enter_dir, -d_sign, DVec3::ZERO),
                         geo_normal: DVec3::select(enter_dir, -d_sign, DVec3::ZERO),
+                        tangent: None,
+                        uv: None,
                         material: &*self.materials[idx],
                     });
                 }
@@ -175,6 +229,143 @@ impl Object for VoxelOctree {
             }
         }
 
+        if let Some(idx) = inside {
+            if octree_exit <= max_t {
+                let exit_dir = ((1.0 - origin) / direction).cmpeq(DVec3::splat(octree_exit));
+                return Some(RayHit {
+                    t: octree_exit,
+                    normal: DVec3::select(exit_dir, d_sign, DVec3::ZERO),
+                    geo_normal: DVec3::select(exit_dir, d_sign, DVec3::ZERO),
+                    tangent: None,
+                    uv: None,
+                    material: &*self.materials[idx],
+                });
+            }
+        }
+
         None
     }
+
+    fn problems(&self, problems: &mut Vec<Problem>) {
+        for m in &self.materials {
+            problems.extend(Problem::of_material(&**m));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::DVec4;
+
+    use super::*;
+    use crate::material::Material;
+    use crate::medium::{Medium, MediumProperties, Vacuum};
+    use crate::random;
+    use crate::scene::Scene;
+    use crate::spectrum;
+
+    const SCATTERING: f64 = 1.5;
+
+    /// Homogeneous fog which only scatters, isotropically.
+    #[derive(Clone)]
+    struct Fog;
+
+    impl Medium for Fog {
+        fn majorant(&self, lambdas: DVec4) -> DVec4 {
+            _ = lambdas;
+            DVec4::splat(SCATTERING)
+        }
+
+        fn properties(&self, pos: DVec3, outgoing: DVec3, lambdas: DVec4) -> MediumProperties {
+            _ = (pos, outgoing, lambdas);
+            MediumProperties {
+                emission: DVec4::ZERO,
+                absorption: DVec4::ZERO,
+                scattering: DVec4::splat(SCATTERING),
+            }
+        }
+
+        fn phase(&self, pos: DVec3, incoming: DVec3, outgoing: DVec3, lambdas: DVec4) -> DVec4 {
+            _ = (pos, incoming, outgoing, lambdas);
+            DVec4::splat(1.0 / (4.0 * std::f64::consts::PI))
+        }
+    }
+
+    #[test]
+    fn fog_voxels_attenuate_like_a_slab() {
+        const SAMPLES: usize = 100_000;
+        // every octant filled with fog, so rays cross voxel boundaries inside the volume
+        let fog: Arc<dyn MaterialErased> = Arc::new(Material {
+            emission: spectrum::ZERO,
+            brdf: (),
+            enter_medium: Fog,
+            exit_medium: Vacuum,
+        });
+        let mut scene = Scene::new();
+        scene.add(VoxelOctree {
+            materials: vec![fog],
+            root: Node(0),
+            nodes: vec![[Node(1 << 31); 8]],
+        });
+
+        let lambdas = DVec4::new(400.0, 500.0, 600.0, 700.0);
+        let diagonal = DVec3::new(1.0, 1.0, 0.5).normalize();
+        let rays: [(DVec3, DVec3, &dyn Medium, f64); 3] = [
+            // straight through from outside, across the whole cube
+            (DVec3::new(-1.0, 0.3, 0.4), DVec3::X, &Vacuum, 1.0),
+            // in at a corner and out through the top
+            (
+                DVec3::new(0.0, 0.0, 0.25) - diagonal,
+                diagonal,
+                &Vacuum,
+                1.0 / diagonal.y,
+            ),
+            // from the middle of the fog, out through a side
+            (DVec3::splat(0.5), -DVec3::Z, &Fog, 0.5),
+        ];
+        random::reseed([2364, 0, 0]);
+        for (origin, direction, medium, length) in rays {
+            let mut transmittance = DVec4::ZERO;
+            for _ in 0..SAMPLES {
+                transmittance +=
+                    scene.transmittance(origin, direction, lambdas, medium, f64::INFINITY);
+            }
+            transmittance /= SAMPLES as f64;
+            let expected = (-SCATTERING * length).exp();
+            assert!(
+                (transmittance - expected).abs().max_element() < 0.03 * expected,
+                "transmittance from {origin} along {direction} is {transmittance}, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let words =
+            |words: &[u32]| -> Vec<u8> { words.iter().flat_map(|w| w.to_le_bytes()).collect() };
+        let bad = [
+            vec![],
+            b"garbage".to_vec(),
+            // more materials than were given, and no root
+            words(&[2, 1 << 31]),
+            words(&[1]),
+            // a node cut short, and references past the end of the materials or the nodes
+            [words(&[1, 0, 1 << 31, !0, 1 << 31]), vec![0; 2]].concat(),
+            words(&[1, 1 << 31 | 1]),
+            words(&[1, 0, 1, 1, 1, 1, 1, 1, 1, 1]),
+        ];
+        let path = std::env::temp_dir().join(format!("pbr-test-{}.dat", std::process::id()));
+        for bytes in bad {
+            std::fs::write(&path, &bytes).unwrap();
+            let material: Arc<dyn MaterialErased> = Arc::new(Material {
+                emission: spectrum::ZERO,
+                brdf: (),
+                enter_medium: Fog,
+                exit_medium: Vacuum,
+            });
+            let result = VoxelOctree::load(&path, vec![material]);
+            assert!(result.is_err(), "loaded {bytes:?}");
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
 }
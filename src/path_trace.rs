@@ -1,7 +1,9 @@
 use glam::{DVec3, DVec4, Vec4Swizzles};
 use rand::prelude::*;
 
-use crate::medium::Medium;
+use crate::medium::{Medium, MediumStack};
+use crate::objects::offset_ray_origin;
+use crate::random;
 use crate::scene::Scene;
 
 pub fn path_trace(
@@ -10,19 +12,22 @@ pub fn path_trace(
     dir: DVec3,
     lambdas: DVec4,
     camera_medium: &dyn Medium,
+    max_depth: Option<u32>,
 ) -> DVec4 {
     let mut throughput = DVec4::ONE;
     let mut radiance = DVec4::ZERO;
     let mut secondary_terminated = false;
     let mut pos = pos;
     let mut dir = dir;
-    let mut medium = camera_medium;
+    let mut media = MediumStack::new(camera_medium);
 
     let mut bounces = 0;
+    let mut depth = 0;
 
     let mut specular_bounce = true;
 
     'mainloop: while throughput != DVec4::ZERO {
+        let medium = media.current();
         let hit = scene.raycast(pos, dir, f64::INFINITY);
         let d = hit.as_ref().map_or(f64::INFINITY, |hit| hit.t);
 
@@ -45,7 +50,7 @@ pub fn path_trace(
             .max_element();
             let mut t = 0.0;
             loop {
-                let dt = -(1.0 - thread_rng().gen::<f64>()).ln() / majorant;
+                let dt = -(1.0 - random::rng().gen::<f64>()).ln() / majorant;
                 t += dt;
                 if t >= d {
                     break;
@@ -57,7 +62,7 @@ pub fn path_trace(
                 let pr_scattering = mp.scattering / majorant;
                 let pr_null = 1.0 - pr_absorption - pr_scattering;
 
-                let rng: f64 = thread_rng().gen();
+                let rng: f64 = random::rng().gen();
                 if rng < pr_absorption.x {
                     if specular_bounce {
                         radiance += throughput * scene.light_emission(pos, dir, lambdas, t);
@@ -72,10 +77,16 @@ pub fn path_trace(
                         radiance += throughput * scene.light_emission(pos, dir, lambdas, t);
                     }
 
+                    if max_depth.is_some_and(|max| depth >= max) {
+                        break 'mainloop;
+                    }
+                    depth += 1;
+
                     throughput *= pr_scattering / pr_scattering.x;
 
-                    if let Some((light, pdf)) = scene.sample_light(p, lambdas, thread_rng().gen()) {
-                        let sample = light.sample(p, lambdas, thread_rng().gen());
+                    if let Some((light, pdf, u)) = scene.sample_light(random::rng().gen()) {
+                        let sample =
+                            light.sample(p, lambdas, random::rng().gen::<DVec3>().with_x(u));
 
                         let tp_f = throughput
                             * medium.phase(p, sample.dir, dir, lambdas)
@@ -88,14 +99,17 @@ pub fn path_trace(
                                 sample.dir,
                                 lambdas,
                                 secondary_terminated,
-                                medium,
+                                media.clone(),
                                 sample.dist,
                             );
                             radiance += tp_f * transmittance / (pdf * sample.pdf);
                         }
                     }
 
-                    let new_dir = medium.sample_phase(p, dir, lambdas, thread_rng().gen());
+                    let new_dir = medium.sample_phase(p, dir, lambdas, random::rng().gen());
+                    if new_dir == DVec3::ZERO {
+                        break 'mainloop;
+                    }
                     let new_dir_pdf = medium.pdf_phase(p, new_dir, dir, lambdas);
 
                     throughput *= medium.phase(p, new_dir, dir, lambdas) / new_dir_pdf;
@@ -121,35 +135,44 @@ pub fn path_trace(
 
         let hit_pos = pos + dir * hit.t;
 
-        radiance += throughput * hit.material.emission_sample(lambdas);
-
         let old_dir = dir;
 
         if let Some(brdf) = hit.material.brdf() {
-            if let Some((light, pdf)) = scene.sample_light(hit_pos, lambdas, thread_rng().gen()) {
-                let sample = light.sample(pos, lambdas, thread_rng().gen());
+            if max_depth.is_some_and(|max| depth >= max) {
+                break;
+            }
+            depth += 1;
+
+            if let Some((light, pdf, u)) = scene.sample_light(random::rng().gen()) {
+                let sample = light.sample(hit_pos, lambdas, random::rng().gen::<DVec3>().with_x(u));
 
                 let tp_f = throughput
-                    * brdf.f(sample.dir, dir, hit.normal, lambdas)
+                    * brdf.f(sample.dir, dir, hit.normal, hit.tangent, hit.uv, lambdas)
                     * sample.emission
                     * sample.dir.dot(hit.normal).abs();
 
                 if tp_f != DVec4::ZERO {
-                    let offset = hit.geo_normal * (1e-6 * hit.geo_normal.dot(sample.dir).signum());
                     let transmittance = transmittance(
                         scene,
-                        hit_pos + offset,
+                        offset_ray_origin(hit_pos, hit.geo_normal, sample.dir),
                         sample.dir,
                         lambdas,
                         secondary_terminated,
-                        medium,
+                        media.clone(),
                         sample.dist,
                     );
                     radiance += tp_f * transmittance / (pdf * sample.pdf);
                 }
             }
 
-            let sample = brdf.sample(dir, hit.normal, lambdas, thread_rng().gen());
+            let sample = brdf.sample(
+                dir,
+                hit.normal,
+                hit.tangent,
+                hit.uv,
+                lambdas,
+                random::rng().gen(),
+            );
 
             if sample.dir == DVec3::ZERO {
                 break;
@@ -170,18 +193,14 @@ pub fn path_trace(
             specular_bounce = sample.singular;
         }
 
-        let offset = hit.geo_normal * (1e-6 * hit.geo_normal.dot(dir).signum());
-        pos = hit_pos + offset;
+        pos = offset_ray_origin(hit_pos, hit.geo_normal, dir);
 
         if old_dir.dot(hit.geo_normal).signum() == dir.dot(hit.geo_normal).signum() {
-            medium = match dir.dot(hit.geo_normal) > 0.0 {
-                true => hit.material.exit_medium(),
-                false => hit.material.enter_medium(),
-            };
+            media.transmit(hit.material, hit.geo_normal, dir);
         }
 
-        if throughput.max_element() < 1.0 {
-            if thread_rng().gen_bool(1.0 - throughput.max_element()) {
+        if throughput.max_element() < 1.0 && max_depth.is_none_or(|max| depth < max) {
+            if random::rng().gen_bool(1.0 - throughput.max_element()) {
                 break;
             } else {
                 throughput /= throughput.max_element();
@@ -191,7 +210,7 @@ pub fn path_trace(
         // unbiased substitute for max depth
         if bounces > 20 {
             bounces = 0;
-            if thread_rng().gen_bool(0.5) {
+            if random::rng().gen_bool(0.5) {
                 break;
             } else {
                 throughput *= 2.0;
@@ -210,11 +229,12 @@ pub fn transmittance<'a>(
     dir: DVec3,
     lambdas: DVec4,
     secondary_terminated: bool,
-    mut medium: &'a dyn Medium,
+    mut media: MediumStack<'a>,
     mut d: f64,
 ) -> DVec4 {
     let mut transmittance = DVec4::ONE;
     while d > 0.0 {
+        let medium = media.current();
         let Some(hit) = scene.raycast(pos, dir, d) else {
             if medium.participating() {
                 panic!("can't exit participating medium?");
@@ -234,7 +254,7 @@ pub fn transmittance<'a>(
             .max_element();
             let mut t = 0.0;
             loop {
-                let dt = -(1.0 - thread_rng().gen::<f64>()).ln() / majorant;
+                let dt = -(1.0 - random::rng().gen::<f64>()).ln() / majorant;
                 t += dt;
                 if t >= hit.t {
                     break;
@@ -249,13 +269,9 @@ pub fn transmittance<'a>(
         }
 
         d -= hit.t;
-        let offset = hit.geo_normal * (1e-6 * hit.geo_normal.dot(dir).signum());
-        pos += hit.t * dir + offset;
+        pos = offset_ray_origin(pos + hit.t * dir, hit.geo_normal, dir);
 
-        medium = match dir.dot(hit.geo_normal) > 0.0 {
-            true => hit.material.exit_medium(),
-            false => hit.material.enter_medium(),
-        };
+        media.transmit(hit.material, hit.geo_normal, dir);
     }
     transmittance
 }
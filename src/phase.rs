@@ -5,6 +5,7 @@ use glam::{DVec3, DVec4, Vec3Swizzles};
 pub trait Phase: Send + Sync {
     fn f(&self, incoming: DVec3, outgoing: DVec3, lambdas: DVec4) -> DVec4;
 
+    /// Samples an incoming direction, or returns [`DVec3::ZERO`] if `outgoing` is degenerate.
     fn sample(&self, outgoing: DVec3, lambdas: DVec4, random: DVec3) -> DVec3 {
         _ = (outgoing, lambdas);
         crate::random::sphere(random.xy())
@@ -16,6 +17,8 @@ pub trait Phase: Send + Sync {
     }
 }
 
+/// Draine's phase function, Henyey-Greenstein at `alpha = 0` and Cornette-Shanks at `alpha = 1`.
+/// Sampling needs `alpha > 0` and `g != 0`, where the inverted cdf has no division by zero.
 #[derive(Clone)]
 pub struct Draine {
     pub alpha: f64,
@@ -29,6 +32,9 @@ impl Phase for Draine {
 
     fn sample(&self, outgoing: DVec3, lambdas: DVec4, random: DVec3) -> DVec3 {
         _ = lambdas;
+        let Some((tangent, bitangent)) = crate::build_frame(outgoing) else {
+            return DVec3::ZERO;
+        };
         // Draine function sampling, see paper:
         // Supplemental: An Approximate Mie Scattering Function for Fog and Cloud Rendering
         // by Johannes Jendersie and Eugene d'Eon, of NVIDIA
@@ -52,7 +58,6 @@ impl Phase for Draine {
 
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
         let (y, x) = (random.y * 2.0 * PI).sin_cos();
-        let (tangent, bitangent) = outgoing.any_orthonormal_pair();
 
         cos_theta * outgoing + sin_theta * (y * tangent + x * bitangent)
     }
@@ -67,3 +72,71 @@ impl Phase for Draine {
         numerator / denominator
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::random::{self, tests::check_sampler};
+
+    const LAMBDAS: DVec4 = DVec4::new(450.0, 520.0, 600.0, 680.0);
+
+    /// Largest relative difference allowed between `f` with its directions swapped, which only
+    /// rounding should separate.
+    const RECIPROCITY_TOLERANCE: f64 = 1e-12;
+
+    /// Relies on the default, uniform sampling.
+    struct Isotropic;
+
+    impl Phase for Isotropic {
+        fn f(&self, incoming: DVec3, outgoing: DVec3, lambdas: DVec4) -> DVec4 {
+            DVec4::splat(self.pdf(incoming, outgoing, lambdas))
+        }
+    }
+
+    fn phases() -> Vec<(String, Box<dyn Phase>)> {
+        let mut phases: Vec<(String, Box<dyn Phase>)> =
+            vec![("isotropic".into(), Box::new(Isotropic))];
+        for (alpha, g) in [(0.1, 0.5), (1.0, 0.3), (0.5, -0.4), (0.2, 0.8)] {
+            phases.push((format!("draine {alpha} {g}"), Box::new(Draine { alpha, g })));
+        }
+        phases
+    }
+
+    #[test]
+    fn samples_follow_the_pdf() {
+        random::reseed([2346, 2, 0]);
+        let outgoing = DVec3::new(0.2, -0.5, 0.8).normalize();
+        for (name, phase) in phases() {
+            check_sampler(
+                &name,
+                outgoing,
+                false,
+                |incoming| phase.pdf(incoming, outgoing, LAMBDAS),
+                || {
+                    let incoming = phase.sample(outgoing, LAMBDAS, random::rng().gen());
+                    (incoming != DVec3::ZERO).then_some(incoming)
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn phases_are_reciprocal() {
+        random::reseed([2346, 3, 0]);
+        for (name, phase) in phases() {
+            for _ in 0..1000 {
+                let a = random::sphere(random::rng().gen());
+                let b = random::sphere(random::rng().gen());
+                let ab = phase.f(a, b, LAMBDAS);
+                let ba = phase.f(b, a, LAMBDAS);
+                let scale = ab.abs().max(ba.abs()).max(DVec4::ONE);
+                assert!(
+                    (ab - ba).abs().cmple(RECIPROCITY_TOLERANCE * scale).all(),
+                    "{name}: f({a}, {b}) = {ab} but f({b}, {a}) = {ba}"
+                );
+            }
+        }
+    }
+}
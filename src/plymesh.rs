@@ -1,15 +1,58 @@
-use std::io::{BufRead, BufReader, Error, Read};
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Read};
+use std::str::FromStr;
 use std::sync::Arc;
 
-use glam::{DVec3, Vec3};
+use glam::{DVec2, DVec3};
 
 use crate::material::MaterialErased;
 use crate::objects::{Object, Triangle};
-use crate::Bounds;
+use crate::{Bounds, Error};
+
+/// Which axis of a mesh file points up. Meshes are rotated so that it becomes +Y, the renderer's up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UpAxis {
+    X,
+    #[default]
+    Y,
+    /// Z up, as in Blender and most CAD tools; their -Y, forward, becomes +Z
+    Z,
+}
+
+/// How to bring the coordinates of a mesh file into the renderer's right-handed, Y-up ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MeshImport {
+    pub up: UpAxis,
+    /// The file is left handed, so it's mirrored front to back (Z negated after the up axis is
+    /// remapped), and the winding of its triangles reversed so that their normals still face out.
+    pub left_handed: bool,
+}
+
+impl MeshImport {
+    fn position(self, p: DVec3) -> DVec3 {
+        let p = match self.up {
+            UpAxis::X => DVec3::new(-p.y, p.x, p.z),
+            UpAxis::Y => p,
+            UpAxis::Z => DVec3::new(p.x, p.z, -p.y),
+        };
+        match self.left_handed {
+            true => p * DVec3::new(1.0, 1.0, -1.0),
+            false => p,
+        }
+    }
+
+    fn winding(self, [a, b, c]: [usize; 3]) -> [usize; 3] {
+        match self.left_handed {
+            true => [a, c, b],
+            false => [a, b, c],
+        }
+    }
+}
 
 pub fn load_plymesh<M: MaterialErased + Clone + 'static>(
     reader: impl Read,
     material: &M,
+    import: MeshImport,
 ) -> Result<(Vec<Arc<dyn Object>>, Bounds), Error> {
     let mut reader = LineReader {
         reader: BufReader::new(reader),
@@ -17,16 +60,16 @@ pub fn load_plymesh<M: MaterialErased + Clone + 'static>(
     };
 
     if reader.line()? != "ply" {
-        return Err(Error::other("not a ply file"));
+        return Err(Error::ply("not a ply file"));
     }
 
     let Some(("format", format)) = reader.line()?.split_once(' ') else {
-        return Err(Error::other("missing format"));
+        return Err(Error::ply("missing format"));
     };
 
     let format = match format {
         "ascii 1.0" => ParseFormat::Ascii,
-        _ => return Err(Error::other(format!("unsupported format `{format}`"))),
+        _ => return Err(Error::ply(format!("unsupported format `{format}`"))),
     };
 
     let mut elements = vec![];
@@ -35,63 +78,69 @@ pub fn load_plymesh<M: MaterialErased + Clone + 'static>(
         let line = reader.line()?;
         let mut tokens = line.split_ascii_whitespace();
 
-        match tokens.next().ok_or(Error::other("unexpected eof"))? {
+        match tokens.next().ok_or(Error::ply("unexpected eof"))? {
             "end_header" => break,
             "comment" => {}
             "element" => {
                 elements.push(Element {
                     name: tokens
                         .next()
-                        .ok_or(Error::other("element missing name"))?
+                        .ok_or(Error::ply("element missing name"))?
                         .to_owned(),
                     count: tokens
                         .next()
-                        .ok_or(Error::other("element missing count"))?
+                        .ok_or(Error::ply("element missing count"))?
                         .parse()
-                        .map_err(Error::other)?,
+                        .map_err(Error::ply)?,
                     props: vec![],
                 });
             }
             "property" => {
-                let ty = match tokens.next().ok_or(Error::other("property missing type"))? {
+                let ty = match tokens.next().ok_or(Error::ply("property missing type"))? {
                     "list" => tokens
                         .next()
                         .and_then(parse_prim_ty)
                         .zip(tokens.next().and_then(parse_prim_ty))
-                        .filter(|(lenty, _)| matches!(lenty, Prim::Uchar))
+                        .filter(|(lenty, _)| matches!(lenty, Prim::Uchar | Prim::Uint))
                         .map(|(a, b)| PropType::List(a, b))
-                        .ok_or(Error::other("invalid list type"))?,
+                        .ok_or(Error::ply("invalid list type"))?,
 
                     s => match parse_prim_ty(s) {
                         Some(p) => PropType::Prim(p),
-                        None => return Err(Error::other(format!("unknown property type: {s}"))),
+                        None => return Err(Error::ply(format!("unknown property type: {s}"))),
                     },
                 };
                 elements
                     .last_mut()
-                    .ok_or(Error::other("property without an element"))?
+                    .ok_or(Error::ply("property without an element"))?
                     .props
                     .push((
                         tokens
                             .next()
-                            .ok_or(Error::other("property missing name"))?
+                            .ok_or(Error::ply("property missing name"))?
                             .to_owned(),
                         ty,
                     ));
             }
-            s => return Err(Error::other(format!("unknown header: {s}"))),
+            s => return Err(Error::ply(format!("unknown header: {s}"))),
         }
     }
 
     let mut vertices = vec![];
+    let mut uvs = vec![];
     let mut triangles = vec![];
 
     for element in elements {
         for _ in 0..element.count {
             match format {
-                ParseFormat::Ascii => {
-                    parse_element_ascii(&mut reader, &element, &mut vertices, &mut triangles)?
-                }
+                ParseFormat::Ascii => parse_element_ascii(
+                    &mut reader,
+                    &element,
+                    import,
+                    &mut vertices,
+                    &mut uvs,
+                    &mut triangles,
+                )?,
             }
         }
     }
@@ -100,6 +149,9 @@ pub fn load_plymesh<M: MaterialErased + Clone + 'static>(
         *n = n.normalize();
     }
 
+    let has_uvs = uvs.len() == vertices.len();
+    let tangents = has_uvs.then(|| vertex_tangents(&vertices, &uvs, &triangles));
+
     let objects = triangles
         .into_iter()
         .map(|[a, b, c]| {
@@ -110,6 +162,8 @@ pub fn load_plymesh<M: MaterialErased + Clone + 'static>(
                 a_n: vertices[a].1,
                 b_n: vertices[b].1,
                 c_n: vertices[c].1,
+                tangents: tangents.as_ref().map(|t| [t[a], t[b], t[c]]),
+                uvs: has_uvs.then(|| [uvs[a], uvs[b], uvs[c]]),
                 material: material.clone(),
             }) as Arc<_>
         })
@@ -120,6 +174,31 @@ pub fn load_plymesh<M: MaterialErased + Clone + 'static>(
     Ok((objects, bounds))
 }
 
+/// Per-vertex directions of increasing u, summed over the faces around each vertex like the
+/// normals are, so that anisotropic materials line up with the texture layout.
+fn vertex_tangents(
+    vertices: &[(DVec3, DVec3)],
+    uvs: &[DVec2],
+    triangles: &[[usize; 3]],
+) -> Vec<DVec3> {
+    let mut tangents = vec![DVec3::ZERO; vertices.len()];
+    for &[a, b, c] in triangles {
+        let e1 = vertices[b].0 - vertices[a].0;
+        let e2 = vertices[c].0 - vertices[a].0;
+        let duv1 = uvs[b] - uvs[a];
+        let duv2 = uvs[c] - uvs[a];
+        let det = duv1.perp_dot(duv2);
+        if det == 0.0 {
+            continue;
+        }
+        let dpdu = (e1 * duv2.y - e2 * duv1.y) / det;
+        for i in [a, b, c] {
+            tangents[i] += dpdu;
+        }
+    }
+    tangents
+}
+
 struct LineReader<R> {
     reader: R,
     line: String,
@@ -139,9 +218,14 @@ enum ParseFormat {
 
 #[derive(Copy, Clone)]
 enum Prim {
+    Char,
     Uchar,
+    Short,
+    Ushort,
     Int,
+    Uint,
     Float,
+    Double,
 }
 
 #[derive(Copy, Clone)]
@@ -150,10 +234,10 @@ enum PropType {
     List(Prim, Prim),
 }
 
+/// Property values, widened from whichever scalar type the file declares.
 enum PropValue {
-    Uchar(u8),
-    Int(i32),
-    Float(f32),
+    Int(i64),
+    Float(f64),
     List(Vec<PropValue>),
 }
 
@@ -165,9 +249,14 @@ struct Element {
 
 fn parse_prim_ty(token: &str) -> Option<Prim> {
     Some(match token {
-        "float" => Prim::Float,
-        "uchar" => Prim::Uchar,
-        "int" => Prim::Int,
+        "char" | "int8" => Prim::Char,
+        "uchar" | "uint8" => Prim::Uchar,
+        "short" | "int16" => Prim::Short,
+        "ushort" | "uint16" => Prim::Ushort,
+        "int" | "int32" => Prim::Int,
+        "uint" | "uint32" => Prim::Uint,
+        "float" | "float32" => Prim::Float,
+        "double" | "float64" => Prim::Double,
         _ => return None,
     })
 }
@@ -175,35 +264,32 @@ fn parse_prim_ty(token: &str) -> Option<Prim> {
 fn parse_element_ascii<R: BufRead>(
     reader: &mut LineReader<R>,
     element: &Element,
+    import: MeshImport,
     vertices: &mut Vec<(DVec3, DVec3)>,
+    uvs: &mut Vec<DVec2>,
     triangles: &mut Vec<[usize; 3]>,
 ) -> Result<(), Error> {
     fn parse_prim<'a>(
         tokens: &mut impl Iterator<Item = &'a str>,
         ty: Prim,
     ) -> Result<PropValue, Error> {
+        fn parse<T: FromStr>(token: &str) -> Result<T, Error>
+        where
+            T::Err: Display,
+        {
+            token.parse().map_err(Error::ply)
+        }
+
+        let token = tokens.next().ok_or(Error::ply("missing property value"))?;
         Ok(match ty {
-            Prim::Uchar => PropValue::Uchar(
-                tokens
-                    .next()
-                    .ok_or(Error::other("missing property value"))?
-                    .parse()
-                    .map_err(Error::other)?,
-            ),
-            Prim::Int => PropValue::Int(
-                tokens
-                    .next()
-                    .ok_or(Error::other("missing property value"))?
-                    .parse()
-                    .map_err(Error::other)?,
-            ),
-            Prim::Float => PropValue::Float(
-                tokens
-                    .next()
-                    .ok_or(Error::other("missing property value"))?
-                    .parse()
-                    .map_err(Error::other)?,
-            ),
+            Prim::Char => PropValue::Int(parse::<i8>(token)?.into()),
+            Prim::Uchar => PropValue::Int(parse::<u8>(token)?.into()),
+            Prim::Short => PropValue::Int(parse::<i16>(token)?.into()),
+            Prim::Ushort => PropValue::Int(parse::<u16>(token)?.into()),
+            Prim::Int => PropValue::Int(parse::<i32>(token)?.into()),
+            Prim::Uint => PropValue::Int(parse::<u32>(token)?.into()),
+            Prim::Float => PropValue::Float(parse::<f32>(token)?.into()),
+            Prim::Double => PropValue::Float(parse(token)?),
         })
     }
 
@@ -214,7 +300,7 @@ fn parse_element_ascii<R: BufRead>(
         match ty {
             PropType::Prim(prim) => parse_prim(tokens, prim),
             PropType::List(len_ty, item_ty) => {
-                let PropValue::Uchar(len) = parse_prim(tokens, len_ty)? else {
+                let PropValue::Int(len) = parse_prim(tokens, len_ty)? else {
                     unreachable!()
                 };
                 Ok(PropValue::List(
@@ -231,6 +317,8 @@ fn parse_element_ascii<R: BufRead>(
     let mut x = None;
     let mut y = None;
     let mut z = None;
+    let mut u = None;
+    let mut v = None;
     let mut indices = None;
 
     'next_prop: for (name, ty) in &element.props {
@@ -239,6 +327,8 @@ fn parse_element_ascii<R: BufRead>(
             ("x", PropValue::Float(v)) => x = Some(v),
             ("y", PropValue::Float(v)) => y = Some(v),
             ("z", PropValue::Float(v)) => z = Some(v),
+            ("u" | "s" | "texture_u", PropValue::Float(value)) => u = Some(value),
+            ("v" | "t" | "texture_v", PropValue::Float(value)) => v = Some(value),
             ("vertex_indices", PropValue::List(idx)) => {
                 if idx.len() != 3 {
                     continue;
@@ -246,7 +336,7 @@ fn parse_element_ascii<R: BufRead>(
                 let mut i = [0; 3];
                 for (to, v) in i.iter_mut().zip(idx) {
                     *to = match v {
-                        PropValue::Int(v) => v.try_into().map_err(Error::other)?,
+                        PropValue::Int(v) => v.try_into().map_err(Error::ply)?,
                         _ => continue 'next_prop,
                     }
                 }
@@ -261,11 +351,19 @@ fn parse_element_ascii<R: BufRead>(
             let ((x, y), z) = x
                 .zip(y)
                 .zip(z)
-                .ok_or(Error::other("vertex does not have position"))?;
-            vertices.push((Vec3::new(x, y, z).as_dvec3(), DVec3::ZERO));
+                .ok_or(Error::ply("vertex does not have position"))?;
+            vertices.push((import.position(DVec3::new(x, y, z)), DVec3::ZERO));
+            if let Some((u, v)) = u.zip(v) {
+                // meshes put v = 0 at the bottom of the image, textures at the top
+                uvs.push(DVec2::new(u, 1.0 - v));
+            }
         }
         "face" => {
-            let is = indices.ok_or(Error::other("face does not have vertex indices"))?;
+            let is = indices.ok_or(Error::ply("face does not have vertex indices"))?;
+            if let Some(i) = is.into_iter().find(|&i| i >= vertices.len()) {
+                return Err(Error::ply(format!("vertex index {i} out of range")));
+            }
+            let is = import.winding(is);
             triangles.push(is);
             let n = (vertices[is[2]].0 - vertices[is[1]].0)
                 .cross(vertices[is[0]].0 - vertices[is[1]].0);
@@ -278,3 +376,186 @@ fn parse_element_ascii<R: BufRead>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::brdf::LambertianBrdf;
+    use crate::material::Material;
+    use crate::spectrum::{self, ConstantSpectrum};
+
+    fn load(ply: &str) -> Result<(Vec<Arc<dyn Object>>, Bounds), Error> {
+        let material = Material {
+            emission: spectrum::ZERO,
+            brdf: LambertianBrdf {
+                albedo: ConstantSpectrum(0.5),
+            },
+            enter_medium: (),
+            exit_medium: (),
+        };
+        load_plymesh(ply.as_bytes(), &material, MeshImport::default())
+    }
+
+    #[test]
+    fn loads_doubles_and_uints() {
+        // 0.1 isn't a float, so it only comes through exactly if read as a double
+        let (triangles, bounds) = load(
+            "ply\n\
+             format ascii 1.0\n\
+             element vertex 4\n\
+             property double x\n\
+             property float64 y\n\
+             property double z\n\
+             element face 2\n\
+             property list uint8 uint vertex_indices\n\
+             end_header\n\
+             0 0 0.1\n\
+             1 0 0.1\n\
+             1 1 0.1\n\
+             0 1 0.1\n\
+             3 0 1 2\n\
+             3 0 2 3\n",
+        )
+        .unwrap();
+        assert_eq!(triangles.len(), 2);
+        assert!(bounds.max.abs_diff_eq(DVec3::new(1.0, 1.0, 0.1), 1e-6));
+        for (x, y) in [(0.9, 0.1), (0.2, 0.7)] {
+            let hits: Vec<_> = triangles
+                .iter()
+                .filter_map(|t| t.raycast(DVec3::new(x, y, 1.0), -DVec3::Z, f64::INFINITY))
+                .collect();
+            assert_eq!(hits.len(), 1);
+            assert!((hits[0].t - 0.9).abs() < 1e-12, "hit at {}", hits[0].t);
+        }
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let header =
+            "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+                      property float z\nelement face 1\nproperty list uchar int vertex_indices\n\
+                      end_header\n";
+        let vertices = "0 0 0\n1 0 0\n0 1 0\n";
+        let bad = [
+            String::new(),
+            "\u{0}\u{1}garbage\u{ff}".into(),
+            "ply\nformat binary_little_endian 1.0\nend_header\n".into(),
+            "ply\nformat ascii 1.0\nelement vertex 3\nproperty quaternion x\n".into(),
+            // truncated in the header, and in the body
+            header[..header.len() / 2].into(),
+            format!("{header}0 0 0\n1 0"),
+            format!("{header}{vertices}"),
+            // values which don't parse, don't fit, or point at vertices that don't exist
+            format!("{header}0 0 zero\n1 0 0\n0 1 0\n3 0 1 2\n"),
+            format!("{header}{vertices}3 0 1 -2\n"),
+            format!("{header}{vertices}3 0 1 3\n"),
+            format!("{header}{vertices}300 0 1 2\n"),
+        ];
+        for ply in bad {
+            assert!(load(&ply).is_err(), "loaded {ply:?}");
+        }
+    }
+
+    #[test]
+    fn z_up_cubes_import_like_y_up_ones() {
+        crate::random::reseed([2425, 0, 0]);
+        // a unit cube away from the origin, so that swapped or negated axes move it
+        let corners: Vec<_> = (0..8)
+            .map(|i| {
+                DVec3::new(1.0, 3.0, 5.0)
+                    + glam::IVec3::new(i & 1, i >> 1 & 1, i >> 2 & 1).as_dvec3()
+            })
+            .collect();
+        // counterclockwise seen from outside
+        let faces = [
+            [0, 4, 6, 2],
+            [1, 3, 7, 5],
+            [0, 1, 5, 4],
+            [2, 6, 7, 3],
+            [0, 2, 3, 1],
+            [4, 5, 7, 6],
+        ];
+        let ply = |file_coords: fn(DVec3) -> DVec3, reversed: bool| {
+            let mut ply = "ply\nformat ascii 1.0\nelement vertex 8\nproperty float x\n\
+                           property float y\nproperty float z\nelement face 12\n\
+                           property list uchar int vertex_indices\nend_header\n"
+                .to_owned();
+            for &p in &corners {
+                let p = file_coords(p);
+                ply += &format!("{} {} {}\n", p.x, p.y, p.z);
+            }
+            for [a, b, c, d] in faces {
+                for [a, b, c] in [[a, b, c], [a, c, d]] {
+                    let [a, b, c] = match reversed {
+                        true => [a, c, b],
+                        false => [a, b, c],
+                    };
+                    ply += &format!("3 {a} {b} {c}\n");
+                }
+            }
+            ply
+        };
+        let material = Material {
+            emission: spectrum::ZERO,
+            brdf: LambertianBrdf {
+                albedo: ConstantSpectrum(0.5),
+            },
+            enter_medium: (),
+            exit_medium: (),
+        };
+        let load = |ply: String, up, left_handed| {
+            let import = MeshImport { up, left_handed };
+            load_plymesh(ply.as_bytes(), &material, import).unwrap()
+        };
+
+        let y_up = load(ply(|p| p, false), UpAxis::Y, false);
+        // Blender's axes: Z up and Y forward, which is the renderer's -Z
+        let z_up = load(ply(|p| DVec3::new(p.x, -p.z, p.y), false), UpAxis::Z, false);
+        // and the mirror image of those, wound the other way
+        let left = load(ply(|p| DVec3::new(p.x, p.z, p.y), true), UpAxis::Z, true);
+
+        let center = DVec3::new(1.5, 3.5, 5.5);
+        for (name, (triangles, bounds)) in [("z up", z_up), ("left handed z up", left)] {
+            assert!(
+                bounds.min.abs_diff_eq(y_up.1.min, 1e-6),
+                "{name}: {:?}",
+                bounds.min
+            );
+            assert!(
+                bounds.max.abs_diff_eq(y_up.1.max, 1e-6),
+                "{name}: {:?}",
+                bounds.max
+            );
+            for _ in 0..200 {
+                let origin = center + 10.0 * crate::random::sphere(crate::random::rng().gen());
+                let target = center + crate::random::rng().gen::<DVec3>() - 0.5;
+                let dir = (target - origin).normalize();
+                // the distance to and normal of the nearest hit
+                let hit = |triangles: &[Arc<dyn Object>]| {
+                    triangles
+                        .iter()
+                        .filter_map(|t| t.raycast(origin, dir, f64::INFINITY))
+                        .map(|hit| (hit.t, hit.geo_normal))
+                        .min_by(|a, b| a.0.total_cmp(&b.0))
+                        .unwrap()
+                };
+                let (expected, actual) = (hit(&y_up.0), hit(&triangles));
+                assert!(
+                    (actual.0 - expected.0).abs() < 1e-6,
+                    "{name}: hit at {}",
+                    actual.0
+                );
+                assert!(
+                    actual.1.abs_diff_eq(expected.1, 1e-6),
+                    "{name}: normal {}, expected {}",
+                    actual.1,
+                    expected.1
+                );
+                // the first face a ray from outside hits faces it
+                assert!(actual.1.dot(dir) < 0.0, "{name}: inward normal");
+            }
+        }
+    }
+}
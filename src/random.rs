@@ -1,7 +1,52 @@
+use std::cell::RefCell;
 use std::f64::consts::PI;
 
 use glam::{DVec2, DVec3};
 use ordered_float::OrderedFloat;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+/// The current thread's random number generator. Unlike `rand::thread_rng`, it can be made
+/// reproducible with [`reseed`].
+pub fn rng() -> LocalRng {
+    LocalRng
+}
+
+/// Makes the numbers [`rng`] gives on this thread from now on depend only on `key`. Different keys
+/// give independent streams.
+pub fn reseed(key: [u64; 3]) {
+    let mut seed = [0; 32];
+    for (bytes, k) in seed.chunks_exact_mut(8).zip(key) {
+        bytes.copy_from_slice(&k.to_le_bytes());
+    }
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::from_seed(seed));
+}
+
+/// Handle to the generator behind [`rng`].
+#[derive(Clone, Copy, Debug)]
+pub struct LocalRng;
+
+impl RngCore for LocalRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|rng| rng.borrow_mut().next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RNG.with(|rng| rng.borrow_mut().next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RNG.with(|rng| rng.borrow_mut().fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        RNG.with(|rng| rng.borrow_mut().try_fill_bytes(dest))
+    }
+}
 
 pub fn sphere(random: DVec2) -> DVec3 {
     let z = 2.0 * random.x - 1.0;
@@ -11,6 +56,20 @@ pub fn sphere(random: DVec2) -> DVec3 {
     DVec3::new(x * r, y * r, z)
 }
 
+/// Uniform over the directions within `acos(cos_max)` of +Z.
+pub fn cone(random: DVec2, cos_max: f64) -> DVec3 {
+    let z = cos_max + (1.0 - cos_max) * random.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let angle = 2.0 * PI * random.y;
+    let (y, x) = angle.sin_cos();
+    DVec3::new(x * r, y * r, z)
+}
+
+/// The solid angle density of [`cone`], for directions inside the cone.
+pub fn cone_pdf(cos_max: f64) -> f64 {
+    1.0 / (2.0 * PI * (1.0 - cos_max))
+}
+
 pub fn disk(random: DVec2) -> DVec2 {
     let r = random.x.sqrt();
     let angle = 2.0 * PI * random.y;
@@ -76,3 +135,148 @@ impl Tabulated1DFunction {
         x * (self.max_x - self.min_x) + self.min_x
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use glam::DMat3;
+    use rand::Rng;
+
+    use super::*;
+
+    /// How far the integral of a direction pdf over the sphere may be from 1. The integral is
+    /// estimated with one jittered point per cell of a fine grid, so it's good to well under this
+    /// unless the pdf has a spike narrower than a cell.
+    pub const PDF_INTEGRAL_TOLERANCE: f64 = 0.01;
+
+    /// How many standard deviations above its mean a chi-square statistic may be before the
+    /// sampled directions are taken not to follow the pdf. The tests are seeded, so a pass is
+    /// reproducible; this only has to be loose enough that the seed isn't hand-picked.
+    pub const CHI_SQUARE_SIGMAS: f64 = 5.0;
+
+    /// Number of directions drawn for each chi-square test.
+    const SAMPLES: usize = 50_000;
+
+    /// Bins of equal solid angle around `axis`: `BANDS` bands of even `cos(theta)`, each split
+    /// into `SECTORS` along `phi`.
+    const BANDS: usize = 16;
+    const SECTORS: usize = 32;
+
+    /// Jittered points per side of a bin when integrating the pdf over it.
+    const SUBDIVISIONS: usize = 16;
+
+    /// Checks a direction sampler against the pdf it claims to have. The pdf must integrate to 1
+    /// over the sphere, and `SAMPLES` directions from `sample` must pass a chi-square test binned
+    /// around `axis`. `sample` returns `None` when it gives up on a direction.
+    ///
+    /// With `hemisphere`, only directions on `axis`' side are expected back: samples anywhere else
+    /// are counted as given up, as is the pdf's mass there.
+    pub fn check_sampler(
+        name: &str,
+        axis: DVec3,
+        hemisphere: bool,
+        pdf: impl Fn(DVec3) -> f64,
+        mut sample: impl FnMut() -> Option<DVec3>,
+    ) {
+        let (x, y) = axis.any_orthonormal_pair();
+        let frame = DMat3::from_cols(x, y, axis);
+        let direction = |cos: f64, phi: f64| {
+            let sin = (1.0 - cos * cos).max(0.0).sqrt();
+            frame * DVec3::new(sin * phi.cos(), sin * phi.sin(), cos)
+        };
+        let bin = |d: DVec3| {
+            let d = frame.transpose() * d;
+            let band = ((d.z + 1.0) / 2.0 * BANDS as f64) as usize;
+            let sector = ((d.y.atan2(d.x) / (2.0 * PI)).rem_euclid(1.0) * SECTORS as f64) as usize;
+            band.min(BANDS - 1) * SECTORS + sector.min(SECTORS - 1)
+        };
+        let kept = |bin: usize| !hemisphere || bin >= BANDS / 2 * SECTORS;
+
+        let cell_area = 4.0 * PI / (BANDS * SECTORS * SUBDIVISIONS * SUBDIVISIONS) as f64;
+        let mut expected = vec![0.0; BANDS * SECTORS];
+        for (i, expected) in expected.iter_mut().enumerate() {
+            let (band, sector) = (i / SECTORS, i % SECTORS);
+            for j in 0..SUBDIVISIONS * SUBDIVISIONS {
+                let jitter: DVec2 = rng().gen();
+                let u = (band * SUBDIVISIONS + j / SUBDIVISIONS) as f64 + jitter.x;
+                let v = (sector * SUBDIVISIONS + j % SUBDIVISIONS) as f64 + jitter.y;
+                let cos = u / (BANDS * SUBDIVISIONS) as f64 * 2.0 - 1.0;
+                let phi = v / (SECTORS * SUBDIVISIONS) as f64 * 2.0 * PI;
+                *expected += pdf(direction(cos, phi)) * cell_area;
+            }
+        }
+        let integral: f64 = expected.iter().sum();
+        assert!(
+            (integral - 1.0).abs() < PDF_INTEGRAL_TOLERANCE,
+            "{name}: pdf integrates to {integral}"
+        );
+
+        let mut observed = vec![0.0; BANDS * SECTORS];
+        let mut given_up = 0.0;
+        for _ in 0..SAMPLES {
+            match sample().map(bin) {
+                Some(i) if kept(i) => observed[i] += 1.0,
+                _ => given_up += 1.0,
+            }
+        }
+        // taken from the pdf's mass outside the hemisphere rather than its shortfall from 1, which
+        // would be swamped by the error in the integral when little is given up
+        let mut expected_given_up = 0.0;
+        let mut pairs = vec![];
+        for (i, (o, e)) in observed.into_iter().zip(expected).enumerate() {
+            match kept(i) {
+                true => pairs.push((o, e * SAMPLES as f64)),
+                false => expected_given_up += e * SAMPLES as f64,
+            }
+        }
+        pairs.push((given_up, expected_given_up));
+
+        let (statistic, dof) = chi_square(&pairs);
+        let limit = dof as f64 + CHI_SQUARE_SIGMAS * (2.0 * dof as f64).sqrt();
+        assert!(
+            statistic < limit,
+            "{name}: chi-square {statistic} over {dof} degrees of freedom, above {limit}"
+        );
+    }
+
+    /// Pearson's chi-square statistic for `(observed, expected)` counts, with its degrees of
+    /// freedom. Bins expecting fewer than 5 counts are pooled into one, where the approximation
+    /// behind the test holds.
+    fn chi_square(pairs: &[(f64, f64)]) -> (f64, usize) {
+        let mut pooled = (0.0, 0.0);
+        let mut statistic = 0.0;
+        let mut bins = 0_usize;
+        for &(o, e) in pairs {
+            if e < 5.0 {
+                pooled = (pooled.0 + o, pooled.1 + e);
+                continue;
+            }
+            statistic += (o - e) * (o - e) / e;
+            bins += 1;
+        }
+        if pooled.1 > 0.0 {
+            statistic += (pooled.0 - pooled.1) * (pooled.0 - pooled.1) / pooled.1;
+            bins += 1;
+        } else {
+            assert_eq!(pooled.0, 0.0, "samples where the pdf is zero");
+        }
+        (statistic, bins.saturating_sub(1))
+    }
+
+    #[test]
+    fn cone_samples_stay_inside_and_follow_the_pdf() {
+        reseed([2401, 0, 0]);
+        // edges on band boundaries, so the integral of the pdf is exact up to the jitter
+        for cos_max in [0.5, -0.25] {
+            let pdf = |d: DVec3| match d.z >= cos_max {
+                true => cone_pdf(cos_max),
+                false => 0.0,
+            };
+            let sample = || {
+                let d = cone(rng().gen(), cos_max);
+                assert!(d.z >= cos_max, "{d} outside the cone of {cos_max}");
+                Some(d)
+            };
+            check_sampler(&format!("cone {cos_max}"), DVec3::Z, false, pdf, sample);
+        }
+    }
+}
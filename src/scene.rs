@@ -1,13 +1,77 @@
-use std::sync::Arc;
+use std::fmt;
+use std::ops::ControlFlow::{self, Break, Continue};
+use std::sync::{Arc, OnceLock};
 
-use glam::{DVec3, DVec4};
+use glam::{DMat4, DVec3, DVec4};
+use rand::prelude::*;
 
-use crate::light::{Light, LightSample};
-use crate::objects::{Object, RayHit};
+use crate::light::{Light, LightSample, ObjectLight};
+use crate::medium::{Medium, MediumStack};
+use crate::objects::{self, Object, Problem, RayHit, Transform};
+use crate::{random, stats, vol_trace, Bounds};
 
 pub struct Scene {
     objects: Vec<Arc<dyn Object>>,
     lights: Vec<Arc<dyn Light>>,
+    object_enabled: Vec<bool>,
+    /// Objects which [`Scene::transform_objects`] leaves where they are.
+    object_fixed: Vec<bool>,
+    /// The [`ObjectLight`] of each emissive object, which is enabled along with it.
+    object_lights: Vec<Option<Handle>>,
+    /// Indices of the enabled lights, which are the ones light sampling chooses between.
+    enabled_lights: Vec<usize>,
+    /// The cdf over [`enabled_lights`](Self::enabled_lights) of their [power](Light::power),
+    /// built on first use after any change to the scene.
+    light_cdf: OnceLock<Box<[f64]>>,
+    background: Option<Handle>,
+}
+
+/// A likely authoring mistake found by [`Scene::validate`]. Objects and lights are numbered in the
+/// order they were added, counting from zero, each separately.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SceneWarning {
+    /// The object has a material that rays pass into with no medium to enter, which panics the
+    /// first time a ray does.
+    MissingMedium { object: usize },
+    /// The object has `count` triangles or quads with no area, the first with these corners.
+    DegenerateTriangles {
+        object: usize,
+        count: usize,
+        first: [DVec3; 3],
+    },
+    /// The light emits nothing.
+    DarkLight { light: usize },
+}
+
+impl fmt::Display for SceneWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneWarning::MissingMedium { object } => write!(
+                f,
+                "object {object} has a transmissive material without an enter medium"
+            ),
+            SceneWarning::DegenerateTriangles {
+                object,
+                count,
+                first: [a, b, c],
+            } => write!(
+                f,
+                "object {object} has {count} degenerate triangles, the first at {a}, {b}, {c}"
+            ),
+            SceneWarning::DarkLight { light } => write!(f, "light {light} has zero emission"),
+        }
+    }
+}
+
+/// Refers to an object or light in the [`Scene`] it was added to, for toggling it with
+/// [`Scene::set_enabled`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Handle(HandleKind);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HandleKind {
+    Object(usize),
+    Light(usize),
 }
 
 impl Scene {
@@ -15,20 +79,164 @@ impl Scene {
         Scene {
             objects: vec![],
             lights: vec![],
+            object_enabled: vec![],
+            object_fixed: vec![],
+            object_lights: vec![],
+            enabled_lights: vec![],
+            light_cdf: OnceLock::new(),
+            background: None,
         }
     }
 
-    pub fn add<O: Object + 'static>(&mut self, obj: impl Into<Arc<O>>) {
-        self.objects.push(obj.into());
+    /// Adds an object to the scene. If it's [emissive](Object::emissive) it's also added as an
+    /// [`ObjectLight`], so that its emission is sampled and MIS weighted like any other light's.
+    pub fn add<O: Object + 'static>(&mut self, obj: impl Into<Arc<O>>) -> Handle {
+        let obj: Arc<O> = obj.into();
+        let light = match obj.emissive() {
+            true => Some(self.add_light(ObjectLight::new(obj.clone()))),
+            false => None,
+        };
+        self.objects.push(obj);
+        // the lights far away are weighted by the size of the scene
+        self.light_cdf.take();
+        self.object_enabled.push(true);
+        self.object_fixed.push(false);
+        self.object_lights.push(light);
+        Handle(HandleKind::Object(self.objects.len() - 1))
     }
 
-    pub fn add_light<L: Light + 'static>(&mut self, light: impl Into<Arc<L>>) {
+    pub fn add_light<L: Light + 'static>(&mut self, light: impl Into<Arc<L>>) -> Handle {
         self.lights.push(light.into());
+        self.enabled_lights.push(self.lights.len() - 1);
+        self.light_cdf.take();
+        Handle(HandleKind::Light(self.lights.len() - 1))
+    }
+
+    /// Adds a light to be seen by rays escaping the scene, usually a
+    /// [`GradientSkyLight`](crate::light::GradientSkyLight), and disables the one from any earlier
+    /// call. It's sampled and weighted like any other light.
+    pub fn set_background<L: Light + 'static>(&mut self, light: impl Into<Arc<L>>) -> Handle {
+        if let Some(old) = self.background {
+            self.set_enabled(old, false);
+        }
+        let handle = self.add_light(light);
+        self.background = Some(handle);
+        handle
+    }
+
+    /// Hides a disabled object from rays, or removes a disabled light from both light sampling and
+    /// the emission seen by escaping rays. Everything starts out enabled.
+    pub fn set_enabled(&mut self, handle: Handle, enabled: bool) {
+        self.light_cdf.take();
+        match handle.0 {
+            HandleKind::Object(i) => {
+                self.object_enabled[i] = enabled;
+                if let Some(light) = self.object_lights[i] {
+                    self.set_enabled(light, enabled);
+                }
+            }
+            HandleKind::Light(i) => {
+                self.enabled_lights.retain(|&l| l != i);
+                if enabled {
+                    self.enabled_lights.push(i);
+                    self.enabled_lights.sort_unstable();
+                }
+            }
+        }
+    }
+
+    /// Keeps an object where it is when the others are moved by
+    /// [`transform_objects`](Self::transform_objects), and out of their
+    /// [`movable_bounds`](Self::movable_bounds). This is for the ground, walls and the like, which
+    /// a turntable should leave in place. Lights that aren't objects never move anyway.
+    pub fn set_fixed(&mut self, handle: Handle) {
+        if let HandleKind::Object(i) = handle.0 {
+            self.object_fixed[i] = true;
+        }
+    }
+
+    /// Moves every object that isn't [fixed](Self::set_fixed) by `transform`, along with the light
+    /// of each emissive one. Lights that aren't objects, like the sun and the background, stay
+    /// where they are.
+    pub fn transform_objects(&mut self, transform: DMat4) {
+        self.light_cdf.take();
+        let objects = self.objects.iter_mut().zip(&self.object_lights);
+        for ((obj, light), _) in objects.zip(&self.object_fixed).filter(|(_, &fixed)| !fixed) {
+            *obj = Arc::new(Transform::new(transform, obj.clone()));
+            if let Some(Handle(HandleKind::Light(i))) = *light {
+                self.lights[i] = Arc::new(ObjectLight::new(obj.clone()));
+            }
+        }
+    }
+
+    /// Union of the bounds of the enabled objects, or `None` if there aren't any. Ground planes and
+    /// other large objects count like everything else.
+    pub fn bounds(&self) -> Option<Bounds> {
+        self.objects
+            .iter()
+            .zip(&self.object_enabled)
+            .filter(|(_, &enabled)| enabled)
+            .map(|(obj, _)| obj.bounds())
+            .reduce(Bounds::union)
+    }
+
+    /// Union of the bounds of the enabled objects that aren't [fixed](Self::set_fixed), or `None`
+    /// if there aren't any.
+    pub fn movable_bounds(&self) -> Option<Bounds> {
+        self.objects
+            .iter()
+            .zip(&self.object_enabled)
+            .zip(&self.object_fixed)
+            .filter(|((_, &enabled), &fixed)| enabled && !fixed)
+            .map(|((obj, _), _)| obj.bounds())
+            .reduce(Bounds::union)
+    }
+
+    /// The closest hit along the ray within `max_t`. Hits in the holes of
+    /// [cut out](crate::material::MaterialErased::cut_out) surfaces are skipped, by casting again
+    /// from just past them.
+    pub fn raycast(&self, origin: DVec3, direction: DVec3, max_t: f64) -> Option<RayHit<'_>> {
+        // the closest hit almost always counts, and going through `raycast_all` for it costs a
+        // noticeable share of the render time
+        let hit = self.closest_hit(origin, direction, max_t)?;
+        match hit.material.cut_out(hit.uv) {
+            true => self.raycast_all(origin, direction, max_t, Break),
+            false => Some(hit),
+        }
     }
 
-    pub fn raycast(&self, origin: DVec3, direction: DVec3, mut max_t: f64) -> Option<RayHit> {
+    /// Calls `f` with every hit along the ray within `max_t`, nearest first, until it breaks, and
+    /// returns what it broke with. Like [`raycast`](Self::raycast), this skips the holes of cut out
+    /// surfaces.
+    pub fn raycast_all<'a, B>(
+        &'a self,
+        origin: DVec3,
+        direction: DVec3,
+        max_t: f64,
+        mut f: impl FnMut(RayHit<'a>) -> ControlFlow<B>,
+    ) -> Option<B> {
+        objects::raycast_all(
+            origin,
+            direction,
+            max_t,
+            |o, d, t| self.closest_hit(o, d, t),
+            |hit| match hit.material.cut_out(hit.uv) {
+                true => Continue(()),
+                false => f(hit),
+            },
+        )
+    }
+
+    fn closest_hit(&self, origin: DVec3, direction: DVec3, mut max_t: f64) -> Option<RayHit<'_>> {
+        stats::ray();
         let mut closest = None;
-        for obj in &self.objects {
+        for (obj, _) in self
+            .objects
+            .iter()
+            .zip(&self.object_enabled)
+            .filter(|(_, &enabled)| enabled)
+        {
+            stats::primitive_test();
             if let Some(hit) = obj.raycast(origin, direction, max_t) {
                 if hit.t < max_t - hit.normal.dot(direction) * 1.0e-12 {
                     max_t = hit.t;
@@ -36,30 +244,185 @@ impl Scene {
                 }
             }
         }
+        if closest.is_some() {
+            stats::hit();
+        }
         closest
     }
 
-    pub fn sample_light(
-        &self,
-        pos: DVec3,
-        lambdas: DVec4,
-        random: f64,
-    ) -> Option<(&dyn Light, f64)> {
-        if self.lights.is_empty() {
+    /// The medium at `point`, or `outside` if it isn't inside any transmissive object.
+    ///
+    /// Containment is decided by following a ray out of the scene from `point` and counting the
+    /// transmissive surfaces it enters and leaves. The first one it leaves without having entered
+    /// encloses `point`, like [`MediumStack`] leaving a surface it never saw the ray enter. This
+    /// needs the volumes to be closed and properly nested.
+    pub fn medium_at<'a>(&'a self, point: DVec3, outside: &'a dyn Medium) -> &'a dyn Medium {
+        // off any axis, so that it doesn't graze the edges of axis-aligned boxes
+        let dir = DVec3::new(0.3, 0.8, 0.5).normalize();
+        let mut entered = 0;
+        let enclosing = self.raycast_all(point, dir, f64::INFINITY, |hit| {
+            if !hit.material.transmissive() {
+                return Continue(());
+            }
+            if dir.dot(hit.geo_normal) < 0.0 {
+                entered += 1;
+            } else if entered > 0 {
+                entered -= 1;
+            } else {
+                return Break(hit.material.enter_medium());
+            }
+            Continue(())
+        });
+        enclosing.unwrap_or(outside)
+    }
+
+    /// Checks every object and light, enabled or not, for mistakes which are easy to make when
+    /// putting a scene together and either panic or silently do nothing during rendering.
+    pub fn validate(&self) -> Result<(), Vec<SceneWarning>> {
+        let mut warnings = vec![];
+        for (i, obj) in self.objects.iter().enumerate() {
+            let mut problems = vec![];
+            obj.problems(&mut problems);
+            if problems.contains(&Problem::MissingMedium) {
+                warnings.push(SceneWarning::MissingMedium { object: i });
+            }
+            let mut degenerate = problems.iter().filter_map(|p| match *p {
+                Problem::Degenerate(corners) => Some(corners),
+                _ => None,
+            });
+            if let Some(first) = degenerate.next() {
+                warnings.push(SceneWarning::DegenerateTriangles {
+                    object: i,
+                    count: 1 + degenerate.count(),
+                    first,
+                });
+            }
+        }
+        for (i, light) in self.lights.iter().enumerate() {
+            if light.is_dark() {
+                warnings.push(SceneWarning::DarkLight { light: i });
+            }
+        }
+        match warnings.is_empty() {
+            true => Ok(()),
+            false => Err(warnings),
+        }
+    }
+
+    /// Chooses an enabled light in proportion to its [power](Light::power), returning it with the
+    /// probability it had of being chosen. The position of `random` within the light's share of
+    /// `[0, 1)` is returned too, as a fresh uniform number for sampling the light, so that
+    /// stratified choices stay stratified within each light.
+    pub fn sample_light(&self, random: f64) -> Option<(&dyn Light, f64, f64)> {
+        let cdf = self.light_cdf();
+        if cdf.len() < 2 {
             return None;
         }
-        let i = random * self.lights.len() as f64;
-        Some((&*self.lights[i as usize], 1.0 / self.lights.len() as f64))
+        // lights with no share are never chosen, since they end where they start
+        let i = cdf[1..]
+            .partition_point(|&c| c <= random)
+            .min(cdf.len() - 2);
+        let pmf = cdf[i + 1] - cdf[i];
+        let remapped = ((random - cdf[i]) / pmf).clamp(0.0, 1.0 - f64::EPSILON / 2.0);
+        Some((&*self.lights[self.enabled_lights[i]], pmf, remapped))
     }
 
-    pub fn light_pmf(&self, pos: DVec3, lambdas: DVec4, light: &dyn Light) -> f64 {
-        1.0 / self.lights.len() as f64
+    /// The probability of [`sample_light`](Self::sample_light) choosing `light`, which is zero if
+    /// it's not one of the enabled lights.
+    pub fn light_pmf(&self, light: &dyn Light) -> f64 {
+        let cdf = self.light_cdf();
+        self.enabled_lights
+            .iter()
+            .position(|&i| std::ptr::addr_eq(&*self.lights[i], light))
+            .map_or(0.0, |i| cdf[i + 1] - cdf[i])
+    }
+
+    fn light_cdf(&self) -> &[f64] {
+        self.light_cdf.get_or_init(|| {
+            let scene_radius = self
+                .bounds()
+                .map_or(1.0, |b| (b.max - b.min).length() / 2.0);
+            let mut powers: Vec<_> = self
+                .enabled_lights
+                .iter()
+                .map(|&i| self.lights[i].power(scene_radius))
+                .collect();
+            // fall back to choosing uniformly when the estimates are no use
+            let total: f64 = powers.iter().sum();
+            if !(total > 0.0 && total.is_finite()) {
+                powers.fill(1.0);
+            }
+            let total: f64 = powers.iter().sum();
+            let mut cdf = vec![0.0; powers.len() + 1];
+            for (i, power) in powers.iter().enumerate() {
+                cdf[i + 1] = cdf[i] + power / total;
+            }
+            // so that rounding can't leave a gap at the top for `random` to land in
+            if let [.., _, last] = &mut cdf[..] {
+                *last = 1.0;
+            }
+            cdf.into_boxed_slice()
+        })
     }
 
+    /// The enabled lights.
     pub fn lights(&self) -> impl Iterator<Item = &dyn Light> {
-        self.lights.iter().map(|l| &**l)
+        self.enabled_lights.iter().map(|&i| &*self.lights[i])
     }
 
+    /// An unbiased estimate of the transmittance from `origin` to `origin + direction * max_t`,
+    /// starting in `medium` and switching media at transmissive surfaces. Zero if an opaque
+    /// surface is in the way.
+    ///
+    /// Like the path tracer, this samples collision distances for one hero wavelength, here picked
+    /// at random, and combines the wavelengths with the balance heuristic.
+    #[allow(unused)]
+    pub fn transmittance(
+        &self,
+        origin: DVec3,
+        direction: DVec3,
+        lambdas: DVec4,
+        medium: &dyn Medium,
+        max_t: f64,
+    ) -> DVec4 {
+        let hero = random::rng().gen_range(0..4);
+        let mut rotated = lambdas.to_array();
+        rotated.rotate_left(hero);
+        let (t_ray, _, r_l) = vol_trace::transmittance_with_path_pr(
+            self,
+            origin,
+            direction,
+            rotated.into(),
+            MediumStack::new(medium),
+            max_t,
+        );
+        let mut t = (t_ray / (r_l.element_sum() / 4.0)).to_array();
+        t.rotate_right(hero);
+        t.into()
+    }
+
+    /// An unbiased estimate of the optical depth along the same path as
+    /// [`transmittance`](Self::transmittance). Infinite if an opaque surface is in the way.
+    #[allow(unused)]
+    pub fn optical_depth(
+        &self,
+        origin: DVec3,
+        direction: DVec3,
+        lambdas: DVec4,
+        medium: &dyn Medium,
+        max_t: f64,
+    ) -> DVec4 {
+        vol_trace::optical_depth(
+            self,
+            origin,
+            direction,
+            lambdas,
+            MediumStack::new(medium),
+            max_t,
+        )
+    }
+
+    #[allow(unused)]
     pub fn light_emission(
         &self,
         pos: DVec3,
@@ -67,9 +430,526 @@ impl Scene {
         lambdas: DVec4,
         max_t: f64,
     ) -> DVec4 {
-        self.lights
-            .iter()
+        self.lights()
             .map(|l| l.emission(pos, direction, lambdas, max_t))
             .sum()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::brdf::{DielectricBrdf, LambertianBrdf};
+    use crate::light::SphereLight;
+    use crate::material::{CutoutMaterial, EmissiveMaterial, Material};
+    use crate::medium::{AtmosphereDryAir, ColoredGlassMedium, TestMedium, Vacuum};
+    use crate::objects::{Quad, Sphere};
+    use crate::spectrum::{self, ConstantSpectrum};
+    use crate::texture::{Filter, Texture, Wrap};
+
+    const PLANET_RADIUS: f64 = 6371000.0;
+    const ATMOSPHERE_HEIGHT: f64 = 50_000.0;
+
+    fn matte() -> Material<ConstantSpectrum, LambertianBrdf<ConstantSpectrum>, (), ()> {
+        Material {
+            emission: spectrum::ZERO,
+            brdf: LambertianBrdf {
+                albedo: ConstantSpectrum(0.5),
+            },
+            enter_medium: (),
+            exit_medium: (),
+        }
+    }
+
+    #[test]
+    fn transform_objects_leaves_fixed_objects() {
+        let mut scene = Scene::new();
+        let ground = scene.add(Quad {
+            origin: DVec3::new(-10.0, 0.0, -10.0),
+            u: DVec3::Z * 20.0,
+            v: DVec3::X * 20.0,
+            material: matte(),
+        });
+        scene.set_fixed(ground);
+        scene.add(Sphere {
+            origin: DVec3::new(3.0, 1.0, 0.0),
+            radius: 1.0,
+            material: matte(),
+        });
+
+        let bounds = scene.movable_bounds().unwrap();
+        assert_eq!(bounds.centroid(), DVec3::new(3.0, 1.0, 0.0));
+        assert!(scene.bounds().unwrap().min.x < -9.0);
+
+        scene.transform_objects(DMat4::from_translation(DVec3::Z * 5.0));
+        let down = |x: f64, z: f64| scene.raycast(DVec3::new(x, 5.0, z), -DVec3::Y, 100.0);
+        // the sphere moved out from above where it was, onto the ground that stayed put
+        assert_eq!(down(3.0, 0.0).map(|hit| hit.t), Some(5.0));
+        assert!((down(3.0, 5.0).unwrap().t - 3.0).abs() < 1e-9);
+        assert_eq!(down(-8.0, -8.0).map(|hit| hit.t), Some(5.0));
+    }
+
+    fn dry_air() -> AtmosphereDryAir {
+        AtmosphereDryAir {
+            origin: DVec3::new(0.0, -PLANET_RADIUS, 0.0),
+            sea_level: PLANET_RADIUS,
+            height_scale: 8000.0,
+            sea_level_air_density: 2.504e25,
+            ozone_start_altitude: 12_000.0,
+            ozone_peak_altitude: 32_000.0,
+            ozone_peak_concentration: 5e-6,
+            ozone_height_scale: 15_000.0,
+            min_ozone_concentration: 10e-9,
+        }
+    }
+
+    /// Optical depth from sea level straight up to `height`, by the midpoint rule.
+    fn integrated_optical_depth(medium: &dyn Medium, lambdas: DVec4, height: f64) -> DVec4 {
+        const STEPS: usize = 100_000;
+        let dt = height / STEPS as f64;
+        (0..STEPS)
+            .map(|i| {
+                let pos = DVec3::Y * (i as f64 + 0.5) * dt;
+                let mp = medium.properties(pos, DVec3::Y, lambdas);
+                (mp.absorption + mp.scattering) * dt
+            })
+            .sum()
+    }
+
+    #[test]
+    fn atmosphere_straight_up_matches_integration() {
+        const SAMPLES: usize = 100_000;
+        let air = dry_air();
+        // the top of the atmosphere, where rays leave it
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            origin: air.origin,
+            radius: PLANET_RADIUS + ATMOSPHERE_HEIGHT,
+            material: Material {
+                emission: spectrum::ZERO,
+                brdf: (),
+                enter_medium: air.clone(),
+                exit_medium: Vacuum,
+            },
+        });
+        let lambdas = DVec4::new(400.0, 500.0, 600.0, 700.0);
+        let expected = integrated_optical_depth(&air, lambdas, ATMOSPHERE_HEIGHT);
+        let (origin, up) = (DVec3::ZERO, DVec3::Y);
+
+        random::reseed([2384, 0, 0]);
+        let mut transmittance = DVec4::ZERO;
+        let mut optical_depth = DVec4::ZERO;
+        for _ in 0..SAMPLES {
+            transmittance += scene.transmittance(origin, up, lambdas, &air, f64::INFINITY);
+            optical_depth += scene.optical_depth(origin, up, lambdas, &air, f64::INFINITY);
+        }
+        transmittance /= SAMPLES as f64;
+        optical_depth /= SAMPLES as f64;
+
+        for i in 0..4 {
+            let expected_tr = (-expected[i]).exp();
+            assert!(
+                (transmittance[i] - expected_tr).abs() < 0.01 * expected_tr,
+                "transmittance at {} nm: {} vs {expected_tr}",
+                lambdas[i],
+                transmittance[i],
+            );
+            assert!(
+                (optical_depth[i] - expected[i]).abs() < 0.02 * expected[i],
+                "optical depth at {} nm: {} vs {}",
+                lambdas[i],
+                optical_depth[i],
+                expected[i],
+            );
+        }
+    }
+
+    #[test]
+    fn nested_spheres_return_to_the_outer_medium() {
+        random::reseed([2392, 0, 0]);
+        // a scattering core of radius 1 inside an absorbing shell of radius 2, both of which
+        // claim to be surrounded by vacuum
+        let shell = ColoredGlassMedium::from_transmission(DVec3::splat((-1.0f64).exp()), 1.0);
+        let core = TestMedium {
+            absorption: spectrum::ZERO,
+            emission: spectrum::ZERO,
+            scattering: ConstantSpectrum(0.5),
+        };
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            origin: DVec3::ZERO,
+            radius: 2.0,
+            material: Material {
+                emission: spectrum::ZERO,
+                brdf: (),
+                enter_medium: shell,
+                exit_medium: Vacuum,
+            },
+        });
+        scene.add(Sphere {
+            origin: DVec3::ZERO,
+            radius: 1.0,
+            material: Material {
+                emission: spectrum::ZERO,
+                brdf: (),
+                enter_medium: core,
+                exit_medium: Vacuum,
+            },
+        });
+
+        // walking along a diameter, the ray is in the shell, the core, the shell again and then
+        // back outside
+        let lambdas = DVec4::splat(550.0);
+        let mut media = MediumStack::new(&Vacuum);
+        let (mut pos, dir) = (DVec3::new(0.0, 0.0, -3.0), DVec3::Z);
+        let mut regions = vec![];
+        while let Some(hit) = scene.raycast(pos, dir, f64::INFINITY) {
+            media.transmit(hit.material, hit.geo_normal, dir);
+            pos += dir * hit.t;
+            // told apart at the center, where the core is at its densest
+            let inside = media.current().properties(DVec3::ZERO, dir, lambdas);
+            regions.push((pos.z, inside.absorption.x, inside.scattering.x));
+            pos += dir * 1e-9;
+        }
+        let expected = [
+            (-2.0, 1.0, 0.0),
+            (-1.0, 0.0, 0.5),
+            (1.0, 1.0, 0.0),
+            (2.0, 0.0, 0.0),
+        ];
+        assert_eq!(regions.len(), expected.len(), "regions {regions:?}");
+        for (region, expected) in regions.iter().zip(expected) {
+            assert!(
+                (region.0 - expected.0).abs() < 1e-6
+                    && (region.1 - expected.1).abs() < 1e-3
+                    && (region.2 - expected.2).abs() < 1e-3,
+                "regions {regions:?}"
+            );
+        }
+
+        // 2m through the shell, and the core's density falls off linearly to 0 at its surface
+        let mut optical_depth = 0.0;
+        for _ in 0..2000 {
+            let origin = DVec3::new(0.0, 0.0, -3.0);
+            optical_depth += scene.optical_depth(origin, dir, lambdas, &Vacuum, 6.0).x / 2000.0;
+        }
+        assert!(
+            (optical_depth - 2.5).abs() < 0.05,
+            "optical depth {optical_depth}"
+        );
+    }
+
+    /// A bright light and a dim one ten times weaker, the same size and 2m above either side of
+    /// the origin.
+    fn bright_and_dim_lights() -> (Scene, [Handle; 2]) {
+        let mut scene = Scene::new();
+        let handles = [(-1.0, 10.0), (1.0, 1.0)].map(|(x, emission)| {
+            scene.add_light(SphereLight {
+                center: DVec3::new(x, 2.0, 0.0),
+                radius: 0.2,
+                emission: ConstantSpectrum(emission),
+            })
+        });
+        (scene, handles)
+    }
+
+    #[test]
+    fn lights_are_chosen_in_proportion_to_their_power() {
+        let (mut scene, [bright, _]) = bright_and_dim_lights();
+        const N: usize = 1100;
+        let mut remapped = [vec![], vec![]];
+        for k in 0..N {
+            let (light, pmf, u) = scene.sample_light((k as f64 + 0.5) / N as f64).unwrap();
+            assert_eq!(pmf, scene.light_pmf(light));
+            let i = match (pmf - 10.0 / 11.0).abs() < 1e-12 {
+                true => 0,
+                false => {
+                    assert!((pmf - 1.0 / 11.0).abs() < 1e-12, "pmf {pmf}");
+                    1
+                }
+            };
+            remapped[i].push(u);
+        }
+        assert_eq!([remapped[0].len(), remapped[1].len()], [1000, 100]);
+        // evenly spaced choices stay evenly spaced within each light
+        for us in &remapped {
+            for (j, u) in us.iter().enumerate() {
+                let expected = (j as f64 + 0.5) / us.len() as f64;
+                assert!((u - expected).abs() < 1e-9, "{u}, expected {expected}");
+            }
+        }
+
+        scene.set_enabled(bright, false);
+        let (light, pmf, u) = scene.sample_light(0.25).unwrap();
+        assert_eq!((pmf, u), (1.0, 0.25));
+        assert_eq!(scene.light_pmf(light), 1.0);
+        let sample = light.sample(DVec3::ZERO, DVec4::splat(550.0), DVec3::splat(0.5));
+        assert_eq!(sample.emission, DVec4::ONE);
+    }
+
+    #[test]
+    fn power_weighted_choices_have_less_variance() {
+        random::reseed([2387, 0, 0]);
+        let (scene, _) = bright_and_dim_lights();
+        let lambdas = DVec4::splat(550.0);
+        // irradiance on a patch facing up at the origin, sampling the chosen light
+        let irradiance = |light: &dyn Light, pmf: f64, u: f64| {
+            let random = random::rng().gen::<DVec3>().with_x(u);
+            let sample = light.sample(DVec3::ZERO, lambdas, random);
+            (sample.emission * sample.dir.y.max(0.0) / (sample.pdf * pmf)).x
+        };
+        let lights: Vec<_> = scene.lights().collect();
+
+        const N: usize = 20_000;
+        let stats = |estimates: Vec<f64>| {
+            let mean = estimates.iter().sum::<f64>() / N as f64;
+            let variance = estimates.iter().map(|e| (e - mean).powi(2)).sum::<f64>();
+            (mean, variance / (N - 1) as f64)
+        };
+        let (weighted_mean, weighted) = stats(
+            (0..N)
+                .map(|_| {
+                    let (light, pmf, u) = scene.sample_light(random::rng().gen()).unwrap();
+                    irradiance(light, pmf, u)
+                })
+                .collect(),
+        );
+        let (uniform_mean, uniform) = stats(
+            (0..N)
+                .map(|_| {
+                    let u = random::rng().gen::<f64>() * 2.0;
+                    irradiance(lights[u as usize], 0.5, u.fract())
+                })
+                .collect(),
+        );
+        assert!(
+            (weighted_mean - uniform_mean).abs() < 0.02 * uniform_mean,
+            "means {weighted_mean} power weighted, {uniform_mean} uniform"
+        );
+        assert!(
+            weighted < 0.1 * uniform,
+            "variance {weighted} power weighted, {uniform} uniform"
+        );
+    }
+
+    #[test]
+    fn emissive_quads_light_the_same_by_any_strategy() {
+        random::reseed([2415, 0, 0]);
+        const N: usize = 100_000;
+        let lambdas = DVec4::new(450.0, 520.0, 600.0, 680.0);
+        let mut scene = Scene::new();
+        scene.add(Quad {
+            origin: DVec3::new(-50.0, 0.0, -50.0),
+            u: DVec3::Z * 100.0,
+            v: DVec3::X * 100.0,
+            material: matte(),
+        });
+        // a unit square glowing down from a height of 1
+        scene.add(Quad {
+            origin: DVec3::new(-0.5, 1.0, -0.5),
+            u: DVec3::X,
+            v: DVec3::Z,
+            material: EmissiveMaterial::new(ConstantSpectrum(2.0)),
+        });
+        let (a, b) = (0.5f64, 0.5f64);
+        let (sa, sb) = ((1.0 + a * a).sqrt(), (1.0 + b * b).sqrt());
+        let corner = (a / sa * (b / sa).atan() + b / sb * (a / sb).atan()) / (2.0 * PI);
+        let expected = PI * 2.0 * 4.0 * corner;
+
+        // just off the floor, so that rays from it don't hit it straight away
+        let pos = DVec3::Y * 1e-9;
+        let (light, pmf, _) = scene.sample_light(0.0).unwrap();
+        assert_eq!(pmf, 1.0);
+        let mut by_light = DVec4::ZERO;
+        let mut by_brdf = DVec4::ZERO;
+        for _ in 0..N {
+            let sample = light.sample(pos, lambdas, random::rng().gen());
+            if sample.pdf > 0.0 {
+                by_light += sample.emission * sample.dir.y.max(0.0) / sample.pdf;
+            }
+            // directions over the hemisphere find the quad by raycasting, as BRDF samples do
+            let dir = random::sphere(random::rng().gen());
+            let dir = dir.with_y(dir.y.abs());
+            let t = scene
+                .raycast(pos, dir, f64::INFINITY)
+                .map_or(f64::INFINITY, |hit| hit.t);
+            by_brdf += scene.light_emission(pos, dir, lambdas, t) * dir.y * 2.0 * PI;
+        }
+        for (name, e) in [("light", by_light), ("brdf", by_brdf)] {
+            let e = e / N as f64;
+            assert!(
+                (e - expected).abs().max_element() < 0.01 * expected,
+                "{e} by {name} sampling, expected {expected}"
+            );
+        }
+
+        // the integrator combines the two without counting the quad twice
+        let camera = DVec3::new(0.0, 0.5, -3.0);
+        let mut radiance = DVec4::ZERO;
+        for _ in 0..N {
+            let r =
+                vol_trace::path_trace(&scene, camera, -camera.normalize(), lambdas, &Vacuum, None);
+            radiance += r.emitted + r.diffuse + r.specular;
+        }
+        let radiance = radiance / N as f64;
+        let expected = 0.5 / PI * expected;
+        assert!(
+            (radiance - expected).abs().max_element() < 0.01 * expected,
+            "{radiance} off the floor, expected {expected}"
+        );
+    }
+
+    #[test]
+    fn cameras_start_in_the_medium_around_them() {
+        let fog = |scattering| Material {
+            emission: spectrum::ZERO,
+            brdf: (),
+            enter_medium: TestMedium {
+                absorption: spectrum::ZERO,
+                emission: spectrum::ZERO,
+                scattering: ConstantSpectrum(scattering),
+            },
+            exit_medium: Vacuum,
+        };
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            origin: DVec3::ZERO,
+            radius: 2.0,
+            material: fog(0.5),
+        });
+        scene.add(Sphere {
+            origin: DVec3::X * 0.5,
+            radius: 1.0,
+            material: fog(3.0),
+        });
+        // opaque things don't enclose anything
+        scene.add(Sphere {
+            origin: DVec3::new(10.0, 0.0, 0.0),
+            radius: 2.0,
+            material: matte(),
+        });
+        let outside = TestMedium {
+            absorption: spectrum::ZERO,
+            emission: spectrum::ZERO,
+            scattering: ConstantSpectrum(0.01),
+        };
+
+        let lambdas = DVec4::splat(550.0);
+        for (camera, expected) in [
+            (DVec3::new(0.0, 0.0, -5.0), 0.01),
+            (DVec3::new(0.0, 0.0, -1.5), 0.5),
+            (DVec3::new(-1.5, 1.0, 0.0), 0.5),
+            (DVec3::ZERO, 3.0),
+            (DVec3::new(1.2, -0.3, 0.2), 3.0),
+            (DVec3::new(10.0, 0.0, 0.0), 0.01),
+        ] {
+            let medium = scene.medium_at(camera, &outside);
+            // test media are densest at the origin, where they're told apart most easily
+            let scattering = medium.properties(DVec3::ZERO, DVec3::Z, lambdas).scattering;
+            assert_eq!(scattering, DVec4::splat(expected), "camera at {camera}");
+        }
+    }
+
+    #[test]
+    fn checker_cutouts_shadow_only_their_opaque_texels() {
+        let alpha = Arc::new(Texture {
+            width: 4,
+            height: 4,
+            data: (0..16)
+                .map(|i| match (i % 4 + i / 4) % 2 {
+                    0 => 1.0,
+                    _ => 0.0,
+                })
+                .collect(),
+            wrap: Wrap::Repeat,
+        });
+        // a 4x4m checkerboard of leaves 1m up, under a solid ceiling
+        let mut scene = Scene::new();
+        scene.add(Quad {
+            origin: DVec3::Y,
+            u: DVec3::Z * 4.0,
+            v: DVec3::X * 4.0,
+            material: CutoutMaterial {
+                material: matte(),
+                alpha,
+                filter: Filter::Nearest,
+                threshold: 0.5,
+            },
+        });
+        scene.add(Quad {
+            origin: DVec3::new(-10.0, 2.0, -10.0),
+            u: DVec3::Z * 20.0,
+            v: DVec3::X * 20.0,
+            material: matte(),
+        });
+
+        let lambdas = DVec4::splat(550.0);
+        for x in 0..4 {
+            for z in 0..4 {
+                let pos = DVec3::new(x as f64 + 0.5, 0.0, z as f64 + 0.5);
+                let opaque = (x + z) % 2 == 0;
+                let hit = scene.raycast(pos, DVec3::Y, f64::INFINITY).unwrap();
+                let expected_t = if opaque { 1.0 } else { 2.0 };
+                assert!(
+                    (hit.t - expected_t).abs() < 1e-6,
+                    "hit at {} above {pos}, expected {expected_t}",
+                    hit.t
+                );
+                let shadow = scene.transmittance(pos, DVec3::Y, lambdas, &Vacuum, 1.5);
+                let expected = if opaque { 0.0 } else { 1.0 };
+                assert_eq!(shadow, DVec4::splat(expected), "shadow at {pos}");
+            }
+        }
+    }
+
+    #[test]
+    fn dielectrics_without_an_enter_medium_are_flagged() {
+        let mut scene = Scene::new();
+        scene.add(Sphere {
+            origin: DVec3::ZERO,
+            radius: 1.0,
+            material: matte(),
+        });
+        scene.add(Sphere {
+            origin: DVec3::X * 3.0,
+            radius: 1.0,
+            material: Material {
+                emission: spectrum::ZERO,
+                brdf: DielectricBrdf {
+                    ior: ConstantSpectrum(1.5),
+                },
+                enter_medium: Vacuum,
+                exit_medium: Vacuum,
+            },
+        });
+        assert_eq!(scene.validate(), Ok(()));
+
+        // `()` as a medium is only for opaque surfaces, which nothing enters
+        scene.add(Sphere {
+            origin: DVec3::X * -3.0,
+            radius: 1.0,
+            material: Material {
+                emission: spectrum::ZERO,
+                brdf: DielectricBrdf {
+                    ior: ConstantSpectrum(1.5),
+                },
+                enter_medium: (),
+                exit_medium: (),
+            },
+        });
+        scene.add_light(SphereLight {
+            center: DVec3::Y * 5.0,
+            radius: 1.0,
+            emission: spectrum::ZERO,
+        });
+        assert_eq!(
+            scene.validate(),
+            Err(vec![
+                SceneWarning::MissingMedium { object: 2 },
+                SceneWarning::DarkLight { light: 0 },
+            ])
+        );
+    }
+}
@@ -1,25 +1,29 @@
 use std::f64::consts::PI;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
 
 use glam::{DMat3, DMat4, DQuat, DVec3, EulerRot};
+use rand::prelude::*;
 
 use crate::brdf::*;
 use crate::bvh::Bvh;
 use crate::light::DistantDiskLight;
-use crate::material::Material;
+use crate::material::{Material, MaterialErased};
 use crate::medium::{
     AtmosphereAerosols, AtmosphereDryAir, CombinedMedium, Medium, TestMedium, Vacuum,
 };
-use crate::objects::{SetMaterial, Sphere, Transform, Triangle, VoxelOctree};
+use crate::objects::{Object, Quad, SetMaterial, Sphere, Transform, Triangle, VoxelOctree};
 use crate::phase::Draine;
-use crate::scene::Scene;
+use crate::plymesh::MeshImport;
+use crate::scene::{Handle, Scene};
 use crate::spectrum::physical::extraterrestrial_solar_irradiance;
 use crate::spectrum::{AmplifiedSpectrum, ConstantSpectrum, PiecewiseLinearSpectrum, ReciprocalSpectrum};
-use crate::{material, plymesh, spectrum};
+use crate::{material, plymesh, spectrum, Error};
 
+/// The `models` scene. `import` applies to both of its meshes.
 #[allow(unused)]
-pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
+pub fn load(import: MeshImport) -> Result<(Scene, DVec3, DMat3, impl Medium), Error> {
     let atmosphere = TestMedium {
         absorption: spectrum::ZERO,
         emission: spectrum::ZERO,
@@ -29,7 +33,7 @@ pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
 
     let t = Instant::now();
     let (dragon, dragon_bounds) = plymesh::load_plymesh(
-        std::fs::File::open("models/dragon_vrip.ply").unwrap(),
+        std::fs::File::open("models/dragon_vrip.ply")?,
         &Material {
             emission: spectrum::ZERO,
             // brdf: LambertianBrdf {
@@ -42,10 +46,10 @@ pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
             enter_medium: Vacuum,
             exit_medium: atmosphere.clone(),
         },
-    )
-    .unwrap();
+        import,
+    )?;
     let (bunny, bunny_bounds) = plymesh::load_plymesh(
-        std::fs::File::open("models/bun_zipper.ply").unwrap(),
+        std::fs::File::open("models/bun_zipper.ply")?,
         &Material {
             emission: spectrum::ZERO,
             // brdf: SmoothConductorBrdf::new(material::physical::ior_gold()),
@@ -53,8 +57,8 @@ pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
             enter_medium: Vacuum,
             exit_medium: Vacuum,
         },
-    )
-    .unwrap();
+        import,
+    )?;
     // let voxel_world = VoxelOctree::load(
     //     "world.dat",
     //     vec![
@@ -91,13 +95,15 @@ pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
 
     let mut scene = Scene::new();
 
-    scene.add(Triangle {
+    let ground = scene.add(Triangle {
         a: DVec3::new(-10.0, 0.0, -10.0),
         b: DVec3::new(10.0, 0.0, 10.0),
         c: DVec3::new(10.0, 0.0, -10.0),
         a_n: DVec3::Y,
         b_n: DVec3::Y,
         c_n: DVec3::Y,
+        tangents: None,
+        uvs: None,
         material: Material {
             emission: spectrum::ZERO,
             // brdf: RoughConductorBrdf::new(material::physical::ior_silver(), 0.1),
@@ -108,13 +114,16 @@ pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
             exit_medium: Vacuum,
         },
     });
-    scene.add(Triangle {
+    scene.set_fixed(ground);
+    let ground = scene.add(Triangle {
         a: DVec3::new(10.0, 0.0, 10.0),
         b: DVec3::new(-10.0, 0.0, -10.0),
         c: DVec3::new(-10.0, 0.0, 10.0),
         a_n: DVec3::Y,
         b_n: DVec3::Y,
         c_n: DVec3::Y,
+        tangents: None,
+        uvs: None,
         material: Material {
             emission: spectrum::ZERO,
             // brdf: RoughConductorBrdf::new(material::physical::ior_silver(), 0.1),
@@ -125,13 +134,14 @@ pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
             exit_medium: Vacuum,
         },
     });
+    scene.set_fixed(ground);
 
     let t = Instant::now();
-    let dragon = Arc::new(Bvh::build(dragon));
-    let bunny = Arc::new(Bvh::build(bunny));
+    let dragon = Arc::new(cached_bvh(dragon, "models/dragon_vrip.ply", import));
+    let bunny = Arc::new(cached_bvh(bunny, "models/bun_zipper.ply", import));
     println!("Took {:.2?} to build BVH", t.elapsed());
 
-    scene.add(SetMaterial {
+    let gold_dragon: Arc<dyn Object> = Arc::new(SetMaterial {
         material: Material {
             emission: spectrum::ZERO,
             // brdf: Arc::new(LambertianBrdf {
@@ -145,16 +155,9 @@ pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
             enter_medium: Vacuum,
             exit_medium: Vacuum,
         },
-        obj: Transform::new(
-            DMat4::from_scale_rotation_translation(
-                DVec3::splat(1.5),
-                DQuat::from_axis_angle(DVec3::Y, -1.0),
-                DVec3::new(dragon_bounds.max.x - dragon_bounds.min.x, 0.0, 0.0) - 1.5 * cb_dragon,
-            ),
-            dragon.clone(),
-        ),
+        obj: dragon.clone(),
     });
-    scene.add(SetMaterial {
+    let copper_dragon: Arc<dyn Object> = Arc::new(SetMaterial {
         material: Material {
             emission: spectrum::ZERO,
             // brdf: Arc::new(LambertianBrdf {
@@ -165,25 +168,36 @@ pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
             enter_medium: Vacuum,
             exit_medium: Vacuum,
         },
-        obj: Transform::new(
+        obj: dragon.clone(),
+    });
+    // scene.add(Transform::new(DMat4::from_translation(-cb_dragon), dragon));
+    scene.add(Bvh::instanced([
+        (
+            DMat4::from_scale_rotation_translation(
+                DVec3::splat(1.5),
+                DQuat::from_axis_angle(DVec3::Y, -1.0),
+                DVec3::new(dragon_bounds.max.x - dragon_bounds.min.x, 0.0, 0.0) - 1.5 * cb_dragon,
+            ),
+            gold_dragon,
+        ),
+        (
             DMat4::from_scale_rotation_translation(
                 DVec3::splat(0.5),
                 DQuat::from_axis_angle(DVec3::Y, 1.0),
                 DVec3::new((dragon_bounds.min.x - dragon_bounds.max.x) * 0.75, 0.0, 0.0)
                     - 0.5 * cb_dragon,
             ),
-            dragon.clone(),
+            copper_dragon,
         ),
-    });
-    // scene.add(Transform::new(DMat4::from_translation(-cb_dragon), dragon));
-    scene.add(Transform::new(
-        DMat4::from_scale_rotation_translation(
-            DVec3::splat(2.0),
-            DQuat::IDENTITY,
-            DVec3::new(0.0, 0.0, dragon_bounds.min.x - dragon_bounds.max.x) - cb_bunny * 2.0,
+        (
+            DMat4::from_scale_rotation_translation(
+                DVec3::splat(2.0),
+                DQuat::IDENTITY,
+                DVec3::new(0.0, 0.0, dragon_bounds.min.x - dragon_bounds.max.x) - cb_bunny * 2.0,
+            ),
+            bunny as Arc<dyn Object>,
         ),
-        bunny,
-    ));
+    ]));
     scene.add(Sphere {
         origin: DVec3::new(
             -(dragon_bounds.max.x - dragon_bounds.min.x) * 0.7,
@@ -292,20 +306,21 @@ pub fn load() -> (Scene, DVec3, DMat3, impl Medium) {
     let looking = DMat3::from_euler(EulerRot::YXZ, PI-0.4, 0.4, 0.0) * DMat3::from_diagonal(DVec3::new(-1.0, 1.0, 1.0));
     let camera = scale * (looking * DVec3::new(0.0, 0.0, -2.0) + DVec3::new(0.0, 0.5, 0.0));
 
-    (scene, camera, looking, atmosphere)
+    Ok((scene, camera, looking, atmosphere))
 }
 
-#[allow(unused)]
 pub fn simple_volume_scene() -> (Scene, DVec3, DMat3, impl Medium) {
     let mut scene = Scene::new();
 
-    scene.add(Triangle {
+    let ground = scene.add(Triangle {
         a: DVec3::new(-100.0, -1.5, -100.0),
         b: DVec3::new(100.0, -1.5, 100.0),
         c: DVec3::new(100.0, -1.5, -100.0),
         a_n: DVec3::Y,
         b_n: DVec3::Y,
         c_n: DVec3::Y,
+        tangents: None,
+        uvs: None,
         material: Material {
             emission: spectrum::ZERO,
             brdf: LambertianBrdf {
@@ -315,13 +330,16 @@ pub fn simple_volume_scene() -> (Scene, DVec3, DMat3, impl Medium) {
             exit_medium: Vacuum,
         },
     });
-    scene.add(Triangle {
+    scene.set_fixed(ground);
+    let ground = scene.add(Triangle {
         a: DVec3::new(100.0, -1.5, 100.0),
         b: DVec3::new(-100.0, -1.5, -100.0),
         c: DVec3::new(-100.0, -1.5, 100.0),
         a_n: DVec3::Y,
         b_n: DVec3::Y,
         c_n: DVec3::Y,
+        tangents: None,
+        uvs: None,
         material: Material {
             emission: spectrum::ZERO,
             brdf: LambertianBrdf {
@@ -331,6 +349,7 @@ pub fn simple_volume_scene() -> (Scene, DVec3, DMat3, impl Medium) {
             exit_medium: Vacuum,
         },
     });
+    scene.set_fixed(ground);
     const STRENGTH: f64 = 500.0;
     scene.add(Sphere {
         origin: DVec3::ZERO,
@@ -389,7 +408,319 @@ pub fn simple_volume_scene() -> (Scene, DVec3, DMat3, impl Medium) {
     (scene, camera, looking, Vacuum)
 }
 
-pub fn atmosphere_scene(time: f64, altitude: f64) -> (Scene, DVec3, DMat3, impl Medium) {
+pub fn cornell_box() -> (Scene, DVec3, DMat3, impl Medium) {
+    let mut scene = Scene::new();
+
+    // approximations of the measured reflectances from the Cornell box data
+    let white = PiecewiseLinearSpectrum::from_points(&[
+        (360.0, 0.34),
+        (400.0, 0.34),
+        (500.0, 0.74),
+        (600.0, 0.77),
+        (700.0, 0.76),
+        (830.0, 0.76),
+    ]);
+    let red = PiecewiseLinearSpectrum::from_points(&[
+        (360.0, 0.04),
+        (400.0, 0.04),
+        (550.0, 0.06),
+        (600.0, 0.56),
+        (700.0, 0.63),
+        (830.0, 0.63),
+    ]);
+    let green = PiecewiseLinearSpectrum::from_points(&[
+        (360.0, 0.09),
+        (400.0, 0.09),
+        (500.0, 0.29),
+        (550.0, 0.47),
+        (600.0, 0.16),
+        (700.0, 0.14),
+        (830.0, 0.14),
+    ]);
+    let light = PiecewiseLinearSpectrum::from_points(&[
+        (360.0, 0.0),
+        (400.0, 0.0),
+        (500.0, 8.0),
+        (600.0, 15.6),
+        (700.0, 18.4),
+        (830.0, 18.4),
+    ]);
+
+    let matte = |albedo: &PiecewiseLinearSpectrum| Material {
+        emission: spectrum::ZERO,
+        brdf: LambertianBrdf {
+            albedo: albedo.clone(),
+        },
+        enter_medium: (),
+        exit_medium: (),
+    };
+
+    const SIZE: f64 = 0.555;
+
+    // floor, ceiling, back wall, left (red) wall, right (green) wall; normals face inwards. The
+    // open side is at +Z, facing the camera.
+    let walls = [
+        scene.add(Quad {
+            origin: DVec3::ZERO,
+            u: DVec3::Z * SIZE,
+            v: DVec3::X * SIZE,
+            material: matte(&white),
+        }),
+        scene.add(Quad {
+            origin: DVec3::Y * SIZE,
+            u: DVec3::X * SIZE,
+            v: DVec3::Z * SIZE,
+            material: matte(&white),
+        }),
+        scene.add(Quad {
+            origin: DVec3::ZERO,
+            u: DVec3::X * SIZE,
+            v: DVec3::Y * SIZE,
+            material: matte(&white),
+        }),
+        scene.add(Quad {
+            origin: DVec3::X * SIZE,
+            u: DVec3::Z * SIZE,
+            v: DVec3::Y * SIZE,
+            material: matte(&red),
+        }),
+        scene.add(Quad {
+            origin: DVec3::ZERO,
+            u: DVec3::Y * SIZE,
+            v: DVec3::Z * SIZE,
+            material: matte(&green),
+        }),
+    ];
+    for wall in walls {
+        scene.set_fixed(wall);
+    }
+
+    // the light is a thin box flush with the ceiling so nothing can get behind it
+    let light = add_box(
+        &mut scene,
+        DVec3::new(0.278, SIZE - 0.0005, SIZE - 0.2795),
+        DVec3::new(0.065, 0.0005, 0.0525),
+        0.0,
+        &Material {
+            emission: light,
+            brdf: LambertianBrdf {
+                albedo: ConstantSpectrum(0.78),
+            },
+            enter_medium: (),
+            exit_medium: (),
+        },
+    );
+    for face in light {
+        scene.set_fixed(face);
+    }
+
+    add_box(
+        &mut scene,
+        DVec3::new(0.186, 0.0825, SIZE - 0.169),
+        DVec3::splat(0.0825),
+        0.29,
+        &matte(&white),
+    );
+    add_box(
+        &mut scene,
+        DVec3::new(0.369, 0.165, SIZE - 0.351),
+        DVec3::new(0.0825, 0.165, 0.0825),
+        -0.29,
+        &matte(&white),
+    );
+
+    // turned around to look down -Z, which puts +X on the left so the red wall is there
+    let looking = DMat3::from_rotation_y(PI);
+    let camera = DVec3::new(0.278, 0.273, SIZE + 0.8);
+
+    (scene, camera, looking, Vacuum)
+}
+
+/// `n` spheres scattered at random through a cube, for stress testing the BVH. The cube grows with
+/// `n` so the density of spheres stays the same, and the same `seed` always gives the same scene.
+///
+/// The spheres are matte, rough metal, glass, or (one in twenty) emissive, and are lit by a sun
+/// as well as by the emissive ones.
+pub fn sphere_field(n: usize, seed: u64) -> (Scene, DVec3, DMat3, impl Medium) {
+    let mut scene = Scene::new();
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let half_size = (n as f64).cbrt() * 1.5;
+    let mut spheres: Vec<Arc<dyn Object>> = Vec::with_capacity(n);
+    for _ in 0..n {
+        let origin = (rng.gen::<DVec3>() * 2.0 - 1.0) * half_size;
+        let radius = rng.gen_range(0.1..0.6);
+        let color = PiecewiseLinearSpectrum::from_points(&[
+            (360.0, rng.gen()),
+            (550.0, rng.gen()),
+            (830.0, rng.gen()),
+        ]);
+        let sphere: Arc<dyn Object> = match rng.gen_range(0..20) {
+            0 => Arc::new(Sphere {
+                origin,
+                radius,
+                material: Material {
+                    emission: AmplifiedSpectrum {
+                        factor: 10.0,
+                        s: color.clone(),
+                    },
+                    brdf: LambertianBrdf { albedo: color },
+                    enter_medium: (),
+                    exit_medium: (),
+                },
+            }),
+            1..=9 => Arc::new(Sphere {
+                origin,
+                radius,
+                material: Material {
+                    emission: spectrum::ZERO,
+                    brdf: LambertianBrdf { albedo: color },
+                    enter_medium: (),
+                    exit_medium: (),
+                },
+            }),
+            10..=14 => Arc::new(Sphere {
+                origin,
+                radius,
+                material: Material {
+                    emission: spectrum::ZERO,
+                    brdf: RoughConductorBrdf::new(
+                        material::physical::ior_gold(),
+                        rng.gen_range(0.0..0.3),
+                    ),
+                    enter_medium: (),
+                    exit_medium: (),
+                },
+            }),
+            _ => Arc::new(Sphere {
+                origin,
+                radius,
+                material: Material {
+                    emission: spectrum::ZERO,
+                    brdf: DielectricBrdf {
+                        ior: material::physical::ior_glass(),
+                    },
+                    enter_medium: Vacuum,
+                    exit_medium: Vacuum,
+                },
+            }),
+        };
+        spheres.push(sphere);
+    }
+
+    let t = Instant::now();
+    scene.add(Bvh::build(spheres));
+    println!("Built BVH over {n} spheres in {:.2?}", t.elapsed());
+
+    scene.add_light(DistantDiskLight::from_irradiance(
+        DVec3::new(-1.0, 0.5, -0.3).normalize(),
+        0.268f64.to_radians().cos(),
+        AmplifiedSpectrum {
+            factor: 3.0,
+            s: spectrum::physical::cie_d65_1nit(),
+        },
+    ));
+
+    let looking = DMat3::from_euler(EulerRot::YXZ, 0.3, 0.3, 0.0);
+    let camera = looking * DVec3::new(0.0, 0.0, -3.0 * half_size);
+
+    (scene, camera, looking, Vacuum)
+}
+
+/// Builds a BVH over the objects loaded from `source`, reusing the `.bvh` cache next to it if it
+/// is newer than the source, and writing a new cache otherwise.
+fn cached_bvh(objects: Vec<Arc<dyn Object>>, source: impl AsRef<Path>, import: MeshImport) -> Bvh {
+    let source = source.as_ref();
+    // the cache stores the bounds of the nodes, so each way of importing needs its own
+    let cache = match import == MeshImport::default() {
+        true => source.with_extension("bvh"),
+        false => source.with_extension(format!(
+            "{}-up{}.bvh",
+            format!("{:?}", import.up).to_lowercase(),
+            if import.left_handed { "-left" } else { "" }
+        )),
+    };
+
+    let modified = |p: &Path| p.metadata().and_then(|m| m.modified()).ok();
+    let fresh = match (modified(source), modified(&cache)) {
+        (Some(source), Some(cache)) => cache > source,
+        _ => false,
+    };
+
+    if fresh {
+        match Bvh::load(&cache, objects.clone()) {
+            Ok(bvh) => return bvh,
+            Err(e) => eprintln!("Ignoring BVH cache {}: {e}", cache.display()),
+        }
+    }
+
+    let bvh = Bvh::build(objects);
+    if let Err(e) = bvh.save(&cache) {
+        eprintln!("Failed to write BVH cache {}: {e}", cache.display());
+    }
+    bvh
+}
+
+/// Adds an axis-aligned box rotated by `angle` around the Y axis, with outward facing normals.
+/// Returns the handles of its faces.
+fn add_box<M: MaterialErased + Clone + 'static>(
+    scene: &mut Scene,
+    center: DVec3,
+    half_size: DVec3,
+    angle: f64,
+    material: &M,
+) -> [Handle; 6] {
+    let faces = [
+        (DVec3::new(-1.0, 1.0, -1.0), DVec3::Z, DVec3::X),
+        (DVec3::new(-1.0, -1.0, -1.0), DVec3::X, DVec3::Z),
+        (DVec3::new(1.0, -1.0, -1.0), DVec3::Y, DVec3::Z),
+        (DVec3::new(-1.0, -1.0, -1.0), DVec3::Z, DVec3::Y),
+        (DVec3::new(-1.0, -1.0, 1.0), DVec3::X, DVec3::Y),
+        (DVec3::new(-1.0, -1.0, -1.0), DVec3::Y, DVec3::X),
+    ];
+    let rotation = DMat3::from_rotation_y(angle);
+    faces.map(|(origin, u, v)| {
+        scene.add(Quad {
+            origin: center + rotation * (origin * half_size),
+            u: rotation * (2.0 * u * half_size),
+            v: rotation * (2.0 * v * half_size),
+            material: material.clone(),
+        })
+    })
+}
+
+pub fn atmosphere_scene(
+    time: f64,
+    altitude: f64,
+) -> Result<(Scene, DVec3, DMat3, impl Medium), Error> {
+    let (mut scene, camera, looking, atmosphere) = sky_scene(time, altitude);
+
+    let terrain = scene.add(Transform::new(
+        DMat4::from_scale_rotation_translation(
+            DVec3::new(8192.0, 8192.0, -8192.0),
+            DQuat::IDENTITY,
+            DVec3::new(-4096.0, 0.0, 4096.0),
+        ),
+        VoxelOctree::load(
+            "world.dat",
+            vec![Arc::new(Material {
+                emission: spectrum::ZERO,
+                brdf: LambertianBrdf {
+                    albedo: ConstantSpectrum(0.3),
+                },
+                enter_medium: (),
+                exit_medium: (),
+            })],
+        )?,
+    ));
+    scene.set_fixed(terrain);
+
+    Ok((scene, camera, looking, atmosphere))
+}
+
+/// The planet, its atmosphere and the sun at `time` hours, from the camera of
+/// [`atmosphere_scene`] but without its terrain.
+pub fn sky_scene(time: f64, altitude: f64) -> (Scene, DVec3, DMat3, impl Medium) {
     let mut scene = Scene::new();
 
     const PLANET_RADIUS: f64 = 6371000.0;
@@ -420,7 +751,7 @@ pub fn atmosphere_scene(time: f64, altitude: f64) -> (Scene, DVec3, DMat3, impl
         },
     };
 
-    scene.add(Sphere {
+    let planet = scene.add(Sphere {
         origin: DVec3::new(0.0, -PLANET_RADIUS, 0.0),
         radius: PLANET_RADIUS,
         material: Material {
@@ -432,8 +763,9 @@ pub fn atmosphere_scene(time: f64, altitude: f64) -> (Scene, DVec3, DMat3, impl
             exit_medium: (),
         },
     });
+    scene.set_fixed(planet);
 
-    scene.add(Sphere {
+    let sky = scene.add(Sphere {
         origin: DVec3::new(0.0, -PLANET_RADIUS, 0.0),
         radius: PLANET_RADIUS + ATMOSPHERE_HEIGHT,
         material: Material {
@@ -443,25 +775,7 @@ pub fn atmosphere_scene(time: f64, altitude: f64) -> (Scene, DVec3, DMat3, impl
             exit_medium: Vacuum,
         },
     });
-
-    scene.add(Transform::new(
-        DMat4::from_scale_rotation_translation(
-            DVec3::new(8192.0, 8192.0, -8192.0),
-            DQuat::IDENTITY,
-            DVec3::new(-4096.0, 0.0, 4096.0),
-        ),
-        VoxelOctree::load(
-            "world.dat",
-            vec![Arc::new(Material {
-                emission: spectrum::ZERO,
-                brdf: LambertianBrdf {
-                    albedo: ConstantSpectrum(0.3),
-                },
-                enter_medium: (),
-                exit_medium: (),
-            })],
-        ),
-    ));
+    scene.set_fixed(sky);
 
     let axis_tilt = 0.40909;
     let time_of_year: f64 = 1.1;//PI/2.0; //0.086; //0.3068;
@@ -504,3 +818,52 @@ pub fn atmosphere_scene(time: f64, altitude: f64) -> (Scene, DVec3, DMat3, impl
 
     (scene, camera, looking, atmosphere)
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::DVec2;
+
+    use super::*;
+
+    /// Where rays from the camera through a `size`x`size` grid over a 53 degree view first hit.
+    fn camera_hits(scene: &(Scene, DVec3, DMat3, impl Medium), size: usize) -> Vec<Option<f64>> {
+        let (scene, camera, looking, _) = scene;
+        let mut hits = vec![];
+        for y in 0..size {
+            for x in 0..size {
+                let uv = (DVec2::new(x as f64, y as f64) + 0.5) / size as f64 * 2.0 - 1.0;
+                let dir = *looking * DVec3::new(uv.x * 0.5, uv.y * 0.5, 1.0).normalize();
+                hits.push(scene.raycast(*camera, dir, f64::INFINITY).map(|hit| hit.t));
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn sphere_fields_are_reproducible() {
+        let hits = camera_hits(&sphere_field(500, 7), 16);
+        // the camera sees both spheres and the gaps between them
+        let seen = hits.iter().filter(|hit| hit.is_some()).count();
+        assert!(0 < seen && seen < hits.len());
+        assert_eq!(camera_hits(&sphere_field(500, 7), 16), hits);
+        assert_ne!(camera_hits(&sphere_field(500, 8), 16), hits);
+    }
+
+    #[cfg(feature = "raycast-stats")]
+    #[test]
+    fn sphere_field_rays_test_few_spheres() {
+        // about twice what the current build gives, which grows slowly with the size of the field
+        for (n, max_tests, max_nodes) in [(1000, 3.0, 40.0), (20_000, 4.5, 120.0)] {
+            let scene = sphere_field(n, 0);
+            crate::stats::take_local();
+            camera_hits(&scene, 64);
+            let stats = crate::stats::take_local();
+            let tests = stats.primitive_tests as f64 / stats.rays as f64;
+            let nodes = stats.nodes_visited as f64 / stats.rays as f64;
+            assert!(
+                tests < max_tests && nodes < max_nodes,
+                "{n} spheres: {tests} tests and {nodes} nodes per ray"
+            );
+        }
+    }
+}
@@ -6,6 +6,7 @@ use glam::{DMat3, DVec3, DVec4, FloatExt};
 use ordered_float::OrderedFloat;
 
 use crate::random::Tabulated1DFunction;
+use crate::Error;
 
 pub mod physical;
 #[allow(unused)]
@@ -17,12 +18,41 @@ pub trait Spectrum: Send + Sync {
     fn sample_multi(&self, lambdas: DVec4) -> DVec4 {
         lambdas.map(|l| self.sample(l))
     }
+
+    /// Whether the spectrum is zero throughout [`VISIBLE`]. By default this checks every whole
+    /// nanometer, which is exact for anything that doesn't have features narrower than that.
+    fn is_zero(&self) -> bool {
+        (VISIBLE.start as usize..=VISIBLE.end as usize).all(|l| self.sample(l as f64) == 0.0)
+    }
+
+    /// An upper bound of the spectrum over `range`, ends included, for majorants of media which
+    /// must never fall below it. By default this samples the ends and every whole nanometer in
+    /// between, which can miss features narrower than that; spectra which can bound themselves
+    /// exactly do so instead.
+    fn max_over(&self, range: Range<f64>) -> f64 {
+        sampled_max(self, range)
+    }
+}
+
+/// The largest of the samples of `s` at the ends of `range` and every whole nanometer in between.
+fn sampled_max(s: &(impl Spectrum + ?Sized), range: Range<f64>) -> f64 {
+    (range.start.ceil() as usize..=range.end.floor() as usize)
+        .map(|l| s.sample(l as f64))
+        .fold(s.sample(range.start).max(s.sample(range.end)), f64::max)
 }
 
 impl<S: Spectrum> Spectrum for &S {
     fn sample(&self, lambda: f64) -> f64 {
         S::sample(*self, lambda)
     }
+
+    fn is_zero(&self) -> bool {
+        S::is_zero(*self)
+    }
+
+    fn max_over(&self, range: Range<f64>) -> f64 {
+        S::max_over(*self, range)
+    }
 }
 
 pub const VISIBLE: Range<f64> = 360.0..830.0;
@@ -37,8 +67,18 @@ impl Spectrum for ConstantSpectrum {
         _ = lambda;
         self.0
     }
+
+    fn is_zero(&self) -> bool {
+        self.0 == 0.0
+    }
+
+    fn max_over(&self, range: Range<f64>) -> f64 {
+        _ = range;
+        self.0
+    }
 }
 
+/// Linear between its data points, and zero outside them.
 #[derive(Clone)]
 pub struct PiecewiseLinearSpectrum {
     data: Box<[(f64, f64)]>,
@@ -76,6 +116,13 @@ impl PiecewiseLinearSpectrum {
 
 impl Spectrum for PiecewiseLinearSpectrum {
     fn sample(&self, lambda: f64) -> f64 {
+        let (first, last) = (self.data[0], self.data[self.data.len() - 1]);
+        if lambda < first.0 || lambda > last.0 {
+            return 0.0;
+        }
+        if lambda == last.0 {
+            return last.1;
+        }
         let i = self
             .data
             .binary_search_by(|&(l, _)| match l <= lambda {
@@ -90,16 +137,29 @@ impl Spectrum for PiecewiseLinearSpectrum {
             (lambda - low_lambda) / (high_lambda - low_lambda),
         )
     }
+
+    fn max_over(&self, range: Range<f64>) -> f64 {
+        // linear in between the data points, so the peak is at one of them or an end of the range
+        let ends = self.sample(range.start).max(self.sample(range.end));
+        self.data
+            .iter()
+            .filter(|&&(l, _)| range.start <= l && l <= range.end)
+            .map(|&(_, v)| v)
+            .fold(ends, f64::max)
+    }
 }
 
+/// A spectrum resampled to 1nm bins over [`VISIBLE`], so that sampling it is an index and a lerp
+/// between neighbouring bin centers instead of a search through the original data points.
 pub struct TabulatedSpectrum {
     f: Tabulated1DFunction,
 }
 
 impl TabulatedSpectrum {
     pub fn from_spectrum(s: impl Spectrum) -> Self {
-        let mut data: Vec<_> = (VISIBLE.start as usize..VISIBLE.end as usize)
-            .map(|wl| s.sample(wl as f64))
+        // each bin holds the value at its center, and the outer half bins hold the end values
+        let data: Vec<_> = (VISIBLE.start as usize..VISIBLE.end as usize)
+            .map(|wl| s.sample(wl as f64 + 0.5))
             .collect();
         TabulatedSpectrum {
             f: Tabulated1DFunction::new(&data, VISIBLE.start, VISIBLE.end),
@@ -113,7 +173,30 @@ impl TabulatedSpectrum {
 
 impl Spectrum for TabulatedSpectrum {
     fn sample(&self, lambda: f64) -> f64 {
-        self.f.f(lambda)
+        if !VISIBLE.contains(&lambda) {
+            return 0.0;
+        }
+        let data = self.f.raw();
+        let x = (lambda - VISIBLE.start - 0.5).clamp(0.0, (data.len() - 1) as f64);
+        let i = (x as usize).min(data.len() - 2);
+        data[i].lerp(data[i + 1], x - i as f64)
+    }
+
+    fn max_over(&self, range: Range<f64>) -> f64 {
+        // linear in between the bin centers, so the peak is at one of them or an end of the range
+        let data = self.f.raw();
+        let ends = self.sample(range.start).max(self.sample(range.end));
+        let first = (range.start - VISIBLE.start - 0.5).ceil().max(0.0);
+        let last = (range.end - VISIBLE.start - 0.5)
+            .floor()
+            .min((data.len() - 1) as f64);
+        match first <= last {
+            true => data[first as usize..=last as usize]
+                .iter()
+                .copied()
+                .fold(ends, f64::max),
+            false => ends,
+        }
     }
 }
 
@@ -127,6 +210,18 @@ impl<S: Spectrum> Spectrum for AmplifiedSpectrum<S> {
     fn sample(&self, lambda: f64) -> f64 {
         self.s.sample(lambda) * self.factor
     }
+
+    fn is_zero(&self) -> bool {
+        self.factor == 0.0 || self.s.is_zero()
+    }
+
+    fn max_over(&self, range: Range<f64>) -> f64 {
+        match self.factor >= 0.0 {
+            true => self.s.max_over(range) * self.factor,
+            // the peak of the product is at the trough of `s`, which it doesn't bound
+            false => sampled_max(self, range),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -138,27 +233,68 @@ impl<S: Spectrum> Spectrum for ReciprocalSpectrum<S> {
     }
 }
 
+/// Cauchy's equation `n(λ) = a + b / λ²` for the index of refraction of transparent materials,
+/// with `λ` in nanometers.
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub struct CauchySpectrum {
+    pub a: f64,
+    pub b: f64,
+}
+
+#[allow(unused)]
+impl CauchySpectrum {
+    /// Fits the equation to a glass catalog description: the index `n_d` at the helium d line
+    /// (587.56 nm) and the Abbe number `V_d = (n_d - 1) / (n_F - n_C)`, where F and C are the
+    /// hydrogen lines at 486.13 nm and 656.27 nm. Lower Abbe numbers disperse more; crown glass is
+    /// around 60 and dense flint around 30.
+    pub fn from_abbe(n_d: f64, abbe: f64) -> Self {
+        const D: f64 = 587.56;
+        const F: f64 = 486.13;
+        const C: f64 = 656.27;
+        let b = (n_d - 1.0) / (abbe * (F.powi(-2) - C.powi(-2)));
+        CauchySpectrum {
+            a: n_d - b / (D * D),
+            b,
+        }
+    }
+}
+
+impl Spectrum for CauchySpectrum {
+    fn sample(&self, lambda: f64) -> f64 {
+        self.a + self.b / (lambda * lambda)
+    }
+}
+
 const SRGB_TO_XYZ_T: DMat3 = DMat3::from_cols_array_2d(&[
     [0.4124, 0.3576, 0.1805],
     [0.2126, 0.7152, 0.0722],
     [0.0193, 0.1192, 0.9505],
 ]);
 
-pub fn xyz_to_srgb(xyz: DVec3) -> DVec3 {
+pub fn xyz_to_srgb_linear(xyz: DVec3) -> DVec3 {
     static XYZ_TO_SRGB_MATRIX: LazyLock<DMat3> =
         LazyLock::new(|| SRGB_TO_XYZ_T.transpose().inverse());
 
-    let srgb_linear = *XYZ_TO_SRGB_MATRIX * xyz;
+    *XYZ_TO_SRGB_MATRIX * xyz
+}
+
+pub fn xyz_to_srgb(xyz: DVec3) -> DVec3 {
+    let srgb_linear = xyz_to_srgb_linear(xyz);
     let low = srgb_linear * 12.92;
     let high = srgb_linear.powf(1.0 / 2.4) * 1.055 - 0.055;
     DVec3::select(srgb_linear.cmplt(DVec3::splat(0.0031308)), low, high)
 }
 
+pub fn srgb_linear_to_xyz(srgb_linear: DVec3) -> DVec3 {
+    SRGB_TO_XYZ_T.transpose() * srgb_linear
+}
+
 pub fn srgb_to_xyz(srgb: DVec3) -> DVec3 {
     let low = srgb / 12.92;
     let high = ((srgb + 0.055) / 1.055).powf(2.4);
     let srgb_linear = DVec3::select(srgb.cmplt(DVec3::splat(0.04045)), low, high);
-    SRGB_TO_XYZ_T.transpose() * srgb_linear
+    srgb_linear_to_xyz(srgb_linear)
 }
 
 pub fn integrate_product(a: &impl Spectrum, b: &impl Spectrum) -> f64 {
@@ -171,9 +307,239 @@ pub fn integrate_product(a: &impl Spectrum, b: &impl Spectrum) -> f64 {
     result / N as f64
 }
 
+/// The spectral sensitivities of the film's three channels.
+pub enum SensorResponse {
+    /// The CIE 1931 standard observer, as in [`lambda_to_xyz_absolute`].
+    Xyz,
+    /// Arbitrary curves, such as a camera's raw RGB sensitivities, in whatever units they came in.
+    Custom([TabulatedSpectrum; 3]),
+}
+
+impl SensorResponse {
+    /// Reads three sensitivity curves laid out like the CIE data: each line is a wavelength in nm
+    /// followed by the three channels' sensitivities. The curves are zero outside the wavelengths
+    /// given.
+    pub fn from_csv(csv: &str) -> Result<Self, Error> {
+        let mut points = [vec![], vec![], vec![]];
+        for (i, line) in csv.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = line
+                .split(',')
+                .map(|field| field.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Error::csv(format!("line {}: {e}", i + 1)))?;
+            let &[lambda, r, g, b] = &fields[..] else {
+                return Err(Error::csv(format!(
+                    "line {}: expected 4 fields, found {}",
+                    i + 1,
+                    fields.len()
+                )));
+            };
+            for (curve, value) in points.iter_mut().zip([r, g, b]) {
+                curve.push((lambda, value));
+            }
+        }
+        if points[0].len() < 2 {
+            return Err(Error::csv("need at least two wavelengths"));
+        }
+        Ok(SensorResponse::Custom(points.map(|curve| {
+            TabulatedSpectrum::from_spectrum(PiecewiseLinearSpectrum::from_points(&curve))
+        })))
+    }
+
+    pub fn response(&self, lambda: f64) -> DVec3 {
+        match self {
+            SensorResponse::Xyz => lambda_to_xyz_absolute(lambda),
+            SensorResponse::Custom(curves) => {
+                curves.each_ref().map(|curve| curve.sample(lambda)).into()
+            }
+        }
+    }
+}
+
 pub fn lambda_to_xyz_absolute(lambda: f64) -> DVec3 {
     physical::cie_xyz_absolute()
         .each_ref()
         .map(|matcher| matcher.sample(lambda))
         .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::material::physical::{ior_copper, ior_glass, ior_gold, ior_silver};
+
+    #[test]
+    fn abbe_number_sets_the_index_spread() {
+        let (d, f, c) = (587.56, 486.13, 656.27);
+        let bk7 = CauchySpectrum::from_abbe(1.5168, 64.17);
+        let flint = CauchySpectrum::from_abbe(1.7174, 29.5);
+        for (glass, n_d, abbe) in [(bk7, 1.5168, 64.17), (flint, 1.7174, 29.5)] {
+            assert!((glass.sample(d) - n_d).abs() < 1e-12);
+            let measured = (glass.sample(d) - 1.0) / (glass.sample(f) - glass.sample(c));
+            assert!(
+                (measured - abbe).abs() < 1e-9,
+                "abbe number {measured}, expected {abbe}"
+            );
+            assert!(glass.sample(400.0) > glass.sample(550.0));
+            assert!(glass.sample(550.0) > glass.sample(700.0));
+        }
+
+        // the spread across the visible band is proportional to (n_d - 1) / V_d
+        let spread = |glass: CauchySpectrum| glass.sample(400.0) - glass.sample(700.0);
+        let expected = (0.7174 / 29.5) / (0.5168 / 64.17);
+        assert!((spread(flint) / spread(bk7) - expected).abs() < 1e-9);
+
+        // catalog BK7 is 1.5302 at the mercury h line and 1.5130 at the helium r line
+        assert!((bk7.sample(404.66) - 1.5302).abs() < 0.002);
+        assert!((bk7.sample(706.52) - 1.5130).abs() < 0.002);
+    }
+
+    /// Checks that `table` is within half a nanometer's change of `original` at each of its knots
+    /// in the visible range, which a lerp between 1nm bin centers can cut a corner by.
+    fn check_knots(name: &str, table: &impl Spectrum, original: &PiecewiseLinearSpectrum) {
+        let data = &original.data;
+        for (i, &(lambda, value)) in data.iter().enumerate() {
+            if !VISIBLE.contains(&lambda) {
+                continue;
+            }
+            let slope = |a: (f64, f64), b: (f64, f64)| ((b.1 - a.1) / (b.0 - a.0)).abs();
+            let left = i.checked_sub(1).map_or(0.0, |j| slope(data[j], data[i]));
+            let right = data.get(i + 1).map_or(0.0, |&next| slope(data[i], next));
+            let tolerance = 0.5 * left.max(right) * (1.0 + 1e-9) + 1e-12 * value.abs();
+            let sampled = table.sample(lambda);
+            assert!(
+                (sampled - value).abs() <= tolerance,
+                "{name} at {lambda} nm: {sampled}, expected {value} within {tolerance}"
+            );
+        }
+    }
+
+    #[test]
+    fn tables_match_their_sources_at_the_knots() {
+        let iors: [(&str, &[_; 2], &str); 3] = [
+            (
+                "gold",
+                ior_gold(),
+                include_str!("material/ior-gold_Johnson.csv"),
+            ),
+            (
+                "silver",
+                ior_silver(),
+                include_str!("material/ior-silver_Johnson.csv"),
+            ),
+            (
+                "copper",
+                ior_copper(),
+                include_str!("material/ior-copper_Johnson.csv"),
+            ),
+        ];
+        for (name, table, csv) in iors {
+            let original = PiecewiseLinearSpectrum::from_csv_multi::<2>(csv);
+            check_knots(&format!("{name} n"), &table[0], &original[0]);
+            check_knots(&format!("{name} k"), &table[1], &original[1]);
+        }
+        check_knots(
+            "glass",
+            ior_glass(),
+            &PiecewiseLinearSpectrum::from_csv(include_str!("material/ior-glass_Rubin.csv")),
+        );
+        let xyz = PiecewiseLinearSpectrum::from_csv_multi::<3>(include_str!(
+            "spectrum/CIE_xyz_1931_2deg.csv"
+        ));
+        for ((name, table), mut original) in ["x", "y", "z"]
+            .into_iter()
+            .zip(physical::cie_xyz_absolute())
+            .zip(xyz)
+        {
+            for v in &mut original.data {
+                v.1 *= 683.002;
+            }
+            check_knots(name, table, &original);
+        }
+        check_knots(
+            "solar irradiance",
+            physical::extraterrestrial_solar_irradiance(),
+            &PiecewiseLinearSpectrum::from_csv(include_str!(
+                "spectrum/gueymard_1995_extraterrestrial_solar.csv"
+            )),
+        );
+        check_knots(
+            "ozone",
+            physical::ozone_absorption_cross_section(),
+            &PiecewiseLinearSpectrum::from_csv(include_str!(
+                "spectrum/ozone-absorption-cross-section-serdyuchenko.csv"
+            )),
+        );
+    }
+
+    #[test]
+    fn piecewise_linear_maxima_bound_the_band() {
+        crate::random::reseed([2419, 0, 0]);
+        let mut rng = crate::random::rng();
+        // a spike between whole nanometers, which sampling at each of them would miss
+        let spike = PiecewiseLinearSpectrum::from_points(&[
+            (400.0, 1.0),
+            (500.2, 1.0),
+            (500.5, 5.0),
+            (500.8, 1.0),
+            (700.0, 2.0),
+        ]);
+        let ozone = PiecewiseLinearSpectrum::from_csv(include_str!(
+            "spectrum/ozone-absorption-cross-section-serdyuchenko.csv"
+        ));
+        for (name, s) in [("spike", &spike), ("ozone", &ozone)] {
+            for _ in 0..200 {
+                let a = rng.gen_range(350.0..750.0);
+                let range = a..a + rng.gen_range(0.0..100.0);
+                let max = s.max_over(range.clone());
+                // a fine scan, plus the knots so that the peaks aren't cut short
+                let scanned = (0..=10_000)
+                    .map(|i| s.sample(range.start + (range.end - range.start) * i as f64 / 1e4))
+                    .chain(
+                        s.data
+                            .iter()
+                            .filter(|(l, _)| range.contains(l))
+                            .map(|&(_, v)| v),
+                    )
+                    .fold(f64::NEG_INFINITY, f64::max);
+                assert!(
+                    max >= scanned,
+                    "{name} over {range:?}: {max}, below the scanned {scanned}"
+                );
+                assert!(
+                    max <= scanned + 1e-3 * scanned.abs(),
+                    "{name} over {range:?}: {max}, above the scanned {scanned}"
+                );
+            }
+        }
+        assert_eq!(spike.max_over(450.0..550.0), 5.0);
+    }
+
+    #[test]
+    fn tabulated_maxima_bound_the_band() {
+        crate::random::reseed([2374, 0, 0]);
+        let mut rng = crate::random::rng();
+        let ozone = physical::ozone_absorption_cross_section();
+        for _ in 0..200 {
+            let a = rng.gen_range(350.0..840.0);
+            let range = a..a + rng.gen_range(0.0..20.0);
+            let max = ozone.max_over(range.clone());
+            let scanned = (0..=10_000)
+                .map(|i| ozone.sample(range.start + (range.end - range.start) * i as f64 / 1e4))
+                .fold(f64::NEG_INFINITY, f64::max);
+            assert!(
+                max >= scanned,
+                "over {range:?}: {max}, below the scanned {scanned}"
+            );
+            assert!(
+                max <= scanned + 1e-3 * scanned.abs(),
+                "over {range:?}: {max}, above the scanned {scanned}"
+            );
+        }
+    }
+}
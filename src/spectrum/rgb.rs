@@ -1,12 +1,15 @@
-use glam::{DVec3, FloatExt};
+use std::sync::LazyLock;
+
+use glam::{DMat3, DVec3, FloatExt};
 use rand::{thread_rng, Rng};
 
 use crate::spectrum::{srgb_to_xyz, xyz_to_srgb, VISIBLE};
 
-use super::physical::cie_d65_1nit;
+use super::physical::{cie_d65_1nit, cie_xyz_absolute};
 use super::{ConstantSpectrum, Spectrum};
 
-#[derive(Debug)]
+/// A smooth reflectance spectrum, the sigmoid of a quadratic in wavelength.
+#[derive(Clone, Copy, Debug)]
 pub struct RgbAlbedo {
     pub a: f64,
     pub b: f64,
@@ -20,9 +23,82 @@ pub struct RgbIlluminant<S> {
 }
 
 impl RgbAlbedo {
+    /// Fits the spectrum whose color under D65 is `srgb`, by Newton's method on the coefficients
+    /// starting from flat 50% gray. Components are clamped to `[0, 1]`. The sigmoid never quite
+    /// reaches 0 or 1, so black, white and fully saturated colors are only approached.
     pub fn new(srgb: DVec3) -> Self {
-        todo!()
+        let target = srgb_to_xyz(srgb.clamp(DVec3::ZERO, DVec3::ONE));
+
+        let mut coeffs = DVec3::ZERO;
+        let (mut xyz, mut jacobian) = fit_eval(coeffs);
+        for _ in 0..50 {
+            let error = (target - xyz).length();
+            if error < 1e-7 {
+                break;
+            }
+            let step = jacobian.inverse() * (target - xyz);
+            if !step.is_finite() {
+                break;
+            }
+
+            // halve the step until it actually gets closer, as Newton overshoots where the
+            // sigmoid flattens out
+            let mut scale = 1.0;
+            while scale > 1e-3 {
+                let (new_xyz, new_jacobian) = fit_eval(coeffs + step * scale);
+                if (target - new_xyz).length() < error {
+                    coeffs += step * scale;
+                    (xyz, jacobian) = (new_xyz, new_jacobian);
+                    break;
+                }
+                scale /= 2.0;
+            }
+            if scale <= 1e-3 {
+                break;
+            }
+        }
+
+        RgbAlbedo {
+            a: coeffs.x,
+            b: coeffs.y,
+            c: coeffs.z,
+        }
+    }
+}
+
+/// The XYZ color under D65 of the [`RgbAlbedo`] with coefficients `(a, b, c)`, and its Jacobian
+/// with respect to them.
+fn fit_eval(coeffs: DVec3) -> (DVec3, DMat3) {
+    // the color matching functions times D65, at evenly spaced points of the fit's [0, 1] domain
+    static RESPONSE: LazyLock<Vec<(f64, DVec3)>> = LazyLock::new(|| {
+        const N: usize = 256;
+        let [x, y, z] = cie_xyz_absolute();
+        let width = VISIBLE.end - VISIBLE.start;
+        (0..N)
+            .map(|i| {
+                let l = (i as f64 + 0.5) / N as f64;
+                let lambda = VISIBLE.start + l * width;
+                let xyz = DVec3::new(x.sample(lambda), y.sample(lambda), z.sample(lambda));
+                (l, xyz * cie_d65_1nit().sample(lambda) * width / N as f64)
+            })
+            .collect()
+    });
+
+    let mut xyz = DVec3::ZERO;
+    let mut jacobian = DMat3::ZERO;
+    for &(l, response) in RESPONSE.iter() {
+        let basis = DVec3::new(l * l, l, 1.0);
+        let q = coeffs.dot(basis);
+        let r = 1.0 / (1.0 + q * q).sqrt();
+        xyz += response * (0.5 + 0.5 * q * r);
+        let dq = 0.5 * r * r * r;
+        jacobian += DMat3::from_cols(
+            response * basis.x * dq,
+            response * basis.y * dq,
+            response * basis.z * dq,
+        );
     }
+    (xyz, jacobian)
 }
 
 impl<S> RgbIlluminant<S> {
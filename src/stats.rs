@@ -0,0 +1,259 @@
+//! Ray traversal counters and path length histograms for profiling, compiled in with the
+//! `raycast-stats` feature. Without it the counting functions are empty and the counts are always
+//! zero.
+//!
+//! Counts are kept per thread and added to the global totals by [`flush`], which the renderer
+//! calls after each pixel.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RaycastStats {
+    /// Rays cast against the whole scene.
+    pub rays: u64,
+    /// BVH nodes whose bounds were tested.
+    pub nodes_visited: u64,
+    /// Intersection tests against objects, both in the scene's object list and in BVH leaves.
+    pub primitive_tests: u64,
+    /// Scene rays which hit something.
+    pub hits: u64,
+}
+
+/// How many paths were how long, over every path traced, counting surface bounces and medium
+/// collisions separately. Element `i` of each histogram is the number of paths with `i` of them,
+/// except that the last also takes every longer path.
+#[derive(Clone, Debug, Default)]
+pub struct PathLengths {
+    /// Scattering events at surfaces.
+    pub surface: Vec<u64>,
+    /// Collisions sampled in participating media, null collisions included, since those cost as
+    /// much as real ones.
+    pub medium: Vec<u64>,
+}
+
+/// Number of buckets in the [`PathLengths`] histograms, set by [`set_path_length_buckets`].
+static PATH_LENGTH_BUCKETS: AtomicUsize = AtomicUsize::new(32);
+
+#[cfg(feature = "raycast-stats")]
+mod counters {
+    use std::cell::{Cell, RefCell};
+    use std::sync::Mutex;
+
+    use super::{PathLengths, RaycastStats};
+
+    thread_local! {
+        pub static LOCAL: Cell<RaycastStats> = Cell::new(RaycastStats::default());
+        pub static LOCAL_PATHS: RefCell<PathLengths> = RefCell::default();
+    }
+
+    pub static TOTAL: Mutex<RaycastStats> = Mutex::new(RaycastStats {
+        rays: 0,
+        nodes_visited: 0,
+        primitive_tests: 0,
+        hits: 0,
+    });
+
+    pub static TOTAL_PATHS: Mutex<PathLengths> = Mutex::new(PathLengths {
+        surface: vec![],
+        medium: vec![],
+    });
+}
+
+#[cfg(feature = "raycast-stats")]
+#[inline(always)]
+fn count(f: impl FnOnce(&mut RaycastStats)) {
+    counters::LOCAL.with(|local| {
+        let mut stats = local.get();
+        f(&mut stats);
+        local.set(stats);
+    });
+}
+
+#[cfg(not(feature = "raycast-stats"))]
+#[inline(always)]
+fn count(_: impl FnOnce(&mut RaycastStats)) {}
+
+#[inline(always)]
+pub fn ray() {
+    count(|s| s.rays += 1);
+}
+
+#[inline(always)]
+pub fn node_visited() {
+    count(|s| s.nodes_visited += 1);
+}
+
+#[inline(always)]
+pub fn primitive_test() {
+    count(|s| s.primitive_tests += 1);
+}
+
+#[inline(always)]
+pub fn hit() {
+    count(|s| s.hits += 1);
+}
+
+/// Records a finished path with `surface` bounces and `medium` collisions.
+#[inline(always)]
+pub fn path(surface: u32, medium: u32) {
+    #[cfg(feature = "raycast-stats")]
+    counters::LOCAL_PATHS.with_borrow_mut(|paths| paths.add(surface, medium));
+    #[cfg(not(feature = "raycast-stats"))]
+    {
+        _ = surface;
+        _ = medium;
+    }
+}
+
+/// Sets the number of buckets in the path length histograms, which should happen before anything
+/// is traced.
+pub fn set_path_length_buckets(buckets: usize) {
+    PATH_LENGTH_BUCKETS.store(buckets.max(1), Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    cfg!(feature = "raycast-stats")
+}
+
+/// Adds the current thread's counts to the totals.
+pub fn flush() {
+    #[cfg(feature = "raycast-stats")]
+    {
+        let local = counters::LOCAL.take();
+        let mut total = counters::TOTAL.lock().unwrap();
+        total.rays += local.rays;
+        total.nodes_visited += local.nodes_visited;
+        total.primitive_tests += local.primitive_tests;
+        total.hits += local.hits;
+        drop(total);
+
+        let local = counters::LOCAL_PATHS.take();
+        counters::TOTAL_PATHS.lock().unwrap().merge(&local);
+    }
+}
+
+/// Takes the current thread's counts without adding them to the totals, so that tests running in
+/// parallel don't see each other's rays.
+#[cfg(all(test, feature = "raycast-stats"))]
+pub fn take_local() -> RaycastStats {
+    counters::LOCAL.take()
+}
+
+/// Takes the current thread's path lengths without adding them to the totals, like
+/// [`take_local`].
+#[cfg(all(test, feature = "raycast-stats"))]
+pub fn take_local_paths() -> PathLengths {
+    counters::LOCAL_PATHS.take()
+}
+
+/// Returns the flushed totals.
+#[cfg(feature = "raycast-stats")]
+pub fn total() -> RaycastStats {
+    *counters::TOTAL.lock().unwrap()
+}
+
+#[cfg(not(feature = "raycast-stats"))]
+pub fn total() -> RaycastStats {
+    RaycastStats::default()
+}
+
+/// Returns the flushed path length histograms.
+#[cfg(feature = "raycast-stats")]
+pub fn path_lengths() -> PathLengths {
+    counters::TOTAL_PATHS.lock().unwrap().clone()
+}
+
+#[cfg(not(feature = "raycast-stats"))]
+pub fn path_lengths() -> PathLengths {
+    PathLengths::default()
+}
+
+impl RaycastStats {
+    pub fn to_json(self, path_lengths: &PathLengths) -> String {
+        format!(
+            "{{\"rays\": {}, \"nodes_visited\": {}, \"primitive_tests\": {}, \"hits\": {}, \
+             \"path_lengths\": {{\"surface\": {:?}, \"medium\": {:?}}}}}\n",
+            self.rays,
+            self.nodes_visited,
+            self.primitive_tests,
+            self.hits,
+            path_lengths.surface,
+            path_lengths.medium
+        )
+    }
+}
+
+impl Display for RaycastStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rays = self.rays.max(1) as f64;
+        write!(
+            f,
+            "{} rays, {:.2} nodes/ray, {:.2} tests/ray, {:.1}% hit",
+            self.rays,
+            self.nodes_visited as f64 / rays,
+            self.primitive_tests as f64 / rays,
+            self.hits as f64 / rays * 100.0
+        )
+    }
+}
+
+#[allow(unused)]
+impl PathLengths {
+    fn add(&mut self, surface: u32, medium: u32) {
+        let buckets = PATH_LENGTH_BUCKETS.load(Ordering::Relaxed);
+        for (histogram, length) in [(&mut self.surface, surface), (&mut self.medium, medium)] {
+            histogram.resize(histogram.len().max(buckets), 0);
+            histogram[(length as usize).min(buckets - 1)] += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &PathLengths) {
+        for (histogram, other) in [
+            (&mut self.surface, &other.surface),
+            (&mut self.medium, &other.medium),
+        ] {
+            histogram.resize(histogram.len().max(other.len()), 0);
+            for (count, other) in histogram.iter_mut().zip(other) {
+                *count += other;
+            }
+        }
+    }
+
+    /// Number of paths recorded.
+    pub fn paths(&self) -> u64 {
+        self.surface.iter().sum()
+    }
+
+    /// The most common number of surface bounces and of medium collisions.
+    pub fn modes(&self) -> (usize, usize) {
+        let mode = |histogram: &[u64]| {
+            (0..histogram.len())
+                .max_by_key(|&i| (histogram[i], std::cmp::Reverse(i)))
+                .unwrap_or(0)
+        };
+        (mode(&self.surface), mode(&self.medium))
+    }
+}
+
+impl Display for PathLengths {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mean = |histogram: &[u64]| {
+            let total: u64 = histogram
+                .iter()
+                .enumerate()
+                .map(|(i, &n)| i as u64 * n)
+                .sum();
+            total as f64 / self.paths().max(1) as f64
+        };
+        let (surface_mode, medium_mode) = self.modes();
+        write!(
+            f,
+            "{} paths, {:.2} surface bounces (mode {surface_mode}), {:.2} medium collisions \
+             (mode {medium_mode})",
+            self.paths(),
+            mean(&self.surface),
+            mean(&self.medium)
+        )
+    }
+}
@@ -0,0 +1,246 @@
+//! Filtered lookups into images, with texel centers at half-integer coordinates and `(0, 0)` at
+//! the top left.
+
+use std::collections::HashMap;
+use std::ops::{Add, Mul};
+use std::path::Path;
+
+use glam::{DVec2, DVec3};
+
+use crate::spectrum::rgb::RgbAlbedo;
+use crate::Error;
+
+/// A `width`x`height` image of texels, stored row by row.
+#[allow(unused)]
+pub struct Texture<T> {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<T>,
+    pub wrap: Wrap,
+}
+
+/// How texel coordinates outside the image are brought back into it.
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wrap {
+    /// The image tiles the plane in both directions.
+    Repeat,
+    /// A latitude-longitude map with the poles along the top and bottom edges. Horizontally it
+    /// wraps around the seam; vertically, stepping past a pole continues down the far side of it,
+    /// half a turn around.
+    Equirect,
+}
+
+/// The reconstruction filter used between texel centers.
+#[allow(unused)]
+#[derive(Clone, Copy, Debug)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    /// The Mitchell-Netravali family of cubics over the 4x4 nearest texels. Unlike bilinear it has
+    /// a continuous derivative, so small bright features like the sun don't show the texel grid,
+    /// at the cost of slight ringing next to sharp edges. `b = c = 1/3` is Mitchell and
+    /// Netravali's recommendation; `b = 0, c = 1/2` is Catmull-Rom.
+    Mitchell {
+        b: f64,
+        c: f64,
+    },
+}
+
+#[allow(unused)]
+impl Filter {
+    pub const MITCHELL: Filter = Filter::Mitchell {
+        b: 1.0 / 3.0,
+        c: 1.0 / 3.0,
+    };
+}
+
+#[allow(unused)]
+impl<T: Copy> Texture<T> {
+    /// The texel at `(x, y)`, with coordinates outside the image wrapped according to `wrap`.
+    pub fn texel(&self, x: isize, y: isize) -> T {
+        let (w, h) = (self.width as isize, self.height as isize);
+        let (x, y) = match self.wrap {
+            Wrap::Repeat => (x, y.rem_euclid(h)),
+            Wrap::Equirect => {
+                // each trip past a pole flips the rows and turns half way around
+                let flips = y.div_euclid(h);
+                let y = match flips % 2 == 0 {
+                    true => y.rem_euclid(h),
+                    false => h - 1 - y.rem_euclid(h),
+                };
+                (x + flips * (w / 2), y)
+            }
+        };
+        self.data[(x.rem_euclid(w) + y * w) as usize]
+    }
+
+    /// The filtered value at `uv`, where `[0, 1]²` spans the image.
+    pub fn lookup(&self, uv: DVec2, filter: Filter) -> T
+    where
+        T: Add<Output = T> + Mul<f64, Output = T>,
+    {
+        self.lookup_with(uv, filter, |t| t)
+    }
+
+    /// Like [`lookup`](Self::lookup), but filters the texels after passing them through `map`,
+    /// for texels which can't be blended directly.
+    pub fn lookup_with<U: Copy + Add<Output = U> + Mul<f64, Output = U>>(
+        &self,
+        uv: DVec2,
+        filter: Filter,
+        map: impl Fn(T) -> U,
+    ) -> U {
+        let texel = |x: isize, y: isize| map(self.texel(x, y));
+        let p = uv * DVec2::new(self.width as f64, self.height as f64);
+        if let Filter::Nearest = filter {
+            return texel(p.x.floor() as isize, p.y.floor() as isize);
+        }
+
+        // position relative to the texel center up and to the left of it
+        let p = p - 0.5;
+        let f = p - p.floor();
+        let (x, y) = (p.x.floor() as isize, p.y.floor() as isize);
+        match filter {
+            Filter::Nearest => unreachable!(),
+            Filter::Bilinear => {
+                let top = texel(x, y) * (1.0 - f.x) + texel(x + 1, y) * f.x;
+                let bottom = texel(x, y + 1) * (1.0 - f.x) + texel(x + 1, y + 1) * f.x;
+                top * (1.0 - f.y) + bottom * f.y
+            }
+            Filter::Mitchell { b, c } => {
+                let wx = mitchell_weights(f.x, b, c);
+                let wy = mitchell_weights(f.y, b, c);
+                let row = |dy: isize| {
+                    (1..4).fold(texel(x - 1, y + dy) * wx[0], |sum, i| {
+                        sum + texel(x - 1 + i as isize, y + dy) * wx[i]
+                    })
+                };
+                (1..4).fold(row(-1) * wy[0], |sum, i| sum + row(i as isize - 1) * wy[i])
+            }
+        }
+    }
+}
+
+#[allow(unused)]
+impl Texture<RgbAlbedo> {
+    /// Loads an sRGB image as reflectance spectra. Fitting a spectrum is slow, so each distinct
+    /// color is only fit once.
+    pub fn load_albedo(path: impl AsRef<Path>, wrap: Wrap) -> Result<Self, Error> {
+        let image = image::open(path)?.into_rgb8();
+        let mut fits = HashMap::new();
+        let data = image
+            .pixels()
+            .map(|p| {
+                *fits.entry(p.0).or_insert_with(|| {
+                    RgbAlbedo::new(DVec3::from_array(p.0.map(|c| c as f64 / 255.0)))
+                })
+            })
+            .collect();
+        Ok(Texture {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            data,
+            wrap,
+        })
+    }
+}
+
+#[allow(unused)]
+impl Texture<f64> {
+    /// Loads the alpha channel of an image, from 0 where it's transparent to 1 where it's opaque.
+    /// Images without one are opaque throughout.
+    pub fn load_alpha(path: impl AsRef<Path>, wrap: Wrap) -> Result<Self, Error> {
+        let image = image::open(path)?.into_luma_alpha16();
+        Ok(Texture {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            data: image
+                .pixels()
+                .map(|p| p.0[1] as f64 / u16::MAX as f64)
+                .collect(),
+            wrap,
+        })
+    }
+}
+
+/// Weights of the texels at offsets `-1 - f`, `-f`, `1 - f` and `2 - f` from a point `f` past a
+/// texel center. They always sum to one, so constant images stay constant.
+fn mitchell_weights(f: f64, b: f64, c: f64) -> [f64; 4] {
+    let k = |x: f64| {
+        let x = x.abs();
+        let x2 = x * x;
+        let x3 = x2 * x;
+        let v = if x < 1.0 {
+            (12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + 6.0 - 2.0 * b
+        } else if x < 2.0 {
+            (-b - 6.0 * c) * x3
+                + (6.0 * b + 30.0 * c) * x2
+                + (-12.0 * b - 48.0 * c) * x
+                + 8.0 * b
+                + 24.0 * c
+        } else {
+            0.0
+        };
+        v / 6.0
+    };
+    [k(1.0 + f), k(f), k(1.0 - f), k(2.0 - f)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CATMULL_ROM: Filter = Filter::Mitchell { b: 0.0, c: 0.5 };
+
+    #[test]
+    fn cubic_filters_overshoot_steps_and_keep_constants() {
+        // dark on the left half, bright on the right, with the step between texels 7 and 8
+        let step = Texture {
+            width: 16,
+            height: 4,
+            data: (0..64).map(|i| (i % 16 >= 8) as u32 as f64).collect(),
+            wrap: Wrap::Repeat,
+        };
+        let row = |filter| -> Vec<f64> {
+            (0..=1600)
+                .map(|i| step.lookup(DVec2::new(i as f64 / 1600.0, 0.3), filter))
+                .collect()
+        };
+        for (name, filter) in [("mitchell", Filter::MITCHELL), ("catmull-rom", CATMULL_ROM)] {
+            let values = row(filter);
+            // a dip just before the step and a bump just after it
+            let (before, after) = (&values[600..800], &values[800..1000]);
+            let low = before.iter().copied().fold(f64::INFINITY, f64::min);
+            let high = after.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            assert!(low < -1e-3 && high > 1.0 + 1e-3, "{name}: {low} to {high}");
+            assert!(low > -0.1 && high < 1.1, "{name}: {low} to {high}");
+            // with no jumps along the way
+            for pair in values.windows(2) {
+                assert!((pair[1] - pair[0]).abs() < 0.02, "{name}: {pair:?}");
+            }
+        }
+        let bilinear = row(Filter::Bilinear);
+        assert!(bilinear.iter().all(|v| (0.0..=1.0).contains(v)));
+
+        for wrap in [Wrap::Repeat, Wrap::Equirect] {
+            let constant = Texture {
+                width: 8,
+                height: 4,
+                data: vec![0.7; 32],
+                wrap,
+            };
+            for uv in [
+                DVec2::new(0.5, 0.5),
+                DVec2::new(0.01, 0.99),
+                DVec2::new(0.999, 0.001),
+                DVec2::new(0.3, 0.0),
+            ] {
+                for filter in [Filter::MITCHELL, CATMULL_ROM] {
+                    let v = constant.lookup(uv, filter);
+                    assert!((v - 0.7).abs() < 1e-12, "{v} at {uv} with {wrap:?}");
+                }
+            }
+        }
+    }
+}
@@ -3,32 +3,67 @@ use std::ops::ControlFlow::{self, Break, Continue};
 use glam::{DVec3, DVec4};
 use rand::prelude::*;
 
-use crate::medium::{Medium, MediumProperties};
+use crate::brdf::Lobe;
+use crate::medium::{Medium, MediumProperties, MediumStack};
+use crate::objects::offset_ray_origin;
+use crate::random;
 use crate::scene::Scene;
+use crate::stats;
+
+/// Radiance arriving along a camera path, split by how the path first scattered.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PathRadiance {
+    /// Light which reached the camera without scattering.
+    pub emitted: DVec4,
+    /// Paths which first scattered off a diffuse lobe or in a medium.
+    pub diffuse: DVec4,
+    /// Paths which first scattered off a specular lobe.
+    pub specular: DVec4,
+}
+
+impl PathRadiance {
+    fn add(&mut self, first_lobe: Option<Lobe>, radiance: DVec4) {
+        match first_lobe {
+            None => self.emitted += radiance,
+            Some(Lobe::Diffuse) => self.diffuse += radiance,
+            Some(Lobe::Specular) => self.specular += radiance,
+        }
+    }
+}
 
+/// Traces a path from `pos` along `dir`. With a `max_depth`, the path ends at its scattering event
+/// after that many, surfaces and media alike, once the light reaching it has been gathered. Depth
+/// 1 is direct lighting only.
 pub fn path_trace(
     scene: &Scene,
     pos: DVec3,
     dir: DVec3,
     lambdas: DVec4,
     camera_medium: &dyn Medium,
-) -> DVec4 {
+    max_depth: Option<u32>,
+) -> PathRadiance {
     // beta = throughput / p_{u, lambda[0]}
     let mut beta = DVec4::ONE;
     // r_u = p_u / p_path, r_l = p_l / p_path
     let mut r_u = DVec4::ONE;
     let mut r_l = DVec4::ONE;
 
-    let mut radiance = DVec4::ZERO;
+    let mut radiance = PathRadiance::default();
+    let mut first_lobe = None;
     let mut pos = pos;
     let mut dir = dir;
-    let mut medium = camera_medium;
+    let mut media = MediumStack::new(camera_medium);
 
     let mut specular_bounce = true;
+    let mut depth = 0;
+    // for the path length statistics
+    let mut bounces = 0;
+    let mut collisions = 0;
 
     let mut prev_interaction = (DVec3::ZERO, DVec3::ZERO);
 
     loop {
+        let medium = media.current();
         let hit = scene.raycast(pos, dir, f64::INFINITY);
         let d = hit.as_ref().map_or(f64::INFINITY, |hit| hit.t);
 
@@ -40,36 +75,50 @@ pub fn path_trace(
             let mut scattered = false;
             let mut terminated = false;
             let t_maj = sample_tmaj(pos, dir, medium, d, lambdas, |p, mp, majorant, t_maj| {
+                collisions += 1;
+
                 // compute emission from the medium
                 if mp.emission != DVec4::ZERO {
                     let pdf = majorant.x * t_maj.x;
                     let beta_prime = beta * t_maj / pdf;
                     let r_e = r_u * majorant * t_maj / pdf;
                     if r_e != DVec4::ZERO {
-                        radiance += beta_prime * mp.absorption * mp.emission / average(r_e);
+                        radiance.add(
+                            first_lobe,
+                            beta_prime * mp.absorption * mp.emission / average(r_e),
+                        );
                     }
                 }
 
                 let pr_absorption = mp.absorption / majorant;
                 let pr_scattering = mp.scattering / majorant;
 
-                let rng: f64 = thread_rng().gen();
+                let rng: f64 = random::rng().gen();
                 if rng < pr_absorption.x {
                     // absorption
                     terminated = true;
                     Break(())
                 } else if rng < pr_absorption.x + pr_scattering.x {
                     // scattering
+                    if max_depth.is_some_and(|max| depth >= max) {
+                        terminated = true;
+                        return Break(());
+                    }
+                    depth += 1;
+                    first_lobe = first_lobe.or(Some(Lobe::Diffuse));
                     let pdf = t_maj.x * mp.scattering.x;
                     beta *= t_maj * mp.scattering / pdf;
                     r_u *= t_maj * mp.scattering / pdf;
 
-                    if let Some((light, pdf)) = scene.sample_light(pos, lambdas, thread_rng().gen())
-                    {
-                        let sample = light.sample(pos, lambdas, thread_rng().gen());
+                    if let Some((light, pdf, u)) = scene.sample_light(random::rng().gen()) {
+                        let sample =
+                            light.sample(p, lambdas, random::rng().gen::<DVec3>().with_x(u));
 
                         let light_pdf = pdf * sample.pdf;
-                        let scatter_pdf = medium.pdf_phase(p, sample.dir, dir, lambdas);
+                        let scatter_pdf = match sample.singular {
+                            true => 0.0,
+                            false => medium.pdf_phase(p, sample.dir, dir, lambdas),
+                        };
 
                         let tp_f =
                             beta * medium.phase(p, sample.dir, dir, lambdas) * sample.emission;
@@ -80,17 +129,21 @@ pub fn path_trace(
                                 p,
                                 sample.dir,
                                 lambdas,
-                                medium,
+                                media.clone(),
                                 sample.dist,
                             );
                             let tr_u = tr_u * r_u * scatter_pdf;
                             let tr_l = tr_l * r_u * light_pdf;
 
-                            radiance += tp_f * transmittance / average(tr_u + tr_l);
+                            radiance.add(first_lobe, tp_f * transmittance / average(tr_u + tr_l));
                         }
                     }
 
-                    let new_dir = medium.sample_phase(p, dir, lambdas, thread_rng().gen());
+                    let new_dir = medium.sample_phase(p, dir, lambdas, random::rng().gen());
+                    if new_dir == DVec3::ZERO {
+                        terminated = true;
+                        return Break(());
+                    }
                     let new_dir_pdf = medium.pdf_phase(p, new_dir, dir, lambdas);
 
                     beta *= medium.phase(p, new_dir, dir, lambdas) / new_dir_pdf;
@@ -138,13 +191,13 @@ pub fn path_trace(
                 continue;
             }
             if specular_bounce {
-                radiance += beta * light_emission / average(r_u);
+                radiance.add(first_lobe, beta * light_emission / average(r_u));
             } else {
-                let light_pdf = scene.light_pmf(prev_interaction.0, lambdas, light)
-                    * light.pdf(prev_interaction.0, dir, lambdas);
+                let light_pdf =
+                    scene.light_pmf(light) * light.pdf(prev_interaction.0, dir, lambdas);
                 // pbrt-v4 accumulates into r_l here, but that seems really, really wrong to me?
                 let r_l = r_l * light_pdf;
-                radiance += beta * light_emission / average(r_u + r_l);
+                radiance.add(first_lobe, beta * light_emission / average(r_u + r_l));
             }
         }
 
@@ -152,58 +205,83 @@ pub fn path_trace(
             break;
         };
 
-        // i don't have the infrastructure for this - can't figure out light sampling probability and stuff
-        // let light_emission = hit.material.emission_sample(lambdas);
-        // if light_emission == DVec4::ZERO {
-        //     continue;
-        // }
-        // if specular_bounce {
-        //     radiance += beta * light_emission / average(r_u);
-        // } else {
-        //     let light_pdf = scene.light_pmf(prev_interaction.0, lambdas, light)
-        //         * light.pdf(prev_interaction.0, dir, lambdas);
-        //     r_l *= light_pdf;
-        // }
+        // emissive surfaces are lights too, so their emission was already added (and MIS
+        // weighted) along with the other lights above
 
         let hit_pos = pos + dir * hit.t;
         let old_dir = dir;
 
         if let Some(brdf) = hit.material.brdf() {
-            if let Some((light, pdf)) = scene.sample_light(pos, lambdas, thread_rng().gen()) {
-                let sample = light.sample(pos, lambdas, thread_rng().gen());
+            // the light reaching the last vertex was gathered along with the emission above
+            if max_depth.is_some_and(|max| depth >= max) {
+                break;
+            }
+            depth += 1;
+            bounces += 1;
+
+            let sample = brdf.sample(
+                dir,
+                hit.normal,
+                hit.tangent,
+                hit.uv,
+                lambdas,
+                random::rng().gen(),
+            );
+
+            if let Some((light, pdf, u)) = scene.sample_light(random::rng().gen()) {
+                let sample = light.sample(hit_pos, lambdas, random::rng().gen::<DVec3>().with_x(u));
 
                 let light_pdf = pdf * sample.pdf;
-                let scatter_pdf = brdf.pdf(sample.dir, dir, hit.normal, lambdas.x);
+                let scatter_pdf = match sample.singular {
+                    true => 0.0,
+                    false => brdf.pdf(sample.dir, dir, hit.normal, hit.tangent, hit.uv, lambdas.x),
+                };
 
-                let tp_f = beta
-                    * brdf.f(sample.dir, dir, hit.normal, lambdas)
-                    * sample.dir.dot(hit.normal).abs()
-                    * sample.emission;
+                let tp = beta * sample.dir.dot(hit.normal).abs() * sample.emission;
+                let f = brdf.f_lobes(sample.dir, dir, hit.normal, hit.tangent, hit.uv, lambdas);
 
-                if tp_f != DVec4::ZERO {
-                    let offset = hit.geo_normal * (1e-6 * hit.geo_normal.dot(sample.dir).signum());
+                if tp * (f[0] + f[1]) != DVec4::ZERO {
                     let (transmittance, tr_u, tr_l) = transmittance_with_path_pr(
                         scene,
-                        hit_pos + offset,
+                        offset_ray_origin(hit_pos, hit.geo_normal, sample.dir),
                         sample.dir,
                         lambdas,
-                        medium,
+                        media.clone(),
                         sample.dist,
                     );
                     let tr_u = tr_u * r_u * scatter_pdf;
                     let tr_l = tr_l * r_u * light_pdf;
-
-                    radiance += tp_f * transmittance / average(tr_u + tr_l);
+                    let weight = tp * transmittance / average(tr_u + tr_l);
+
+                    // light sampled at the first vertex goes to whichever lobes reflect it, and
+                    // further along to the lobe the path started with
+                    match first_lobe {
+                        Some(_) => radiance.add(first_lobe, (f[0] + f[1]) * weight),
+                        None => {
+                            radiance.add(Some(Lobe::Diffuse), f[0] * weight);
+                            radiance.add(Some(Lobe::Specular), f[1] * weight);
+                        }
+                    }
                 }
             }
-            prev_interaction = (hit_pos, hit.normal);
+            first_lobe = first_lobe.or(Some(sample.lobe));
 
-            let sample = brdf.sample(dir, hit.normal, lambdas, thread_rng().gen());
+            // off the surface, so that the light pdf for the next hit doesn't find this one again
+            let origin = offset_ray_origin(hit_pos, hit.geo_normal, sample.dir);
+            prev_interaction = (origin, hit.normal);
 
             if sample.dir == DVec3::ZERO {
                 break;
             }
 
+            if sample.terminate_secondary {
+                // the other wavelengths would have gone in different directions, so their pdf for
+                // the rest of this path is 0. dropping them from r_u leaves the hero wavelength with
+                // 4 times the weight, which is what the single-wavelength estimator needs.
+                beta *= DVec4::X;
+                r_u *= DVec4::X;
+            }
+
             let cos_theta = sample.dir.dot(hit.normal).abs();
             beta *= sample.f * cos_theta / sample.pdf;
             r_l = r_u / sample.pdf;
@@ -212,20 +290,18 @@ pub fn path_trace(
             specular_bounce = sample.singular;
         }
 
-        let offset = hit.geo_normal * (1e-6 * hit.geo_normal.dot(dir).signum());
-        pos = hit_pos + offset;
+        pos = offset_ray_origin(hit_pos, hit.geo_normal, dir);
 
         if old_dir.dot(hit.geo_normal).signum() == dir.dot(hit.geo_normal).signum() {
-            medium = match dir.dot(hit.geo_normal) > 0.0 {
-                true => hit.material.exit_medium(),
-                false => hit.material.enter_medium(),
-            };
+            media.transmit(hit.material, hit.geo_normal, dir);
         }
 
+        // past the last vertex the path only looks for emission, which roulette would just make
+        // noisier
         let rr_beta = beta / average(r_u);
-        if rr_beta.max_element() < 1.0 {
+        if rr_beta.max_element() < 1.0 && max_depth.is_none_or(|max| depth < max) {
             let q = 1.0 - rr_beta.max_element();
-            if thread_rng().gen_bool(q) {
+            if random::rng().gen_bool(q) {
                 break;
             } else {
                 beta /= 1.0 - q;
@@ -233,6 +309,7 @@ pub fn path_trace(
         }
     }
 
+    stats::path(bounces, collisions);
     radiance
 }
 
@@ -241,7 +318,7 @@ pub fn transmittance_with_path_pr<'a>(
     mut pos: DVec3,
     dir: DVec3,
     lambdas: DVec4,
-    mut medium: &'a dyn Medium,
+    mut media: MediumStack<'a>,
     mut d: f64,
 ) -> (DVec4, DVec4, DVec4) {
     let mut t_ray = DVec4::ONE;
@@ -249,6 +326,7 @@ pub fn transmittance_with_path_pr<'a>(
     let mut r_u = DVec4::ONE;
 
     loop {
+        let medium = media.current();
         let Some(hit) = scene.raycast(pos, dir, d) else {
             if medium.participating() {
                 panic!("can't exit participating medium?");
@@ -277,18 +355,59 @@ pub fn transmittance_with_path_pr<'a>(
         }
 
         d -= hit.t;
-        let offset = hit.geo_normal * (1e-6 * hit.geo_normal.dot(dir).signum());
-        pos += hit.t * dir + offset;
+        pos = offset_ray_origin(pos + hit.t * dir, hit.geo_normal, dir);
 
-        medium = match dir.dot(hit.geo_normal) > 0.0 {
-            true => hit.material.exit_medium(),
-            false => hit.material.enter_medium(),
-        };
+        media.transmit(hit.material, hit.geo_normal, dir);
     }
 
     (t_ray, r_u, r_l)
 }
 
+/// An unbiased estimate of the optical depth from `pos` to `pos + dir * d`, from one uniformly
+/// placed sample of the extinction in each medium the ray passes through. Infinite if an opaque
+/// surface is in the way.
+#[allow(unused)]
+pub fn optical_depth<'a>(
+    scene: &'a Scene,
+    mut pos: DVec3,
+    dir: DVec3,
+    lambdas: DVec4,
+    mut media: MediumStack<'a>,
+    mut d: f64,
+) -> DVec4 {
+    let mut tau = DVec4::ZERO;
+
+    loop {
+        let medium = media.current();
+        let hit = scene.raycast(pos, dir, d);
+        let t_max = hit.as_ref().map_or(d, |hit| hit.t);
+
+        if medium.participating() {
+            if t_max.is_infinite() {
+                panic!("can't exit participating medium?");
+            }
+            let p = pos + dir * (random::rng().gen::<f64>() * t_max);
+            let mp = medium.properties(p, dir, lambdas);
+            tau += (mp.absorption + mp.scattering) * t_max * dir.length();
+        }
+
+        let Some(hit) = hit else {
+            break;
+        };
+
+        if hit.material.brdf().is_some() {
+            return DVec4::INFINITY;
+        }
+
+        d -= hit.t;
+        pos = offset_ray_origin(pos + hit.t * dir, hit.geo_normal, dir);
+
+        media.transmit(hit.material, hit.geo_normal, dir);
+    }
+
+    tau
+}
+
 fn sample_tmaj(
     pos: DVec3,
     mut dir: DVec3,
@@ -310,7 +429,7 @@ fn sample_tmaj(
 
     let mut t_min = 0.0;
     loop {
-        let t = t_min - (1.0 - thread_rng().gen::<f64>()).ln() / majorant.x;
+        let t = t_min - (1.0 - random::rng().gen::<f64>()).ln() / majorant.x;
         if t >= t_max {
             return (-(t_max - t_min) * majorant).exp();
         }
@@ -328,3 +447,279 @@ fn sample_tmaj(
 fn average(v: DVec4) -> f64 {
     v.element_sum() / 4.0
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::brdf::{Brdf, DielectricBrdf, LambertianBrdf};
+    use crate::light::{DistantDiskLight, GradientSkyLight};
+    use crate::material::{EmissiveMaterial, Material};
+    use crate::medium::{ColoredGlassMedium, Vacuum};
+    use crate::objects::{Quad, Sphere};
+    use crate::spectrum::{CauchySpectrum, ConstantSpectrum, Spectrum};
+
+    /// A 1m thick slab of glass tinted by `tint`, with its top at y = 0, above a glowing floor in
+    /// the dark.
+    fn slab_scene(ior: impl Spectrum + Clone + 'static, tint: DVec3) -> Scene {
+        let face = |origin, u, v| Quad {
+            origin,
+            u,
+            v,
+            material: Material {
+                emission: crate::spectrum::ZERO,
+                brdf: DielectricBrdf { ior: ior.clone() },
+                enter_medium: ColoredGlassMedium::from_transmission(tint, 1.0),
+                exit_medium: Vacuum,
+            },
+        };
+        let mut scene = Scene::new();
+        scene.add(face(
+            DVec3::new(-50.0, 0.0, -50.0),
+            DVec3::Z * 100.0,
+            DVec3::X * 100.0,
+        ));
+        scene.add(face(
+            DVec3::new(-50.0, -1.0, -50.0),
+            DVec3::X * 100.0,
+            DVec3::Z * 100.0,
+        ));
+        scene.add(Quad {
+            origin: DVec3::new(-50.0, -2.0, -50.0),
+            u: DVec3::Z * 100.0,
+            v: DVec3::X * 100.0,
+            material: EmissiveMaterial::new(ConstantSpectrum(1.0)),
+        });
+        scene
+    }
+
+    #[test]
+    fn dispersive_tinted_slabs_transmit_beer_lambert() {
+        random::reseed([2390, 0, 0]);
+        const PATHS: usize = 20_000;
+        let lambdas = DVec4::new(450.0, 520.0, 600.0, 680.0);
+        let tint = DVec3::new(0.8, 0.5, 0.2);
+        let absorption = ColoredGlassMedium::from_transmission(tint, 1.0)
+            .absorption
+            .sample_multi(lambdas);
+        let flint = CauchySpectrum::from_abbe(1.6, 20.0);
+
+        let crown = ConstantSpectrum(1.5);
+        for (name, ior, scene) in [
+            (
+                "crown",
+                crown.sample_multi(lambdas),
+                slab_scene(crown, tint),
+            ),
+            (
+                "flint",
+                flint.sample_multi(lambdas),
+                slab_scene(flint, tint),
+            ),
+        ] {
+            // each wavelength takes its turn as the hero, as in a render
+            let mut total = DVec4::ZERO;
+            for hero in 0..4 {
+                let mut rotated = lambdas.to_array();
+                rotated.rotate_left(hero);
+                for _ in 0..PATHS {
+                    let r = path_trace(&scene, DVec3::Y, -DVec3::Y, rotated.into(), &Vacuum, None);
+                    let radiance = r.emitted + r.diffuse + r.specular;
+                    assert!(
+                        radiance.is_finite() && radiance.max_element() <= 4.0 + 1e-9,
+                        "{name} path brought back {radiance}"
+                    );
+                    let mut radiance = radiance.to_array();
+                    radiance.rotate_right(hero);
+                    total += DVec4::from(radiance);
+                }
+            }
+            let transmitted = total / (4 * PATHS) as f64;
+
+            // Fresnel at both faces, with the light bouncing back and forth between them
+            let r = ((ior - 1.0) / (ior + 1.0)).powf(2.0);
+            let t = (-absorption).exp();
+            let expected = (1.0 - r) * (1.0 - r) * t / (1.0 - r * r * t * t);
+            assert!(
+                ((transmitted - expected) / expected).abs().max_element() < 0.03,
+                "{name} transmits {transmitted}, expected {expected}"
+            );
+        }
+
+        // the flint splits white light, bending blue more than red
+        let brdf = DielectricBrdf { ior: flint };
+        let outgoing = DVec3::new(0.6, -0.8, 0.0);
+        let refracted = |lambdas: DVec4| {
+            let sample = brdf.sample(
+                outgoing,
+                DVec3::Y,
+                None,
+                None,
+                lambdas,
+                DVec3::new(0.0, 0.0, 0.99),
+            );
+            assert!(sample.terminate_secondary);
+            sample.dir
+        };
+        let blue = refracted(lambdas);
+        let red = refracted(DVec4::new(680.0, 450.0, 520.0, 600.0));
+        assert!(
+            blue.x < red.x && (blue.x - red.x).abs() > 0.01,
+            "blue {blue}, red {red}"
+        );
+    }
+
+    #[test]
+    fn diffuse_spheres_under_a_background_see_its_average() {
+        random::reseed([2408, 0, 0]);
+        const PATHS: usize = 20_000;
+        let lambdas = DVec4::new(450.0, 520.0, 600.0, 680.0);
+        let sphere = || Sphere {
+            origin: DVec3::ZERO,
+            radius: 1.0,
+            material: Material {
+                emission: crate::spectrum::ZERO,
+                brdf: LambertianBrdf {
+                    albedo: ConstantSpectrum(0.5),
+                },
+                enter_medium: (),
+                exit_medium: (),
+            },
+        };
+        let mut uniform = Scene::new();
+        uniform.add(sphere());
+        uniform.set_background(GradientSkyLight::uniform(ConstantSpectrum(1.0)));
+        // the cosine-weighted average of a sky going from 1 at the zenith to 0 at the nadir
+        let mut gradient = Scene::new();
+        gradient.add(sphere());
+        gradient.set_background(GradientSkyLight {
+            up: DVec3::Y,
+            zenith: ConstantSpectrum(1.0),
+            nadir: crate::spectrum::ZERO,
+        });
+
+        for y in [-0.9, -0.4, 0.0, 0.5, 0.95] {
+            let pos = DVec3::new(0.0, 0.0, -5.0);
+            let dir = (DVec3::new(0.0, y, 0.0) - pos).normalize();
+            let t = -pos.dot(dir) - (1.0 - pos.reject_from(dir).length_squared()).sqrt();
+            let normal = pos + dir * t;
+            for (name, scene, expected) in [
+                ("uniform", &uniform, 0.5),
+                ("gradient", &gradient, 0.5 * (0.5 + normal.y / 3.0)),
+            ] {
+                let mut total = DVec4::ZERO;
+                for _ in 0..PATHS {
+                    let r = path_trace(scene, pos, dir, lambdas, &Vacuum, None);
+                    total += r.emitted + r.diffuse + r.specular;
+                }
+                let radiance = total / PATHS as f64;
+                assert!(
+                    (radiance - expected).abs().max_element() < 0.01,
+                    "{name} sky: {radiance} at normal {normal}, expected {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn direct_only_floors_are_albedo_over_pi_times_irradiance() {
+        random::reseed([2418, 0, 0]);
+        const PATHS: usize = 20_000;
+        let lambdas = DVec4::new(450.0, 520.0, 600.0, 680.0);
+        let matte = |albedo| Material {
+            emission: crate::spectrum::ZERO,
+            brdf: LambertianBrdf {
+                albedo: ConstantSpectrum(albedo),
+            },
+            enter_medium: (),
+            exit_medium: (),
+        };
+        // a floor up to a white wall facing it, under a small sun straight overhead
+        let mut scene = Scene::new();
+        scene.add(Quad {
+            origin: DVec3::new(-50.0, 0.0, -50.0),
+            u: DVec3::Z * 100.0,
+            v: DVec3::X * 51.0,
+            material: matte(0.5),
+        });
+        scene.add(Quad {
+            origin: DVec3::new(1.0, 0.0, -50.0),
+            u: DVec3::Z * 100.0,
+            v: DVec3::Y * 10.0,
+            material: matte(1.0),
+        });
+        scene.add_light(DistantDiskLight::from_irradiance(
+            DVec3::Y,
+            0.9999,
+            ConstantSpectrum(3.0),
+        ));
+
+        let (pos, dir) = (
+            DVec3::new(-1.0, 1.0, 0.0),
+            DVec3::new(1.0, -1.0, 0.0).normalize(),
+        );
+        let radiance = |max_depth| {
+            let mut total = DVec4::ZERO;
+            for _ in 0..PATHS {
+                let r = path_trace(&scene, pos, dir, lambdas, &Vacuum, max_depth);
+                total += r.emitted + r.diffuse + r.specular;
+            }
+            total / PATHS as f64
+        };
+        let expected = 0.5 / PI * 3.0;
+        let direct = radiance(Some(1));
+        assert!(
+            (direct - expected).abs().max_element() < 0.005 * expected,
+            "{direct} with direct lighting only, expected {expected}"
+        );
+        assert_eq!(radiance(Some(0)), DVec4::ZERO);
+        let all = radiance(None);
+        assert!(
+            all.min_element() > 1.05 * expected,
+            "{all} with the wall's bounce light, {expected} without"
+        );
+    }
+
+    #[cfg(feature = "raycast-stats")]
+    #[test]
+    fn two_bounce_paths_peak_at_two_bounces() {
+        random::reseed([2423, 0, 0]);
+        let white = || Material {
+            emission: crate::spectrum::ZERO,
+            brdf: LambertianBrdf {
+                albedo: ConstantSpectrum(1.0),
+            },
+            enter_medium: (),
+            exit_medium: (),
+        };
+        // a closed white box around the origin, facing in, which nothing escapes or is absorbed by
+        let mut scene = Scene::new();
+        for (origin, u, v) in [
+            (DVec3::splat(-1.0), DVec3::Z, DVec3::X),
+            (DVec3::new(-1.0, 1.0, -1.0), DVec3::X, DVec3::Z),
+            (DVec3::splat(-1.0), DVec3::Y, DVec3::Z),
+            (DVec3::new(1.0, -1.0, -1.0), DVec3::Z, DVec3::Y),
+            (DVec3::splat(-1.0), DVec3::X, DVec3::Y),
+            (DVec3::new(-1.0, -1.0, 1.0), DVec3::Y, DVec3::X),
+        ] {
+            scene.add(Quad {
+                origin,
+                u: u * 2.0,
+                v: v * 2.0,
+                material: white(),
+            });
+        }
+
+        let lambdas = DVec4::new(450.0, 520.0, 600.0, 680.0);
+        stats::take_local_paths();
+        for _ in 0..1000 {
+            let dir = random::sphere(random::rng().gen());
+            path_trace(&scene, DVec3::ZERO, dir, lambdas, &Vacuum, Some(2));
+        }
+        let lengths = stats::take_local_paths();
+        assert_eq!(lengths.paths(), 1000);
+        assert_eq!(lengths.modes(), (2, 0));
+        assert_eq!(lengths.surface[2], 1000, "{:?}", lengths.surface);
+    }
+}
@@ -0,0 +1,121 @@
+//! Renders small seeded images with the `pbr` binary and compares them against the EXRs checked
+//! in under `tests/references`, so that changes to the output don't go unnoticed.
+//!
+//! Seeded renders repeat exactly on one machine, but libm and the compiler may round differently
+//! elsewhere, so every channel of every pixel only has to be within [`TOLERANCE`] of the reference,
+//! relative to the larger of the two.
+//!
+//! After a change that is meant to alter the images, regenerate the references with
+//!
+//! ```text
+//! PBR_UPDATE_REFERENCES=1 cargo test --release --test reference
+//! ```
+//!
+//! and check the new files into `tests/references` along with it, after looking at them in the
+//! viewer.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use exr::prelude::*;
+
+const TOLERANCE: f32 = 1e-3;
+
+const WIDTH: usize = 16;
+const HEIGHT: usize = 12;
+
+/// Values this small count as zero, so that the relative error of nearly empty pixels doesn't blow
+/// up.
+const FLOOR: f32 = 1e-6;
+
+fn reference_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/references")
+        .join(format!("{name}.exr"))
+}
+
+/// Renders `scene` at [`WIDTH`]x[`HEIGHT`] with a fixed seed into a directory of its own, returning the output
+/// file.
+fn render(name: &str, scene: &str, args: &[&str]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pbr-test-{}-{name}", std::process::id()));
+    // progressive passes are saved to `partial/` beside the output
+    std::fs::create_dir_all(dir.join("partial")).unwrap();
+    let output = dir.join("render.exr");
+    let out = Command::new(env!("CARGO_BIN_EXE_pbr"))
+        .arg(format!("-W{WIDTH}"))
+        .arg(format!("-H{HEIGHT}"))
+        .args(["-s16", "--render-seed", "1", "--scene", scene])
+        .args(args)
+        .arg("--output")
+        .arg(&output)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "rendering {scene} failed: {}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    output
+}
+
+/// Every channel of every layer, keyed by layer and channel name.
+fn channels(path: &Path) -> Vec<(String, Vec<f32>)> {
+    let image = read_all_flat_layers_from_file(path)
+        .unwrap_or_else(|e| panic!("failed to read {}: {e}", path.display()));
+    let mut channels = vec![];
+    for layer in &image.layer_data {
+        let layer_name = layer.attributes.layer_name.as_ref().map(Text::to_string);
+        for channel in &layer.channel_data.list {
+            let name = format!("{}.{}", layer_name.as_deref().unwrap_or(""), channel.name);
+            channels.push((name, channel.sample_data.values_as_f32().collect()));
+        }
+    }
+    channels
+}
+
+fn check(name: &str, scene: &str, args: &[&str]) {
+    let rendered = render(name, scene, args);
+    let reference = reference_path(name);
+    if std::env::var_os("PBR_UPDATE_REFERENCES").is_some() {
+        std::fs::create_dir_all(reference.parent().unwrap()).unwrap();
+        std::fs::copy(&rendered, &reference).unwrap();
+    }
+    let actual = channels(&rendered);
+    std::fs::remove_dir_all(rendered.parent().unwrap()).unwrap();
+    let expected = channels(&reference);
+
+    let names = |channels: &[(String, Vec<f32>)]| -> Vec<String> {
+        channels.iter().map(|(name, _)| name.clone()).collect()
+    };
+    assert_eq!(names(&actual), names(&expected), "{name}: channels differ");
+    for ((channel, actual), (_, expected)) in actual.iter().zip(&expected) {
+        assert_eq!(actual.len(), expected.len(), "{name}: size of {channel}");
+        for (i, (&a, &e)) in actual.iter().zip(expected).enumerate() {
+            let (x, y) = (i % WIDTH, i / WIDTH);
+            let error = (a - e).abs() / a.abs().max(e.abs()).max(FLOOR);
+            assert!(
+                error <= TOLERANCE,
+                "{name}: {channel} at ({x}, {y}) is {a}, the reference has {e}"
+            );
+        }
+    }
+}
+
+#[test]
+fn cornell_box_matches_its_reference() {
+    check("cornell-box", "cornell-box", &[]);
+}
+
+#[test]
+fn simple_volume_matches_its_reference() {
+    check("simple-volume", "simple-volume", &[]);
+}
+
+#[test]
+fn cornell_box_normals_match_their_reference() {
+    check(
+        "cornell-box-normals",
+        "cornell-box",
+        &["--integrator", "normals"],
+    );
+}
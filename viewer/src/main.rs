@@ -1,9 +1,9 @@
 mod egui_setup;
+mod source;
 mod tonemap;
 mod viewer;
 
 use std::error::Error;
-use std::ops::{Index, IndexMut};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -11,12 +11,16 @@ use clap::Parser;
 use egui::{Slider, Ui, Widget};
 use egui_setup::EguiSetup;
 use glam::{Vec2, Vec3, Vec4};
-use tonemap::TonemapOptions;
+use imagebuf::{CameraExposure, Image};
+use source::ImageSource;
+use tonemap::{DisplaySettings, GamutMapping, LuminanceStats, TonemapOptions, Transfer};
 use viewer::Viewer;
 use winit::application::ApplicationHandler;
 use winit::dpi::PhysicalSize;
 use winit::event::{MouseButton, WindowEvent};
 use winit::event_loop::{ActiveEventLoop, EventLoop, EventLoopProxy};
+use winit::keyboard::{Key, NamedKey};
+use winit::monitor::MonitorHandle;
 use winit::window::{Window, WindowAttributes, WindowId};
 
 struct App {
@@ -32,7 +36,9 @@ struct App {
     viewer: Viewer,
 
     tonemappers: Vec<TonemapOptions>,
+    display: DisplaySettings,
     images: Vec<(String, Arc<Image<Vec3>>, PathBuf)>,
+    sources: Vec<ImageSource>,
     selected: usize,
     scale: f32,
 
@@ -42,6 +48,10 @@ struct App {
 
     panning: bool,
     last_pos: Vec2,
+
+    resize_pending: bool,
+    recreate_surface_pending: bool,
+    size_warning: Option<String>,
 }
 
 #[derive(Parser)]
@@ -50,37 +60,101 @@ struct Options {
     tonemap: Option<String>,
     #[arg(long)]
     adapting_luminance: Option<f32>,
+    /// Box-downsample the tonemapped PNG export by this factor
+    #[arg(long, default_value_t = 1)]
+    preview_scale: usize,
+    /// After each `--tonemap` export, print the percentage of pixels at 0 and at 255 in each
+    /// channel, to catch crushed shadows and clipped highlights
+    #[arg(long)]
+    clip_report: bool,
+    /// Also write a copy of each `--tonemap` export next to it as `<name>.clip.png`, with pixels
+    /// that have a channel at 255 in magenta and the rest of those with a channel at 0 in cyan
+    #[arg(long)]
+    clip_mask: bool,
+    /// Dither the `--tonemap` export instead of rounding each channel to the nearest level, to
+    /// break up banding in smooth gradients
+    #[arg(long)]
+    dither: bool,
+    /// Exposure adjustment in stops, applied before tonemapping
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers(true))]
+    ev: f32,
+    /// Expose like a camera at this ISO speed, so that luminance is shown relative to what
+    /// saturates the camera instead of to the image's average. The settings that aren't given are
+    /// those of the sunny 16 rule: ISO 100, 1/100 s at f/16.
+    #[arg(long)]
+    iso: Option<f64>,
+    /// Shutter time of the camera exposure in seconds, e.g. `0.004` or `1/250`
+    #[arg(long, value_parser = imagebuf::parse_shutter)]
+    shutter: Option<f64>,
+    /// Aperture of the camera exposure as an f-number
+    #[arg(long)]
+    f_stop: Option<f64>,
+    /// How colors outside the sRGB gamut are displayed
+    #[arg(long, value_enum, default_value_t = GamutMapping::Desaturate)]
+    gamut: GamutMapping,
+    #[arg(long, value_enum, default_value_t = Transfer::Srgb)]
+    transfer: Transfer,
     #[arg(required = true)]
     imgs: Vec<PathBuf>,
 }
 
+impl Options {
+    fn camera_exposure(&self) -> Option<CameraExposure> {
+        CameraExposure::from_args(self.iso, self.shutter, self.f_stop)
+    }
+
+    fn display(&self) -> DisplaySettings {
+        DisplaySettings::new(self.ev, self.camera_exposure(), self.gamut, self.transfer)
+    }
+
+    /// The adapting luminance of `--adapting-luminance`, or with a camera exposure, its saturation.
+    /// The camera has already scaled the image by then, so the tonemappers don't adapt to the
+    /// image's own average and undo it.
+    fn adapting_luminance(&self) -> Option<f32> {
+        match self.camera_exposure() {
+            Some(_) => Some(self.adapting_luminance.unwrap_or(1.0)),
+            None => self.adapting_luminance,
+        }
+    }
+}
+
 type InitArgs = (
     EventLoopProxy<Image<Vec4>>,
     Options,
     Vec<(String, Arc<Image<Vec3>>, PathBuf)>,
+    Vec<ImageSource>,
 );
 
 impl App {
-    async fn new(el: &ActiveEventLoop, (proxy, options, images): InitArgs) -> Self {
+    async fn new(el: &ActiveEventLoop, (proxy, options, images, sources): InitArgs) -> Self {
+        let display = options.display();
         let tonemappers = images
             .iter()
             .map(|(_, img, _)| {
-                let mut opt = TonemapOptions::new(img.clone(), proxy.clone());
-                if let Some(al) = options.adapting_luminance {
+                let mut opt = TonemapOptions::new(img.clone(), display.clone(), proxy.clone());
+                if let Some(al) = options.adapting_luminance() {
                     opt.set_adapting_luminance(al);
                 }
                 opt
             })
             .collect();
 
+        // the device doesn't exist yet, so assume the default texture size limit for now
+        let requested =
+            PhysicalSize::new(images[0].1.width as u32 * 3, images[0].1.height as u32 * 3);
+        let initial_size = clamp_size(
+            requested,
+            max_window_size(
+                el.primary_monitor(),
+                wgpu::Limits::default().max_texture_dimension_2d,
+            ),
+        );
+        let size_warning = size_warning(requested, initial_size);
         let window = el
             .create_window(
                 WindowAttributes::default()
                     .with_title(format!("MinusKelvin PBR Viewer - {}", images[0].0))
-                    .with_inner_size(PhysicalSize::new(
-                        images[0].1.width as u32 * 3,
-                        images[0].1.height as u32 * 3,
-                    ))
+                    .with_inner_size(initial_size)
                     .with_resizable(false),
             )
             .unwrap();
@@ -114,18 +188,8 @@ impl App {
         let formats = surface.get_capabilities(&adapter).formats;
         dbg!(&formats);
 
-        let hdr_format = formats
-            .iter()
-            .copied()
-            .find(|f| matches!(f, wgpu::TextureFormat::Rgba16Float));
-        dbg!(hdr_format);
-
-        let sdr_format = formats
-            .iter()
-            .copied()
-            .find(|f| f.is_srgb())
-            .unwrap_or_else(|| panic!("no sdr format available?"));
-        dbg!(sdr_format);
+        let (hdr_format, sdr_format) = surface_formats(&formats);
+        let sdr_format = sdr_format.unwrap_or_else(|| panic!("no sdr format available?"));
 
         let size = window.inner_size();
         let config = wgpu::SurfaceConfiguration {
@@ -162,7 +226,9 @@ impl App {
             viewer,
 
             tonemappers,
+            display,
             images,
+            sources,
             selected: 0,
             scale: 3.0,
             mpos: None,
@@ -171,6 +237,10 @@ impl App {
 
             panning: false,
             last_pos: Vec2::ZERO,
+
+            resize_pending: false,
+            recreate_surface_pending: false,
+            size_warning,
         };
         this.tonemappers[0].refresh();
         this
@@ -181,6 +251,43 @@ impl App {
         self.tonemappers[self.selected].updated();
     }
 
+    fn select_image(&mut self, selected: usize) {
+        self.selected = selected;
+        self.tonemappers[self.selected].set_display(self.display.clone());
+        self.tonemappers[self.selected].refresh();
+        self.window.set_title(&self.images[self.selected].0);
+        self.resize_pending = true;
+    }
+
+    fn key_pressed(&mut self, key: Key) {
+        let count = self.images.len();
+        match key.as_ref() {
+            Key::Named(NamedKey::ArrowLeft | NamedKey::PageUp) => {
+                self.select_image(cycle(self.selected, count, -1))
+            }
+            Key::Named(NamedKey::ArrowRight | NamedKey::PageDown) => {
+                self.select_image(cycle(self.selected, count, 1))
+            }
+            Key::Character("h") => {
+                if let Some(hdr_format) = self.hdr_format {
+                    if self.config.format == hdr_format {
+                        self.config.format = self.sdr_format;
+                    } else {
+                        self.config.format = hdr_format;
+                    }
+                    self.recreate_surface_pending = true;
+                }
+            }
+            Key::Character("r") => {
+                self.viewer.yaw = 0.0;
+                self.viewer.pitch = 0.0;
+                self.scale = 3.0;
+                self.resize_pending = true;
+            }
+            _ => {}
+        }
+    }
+
     fn event(&mut self, event: WindowEvent, el: &ActiveEventLoop) {
         let response = self.egui.event(&event);
         if response.consumed {
@@ -228,14 +335,18 @@ impl App {
                 button: MouseButton::Left,
                 ..
             } => self.panning = state.is_pressed(),
+            WindowEvent::KeyboardInput { event, .. } if event.state.is_pressed() => {
+                self.key_pressed(event.logical_key)
+            }
             WindowEvent::RedrawRequested => match self.surface.get_current_texture() {
                 Ok(frame) => {
                     let output = frame.texture.create_view(&Default::default());
 
                     let mut encoder = self.device.create_command_encoder(&Default::default());
 
-                    let mut recreate_surface = false;
-                    let mut resize = false;
+                    let mut recreate_surface = std::mem::take(&mut self.recreate_surface_pending);
+                    let mut resize = std::mem::take(&mut self.resize_pending);
+                    let mut slid = false;
 
                     let render = |ui: &mut Ui| {
                         match self.mpos {
@@ -246,16 +357,16 @@ impl App {
                         };
 
                         if self.images.len() > 1 {
-                            let changed =
-                                Slider::new(&mut self.selected, 0..=self.images.len() - 1)
-                                    .drag_value_speed(0.1)
-                                    .ui(ui)
-                                    .changed();
-                            if changed {
-                                resize |= true;
-                                self.tonemappers[self.selected].refresh();
-                                self.window.set_title(&self.images[self.selected].0);
-                            }
+                            slid = Slider::new(&mut self.selected, 0..=self.images.len() - 1)
+                                .drag_value_speed(0.1)
+                                .ui(ui)
+                                .changed();
+                        }
+
+                        if self.sources[self.selected].ui(ui) {
+                            let image = Arc::new(self.sources[self.selected].image());
+                            self.images[self.selected].1 = image.clone();
+                            self.tonemappers[self.selected].set_image(image);
                         }
 
                         resize |= Slider::new(&mut self.scale, 0.5..=8.0)
@@ -263,16 +374,35 @@ impl App {
                             .ui(ui)
                             .changed();
 
-                        if let Some(hdr_format) = self.hdr_format {
-                            let mut hdr = self.config.format == hdr_format;
-                            if ui.checkbox(&mut hdr, "HDR").changed() {
-                                if hdr {
-                                    self.config.format = hdr_format;
-                                } else {
-                                    self.config.format = self.sdr_format;
+                        if let Some(warning) = &self.size_warning {
+                            ui.colored_label(egui::Color32::YELLOW, warning);
+                        }
+
+                        match self.hdr_format {
+                            Some(hdr_format) => {
+                                let mut hdr = self.config.format == hdr_format;
+                                if ui.checkbox(&mut hdr, "HDR").changed() {
+                                    if hdr {
+                                        self.config.format = hdr_format;
+                                    } else {
+                                        self.config.format = self.sdr_format;
+                                    }
+                                    recreate_surface = true;
                                 }
-                                recreate_surface = true;
                             }
+                            None => {
+                                ui.add_enabled(false, egui::Checkbox::new(&mut false, "HDR"))
+                                    .on_disabled_hover_text(
+                                        "HDR surface not supported on this display",
+                                    );
+                            }
+                        }
+
+                        ui.separator();
+
+                        if self.display.ui(ui) {
+                            self.tonemappers[self.selected].set_display(self.display.clone());
+                            self.tonemappers[self.selected].refresh();
                         }
 
                         ui.separator();
@@ -283,6 +413,11 @@ impl App {
                     self.egui
                         .render(&self.device, &self.queue, &mut encoder, render);
 
+                    if slid {
+                        self.select_image(self.selected);
+                        resize |= std::mem::take(&mut self.resize_pending);
+                    }
+
                     {
                         let mut rp = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                             label: None,
@@ -314,10 +449,14 @@ impl App {
                             (self.scale * self.images[self.selected].1.width as f32).round() as u32;
                         let height = (self.scale * self.images[self.selected].1.height as f32)
                             .round() as u32;
-                        if let Some(size) = self
-                            .window
-                            .request_inner_size(PhysicalSize::new(width, height))
-                        {
+                        let requested = PhysicalSize::new(width, height);
+                        let max = max_window_size(
+                            self.window.current_monitor(),
+                            self.device.limits().max_texture_dimension_2d,
+                        );
+                        let clamped = clamp_size(requested, max);
+                        self.size_warning = size_warning(requested, clamped);
+                        if let Some(size) = self.window.request_inner_size(clamped) {
                             self.config.width = size.width;
                             self.config.height = size.height;
                             self.egui.resize(&self.device, size);
@@ -342,113 +481,142 @@ impl App {
     }
 }
 
-struct Image<T> {
-    data: Box<[T]>,
-    width: usize,
-    height: usize,
+/// Picks the surface formats to present with: a float format for HDR output if the surface offers
+/// one, and an sRGB format for SDR. The displayed image is tonemapped in float either way, so
+/// without an HDR format only the final blit is limited to SDR.
+fn surface_formats(
+    formats: &[wgpu::TextureFormat],
+) -> (Option<wgpu::TextureFormat>, Option<wgpu::TextureFormat>) {
+    let hdr = formats
+        .iter()
+        .copied()
+        .find(|f| matches!(f, wgpu::TextureFormat::Rgba16Float));
+    let sdr = formats.iter().copied().find(|f| f.is_srgb());
+    (hdr, sdr)
 }
 
-impl<T> Image<T> {
-    fn new(width: usize, height: usize, init: impl Fn(usize, usize) -> T) -> Self {
-        let init = &init;
-        let data = (0..height)
-            .flat_map(|y| (0..width).map(move |x| init(x, y)))
-            .collect();
-        Image {
-            data,
-            width,
-            height,
-        }
-    }
-
-    fn from_pixel(width: usize, height: usize, pixel: T) -> Self
-    where
-        T: Clone,
-    {
-        Self::new(width, height, |_, _| pixel.clone())
-    }
+/// The image `step` images on from `selected` out of `count`, wrapping around at the ends.
+fn cycle(selected: usize, count: usize, step: isize) -> usize {
+    (selected as isize + step).rem_euclid(count as isize) as usize
 }
 
-impl<T> Index<(usize, usize)> for Image<T> {
-    type Output = T;
+/// The largest window that fits on `monitor` and whose surface the device can create.
+fn max_window_size(monitor: Option<MonitorHandle>, max_texture: u32) -> PhysicalSize<u32> {
+    let monitor = monitor.map_or(PhysicalSize::new(u32::MAX, u32::MAX), |m| m.size());
+    PhysicalSize::new(
+        monitor.width.min(max_texture),
+        monitor.height.min(max_texture),
+    )
+}
 
-    fn index(&self, (x, y): (usize, usize)) -> &Self::Output {
-        assert!(x < self.width);
-        assert!(y < self.height);
-        &self.data[x + y * self.width]
+/// Uniformly shrinks `size` to fit within `max`, keeping the aspect ratio.
+fn clamp_size(size: PhysicalSize<u32>, max: PhysicalSize<u32>) -> PhysicalSize<u32> {
+    if size.width <= max.width && size.height <= max.height {
+        return size;
     }
+    let factor = (max.width as f64 / size.width as f64).min(max.height as f64 / size.height as f64);
+    PhysicalSize::new(
+        ((size.width as f64 * factor) as u32).clamp(1, max.width),
+        ((size.height as f64 * factor) as u32).clamp(1, max.height),
+    )
 }
 
-impl<T> IndexMut<(usize, usize)> for Image<T> {
-    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut Self::Output {
-        assert!(x < self.width);
-        assert!(y < self.height);
-        &mut self.data[x + y * self.width]
-    }
+fn size_warning(requested: PhysicalSize<u32>, clamped: PhysicalSize<u32>) -> Option<String> {
+    (clamped != requested).then(|| {
+        format!(
+            "Window limited to {}x{} (requested {}x{})",
+            clamped.width, clamped.height, requested.width, requested.height
+        )
+    })
 }
 
 fn main() {
     let mut options = Options::parse();
 
     let mut images = vec![];
+    let mut sources = vec![];
     for path in options.imgs.drain(..) {
-        use exr::prelude::*;
-        let image = read()
-            .no_deep_data()
-            .largest_resolution_level()
-            .rgb_channels(
-                |size, _| crate::Image::from_pixel(size.0, size.1, Vec3::ZERO),
-                |pixels, xy, p| pixels[(xy.0, xy.1)] = Vec3::from(p),
-            )
-            .first_valid_layer()
-            .all_attributes()
-            .from_file(&path)
-            .unwrap();
-        let image = image.layer_data.channel_data.pixels;
+        let source = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("png" | "jpg" | "jpeg") => ImageSource::load_ldr(&path),
+            _ => ImageSource::load_exr(&path),
+        }
+        .unwrap_or_else(|e| {
+            eprintln!("error: {}: {e}", path.display());
+            std::process::exit(1);
+        });
 
         images.push((
             path.file_stem().unwrap().to_string_lossy().into_owned(),
-            Arc::new(image),
+            Arc::new(source.image()),
             path.to_owned(),
         ));
+        sources.push(source);
     }
     if images.is_empty() {
         eprintln!("At least one image must be provided");
         std::process::exit(1);
     }
 
-    if let Some(mapper) = options.tonemap {
+    if let Some(mapper) = options.tonemap.take() {
         for (_, image, path) in images {
+            let stats = LuminanceStats::new(&image);
+            let exposure = options.display().exposure();
             let result = match &*mapper {
                 "krawczyk2005" | "default" => {
-                    let mut mapper = tonemap::krawczyk_2005::Options::new(&image);
-                    if let Some(al) = options.adapting_luminance {
+                    let mut mapper = tonemap::krawczyk_2005::Options::new(&stats);
+                    if let Some(al) = options.adapting_luminance() {
                         mapper.set_adapting_luminance(al);
                     }
-                    mapper.process(&image)
+                    mapper.process(&image, exposure)
                 }
                 "none" => {
-                    let mut mapper = tonemap::none::Options::new(&image);
-                    if let Some(al) = options.adapting_luminance {
+                    let mut mapper = tonemap::none::Options::new(&stats);
+                    if let Some(al) = options.adapting_luminance() {
+                        mapper.set_adapting_luminance(al);
+                    }
+                    mapper.process(&image, exposure)
+                }
+                "reinhard2002" => {
+                    let mut mapper = tonemap::reinhard_2002::Options::new(&stats);
+                    if let Some(al) = options.adapting_luminance() {
                         mapper.set_adapting_luminance(al);
                     }
-                    mapper.process(&image)
+                    mapper.process(&image, exposure)
                 }
                 _ => {
                     eprintln!("unrecognized tonemapper: {mapper}");
                     std::process::exit(1);
                 }
             };
+            let result = Image::par_new(result.width, result.height, |x, y| {
+                let p = result[(x, y)];
+                options.gamut.apply(p.truncate()).extend(p.w)
+            });
+            let result = match options.preview_scale {
+                0 | 1 => result,
+                factor => downsample(&result, factor),
+            };
 
-            let img =
-                image::RgbaImage::from_fn(result.width as u32, result.height as u32, |x, y| {
-                    image::Rgba(
-                        result[(x as usize, y as usize)]
-                            .map(|v| (egui::ecolor::gamma_from_linear(v) * 255.0).round())
-                            .as_u8vec4()
-                            .to_array(),
-                    )
-                });
+            let img = encode_8bit(&result, options.transfer, options.dither);
+            if options.clip_report {
+                let [low, high] = clipped_fractions(&img).map(|f| f.map(|f| f * 100.0));
+                println!(
+                    "{}: clipped to 0: R {:.2}%, G {:.2}%, B {:.2}%; to 255: R {:.2}%, G {:.2}%, \
+                     B {:.2}%",
+                    path.with_extension("png").display(),
+                    low[0],
+                    low[1],
+                    low[2],
+                    high[0],
+                    high[1],
+                    high[2]
+                );
+            }
+            if options.clip_mask {
+                clip_mask(&img)
+                    .save(path.with_extension("clip.png"))
+                    .unwrap();
+            }
             img.save(path.with_extension("png")).unwrap();
         }
         std::process::exit(0);
@@ -459,11 +627,28 @@ fn main() {
 
     el.run_app(&mut LateinitApp {
         app: None,
-        args: Some((proxy, options, images)),
+        args: Some((proxy, options, images, sources)),
     })
     .unwrap();
 }
 
+/// Averages `factor`x`factor` blocks. This must be done on linear values; averaging after gamma
+/// encoding darkens high-contrast detail.
+fn downsample(image: &Image<Vec4>, factor: usize) -> Image<Vec4> {
+    let width = image.width.div_ceil(factor);
+    let height = image.height.div_ceil(factor);
+    Image::new(width, height, |x, y| {
+        let xs = x * factor..((x + 1) * factor).min(image.width);
+        let ys = y * factor..((y + 1) * factor).min(image.height);
+        let count = (xs.len() * ys.len()) as f32;
+        let sum: Vec4 = ys
+            .flat_map(|y| xs.clone().map(move |x| (x, y)))
+            .map(|p| image[p])
+            .sum();
+        sum / count
+    })
+}
+
 struct LateinitApp {
     app: Option<App>,
     args: Option<InitArgs>,
@@ -489,3 +674,160 @@ impl ApplicationHandler<Image<Vec4>> for LateinitApp {
         }
     }
 }
+
+/// Encodes a tonemapped image with `transfer` and rounds it to 8 bits, or with `dither`, up or down
+/// by [`dither_threshold`](imagebuf::dither_threshold).
+fn encode_8bit(image: &Image<Vec4>, transfer: Transfer, dither: bool) -> image::RgbaImage {
+    image::RgbaImage::from_fn(image.width as u32, image.height as u32, |x, y| {
+        let (x, y) = (x as usize, y as usize);
+        let offset = match dither {
+            true => imagebuf::dither_threshold(x, y) as f32,
+            false => 0.5,
+        };
+        image::Rgba(
+            image[(x, y)]
+                .map(|v| (transfer.encode(v) * 255.0 + offset).floor())
+                .as_u8vec4()
+                .to_array(),
+        )
+    })
+}
+
+/// The fractions of `image`'s pixels with each of the red, green and blue channels at 0, then at
+/// 255.
+fn clipped_fractions(image: &image::RgbaImage) -> [[f64; 3]; 2] {
+    let mut counts = [[0usize; 3]; 2];
+    for p in image.pixels() {
+        for (c, &v) in p.0[..3].iter().enumerate() {
+            counts[0][c] += (v == 0) as usize;
+            counts[1][c] += (v == 255) as usize;
+        }
+    }
+    let pixels = image.pixels().len().max(1) as f64;
+    counts.map(|counts| counts.map(|n| n as f64 / pixels))
+}
+
+/// `image` with the pixels that have a channel at 255 in magenta, and the rest of those with a
+/// channel at 0 in cyan.
+fn clip_mask(image: &image::RgbaImage) -> image::RgbaImage {
+    image::RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+        let p = *image.get_pixel(x, y);
+        let rgb = &p.0[..3];
+        if rgb.contains(&255) {
+            image::Rgba([255, 0, 255, 255])
+        } else if rgb.contains(&0) {
+            image::Rgba([0, 255, 255, 255])
+        } else {
+            p
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downsampled_checkerboard_is_mid_gray() {
+        let checkerboard = Image::new(8, 6, |x, y| Vec4::splat(((x + y) % 2) as f32));
+        let small = downsample(&checkerboard, 2);
+        assert_eq!((small.width, small.height), (4, 3));
+        for y in 0..3 {
+            for x in 0..4 {
+                assert_eq!(small[(x, y)], Vec4::splat(0.5), "pixel ({x}, {y})");
+            }
+        }
+
+        // half the light encodes to 188, where averaging the encoded 0 and 255 would give 128
+        let encoded = (Transfer::Srgb.encode(small[(0, 0)].x) * 255.0 + 0.5).floor();
+        assert_eq!(encoded, 188.0);
+    }
+
+    #[test]
+    fn arrow_keys_cycle_through_the_images() {
+        assert_eq!(cycle(0, 3, 1), 1);
+        assert_eq!(cycle(2, 3, 1), 0);
+        assert_eq!(cycle(0, 3, -1), 2);
+        assert_eq!(cycle(1, 3, -1), 0);
+        assert_eq!(cycle(0, 1, 1), 0);
+        assert_eq!(cycle(0, 1, -1), 0);
+    }
+
+    #[test]
+    fn oversized_windows_are_clamped() {
+        let size = PhysicalSize::new;
+        let max = size(2560, 1440);
+        // fits already
+        assert_eq!(clamp_size(size(1920, 1080), max), size(1920, 1080));
+        assert_eq!(clamp_size(size(2560, 1440), max), size(2560, 1440));
+        // too wide, too tall, and both, shrunk keeping the aspect ratio
+        assert_eq!(clamp_size(size(5120, 1440), max), size(2560, 720));
+        assert_eq!(clamp_size(size(1000, 2880), max), size(500, 1440));
+        assert_eq!(clamp_size(size(7680, 4320), max), size(2560, 1440));
+        // a sliver keeps at least a pixel
+        assert_eq!(clamp_size(size(100_000, 10), max), size(2560, 1));
+
+        let requested = size(6000, 3000);
+        let clamped = clamp_size(requested, max_window_size(None, 4096));
+        assert_eq!(clamped, size(4096, 2048));
+        assert_eq!(
+            size_warning(requested, clamped).as_deref(),
+            Some("Window limited to 4096x2048 (requested 6000x3000)")
+        );
+        assert_eq!(size_warning(clamped, clamped), None);
+    }
+
+    #[test]
+    fn hdr_is_offered_only_with_a_float_format() {
+        use wgpu::TextureFormat::*;
+
+        assert_eq!(
+            surface_formats(&[Bgra8Unorm, Bgra8UnormSrgb, Rgba16Float]),
+            (Some(Rgba16Float), Some(Bgra8UnormSrgb))
+        );
+        // without a float format there's still an SDR one, and the HDR toggle is disabled
+        assert_eq!(
+            surface_formats(&[Bgra8Unorm, Rgba8UnormSrgb, Rgb10a2Unorm]),
+            (None, Some(Rgba8UnormSrgb))
+        );
+        assert_eq!(surface_formats(&[Rgba16Float]), (Some(Rgba16Float), None));
+        assert_eq!(surface_formats(&[]), (None, None));
+    }
+
+    #[test]
+    fn clip_reports_count_the_clipped_pixels() {
+        // black on the left, far too bright on the right, and mid-gray in between
+        let value = |x: usize| match x {
+            0..4 => 0.0,
+            12.. => 1000.0,
+            _ => 0.2,
+        };
+        let path =
+            std::env::temp_dir().join(format!("viewer-test-{}-clip.exr", std::process::id()));
+        exr::prelude::write_rgb_file(&path, 16, 8, |x, _| {
+            let v = value(x);
+            (v, v, v)
+        })
+        .unwrap();
+        let image = ImageSource::load_exr(&path).unwrap().image();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut mapper = tonemap::none::Options::new(&LuminanceStats::new(&image));
+        mapper.set_adapting_luminance(1.0);
+        let encoded = encode_8bit(&mapper.process(&image, 1.0), Transfer::Srgb, false);
+
+        let pixels = 16.0 * 8.0;
+        let count = |f: &dyn Fn(f32) -> bool| {
+            (0..16).filter(|&x| f(value(x))).count() as f64 * 8.0 / pixels
+        };
+        let (black, bright) = (count(&|v| v == 0.0), count(&|v| v >= 1000.0));
+        assert!(black > 0.0 && bright > 0.0);
+        assert_eq!(clipped_fractions(&encoded), [[black; 3], [bright; 3]]);
+
+        let mask = clip_mask(&encoded);
+        let magenta = mask.pixels().filter(|p| p.0 == [255, 0, 255, 255]).count();
+        let cyan = mask.pixels().filter(|p| p.0 == [0, 255, 255, 255]).count();
+        assert_eq!(magenta as f64 / pixels, bright);
+        assert_eq!(cyan as f64 / pixels, black);
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::Path;
+
+use egui::{ComboBox, Ui};
+use glam::Vec3;
+
+use crate::Image;
+
+/// Every layer and channel of a loaded file, and which of them are shown as the displayed XYZ.
+pub struct ImageSource {
+    layers: Vec<SourceLayer>,
+    layer: usize,
+    channels: [usize; 3],
+    grayscale: bool,
+}
+
+struct SourceLayer {
+    name: String,
+    width: usize,
+    height: usize,
+    channels: Vec<(String, Box<[f32]>)>,
+}
+
+impl ImageSource {
+    /// Loads every layer of an EXR that has a channel to show. Subsampled channels, which have
+    /// fewer pixels than their layer, are left out, as are layers with nothing else.
+    pub fn load_exr(path: &Path) -> Result<Self, String> {
+        use exr::prelude::*;
+
+        let image = read_all_flat_layers_from_file(path).map_err(|e| e.to_string())?;
+        let layers: Vec<_> = image
+            .layer_data
+            .into_iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                let pixels = layer.size.0 * layer.size.1;
+                SourceLayer {
+                    name: match layer.attributes.layer_name {
+                        Some(name) => name.to_string(),
+                        None => format!("Layer {i}"),
+                    },
+                    width: layer.size.0,
+                    height: layer.size.1,
+                    channels: layer
+                        .channel_data
+                        .list
+                        .into_iter()
+                        .filter(|c| c.sample_data.len() == pixels)
+                        .map(|c| (c.name.to_string(), c.sample_data.values_as_f32().collect()))
+                        .collect(),
+                }
+            })
+            .filter(|layer| !layer.channels.is_empty())
+            .collect();
+        if layers.is_empty() {
+            return Err("no layer has a channel that can be shown".to_owned());
+        }
+
+        let mut this = ImageSource {
+            layers,
+            layer: 0,
+            channels: [0; 3],
+            grayscale: false,
+        };
+        let layer = (0..this.layers.len())
+            .find(|&i| this.layers[i].rgb().is_some())
+            .unwrap_or(0);
+        this.select_layer(layer);
+        Ok(this)
+    }
+
+    /// Loads an 8-bit sRGB image (PNG, JPEG) as a single XYZ layer.
+    pub fn load_ldr(path: &Path) -> Result<Self, String> {
+        let image = image::open(path).map_err(|e| e.to_string())?.into_rgb8();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let xyz: Vec<_> = image
+            .pixels()
+            .map(|p| crate::tonemap::srgb_to_xyz(Vec3::from(p.0.map(|c| c as f32 / 255.0))))
+            .collect();
+        let channels = ["X", "Y", "Z"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (name.to_owned(), xyz.iter().map(|v| v[i]).collect()))
+            .collect();
+
+        let mut this = ImageSource {
+            layers: vec![SourceLayer {
+                name: "sRGB".to_owned(),
+                width,
+                height,
+                channels,
+            }],
+            layer: 0,
+            channels: [0; 3],
+            grayscale: false,
+        };
+        this.select_layer(0);
+        Ok(this)
+    }
+
+    pub fn image(&self) -> Image<Vec3> {
+        let layer = &self.layers[self.layer];
+        let [r, g, b] = match self.grayscale {
+            true => [self.channels[0]; 3],
+            false => self.channels,
+        };
+        let [r, g, b] = [r, g, b].map(|c| &layer.channels[c].1);
+        Image::new(layer.width, layer.height, |x, y| {
+            let i = x + y * layer.width;
+            Vec3::new(r[i], g[i], b[i])
+        })
+    }
+
+    /// Returns `true` if the selection changed and the image needs to be re-read.
+    pub fn ui(&mut self, ui: &mut Ui) -> bool {
+        if self.layers.len() == 1 && self.layers[0].channels.len() == 3 {
+            return false;
+        }
+
+        let mut changed = false;
+
+        // the display texture has a fixed size, so only offer layers matching the current one
+        let (width, height) = (
+            self.layers[self.layer].width,
+            self.layers[self.layer].height,
+        );
+        let mut layer = self.layer;
+        ComboBox::from_label("Layer")
+            .selected_text(&self.layers[layer].name)
+            .show_ui(ui, |ui| {
+                for (i, l) in self.layers.iter().enumerate() {
+                    if l.width == width && l.height == height {
+                        ui.selectable_value(&mut layer, i, &l.name);
+                    }
+                }
+            });
+        if layer != self.layer {
+            self.select_layer(layer);
+            changed = true;
+        }
+
+        let channels = &self.layers[self.layer].channels;
+        changed |= ui.checkbox(&mut self.grayscale, "Grayscale").changed();
+        let labels: &[&str] = match self.grayscale {
+            true => &["Channel"],
+            false => &["X", "Y", "Z"],
+        };
+        for (label, selected) in labels.iter().zip(&mut self.channels) {
+            changed |= ComboBox::from_label(*label)
+                .show_index(ui, selected, channels.len(), |i| &channels[i].0)
+                .changed();
+        }
+
+        changed
+    }
+
+    fn select_layer(&mut self, layer: usize) {
+        self.layer = layer;
+        let l = &self.layers[layer];
+        self.grayscale = l.channels.len() == 1;
+        self.channels = l.rgb().unwrap_or(match l.channels.len() {
+            1 | 2 => [0; 3],
+            _ => [0, 1, 2],
+        });
+    }
+}
+
+impl SourceLayer {
+    fn rgb(&self) -> Option<[usize; 3]> {
+        let find = |name| self.channels.iter().position(|(n, _)| n == name);
+        Some([find("R")?, find("G")?, find("B")?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_any_three_channels() {
+        let path = std::env::temp_dir().join(format!("viewer-test-{}.exr", std::process::id()));
+        exr::prelude::write_rgba_file(&path, 3, 2, |x, y| {
+            let (x, y) = (x as f32, y as f32);
+            (x, y, x + y, 10.0 + x * y)
+        })
+        .unwrap();
+        let mut source = ImageSource::load_exr(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let layer = &source.layers[source.layer];
+        let find = |name| layer.channels.iter().position(|(n, _)| n == name).unwrap();
+        let [r, g, b, a] = ["R", "G", "B", "A"].map(find);
+        assert_eq!(source.channels, [r, g, b]);
+
+        source.channels = [a, b, r];
+        let image = source.image();
+        for y in 0..2 {
+            for x in 0..3 {
+                let (x_f, y_f) = (x as f32, y as f32);
+                let expected = Vec3::new(10.0 + x_f * y_f, x_f + y_f, x_f);
+                assert_eq!(image[(x, y)], expected, "pixel ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn unreadable_images_are_errors() {
+        let path = std::env::temp_dir().join(format!("viewer-test-{}-bad.exr", std::process::id()));
+        std::fs::write(&path, b"not an exr").unwrap();
+        let exr = ImageSource::load_exr(&path);
+        let ldr = ImageSource::load_ldr(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(exr.is_err());
+        assert!(ldr.is_err());
+    }
+
+    #[test]
+    fn ldr_images_load_as_xyz() {
+        let path = std::env::temp_dir().join(format!("viewer-test-{}.png", std::process::id()));
+        image::RgbImage::from_fn(2, 1, |x, _| match x {
+            0 => image::Rgb([255, 255, 255]),
+            _ => image::Rgb([255, 0, 0]),
+        })
+        .save(&path)
+        .unwrap();
+        let source = ImageSource::load_ldr(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // sRGB's D65 white point with unit luminance, and its red primary
+        let image = source.image();
+        let expected = [
+            Vec3::new(0.9505, 1.0, 1.089),
+            Vec3::new(0.4124, 0.2126, 0.0193),
+        ];
+        for (x, expected) in expected.into_iter().enumerate() {
+            assert!(
+                image[(x, 0)].abs_diff_eq(expected, 1e-3),
+                "pixel {x} is {}, expected {expected}",
+                image[(x, 0)]
+            );
+        }
+    }
+}
@@ -3,18 +3,24 @@ use std::sync::{Arc, LazyLock};
 
 use egui::{ComboBox, Slider, Ui, Widget};
 use glam::{Mat3, Vec3, Vec4};
+use imagebuf::CameraExposure;
 use winit::event_loop::EventLoopProxy;
 
 pub mod krawczyk_2005;
 pub mod none;
+pub mod reinhard_2002;
 
 use crate::Image;
 
 pub struct TonemapOptions {
     none: none::Options,
     krawczyk_2005: krawczyk_2005::Options,
+    reinhard_2002: reinhard_2002::Options,
 
     selected: usize,
+    display: DisplaySettings,
+    adapting_luminance: Option<f32>,
+    stats: LuminanceStats,
     image: Arc<Image<Vec3>>,
     needs_update: bool,
     proxy: EventLoopProxy<Image<Vec4>>,
@@ -24,17 +30,31 @@ pub struct TonemapOptions {
 enum Tonemapper {
     None,
     Krawczyk2005,
+    Reinhard2002,
 }
 
-const TONEMAPPERS: &[Tonemapper] = &[Tonemapper::None, Tonemapper::Krawczyk2005];
+const TONEMAPPERS: &[Tonemapper] = &[
+    Tonemapper::None,
+    Tonemapper::Krawczyk2005,
+    Tonemapper::Reinhard2002,
+];
 
 impl TonemapOptions {
-    pub fn new(image: Arc<Image<Vec3>>, proxy: EventLoopProxy<Image<Vec4>>) -> TonemapOptions {
+    pub fn new(
+        image: Arc<Image<Vec3>>,
+        display: DisplaySettings,
+        proxy: EventLoopProxy<Image<Vec4>>,
+    ) -> TonemapOptions {
+        let stats = LuminanceStats::new(&image);
         TonemapOptions {
-            none: none::Options::new(&image),
-            krawczyk_2005: krawczyk_2005::Options::new(&image),
+            none: none::Options::new(&stats),
+            krawczyk_2005: krawczyk_2005::Options::new(&stats),
+            reinhard_2002: reinhard_2002::Options::new(&stats),
 
             selected: 1,
+            display,
+            adapting_luminance: None,
+            stats,
             image,
             needs_update: true,
             proxy,
@@ -44,6 +64,11 @@ impl TonemapOptions {
     pub fn ui(&mut self, ui: &mut Ui) {
         let waiting = self.needs_update;
 
+        ui.label(format!(
+            "Luminance: {:.4} - {:.4} (log-average {:.4})",
+            self.stats.min, self.stats.max, self.stats.log_average
+        ));
+
         self.needs_update |= ComboBox::from_label("Algorithm")
             .show_index(ui, &mut self.selected, TONEMAPPERS.len(), |id| {
                 format!("{:?}", TONEMAPPERS[id])
@@ -53,6 +78,7 @@ impl TonemapOptions {
         match TONEMAPPERS[self.selected] {
             Tonemapper::None => self.none.ui(ui, &mut self.needs_update),
             Tonemapper::Krawczyk2005 => self.krawczyk_2005.ui(ui, &mut self.needs_update),
+            Tonemapper::Reinhard2002 => self.reinhard_2002.ui(ui, &mut self.needs_update),
         }
 
         if !waiting && self.needs_update {
@@ -70,42 +96,288 @@ impl TonemapOptions {
         self.process();
     }
 
+    pub fn set_image(&mut self, image: Arc<Image<Vec3>>) {
+        self.stats = LuminanceStats::new(&image);
+        self.image = image;
+
+        self.none = none::Options::new(&self.stats);
+        self.krawczyk_2005 = krawczyk_2005::Options::new(&self.stats);
+        self.reinhard_2002 = reinhard_2002::Options::new(&self.stats);
+        if let Some(adapting_luminance) = self.adapting_luminance {
+            self.set_adapting_luminance(adapting_luminance);
+        }
+
+        self.process();
+    }
+
+    pub fn set_display(&mut self, display: DisplaySettings) {
+        self.display = display;
+    }
+
     pub fn set_adapting_luminance(&mut self, adapting_luminance: f32) {
-        self.krawczyk_2005.set_adapting_luminance(adapting_luminance);
+        self.adapting_luminance = Some(adapting_luminance);
+        self.krawczyk_2005
+            .set_adapting_luminance(adapting_luminance);
         self.none.set_adapting_luminance(adapting_luminance);
+        self.reinhard_2002
+            .set_adapting_luminance(adapting_luminance);
     }
 
     fn process(&mut self) {
         let proxy = self.proxy.clone();
         let image = self.image.clone();
+        let display = self.display.clone();
+        let exposure = display.exposure();
         match TONEMAPPERS[self.selected] {
             Tonemapper::None => {
                 let mapper = self.none.clone();
-                std::thread::spawn(move || proxy.send_event(mapper.process(&image)));
+                std::thread::spawn(move || {
+                    proxy.send_event(display.present(mapper.process(&image, exposure)))
+                });
             }
             Tonemapper::Krawczyk2005 => {
                 let mapper = self.krawczyk_2005.clone();
-                std::thread::spawn(move || proxy.send_event(mapper.process(&image)));
+                std::thread::spawn(move || {
+                    proxy.send_event(display.present(mapper.process(&image, exposure)))
+                });
+            }
+            Tonemapper::Reinhard2002 => {
+                let mapper = self.reinhard_2002.clone();
+                std::thread::spawn(move || {
+                    proxy.send_event(display.present(mapper.process(&image, exposure)))
+                });
             }
         };
         self.needs_update = false;
     }
 }
 
+/// Settings applied to every image: an exposure before tonemapping, and a gamut mapping and an
+/// output transfer function after it.
+#[derive(Clone)]
+pub struct DisplaySettings {
+    ev: DefaultValueSlider,
+    camera: Option<CameraSettings>,
+    pub gamut: GamutMapping,
+    pub transfer: Transfer,
+}
+
+/// How colors outside the sRGB gamut, which spectral renders produce plenty of, are brought into
+/// it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum GamutMapping {
+    /// Desaturate towards gray of the same luminance until no channel is negative.
+    Desaturate,
+    /// Leave negative channels to be clipped by the display, which shifts hues and leaves black
+    /// specks. Useful to see where the image is out of gamut.
+    Clip,
+}
+
+const GAMUT_MAPPINGS: &[GamutMapping] = &[GamutMapping::Desaturate, GamutMapping::Clip];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Transfer {
+    Srgb,
+    Gamma22,
+    Linear,
+}
+
+const TRANSFERS: &[Transfer] = &[Transfer::Srgb, Transfer::Gamma22, Transfer::Linear];
+
+/// The sliders of a [`CameraExposure`], starting at the settings it was given.
+#[derive(Clone)]
+struct CameraSettings {
+    iso: DefaultValueSlider,
+    shutter_seconds: DefaultValueSlider,
+    f_stop: DefaultValueSlider,
+}
+
+impl CameraSettings {
+    fn new(camera: CameraExposure) -> Self {
+        CameraSettings {
+            iso: DefaultValueSlider::new(camera.iso as f32, 25.0..=25600.0, true),
+            shutter_seconds: DefaultValueSlider::new(
+                camera.shutter_seconds as f32,
+                1e-4..=30.0,
+                true,
+            ),
+            f_stop: DefaultValueSlider::new(camera.f_stop as f32, 1.0..=32.0, true),
+        }
+    }
+
+    fn exposure(&self) -> CameraExposure {
+        CameraExposure {
+            iso: self.iso.value as f64,
+            shutter_seconds: self.shutter_seconds.value as f64,
+            f_stop: self.f_stop.value as f64,
+        }
+    }
+}
+
+impl DisplaySettings {
+    pub fn new(
+        ev: f32,
+        camera: Option<CameraExposure>,
+        gamut: GamutMapping,
+        transfer: Transfer,
+    ) -> Self {
+        let mut ev_slider = DefaultValueSlider::new(0.0, -10.0..=10.0, false);
+        ev_slider.value = ev;
+        DisplaySettings {
+            ev: ev_slider,
+            camera: camera.map(CameraSettings::new),
+            gamut,
+            transfer,
+        }
+    }
+
+    /// Linear scale applied to the scene luminance before tonemapping. With a camera, this takes
+    /// the luminance to fractions of the camera's saturation.
+    pub fn exposure(&self) -> f32 {
+        let camera = match &self.camera {
+            Some(camera) => camera.exposure().scale() as f32,
+            None => 1.0,
+        };
+        self.ev.value.exp2() * camera
+    }
+
+    /// Returns `true` if a setting changed.
+    pub fn ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = self.ev.show(ui, "Exposure (EV)");
+
+        if let Some(camera) = &mut self.camera {
+            changed |= camera.iso.show(ui, "ISO");
+            changed |= camera.shutter_seconds.show(ui, "Shutter (s)");
+            changed |= camera.f_stop.show(ui, "Aperture (f-number)");
+            ui.label(format!("EV100: {:.2}", camera.exposure().ev100()));
+        }
+
+        let mut selected = GAMUT_MAPPINGS
+            .iter()
+            .position(|&g| g == self.gamut)
+            .unwrap();
+        changed |= ComboBox::from_label("Gamut Mapping")
+            .show_index(ui, &mut selected, GAMUT_MAPPINGS.len(), |id| {
+                format!("{:?}", GAMUT_MAPPINGS[id])
+            })
+            .changed();
+        self.gamut = GAMUT_MAPPINGS[selected];
+
+        let mut selected = TRANSFERS.iter().position(|&t| t == self.transfer).unwrap();
+        changed |= ComboBox::from_label("Transfer Function")
+            .show_index(ui, &mut selected, TRANSFERS.len(), |id| {
+                format!("{:?}", TRANSFERS[id])
+            })
+            .changed();
+        self.transfer = TRANSFERS[selected];
+
+        changed
+    }
+
+    /// Maps linear sRGB output into gamut and adjusts it so that the window's sRGB encoding shows
+    /// the selected transfer function.
+    pub fn present(&self, image: Image<Vec4>) -> Image<Vec4> {
+        if self.gamut == GamutMapping::Clip && self.transfer == Transfer::Srgb {
+            return image;
+        }
+        Image::par_new(image.width, image.height, |x, y| {
+            let p = image[(x, y)];
+            let rgb = self.gamut.apply(p.truncate());
+            match self.transfer {
+                Transfer::Srgb => rgb,
+                _ => rgb.map(|v| egui::ecolor::linear_from_gamma(self.transfer.encode(v))),
+            }
+            .extend(p.w)
+        })
+    }
+}
+
+impl GamutMapping {
+    /// Maps a linear sRGB color. Channels above 1 are left alone for HDR output.
+    pub fn apply(self, rgb: Vec3) -> Vec3 {
+        match self {
+            GamutMapping::Desaturate => desaturate_to_gamut(rgb),
+            GamutMapping::Clip => rgb,
+        }
+    }
+}
+
+/// Mixes `rgb` with the gray of the same luminance just enough that its smallest channel becomes
+/// 0. Colors with no positive luminance have no such gray and become black.
+fn desaturate_to_gamut(rgb: Vec3) -> Vec3 {
+    let min = rgb.min_element();
+    if min >= 0.0 {
+        return rgb;
+    }
+    let y = SRGB_TO_XYZ_MATRIX.row(1).dot(rgb);
+    if y <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let t = y / (y - min);
+    (y + t * (rgb - y)).max(Vec3::ZERO)
+}
+
+impl Transfer {
+    pub fn encode(self, linear: f32) -> f32 {
+        match self {
+            Transfer::Srgb => egui::ecolor::gamma_from_linear(linear),
+            Transfer::Gamma22 => linear.max(0.0).powf(1.0 / 2.2),
+            Transfer::Linear => linear,
+        }
+    }
+}
+
+/// Luminance statistics of an image shared by the tonemappers' default settings.
+#[derive(Clone, Copy, Debug)]
+pub struct LuminanceStats {
+    /// Log-average of the luminance, ignoring pixels darker than `1e-4`.
+    pub log_average: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl LuminanceStats {
+    pub fn new(img: &Image<Vec3>) -> Self {
+        let mut total_log_lum = 1e-4f32.ln();
+        let mut count = 1.0;
+        let mut min = f32::INFINITY;
+        let mut max = f32::NEG_INFINITY;
+        for xyz in &img.data {
+            if xyz.y > 1e-4 {
+                total_log_lum += xyz.y.ln();
+                count += 1.0;
+            }
+            min = min.min(xyz.y);
+            max = max.max(xyz.y);
+        }
+
+        LuminanceStats {
+            log_average: (total_log_lum / count).exp(),
+            min,
+            max,
+        }
+    }
+}
+
+static SRGB_TO_XYZ_MATRIX: LazyLock<Mat3> = LazyLock::new(|| {
+    Mat3::from_cols_array_2d(&[
+        [0.4124, 0.3576, 0.1805],
+        [0.2126, 0.7152, 0.0722],
+        [0.0193, 0.1192, 0.9505],
+    ])
+    .transpose()
+});
+
 fn xyz_to_srgb_linear(xyz: Vec3) -> Vec3 {
-    static XYZ_TO_SRGB_MATRIX: LazyLock<Mat3> = LazyLock::new(|| {
-        Mat3::from_cols_array_2d(&[
-            [0.4124, 0.3576, 0.1805],
-            [0.2126, 0.7152, 0.0722],
-            [0.0193, 0.1192, 0.9505],
-        ])
-        .transpose()
-        .inverse()
-    });
+    static XYZ_TO_SRGB_MATRIX: LazyLock<Mat3> = LazyLock::new(|| SRGB_TO_XYZ_MATRIX.inverse());
 
     *XYZ_TO_SRGB_MATRIX * xyz
 }
 
+pub fn srgb_to_xyz(srgb: Vec3) -> Vec3 {
+    *SRGB_TO_XYZ_MATRIX * srgb.map(egui::ecolor::linear_from_gamma)
+}
+
 #[derive(Clone)]
 struct DefaultValueSlider {
     value: f32,
@@ -150,3 +422,118 @@ impl DefaultValueSlider {
         needs_update
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Luminances from black through to bright, with some pixels under the `1e-4` cutoff.
+    fn test_image() -> Image<Vec3> {
+        Image::new(9, 7, |x, y| {
+            let y_lum = match (x + y) % 5 {
+                0 => 0.0,
+                1 => 5e-5,
+                _ => (x as f32 - 3.0).exp2() * (y as f32 + 1.0),
+            };
+            Vec3::new(0.9 * y_lum, y_lum, 1.1 * y_lum)
+        })
+    }
+
+    #[test]
+    fn shared_stats_match_the_tonemappers_own_loops() {
+        let image = test_image();
+        let stats = LuminanceStats::new(&image);
+
+        // what none and krawczyk_2005 each computed for themselves before sharing the stats
+        let mut total_log_lum = 1e-4f32.ln();
+        let mut count = 1.0;
+        for xyz in &image.data {
+            if xyz.y > 1e-4 {
+                total_log_lum += xyz.y.ln();
+                count += 1.0;
+            }
+        }
+        assert_eq!(stats.log_average, (total_log_lum / count).exp());
+
+        let luminances = || image.data.iter().map(|xyz| xyz.y);
+        assert_eq!(stats.min, luminances().fold(f32::INFINITY, f32::min));
+        assert_eq!(stats.max, luminances().fold(f32::NEG_INFINITY, f32::max));
+
+        // and none divides by it
+        let mapped = none::Options::new(&stats).process(&image, 1.0);
+        for y in 0..7 {
+            for x in 0..9 {
+                let expected = xyz_to_srgb_linear(image[(x, y)] / stats.log_average);
+                assert_eq!(mapped[(x, y)], expected.extend(1.0), "pixel ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn one_ev_doubles_the_luminance() {
+        let display = |ev| DisplaySettings::new(ev, None, GamutMapping::Clip, Transfer::Linear);
+        let (base, brighter) = (display(0.0).exposure(), display(1.0).exposure());
+        assert_eq!(brighter, 2.0 * base);
+
+        // the exposure scales the luminance going into the tonemapper, which for none is linear
+        let image = test_image();
+        let none = none::Options::new(&LuminanceStats::new(&image));
+        let base = none.clone().process(&image, base);
+        let brighter = none.process(&image, brighter);
+        for (base, brighter) in base.data.iter().zip(&brighter.data) {
+            assert_eq!(brighter.truncate(), 2.0 * base.truncate());
+        }
+    }
+
+    #[test]
+    fn doubling_the_shutter_doubles_the_exposure() {
+        let camera = |shutter_seconds| CameraExposure {
+            iso: 200.0,
+            shutter_seconds,
+            f_stop: 8.0,
+        };
+        let (fast, slow) = (camera(1.0 / 250.0), camera(1.0 / 125.0));
+        assert!((slow.scale() / fast.scale() - 2.0).abs() < 1e-12);
+        assert!((fast.ev100() - slow.ev100() - 1.0).abs() < 1e-12);
+
+        let display =
+            |camera| DisplaySettings::new(0.0, Some(camera), GamutMapping::Clip, Transfer::Linear);
+        let (fast, slow) = (display(fast).exposure(), display(slow).exposure());
+        assert!((slow / fast - 2.0).abs() < 1e-6, "{slow} / {fast}");
+
+        let image = test_image();
+        let none = none::Options::new(&LuminanceStats::new(&image));
+        let fast = none.clone().process(&image, fast);
+        let slow = none.process(&image, slow);
+        for (fast, slow) in fast.data.iter().zip(&slow.data) {
+            assert!(
+                slow.truncate()
+                    .abs_diff_eq(2.0 * fast.truncate(), 1e-6 * slow.length()),
+                "{slow} with twice the shutter time of {fast}"
+            );
+        }
+    }
+
+    #[test]
+    fn desaturating_keeps_out_of_gamut_colors_nonnegative() {
+        // monochromatic 520 nm light, far outside sRGB, and a noisy pixel with negative luminance
+        let green = xyz_to_srgb_linear(Vec3::new(0.0633, 0.71, 0.0782));
+        let negative = xyz_to_srgb_linear(Vec3::new(0.1, -0.05, 0.2));
+        assert!(green.x < 0.0);
+        assert_eq!(GamutMapping::Clip.apply(green), green);
+
+        let mapped = GamutMapping::Desaturate.apply(green);
+        assert!(mapped.min_element() >= 0.0, "{green} mapped to {mapped}");
+        assert_eq!(mapped.x, 0.0);
+        let luminance = |rgb| SRGB_TO_XYZ_MATRIX.row(1).dot(rgb);
+        assert!((luminance(mapped) - luminance(green)).abs() < 1e-5);
+        assert!(mapped.y > mapped.z);
+        assert_eq!(GamutMapping::Desaturate.apply(negative), Vec3::ZERO);
+
+        let display = DisplaySettings::new(0.0, None, GamutMapping::Desaturate, Transfer::Srgb);
+        let image = Image::new(2, 1, |x, _| [green, negative][x].extend(1.0));
+        for p in display.present(image).data {
+            assert!(p.min_element() >= 0.0, "presented {p}");
+        }
+    }
+}
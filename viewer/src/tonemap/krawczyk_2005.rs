@@ -3,7 +3,7 @@ use glam::{Vec3, Vec4};
 
 use crate::Image;
 
-use super::DefaultValueSlider;
+use super::{DefaultValueSlider, LuminanceStats};
 
 #[derive(Clone)]
 pub struct Options {
@@ -13,16 +13,8 @@ pub struct Options {
 }
 
 impl Options {
-    pub fn new(img: &Image<Vec3>) -> Self {
-        let mut total_log_lum = 1e-4f32.ln();
-        let mut count = 1.0;
-        for xyz in &img.data {
-            if xyz.y > 1e-4 {
-                total_log_lum += xyz.y.ln();
-                count += 1.0;
-            }
-        }
-        let avg_luminance = (total_log_lum / count).exp();
+    pub fn new(stats: &LuminanceStats) -> Self {
+        let avg_luminance = stats.log_average;
 
         let key_value = 1.03 - 2.0 / (2.0 + (avg_luminance + 1.0).log10());
 
@@ -41,19 +33,22 @@ impl Options {
             .changed();
     }
 
-    pub fn process(self, image: &Image<Vec3>) -> Image<Vec4> {
-        Image::new(image.width, image.height, |x, y| {
+    pub fn process(self, image: &Image<Vec3>, exposure: f32) -> Image<Vec4> {
+        Image::par_new(image.width, image.height, |x, y| {
+            // rod vision depends on how bright the scene actually is, so the scotopic term sees
+            // the luminance before the exposure and EV adjustments
             let xyz = image[(x, y)];
+            let scotopic = match self.scotopic {
+                true => 0.04 / (0.04 + xyz.y.max(1e-6)),
+                false => 0.0,
+            };
+
+            let xyz = xyz * exposure;
             let y = xyz.y.max(1e-6);
 
             let y_r = self.key_value.value * y / self.adapting_luminance.value;
             let l = y_r / (1.0 + y_r);
 
-            let scotopic = match self.scotopic {
-                true => 0.04 / (0.04 + y),
-                false => 0.0,
-            };
-
             let rgb = super::xyz_to_srgb_linear(xyz);
             let rgb_l =
                 (l / y) * (1.0 - scotopic) * rgb + scotopic * l * Vec3::new(1.05, 0.97, 1.27);
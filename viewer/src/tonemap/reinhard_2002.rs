@@ -0,0 +1,215 @@
+//! The local operator of Reinhard et al. 2002, "Photographic Tone Reproduction for Digital
+//! Images", which dodges and burns each pixel by the average luminance of the largest
+//! neighborhood around it without strong contrast. Bright spots like the sun are compressed on
+//! their own instead of darkening the whole image.
+
+use egui::Ui;
+use glam::{Vec3, Vec4};
+
+use crate::Image;
+
+use super::{DefaultValueSlider, LuminanceStats};
+
+/// Number of center-surround scales compared per pixel, from 1 pixel up to `1.6^(SCALES - 1)`.
+const SCALES: usize = 8;
+
+/// Ratio between consecutive scales, which is also the ratio of the surround to the center.
+const SCALE_STEP: f32 = 1.6;
+
+#[derive(Clone)]
+pub struct Options {
+    adapting_luminance: DefaultValueSlider,
+    key_value: DefaultValueSlider,
+    sharpening: DefaultValueSlider,
+    threshold: DefaultValueSlider,
+}
+
+impl Options {
+    pub fn new(stats: &LuminanceStats) -> Self {
+        Options {
+            adapting_luminance: DefaultValueSlider::new(stats.log_average, 1e-4..=1e8, true),
+            key_value: DefaultValueSlider::new(0.18, 0.0..=1.0, false),
+            sharpening: DefaultValueSlider::new(8.0, 1.0..=20.0, false),
+            threshold: DefaultValueSlider::new(0.05, 1e-3..=0.5, true),
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, needs_update: &mut bool) {
+        *needs_update |= self.adapting_luminance.show(ui, "Adapting Luminance");
+        *needs_update |= self.key_value.show(ui, "Key Value");
+        *needs_update |= self.sharpening.show(ui, "Sharpening");
+        *needs_update |= self.threshold.show(ui, "Contrast Threshold");
+    }
+
+    pub fn process(self, image: &Image<Vec3>, exposure: f32) -> Image<Vec4> {
+        let key = self.key_value.value;
+        let scale = exposure * key / self.adapting_luminance.value;
+        let luminance = Image::par_new(image.width, image.height, |x, y| {
+            (image[(x, y)].y * scale).max(0.0)
+        });
+
+        // the surround of each scale is the center of the next, so one more blur covers them all
+        let pyramid = pyramid(&luminance);
+        let blurred: Vec<_> = (0..=SCALES)
+            .map(|i| gaussian(&pyramid, center_sigma(SCALE_STEP.powi(i as i32))))
+            .collect();
+        let sharpening = self.sharpening.value.exp2() * key;
+
+        Image::par_new(image.width, image.height, |x, y| {
+            let mut adaptation = blurred[0][(x, y)];
+            for i in 0..SCALES {
+                let s = SCALE_STEP.powi(i as i32);
+                let center = blurred[i][(x, y)];
+                let surround = blurred[i + 1][(x, y)];
+                let contrast = (center - surround) / (sharpening / (s * s) + center);
+                if contrast.abs() >= self.threshold.value {
+                    break;
+                }
+                adaptation = center;
+            }
+
+            let xyz = image[(x, y)] * exposure;
+            let l = luminance[(x, y)] / (1.0 + adaptation);
+            let rgb = super::xyz_to_srgb_linear(xyz);
+            (rgb * l / xyz.y.max(1e-6)).extend(1.0)
+        })
+    }
+
+    pub fn set_adapting_luminance(&mut self, adapting_luminance: f32) {
+        self.adapting_luminance.value = adapting_luminance;
+    }
+}
+
+/// Standard deviation in pixels of the center profile at scale `s`, which the paper gives as
+/// `exp(-r² / (α s)²)` with `α = 1 / (2√2)`.
+fn center_sigma(s: f32) -> f32 {
+    s / 4.0
+}
+
+/// `image` followed by successive halvings, each blurred by the binomial filter `[1 4 6 4 1] / 16`
+/// before dropping every other pixel, down to a single pixel. Pixel `i` of level `k` is centered
+/// on pixel `i * 2^k` of `image`, and level `k` is `image` blurred with variance `(4^k - 1) / 3`.
+fn pyramid(image: &Image<f32>) -> Vec<Image<f32>> {
+    const BINOMIAL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+
+    let mut levels = vec![image.clone()];
+    loop {
+        let last = levels.last().unwrap();
+        if last.width == 1 && last.height == 1 {
+            return levels;
+        }
+        let rows = convolve(last, &BINOMIAL, true);
+        let both = convolve(&rows, &BINOMIAL, false);
+        let level = Image::par_new(last.width.div_ceil(2), last.height.div_ceil(2), |x, y| {
+            both[(x * 2, y * 2)]
+        });
+        levels.push(level);
+    }
+}
+
+/// The base of `pyramid` blurred by a gaussian with standard deviation `sigma`. Wide blurs start
+/// from a coarser level, which already has most of the blur, and are upsampled bilinearly.
+fn gaussian(pyramid: &[Image<f32>], sigma: f32) -> Image<f32> {
+    let level = (0..pyramid.len())
+        .take_while(|&k| (1 << k) as f32 <= sigma / 2.0)
+        .last()
+        .unwrap_or(0);
+    let done = ((4f32.powi(level as i32) - 1.0) / 3.0).sqrt();
+    let residual = (sigma * sigma - done * done).max(0.0).sqrt() / (1 << level) as f32;
+
+    let radius = (residual * 3.0).ceil() as isize;
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|i| (-(i * i) as f32 / (2.0 * residual * residual)).exp())
+        .collect();
+    let total: f32 = kernel.iter().sum();
+    let kernel: Vec<f32> = kernel.iter().map(|w| w / total).collect();
+
+    let coarse = &pyramid[level];
+    let blurred = convolve(&convolve(coarse, &kernel, true), &kernel, false);
+    if level == 0 {
+        return blurred;
+    }
+
+    let base = &pyramid[0];
+    let step = (1 << level) as f32;
+    Image::par_new(base.width, base.height, |x, y| {
+        bilinear(&blurred, x as f32 / step, y as f32 / step)
+    })
+}
+
+/// Convolves each row (or column) of `image` with the odd-length `kernel`, repeating the pixels
+/// at the edges.
+fn convolve(image: &Image<f32>, kernel: &[f32], horizontal: bool) -> Image<f32> {
+    let radius = (kernel.len() / 2) as isize;
+    let offset = |i: usize, d: isize, len: usize| (i as isize + d).clamp(0, len as isize - 1);
+    Image::par_new(image.width, image.height, |x, y| {
+        kernel
+            .iter()
+            .zip(-radius..)
+            .map(|(w, d)| {
+                let (sx, sy) = match horizontal {
+                    true => (offset(x, d, image.width) as usize, y),
+                    false => (x, offset(y, d, image.height) as usize),
+                };
+                w * image[(sx, sy)]
+            })
+            .sum()
+    })
+}
+
+fn bilinear(image: &Image<f32>, x: f32, y: f32) -> f32 {
+    let x0 = (x.floor() as usize).min(image.width - 1);
+    let y0 = (y.floor() as usize).min(image.height - 1);
+    let x1 = (x0 + 1).min(image.width - 1);
+    let y1 = (y0 + 1).min(image.height - 1);
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let top = image[(x0, y0)] * (1.0 - fx) + image[(x1, y0)] * fx;
+    let bottom = image[(x0, y1)] * (1.0 - fx) + image[(x1, y1)] * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_bright_spots_keep_the_contrast_around_them() {
+        // a checkered landscape of luminance 1 and 2, with a 2x2 sun of 10^5 in the middle
+        let landscape = |x: usize, y: usize| Vec3::splat(1.0 + ((x / 2 + y / 2) % 2) as f32);
+        let sun = |x: usize, y: usize| (31..33).contains(&x) && (31..33).contains(&y);
+        let plain = Image::new(64, 64, landscape);
+        let sunny = Image::new(64, 64, |x, y| match sun(x, y) {
+            true => Vec3::splat(1e5),
+            false => landscape(x, y),
+        });
+        let stats = LuminanceStats::new(&plain);
+        let brightness = |image: &Image<Vec3>| {
+            let mut mapper = Options::new(&stats);
+            mapper.set_adapting_luminance(stats.log_average);
+            let mapped = mapper.process(image, 1.0);
+            Image::new(64, 64, |x, y| mapped[(x, y)].truncate().dot(Vec3::ONE))
+        };
+        let (plain, sunny) = (brightness(&plain), brightness(&sunny));
+
+        // everything a few pixels from the sun looks as it does without it, checks included
+        for y in 0..64usize {
+            for x in 0..64usize {
+                if x.abs_diff(31) < 4 && y.abs_diff(31) < 4 {
+                    continue;
+                }
+                let ratio = sunny[(x, y)] / plain[(x, y)];
+                assert!(
+                    (ratio - 1.0).abs() < 0.05,
+                    "({x}, {y}) is {ratio} times as bright next to the sun"
+                );
+            }
+        }
+        let contrast = plain[(2, 0)] / plain[(0, 0)];
+        assert!(contrast > 1.5, "checks only {contrast} times brighter");
+
+        // while the sun is compressed to about white on its own, summed over red, green and blue
+        let max = sunny.data.iter().copied().fold(0.0, f32::max);
+        assert_eq!(max, sunny[(31, 31)]);
+        assert!(max < 3.5, "sun at {max}");
+    }
+}
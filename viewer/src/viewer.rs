@@ -52,7 +52,7 @@ impl Viewer {
             min_filter: wgpu::FilterMode::Linear,
             mipmap_filter: wgpu::FilterMode::Linear,
             lod_min_clamp: 0.0,
-            lod_max_clamp: 0.0,
+            lod_max_clamp: 32.0,
             compare: None,
             anisotropy_clamp: 1,
             border_color: None,
@@ -105,7 +105,7 @@ impl Viewer {
                 height,
                 depth_or_array_layers: 1,
             },
-            mip_level_count: 1,
+            mip_level_count: width.max(height).ilog2() + 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format: wgpu::TextureFormat::Rgba32Float,
@@ -208,24 +208,94 @@ impl Viewer {
     }
 
     pub fn update_image(&mut self, queue: &wgpu::Queue, updated: Image<Vec4>) {
-        queue.write_texture(
-            wgpu::TexelCopyTextureInfo {
-                texture: &self.image,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            bytemuck::cast_slice(&updated.data),
-            wgpu::TexelCopyBufferLayout {
-                offset: 0,
-                bytes_per_row: Some((updated.width * std::mem::size_of::<Vec4>()) as u32),
-                rows_per_image: Some(updated.height as u32),
-            },
-            wgpu::Extent3d {
-                width: updated.width as u32,
-                height: updated.height as u32,
-                depth_or_array_layers: 1,
-            },
-        );
+        for (level, image) in mip_chain(updated).iter().enumerate() {
+            queue.write_texture(
+                wgpu::TexelCopyTextureInfo {
+                    texture: &self.image,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                bytemuck::cast_slice(&image.data),
+                wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some((image.width * std::mem::size_of::<Vec4>()) as u32),
+                    rows_per_image: Some(image.height as u32),
+                },
+                wgpu::Extent3d {
+                    width: image.width as u32,
+                    height: image.height as u32,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+}
+
+/// `image` followed by successively halved copies of it, down to 1x1, with the sizes wgpu expects
+/// of a full mip chain. The image must be linear light, so that the box filter preserves energy.
+///
+/// Odd sizes round down, so a texel of the next level can straddle three texels, which are
+/// weighted by how much of each it covers. Nothing is read from outside the image. This matters
+/// for equal-area images, where every texel covers the same solid angle: a box filter over the
+/// square averages over solid angle, and the seam folds of the map never get mixed together.
+fn mip_chain(image: Image<Vec4>) -> Vec<Image<Vec4>> {
+    let mut chain = vec![image];
+    loop {
+        let last = chain.last().unwrap();
+        if last.width == 1 && last.height == 1 {
+            return chain;
+        }
+        let half = |n: usize| (n / 2).max(1);
+        let rows = Image::par_new(half(last.width), last.height, |x, y| {
+            box_filter(last.width, half(last.width), x, |sx| last[(sx, y)])
+        });
+        let level = Image::par_new(half(last.width), half(last.height), |x, y| {
+            box_filter(last.height, half(last.height), y, |sy| rows[(x, sy)])
+        });
+        chain.push(level);
+    }
+}
+
+/// Average of `texel` over the part of `0..from` that texel `i` of `0..to` covers.
+fn box_filter(from: usize, to: usize, i: usize, texel: impl Fn(usize) -> Vec4) -> Vec4 {
+    let scale = from as f32 / to as f32;
+    let (start, end) = (i as f32 * scale, (i + 1) as f32 * scale);
+    let mut sum = Vec4::ZERO;
+    for j in start.floor() as usize..(end.ceil() as usize).min(from) {
+        let overlap = end.min(j as f32 + 1.0) - start.max(j as f32);
+        sum += texel(j) * overlap;
+    }
+    sum / scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mips_keep_constants_and_average_checkerboards() {
+        let color = Vec4::new(0.25, 2.0, 7.5, 1.0);
+        for (width, height) in [(64, 64), (37, 21), (1000, 3)] {
+            let constant = Image::new(width, height, |_, _| color);
+            let chain = mip_chain(constant);
+            assert_eq!(chain.len() as u32, width.max(height).ilog2() + 1);
+            for (level, image) in chain.iter().enumerate() {
+                assert_eq!(
+                    (image.width, image.height),
+                    ((width >> level).max(1), (height >> level).max(1))
+                );
+                for p in &image.data {
+                    let error = ((*p - color) / color).abs().max_element();
+                    assert!(error < 1e-5, "{p} in level {level} of {width}x{height}");
+                }
+            }
+        }
+
+        let checkerboard = Image::new(64, 64, |x, y| Vec4::splat(((x + y) % 2) as f32));
+        let chain = mip_chain(checkerboard);
+        let coarsest = chain.last().unwrap();
+        assert_eq!((coarsest.width, coarsest.height), (1, 1));
+        assert!((coarsest.data[0] - Vec4::splat(0.5)).abs().max_element() < 1e-6);
     }
 }
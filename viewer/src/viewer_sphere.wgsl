@@ -27,9 +27,9 @@ fn vs_main(@builtin(vertex_index) idx: u32) -> VertexOutput {
     );
 }
 
-fn equal_area_sphere_to_square(dir: vec3f) -> vec2f {
-    const PI = 3.1415926535;
+const PI = 3.1415926535;
 
+fn equal_area_sphere_to_square(dir: vec3f) -> vec2f {
     let d = abs(dir);
     let r = sqrt(1.0 - d.y);
 
@@ -55,5 +55,13 @@ fn equal_area_sphere_to_square(dir: vec3f) -> vec2f {
 fn fs_main(inp: VertexOutput) -> @location(0) vec4<f32> {
     let d = rot * normalize(vec3(inp.texcoord * 2.0 - 1.0, 1.0));
     let tc = equal_area_sphere_to_square(d);
-    return textureSample(image, samp, tc);
+
+    // The texture coordinates jump across the folds of the map, which would make the hardware pick
+    // the smallest mip along them. Every texel covers the same solid angle, so the level can come
+    // from the solid angle this pixel covers instead, which is smooth everywhere.
+    let pixel_solid_angle = length(cross(dpdx(d), dpdy(d)));
+    let size = vec2f(textureDimensions(image));
+    let texel_solid_angle = 4.0 * PI / (size.x * size.y);
+    let lod = 0.5 * log2(pixel_solid_angle / texel_solid_angle);
+    return textureSampleLevel(image, samp, tc, lod);
 }